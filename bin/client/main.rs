@@ -146,11 +146,12 @@ async fn run() -> std::result::Result<(), ClientError> {
         .verification_strategy(opts.strategy)
         .build(&opts.server_url, token, keyring)?;
 
-    let local = bindle::provider::file::FileProvider::new(
+    let local = bindle::provider::file::FileProvider::try_new(
         &bindle_dir,
         bindle::search::NoopEngine::default(),
     )
-    .await;
+    .await
+    .map_err(map_storage_error)?;
     let cache = DumbCache::new(bindle_client.clone(), local);
 
     match opts.subcmd {
@@ -202,6 +203,15 @@ async fn run() -> std::result::Result<(), ClientError> {
             bindle_client.yank_invoice(&yank_opts.bindle_id).await?;
             println!("Bindle {} yanked", yank_opts.bindle_id);
         }
+        SubCommand::Delete(delete_opts) => {
+            bindle_client.delete_invoice(&delete_opts.bindle_id).await?;
+            // Make sure we don't keep serving the deleted bindle from the local cache
+            match cache.delete_invoice(&delete_opts.bindle_id).await {
+                Ok(_) | Err(ProviderError::NotFound) => (),
+                Err(e) => warn!("Unable to remove bindle from local cache: {}", e),
+            }
+            println!("Bindle {} deleted", delete_opts.bindle_id);
+        }
         SubCommand::Search(search_opts) => {
             // TODO: Do we want to use the cache for searching?
             let matches = bindle_client
@@ -229,10 +239,24 @@ async fn run() -> std::result::Result<(), ClientError> {
         SubCommand::Get(get_opts) => get_all(cache, get_opts).await?,
         SubCommand::Push(push_opts) => push_all(bindle_client, push_opts).await?,
         SubCommand::PushInvoice(push_opts) => {
-            let resp = bindle_client
-                .create_invoice_from_file(push_opts.path)
-                .await?;
-            println!("Invoice {} created", resp.invoice.bindle.id);
+            if push_opts.staged {
+                let resp = bindle_client
+                    .create_staged_invoice_from_file(push_opts.path)
+                    .await?;
+                match resp.missing {
+                    Some(missing) if !missing.is_empty() => println!(
+                        "Invoice {} staged. It will be published once its {} missing parcels have been pushed",
+                        resp.invoice.bindle.id,
+                        missing.len()
+                    ),
+                    _ => println!("Invoice {} created", resp.invoice.bindle.id),
+                }
+            } else {
+                let resp = bindle_client
+                    .create_invoice_from_file(push_opts.path)
+                    .await?;
+                println!("Invoice {} created", resp.invoice.bindle.id);
+            }
         }
         SubCommand::SignInvoice(sign_opts) => {
             // Role
@@ -543,9 +567,7 @@ async fn get_parcel<C: Cache + Send + Sync + Clone>(cache: C, opts: GetParcel) -
         .await?;
 
     tokio::io::copy(
-        &mut StreamReader::new(
-            parcel.map(|res| res.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))),
-        ),
+        &mut StreamReader::new(parcel.map(|res| res.map_err(std::io::Error::other))),
         &mut file,
     )
     .await?;
@@ -607,18 +629,14 @@ async fn get_all<C: Cache + Send + Sync + Clone>(cache: C, opts: Get) -> Result<
                     if is_export {
                         parcels.lock().await.insert(
                             sha,
-                            StreamReader::new(p.map(|res| {
-                                res.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
-                            })),
+                            StreamReader::new(p.map(|res| res.map_err(std::io::Error::other))),
                         );
                     }
                 }
                 Err(e) => {
                     match e {
                         ProviderError::NotFound => warn!("Parcel {} does not exist", sha),
-                        ProviderError::ProxyError(err)
-                            if matches!(err, ClientError::ParcelNotFound) =>
-                        {
+                        ProviderError::ProxyError(ClientError::ParcelNotFound) => {
                             warn!("Parcel {} does not exist", sha)
                         }
                         // Only return an error if it isn't a not found error. By design, an invoice
@@ -100,6 +100,11 @@ pub enum SubCommand {
     Get(Get),
     #[clap(name = "yank", about = "Yank an existing bindle")]
     Yank(Yank),
+    #[clap(
+        name = "delete",
+        about = "Permanently delete an existing bindle. This requires admin access to the server and cannot be undone"
+    )]
+    Delete(Delete),
     #[clap(name = "search", about = "Search for bindles")]
     Search(Search),
     #[clap(
@@ -229,6 +234,16 @@ pub struct Yank {
     pub bindle_id: String,
 }
 
+#[derive(Parser)]
+pub struct Delete {
+    #[clap(
+        index = 1,
+        value_name = "BINDLE",
+        help = "The name of the bindle, e.g. example.com/mybindle/1.2.3"
+    )]
+    pub bindle_id: String,
+}
+
 const VERSION_QUERY: &str = r#"version constraint of the bindle to search for. This is a semver range modifier that can either denote an exact version, or a range of versions.
 
 For example, the range modifier `v=1.0.0-beta.1` indicates that a version MUST match version `1.0.0-beta.1`. Version `1.0.0-beta.12` does NOT match this modifier. 
@@ -496,6 +511,11 @@ pub struct PushInvoice {
         help = "The path to the invoice TOML file"
     )]
     pub path: PathBuf,
+    #[clap(
+        long = "staged",
+        help = "Stage the invoice as a draft that is only published once all of its parcels have been pushed"
+    )]
+    pub staged: bool,
 }
 
 #[derive(Parser)]
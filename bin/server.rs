@@ -1,17 +1,33 @@
 use std::io::IsTerminal;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use std::{net::SocketAddr, path::Path};
 
 use bindle::signature::KeyRingSaver;
 use clap::Parser;
-use tracing::{debug, info, warn};
+use tracing::{debug, error, info, warn};
 
 use bindle::{
+    authz::{admin::AdminAuthorizer, anonymous_get::AnonymousGet},
     invoice::signature::{KeyRing, SignatureRole},
-    provider, search,
-    server::{server, TlsConfig},
+    provider::{
+        self,
+        compression::CompressionPolicy,
+        dynamic::DynamicProvider,
+        encryption::EncryptionKeyFile,
+        fsck::{FsckOptions, FsckReport},
+        gc::GcOptions,
+        retention::{self, RetentionOptions, RetentionRule},
+    },
+    search::{self, Search},
+    server::{
+        audit::{AuditLog, FileAuditSink, SledAuditSink},
+        quota::{QuotaConfig, QuotaTracker},
+        server, TlsConfig,
+    },
     signature::{KeyEntry, KeyRingLoader, SecretKeyFile},
-    SecretKeyEntry,
+    AuditAction, SecretKeyEntry,
 };
 
 enum AuthType {
@@ -23,6 +39,11 @@ enum AuthType {
     None,
 }
 
+/// The default number of seconds a draft invoice can wait for its missing parcels
+const DEFAULT_DRAFT_TTL: u64 = 24 * 60 * 60;
+/// The principal recorded in the audit log for invoices expired by a retention policy
+const RETENTION_PRINCIPAL: &str = "retention";
+
 const DESCRIPTION: &str = r#"
 The Bindle Server
 
@@ -106,6 +127,125 @@ struct Opts {
     #[serde(default)]
     use_embedded_db: bool,
 
+    #[clap(
+        name = "large-parcel-threshold",
+        long = "large-parcel-threshold",
+        value_name = "BYTES",
+        env = "BINDLE_LARGE_PARCEL_THRESHOLD",
+        help = "When using the embedded database, parcels larger than this size are stored as files beside the database instead of inside it [default: 4194304]"
+    )]
+    large_parcel_threshold: Option<u64>,
+
+    #[clap(
+        name = "search-index-dir",
+        long = "search-index-dir",
+        value_name = "DIRECTORY",
+        env = "BINDLE_SEARCH_INDEX_DIR",
+        help = "Keep the search index in a database in the given directory, so it survives restarts instead of being rebuilt from every invoice when the server starts. It is only rebuilt if it is out of sync with the stored invoices. The directory can only be used by one process at a time"
+    )]
+    search_index_dir: Option<PathBuf>,
+
+    #[clap(
+        name = "fsck-interval",
+        long = "fsck-interval",
+        value_name = "SECONDS",
+        env = "BINDLE_FSCK_INTERVAL",
+        help = "If set, an integrity check of all stored invoices and parcels will run in the background at the given interval. Problems are logged, but nothing is quarantined"
+    )]
+    fsck_interval: Option<u64>,
+
+    #[clap(
+        name = "compress",
+        long = "compress",
+        env = "BINDLE_COMPRESS",
+        help = "Store newly uploaded parcels compressed with zstd. Which parcels are compressed can be limited with the other --compress-* options. Existing parcels are left as they are"
+    )]
+    #[serde(default)]
+    compress: bool,
+
+    #[clap(
+        name = "compress-min-size",
+        long = "compress-min-size",
+        value_name = "BYTES",
+        env = "BINDLE_COMPRESS_MIN_SIZE",
+        help = "Parcels smaller than this size are not compressed [default: 4096]"
+    )]
+    compress_min_size: Option<u64>,
+
+    #[clap(
+        name = "compress-media-type",
+        long = "compress-media-type",
+        env = "BINDLE_COMPRESS_MEDIA_TYPES",
+        value_delimiter = ',',
+        help = "A media type of parcels to compress, such as text/plain or text/*. Can be given multiple times or as a comma separated list. If not set, parcels of all media types are compressed"
+    )]
+    #[serde(default)]
+    compress_media_types: Vec<String>,
+
+    #[clap(
+        name = "compress-level",
+        long = "compress-level",
+        env = "BINDLE_COMPRESS_LEVEL",
+        help = "The zstd compression level to use, from 1 to 21 [default: 3]"
+    )]
+    compress_level: Option<u32>,
+
+    #[clap(
+        name = "encryption-key-file",
+        long = "encryption-key-file",
+        env = "BINDLE_ENCRYPTION_KEY_FILE",
+        help = "If set, newly stored parcels are encrypted with the first key in the given key file. All keys in the file can be used to read existing parcels. Use the rotate-encryption-key command to create one"
+    )]
+    encryption_key_file: Option<PathBuf>,
+
+    #[clap(
+        name = "quota-file",
+        long = "quota-file",
+        env = "BINDLE_QUOTA_FILE",
+        help = "If set, the storage quotas in the given TOML file are enforced. Each quota limits the total parcel size and number of invoices of all bindles whose names start with a prefix"
+    )]
+    quota_file: Option<PathBuf>,
+
+    #[clap(
+        name = "audit-log-file",
+        long = "audit-log-file",
+        env = "BINDLE_AUDIT_LOG_FILE",
+        conflicts_with = "audit-db",
+        help = "If set, every invoice and parcel creation, yank, purge and login is appended to the given file as a line of JSON"
+    )]
+    audit_log_file: Option<PathBuf>,
+
+    #[clap(
+        name = "audit-db",
+        long = "audit-db",
+        env = "BINDLE_AUDIT_DB",
+        help = "If set, every invoice and parcel creation, yank, purge and login is recorded in an embedded database at the given path"
+    )]
+    audit_db: Option<PathBuf>,
+
+    #[clap(
+        name = "retention-interval",
+        long = "retention-interval",
+        value_name = "SECONDS",
+        env = "BINDLE_RETENTION_INTERVAL",
+        help = "How often the retention rules in the config file are applied in the background [default: 3600]"
+    )]
+    retention_interval: Option<u64>,
+
+    #[clap(
+        name = "draft-ttl",
+        long = "draft-ttl",
+        value_name = "SECONDS",
+        env = "BINDLE_DRAFT_TTL",
+        help = "How long an invoice created with ?staged=true can wait for its missing parcels before it is removed [default: 86400]"
+    )]
+    draft_ttl: Option<u64>,
+
+    /// Retention rules, which can only be set as `[[retention]]` tables in the config file
+    #[clap(skip)]
+    #[serde(default)]
+    retention: Vec<RetentionRule>,
+
     #[clap(
         name = "htpasswd-file",
         long = "htpasswd-file",
@@ -144,6 +284,16 @@ struct Opts {
     )]
     oidc_issuer_url: Option<String>,
 
+    #[clap(
+        name = "admin",
+        long = "admin",
+        env = "BINDLE_ADMINS",
+        value_delimiter = ',',
+        help = "A user or group that is allowed to perform administrative operations, such as permanently deleting bindles. Can be given multiple times or as a comma separated list. Has no effect when running with --unauthenticated"
+    )]
+    #[serde(default)]
+    admins: Vec<String>,
+
     #[clap(
         name = "unauthenticated",
         long = "unauthenticated",
@@ -151,6 +301,149 @@ struct Opts {
     )]
     #[serde(default)]
     unauthenticated: bool,
+
+    #[clap(subcommand)]
+    #[serde(skip)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    #[clap(
+        name = "gc",
+        about = "Removes parcels that are no longer referenced by any invoice, as well as abandoned partial uploads, and then exits"
+    )]
+    Gc(GcArgs),
+    #[clap(
+        name = "migrate",
+        about = "Copies all bindles from one storage backend to another and then exits. The server should not be running against either directory. If interrupted, run it again with the same arguments to resume"
+    )]
+    Migrate(MigrateArgs),
+    #[clap(
+        name = "fsck",
+        about = "Checks the integrity of all stored invoices and parcels and then exits. Exits with an error if any corruption is found"
+    )]
+    Fsck(FsckArgs),
+    #[clap(
+        name = "rotate-encryption-key",
+        about = "Generates a new encryption key and adds it to the front of the file given by --encryption-key-file (creating the file if needed) and then exits. Older keys are kept so parcels encrypted with them can still be read. Restart the server to start using the new key"
+    )]
+    RotateEncryptionKey(RotateEncryptionKeyArgs),
+    #[clap(
+        name = "backup",
+        about = "Writes all invoices and parcels to a tar archive and then exits. A store using the file backend can be backed up while the server is running, but the embedded database can only be opened by one process at a time"
+    )]
+    Backup(BackupArgs),
+    #[clap(
+        name = "restore",
+        about = "Restores all invoices and parcels from an archive created by the backup command and then exits. Anything that already exists is skipped. Restore an incremental backup after the backup it was based on"
+    )]
+    Restore(RestoreArgs),
+    #[clap(
+        name = "upgrade-layout",
+        about = "Converts a store using the file backend to the sharded storage layout, which stays fast with large numbers of bindles, and then exits. The server must not be running. If interrupted, run it again to finish the upgrade"
+    )]
+    UpgradeLayout,
+    #[clap(
+        name = "reindex",
+        about = "Rebuilds the search index in the directory given by --search-index-dir from every stored invoice and then exits. The server must not be running"
+    )]
+    Reindex,
+}
+
+#[derive(clap::Args)]
+struct GcArgs {
+    #[clap(
+        long = "dry-run",
+        help = "Report what would be removed without removing anything"
+    )]
+    dry_run: bool,
+
+    #[clap(
+        long = "grace-period",
+        value_name = "SECONDS",
+        default_value_t = provider::gc::DEFAULT_GRACE_PERIOD.as_secs(),
+        help = "How long a partial upload must be untouched before it is considered abandoned"
+    )]
+    grace_period: u64,
+}
+
+#[derive(clap::Args)]
+struct FsckArgs {
+    #[clap(
+        long = "quarantine",
+        help = "Move corrupted parcels and invalid invoices into a quarantine area so they are no longer served"
+    )]
+    quarantine: bool,
+}
+
+#[derive(clap::Args)]
+struct RotateEncryptionKeyArgs {
+    #[clap(
+        long = "id",
+        value_name = "ID",
+        help = "A unique ID for the new key [default: the current UNIX timestamp]"
+    )]
+    id: Option<String>,
+}
+
+#[derive(clap::Args)]
+struct BackupArgs {
+    #[clap(value_name = "ARCHIVE", help = "The path to write the archive to")]
+    archive: PathBuf,
+
+    #[clap(
+        long = "since",
+        value_name = "ARCHIVE",
+        help = "Take an incremental backup that leaves out all parcels in the given earlier backup"
+    )]
+    since: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct RestoreArgs {
+    #[clap(value_name = "ARCHIVE", help = "The archive to restore from")]
+    archive: PathBuf,
+}
+
+#[derive(clap::Args)]
+struct MigrateArgs {
+    #[clap(
+        long = "from",
+        value_name = "BACKEND:DIR",
+        help = "The storage to copy bindles from, e.g. file:/var/lib/bindle"
+    )]
+    from: StorageLocation,
+
+    #[clap(
+        long = "to",
+        value_name = "BACKEND:DIR",
+        help = "The storage to copy bindles to, e.g. embedded:/var/lib/bindle-db"
+    )]
+    to: StorageLocation,
+}
+
+/// A storage backend and the directory it stores its data in, given as `file:<dir>` or
+/// `embedded:<dir>`
+#[derive(Clone, Debug)]
+enum StorageLocation {
+    File(PathBuf),
+    Embedded(PathBuf),
+}
+
+impl std::str::FromStr for StorageLocation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("file", dir)) if !dir.is_empty() => Ok(StorageLocation::File(dir.into())),
+            Some(("embedded", dir)) if !dir.is_empty() => Ok(StorageLocation::Embedded(dir.into())),
+            _ => anyhow::bail!(
+                "Invalid storage location {}. Expected file:<dir> or embedded:<dir>",
+                s
+            ),
+        }
+    }
 }
 
 #[tokio::main]
@@ -175,6 +468,17 @@ async fn main() -> anyhow::Result<()> {
             .join("bindle")
     });
 
+    if let Some(command) = config.command {
+        return run_command(
+            command,
+            &bindle_directory,
+            config.use_embedded_db,
+            config.encryption_key_file.as_deref(),
+            config.search_index_dir.as_deref(),
+        )
+        .await;
+    }
+
     let keyring_file: PathBuf = config
         .keyring_file
         .unwrap_or_else(|| default_config_dir().join("keyring.toml"));
@@ -226,9 +530,76 @@ async fn main() -> anyhow::Result<()> {
 
     let strategy = config.verification_strategy.unwrap_or_default();
 
+    let compression = config.compress.then(|| {
+        let defaults = CompressionPolicy::default();
+        CompressionPolicy {
+            min_size: config.compress_min_size.unwrap_or(defaults.min_size),
+            media_types: config.compress_media_types,
+            level: config.compress_level.unwrap_or(defaults.level),
+        }
+    });
+    if let Some(policy) = compression.as_ref() {
+        info!(?policy, "Compressing newly stored parcels");
+    }
+    let encryption = load_encryption_keys(config.encryption_key_file.as_deref()).await?;
+    if let Some(keys) = encryption.as_ref() {
+        // The unwrap is safe as loading the key file validates that it isn't empty
+        info!(key_id = %keys.active().unwrap().id, "Encrypting newly stored parcels");
+    }
+
+    let quota_config = match config.quota_file {
+        Some(path) => QuotaConfig::load_file(&path)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to load quota file {}: {}", path.display(), e))?,
+        None => QuotaConfig::default(),
+    };
+    if !quota_config.quota.is_empty() {
+        info!(
+            quotas = quota_config.quota.len(),
+            "Enforcing storage quotas"
+        );
+    }
+
+    let audit = match (config.audit_log_file, config.audit_db) {
+        (Some(_), Some(_)) => {
+            anyhow::bail!("Only one of an audit log file or an audit database can be set")
+        }
+        (Some(path), None) => {
+            info!(path = %path.display(), "Writing audit log to file");
+            AuditLog::new(FileAuditSink::new(&path).await.map_err(|e| {
+                anyhow::anyhow!("Failed to open audit log file {}: {}", path.display(), e)
+            })?)
+        }
+        (None, Some(path)) => {
+            info!(path = %path.display(), "Writing audit log to embedded database");
+            AuditLog::new(SledAuditSink::new(&path).await.map_err(|e| {
+                anyhow::anyhow!("Failed to open audit database {}: {}", path.display(), e)
+            })?)
+        }
+        (None, None) => AuditLog::default(),
+    };
+
+    let retention_rules = config.retention;
+    for rule in retention_rules.iter() {
+        rule.validate()?;
+    }
+    let retention_interval = config
+        .retention_interval
+        .unwrap_or(retention::DEFAULT_RETENTION_INTERVAL.as_secs());
+    let draft_ttl = config.draft_ttl.unwrap_or(DEFAULT_DRAFT_TTL);
+
     tracing::info!("Using verification strategy of {:?}", strategy);
 
-    let index = search::StrictEngine::default();
+    // Like the provider, the search engine is picked at runtime and erased into a trait object
+    let index: Arc<dyn Search + Send + Sync> = match config.search_index_dir {
+        Some(dir) => {
+            info!(path = %dir.display(), "Using persistent search index");
+            Arc::new(search::PersistentEngine::open(&dir).await.map_err(|e| {
+                anyhow::anyhow!("Failed to open search index {}: {}", dir.display(), e)
+            })?)
+        }
+        None => Arc::new(search::StandardEngine::default()),
+    };
     let secret_store = SecretKeyFile::load_file(&signing_keys).await.map_err(|e| {
         anyhow::anyhow!(
             "Failed to load secret key file from {}: {} HINT: Try the flag --signing-keys",
@@ -252,11 +623,11 @@ async fn main() -> anyhow::Result<()> {
         bindle_directory.display()
     );
 
-    let auth_method = if config.oidc_client_id.is_some() {
+    let auth_method = if let Some(oidc_client_id) = config.oidc_client_id {
         // We can unwrap safely here because Clap checks that all args exist and we already
         // checked that one of them exists
         AuthType::Oidc(
-            config.oidc_client_id.unwrap(),
+            oidc_client_id,
             config.oidc_issuer_url.unwrap(),
             config.oidc_device_url.unwrap(),
         )
@@ -270,19 +641,72 @@ async fn main() -> anyhow::Result<()> {
         );
     };
 
-    // TODO: This is really gnarly, but the associated type on `Authenticator` makes turning it into
-    // a Boxed dynner really difficult. I also tried rolling our own type erasure and ran into
+    // The provider is picked at runtime and erased into a `DynamicProvider` so that the rest of
+    // the setup only has to be written once. Anything that isn't part of the `Provider` trait (like
+    // fsck) has to be set up before that
+    let store = if config.use_embedded_db {
+        warn!("Using EmbeddedProvider. This is currently experimental");
+        let mut store =
+            provider::embedded::EmbeddedProvider::new(&bindle_directory, index.clone()).await?;
+        if let Some(threshold) = config.large_parcel_threshold {
+            store = store.with_blob_threshold(threshold);
+        }
+        if let Some(policy) = compression {
+            store = store.with_compression(policy);
+        }
+        if let Some(keys) = encryption {
+            store = store.with_encryption(keys);
+        }
+        if let Some(interval) = config.fsck_interval {
+            let store = store.clone();
+            spawn_fsck(interval, move || {
+                let store = store.clone();
+                async move { store.fsck(FsckOptions::default()).await }
+            });
+        }
+        DynamicProvider::new(store)
+    } else {
+        info!("Using FileProvider");
+        let mut store =
+            provider::file::FileProvider::try_new(&bindle_directory, index.clone()).await?;
+        if let Err(e) = store.recover().await {
+            warn!(error = %e, "Error recovering from interrupted writes");
+        }
+        if let Some(policy) = compression {
+            store = store.with_compression(policy);
+        }
+        if let Some(keys) = encryption {
+            store = store.with_encryption(keys);
+        }
+        if let Some(interval) = config.fsck_interval {
+            let store = store.clone();
+            spawn_fsck(interval, move || {
+                let store = store.clone();
+                async move { store.fsck(FsckOptions::default()).await }
+            });
+        }
+        DynamicProvider::new(store)
+    };
+
+    let quotas = QuotaTracker::new(quota_config, &store).await?;
+    if !retention_rules.is_empty() {
+        spawn_retention(
+            retention_interval,
+            retention_rules,
+            store.clone(),
+            quotas.clone(),
+            audit.clone(),
+        );
+    }
+    spawn_draft_expiry(draft_ttl, store.clone());
+
+    // TODO: This is still a bit gnarly, but the associated type on `Authenticator` makes turning it
+    // into a Boxed dynner really difficult. I also tried rolling our own type erasure and ran into
     // similar issues (though I think it could be fixed, it would be a lot of code). So we might
-    // have to resort to some sort of dependency injection here. The same goes for providers as the
-    // methods have generic parameters
-    match (config.use_embedded_db, auth_method) {
-        // Embedded DB and oidc auth
-        (true, AuthType::Oidc(client_id, issuer, token_url)) => {
-            warn!("Using EmbeddedProvider. This is currently experimental");
+    // have to resort to some sort of dependency injection here
+    match auth_method {
+        AuthType::Oidc(client_id, issuer, token_url) => {
             info!("Using OIDC token authentication");
-            let store =
-                provider::embedded::EmbeddedProvider::new(&bindle_directory, index.clone()).await?;
-
             let authn =
                 bindle::authn::oidc::OidcAuthenticator::new(&issuer, &token_url, &client_id)
                     .await?;
@@ -290,59 +714,36 @@ async fn main() -> anyhow::Result<()> {
                 store,
                 index,
                 authn,
-                bindle::authz::anonymous_get::AnonymousGet,
+                AdminAuthorizer::new(AnonymousGet, config.admins),
                 addr,
                 tls,
                 secret_store,
                 strategy,
                 keyring,
+                quotas,
+                audit,
             )
             .await
         }
-        // Embedded DB and no auth
-        (true, AuthType::None) => {
-            warn!("Using EmbeddedProvider. This is currently experimental");
-            let store =
-                provider::embedded::EmbeddedProvider::new(&bindle_directory, index.clone()).await?;
-            server(
-                store,
-                index,
-                bindle::authn::always::AlwaysAuthenticate,
-                bindle::authz::always::AlwaysAuthorize,
-                addr,
-                tls,
-                secret_store,
-                strategy,
-                keyring,
-            )
-            .await
-        }
-        // File system and oidc auth
-        (false, AuthType::Oidc(client_id, issuer, token_url)) => {
-            info!("Using FileProvider");
-            info!("Using OIDC token authentication");
-            let store = provider::file::FileProvider::new(&bindle_directory, index.clone()).await;
-
-            let authn =
-                bindle::authn::oidc::OidcAuthenticator::new(&issuer, &token_url, &client_id)
-                    .await?;
+        AuthType::HttpBasic(filename) => {
+            info!("Auth mode: HTTP Basic Auth");
+            let authn = bindle::authn::http_basic::HttpBasic::from_file(filename).await?;
             server(
                 store,
                 index,
                 authn,
-                bindle::authz::anonymous_get::AnonymousGet,
+                AdminAuthorizer::new(AnonymousGet, config.admins),
                 addr,
                 tls,
                 secret_store,
                 strategy,
                 keyring,
+                quotas,
+                audit,
             )
             .await
         }
-        // File system and no GH auth
-        (false, AuthType::None) => {
-            info!("Using FileProvider");
-            let store = provider::file::FileProvider::new(&bindle_directory, index.clone()).await;
+        AuthType::None => {
             server(
                 store,
                 index,
@@ -353,49 +754,532 @@ async fn main() -> anyhow::Result<()> {
                 secret_store,
                 strategy,
                 keyring,
+                quotas,
+                audit,
             )
             .await
         }
-        // DB with HttpBasic
-        (true, AuthType::HttpBasic(filename)) => {
-            warn!("Using EmbeddedProvider. This is currently experimental");
-            info!("Auth mode: HTTP Basic Auth");
-            let store =
-                provider::embedded::EmbeddedProvider::new(&bindle_directory, index.clone()).await?;
-            let authn = bindle::authn::http_basic::HttpBasic::from_file(filename).await?;
-            server(
-                store,
-                index,
-                authn,
-                bindle::authz::anonymous_get::AnonymousGet,
-                addr,
-                tls,
-                secret_store,
-                strategy,
-                keyring,
-            )
-            .await
+    }
+}
+
+/// Runs the given administrative command against the bindles stored in the given directory
+async fn run_command(
+    command: Command,
+    bindle_directory: &Path,
+    use_embedded_db: bool,
+    encryption_key_file: Option<&Path>,
+    search_index_dir: Option<&Path>,
+) -> anyhow::Result<()> {
+    match command {
+        Command::Gc(args) => {
+            let options = GcOptions {
+                dry_run: args.dry_run,
+                grace_period: Duration::from_secs(args.grace_period),
+            };
+            // The search index isn't needed for any administrative commands
+            let index = search::NoopEngine::default();
+            let report = if use_embedded_db {
+                provider::embedded::EmbeddedProvider::new(bindle_directory, index)
+                    .await?
+                    .gc(options)
+                    .await?
+            } else {
+                provider::file::FileProvider::try_new(bindle_directory, index)
+                    .await?
+                    .gc(options)
+                    .await?
+            };
+
+            let verb = if report.dry_run {
+                "Would remove"
+            } else {
+                "Removed"
+            };
+            for parcel in report.removed_parcels.iter() {
+                println!("{} parcel {}", verb, parcel);
+            }
+            for part in report.removed_part_files.iter() {
+                println!("{} part file {}", verb, part.display());
+            }
+            println!(
+                "{} {} unreferenced parcels and {} part files ({} bytes). {} parcels are still referenced",
+                verb,
+                report.removed_parcels.len(),
+                report.removed_part_files.len(),
+                report.reclaimed_bytes,
+                report.live_parcels
+            );
         }
-        // File system with HttpBasic
-        (false, AuthType::HttpBasic(filename)) => {
-            info!("Using FileProvider");
-            info!("Auth mode: HTTP Basic Auth");
-            let authn = bindle::authn::http_basic::HttpBasic::from_file(filename).await?;
-            let store = provider::file::FileProvider::new(&bindle_directory, index.clone()).await;
-            server(
-                store,
-                index,
-                authn,
-                bindle::authz::anonymous_get::AnonymousGet,
-                addr,
-                tls,
-                secret_store,
-                strategy,
-                keyring,
-            )
-            .await
+        Command::Fsck(args) => {
+            let options = FsckOptions {
+                quarantine: args.quarantine,
+            };
+            // Encrypted parcels can only be checked with their keys
+            let encryption = load_encryption_keys(encryption_key_file).await?;
+            // The search index isn't needed for any administrative commands
+            let index = search::NoopEngine::default();
+            let report = if use_embedded_db {
+                let mut store =
+                    provider::embedded::EmbeddedProvider::new(bindle_directory, index).await?;
+                if let Some(keys) = encryption {
+                    store = store.with_encryption(keys);
+                }
+                store.fsck(options).await?
+            } else {
+                let mut store =
+                    provider::file::FileProvider::try_new(bindle_directory, index).await?;
+                if let Some(keys) = encryption {
+                    store = store.with_encryption(keys);
+                }
+                store.fsck(options).await?
+            };
+
+            let suffix = if report.quarantined {
+                " (quarantined)"
+            } else {
+                ""
+            };
+            for parcel in report.corrupt_parcels.iter() {
+                println!("Corrupt parcel {}{}", parcel, suffix);
+            }
+            for invoice in report.unreadable_invoices.iter() {
+                println!("Unreadable invoice {}{}", invoice, suffix);
+            }
+            for invoice in report.misnamed_invoices.iter() {
+                println!(
+                    "Invoice not stored under its canonical name {}{}",
+                    invoice, suffix
+                );
+            }
+            for (id, parcel) in report.size_mismatches.iter() {
+                println!(
+                    "Size of parcel {} does not match label in invoice {}",
+                    parcel, id
+                );
+            }
+            for (id, parcel) in report.missing_parcels.iter() {
+                println!("Parcel {} for invoice {} has not been uploaded", parcel, id);
+            }
+            println!(
+                "Checked {} invoices and {} parcels",
+                report.checked_invoices, report.checked_parcels
+            );
+            if !report.is_clean() {
+                anyhow::bail!("Integrity check found problems")
+            }
         }
+        Command::Migrate(args) => {
+            use provider::{embedded::EmbeddedProvider, file::FileProvider};
+            use StorageLocation::*;
+
+            // The same keys are used to read encrypted parcels from the source and to encrypt
+            // them in the destination
+            let encryption = load_encryption_keys(encryption_key_file).await?;
+            macro_rules! with_keys {
+                ($store:expr) => {
+                    match encryption.clone() {
+                        Some(keys) => $store.with_encryption(keys),
+                        None => $store,
+                    }
+                };
+            }
+
+            // The search index isn't needed for any administrative commands
+            let index = search::NoopEngine::default();
+            match (args.from, args.to) {
+                (File(from), File(to)) => {
+                    let source = with_keys!(FileProvider::try_new(from, index.clone()).await?);
+                    migrate(
+                        &source,
+                        &with_keys!(FileProvider::try_new(to, index).await?),
+                    )
+                    .await?
+                }
+                (File(from), Embedded(to)) => {
+                    let source = with_keys!(FileProvider::try_new(from, index.clone()).await?);
+                    migrate(
+                        &source,
+                        &with_keys!(EmbeddedProvider::new(to, index).await?),
+                    )
+                    .await?
+                }
+                (Embedded(from), File(to)) => {
+                    let source = with_keys!(EmbeddedProvider::new(from, index.clone()).await?);
+                    migrate(
+                        &source,
+                        &with_keys!(FileProvider::try_new(to, index).await?),
+                    )
+                    .await?
+                }
+                (Embedded(from), Embedded(to)) => {
+                    let source = with_keys!(EmbeddedProvider::new(from, index.clone()).await?);
+                    migrate(
+                        &source,
+                        &with_keys!(EmbeddedProvider::new(to, index).await?),
+                    )
+                    .await?
+                }
+            }
+        }
+        Command::RotateEncryptionKey(args) => {
+            let path = encryption_key_file.ok_or_else(|| {
+                anyhow::anyhow!("The rotate-encryption-key command requires --encryption-key-file")
+            })?;
+            let mut keys = match tokio::fs::metadata(path).await {
+                Ok(_) => EncryptionKeyFile::load_file(path).await?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => EncryptionKeyFile::default(),
+                Err(e) => anyhow::bail!("failed to read file {}: {}", path.display(), e),
+            };
+            let id = args.id.unwrap_or_else(|| {
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs()
+                    .to_string()
+            });
+            keys.rotate(&id)?;
+            keys.save_file(path).await?;
+            println!(
+                "Added encryption key {} to {}. It will be used to encrypt parcels stored from now on",
+                id,
+                path.display()
+            );
+        }
+        Command::Backup(args) => {
+            let base = match args.since {
+                Some(path) => {
+                    let file = tokio::fs::File::open(&path).await.map_err(|e| {
+                        anyhow::anyhow!("Unable to open archive {}: {}", path.display(), e)
+                    })?;
+                    Some(provider::backup::read_manifest(file).await.map_err(|e| {
+                        anyhow::anyhow!("Unable to read archive {}: {}", path.display(), e)
+                    })?)
+                }
+                None => None,
+            };
+            // Write to a temporary file first so an interrupted backup doesn't leave behind
+            // something that looks like a complete archive
+            let mut partial = args.archive.clone().into_os_string();
+            partial.push(".part");
+            let partial = PathBuf::from(partial);
+            let file = tokio::fs::File::create(&partial).await?;
+
+            // Encrypted parcels are decrypted so the archive can be restored without the keys
+            let encryption = load_encryption_keys(encryption_key_file).await?;
+            // The search index isn't needed for any administrative commands
+            let index = search::NoopEngine::default();
+            let report = if use_embedded_db {
+                let mut store =
+                    provider::embedded::EmbeddedProvider::new(bindle_directory, index).await?;
+                if let Some(keys) = encryption {
+                    store = store.with_encryption(keys);
+                }
+                provider::backup::backup(&store, file, base.as_ref()).await?
+            } else {
+                let mut store =
+                    provider::file::FileProvider::try_new(bindle_directory, index).await?;
+                if let Some(keys) = encryption {
+                    store = store.with_encryption(keys);
+                }
+                provider::backup::backup(&store, file, base.as_ref()).await?
+            };
+            tokio::fs::rename(&partial, &args.archive).await?;
+
+            println!(
+                "Backed up {} invoices and {} parcels ({} bytes) to {}",
+                report.invoices,
+                report.parcels,
+                report.bytes,
+                args.archive.display()
+            );
+            if report.skipped_parcels > 0 {
+                println!(
+                    "Left out {} parcels that are in the earlier backup",
+                    report.skipped_parcels
+                );
+            }
+            if report.absent_parcels > 0 {
+                println!(
+                    "{} referenced parcels were never uploaded and were not backed up",
+                    report.absent_parcels
+                );
+            }
+            for failure in report.failures.iter() {
+                println!("Error: {}", failure);
+            }
+            if !report.is_complete() {
+                anyhow::bail!(
+                    "Backup finished with errors. The archive is missing the parcels listed above"
+                )
+            }
+        }
+        Command::Restore(args) => {
+            let file = tokio::fs::File::open(&args.archive).await.map_err(|e| {
+                anyhow::anyhow!("Unable to open archive {}: {}", args.archive.display(), e)
+            })?;
+            // Restored parcels are encrypted the same way as newly uploaded ones
+            let encryption = load_encryption_keys(encryption_key_file).await?;
+            // The search index isn't needed for any administrative commands
+            let index = search::NoopEngine::default();
+            let report = if use_embedded_db {
+                let mut store =
+                    provider::embedded::EmbeddedProvider::new(bindle_directory, index).await?;
+                if let Some(keys) = encryption {
+                    store = store.with_encryption(keys);
+                }
+                provider::backup::restore(&store, file).await?
+            } else {
+                let mut store =
+                    provider::file::FileProvider::try_new(bindle_directory, index).await?;
+                if let Some(keys) = encryption {
+                    store = store.with_encryption(keys);
+                }
+                provider::backup::restore(&store, file).await?
+            };
+
+            println!(
+                "Restored {} invoices and {} parcels ({} bytes). Skipped {} invoices and {} parcels that already existed",
+                report.restored_invoices,
+                report.restored_parcels,
+                report.restored_bytes,
+                report.skipped_invoices,
+                report.skipped_parcels
+            );
+            for failure in report.failures.iter() {
+                println!("Error: {}", failure);
+            }
+            for id in report.missing_invoices.iter() {
+                println!("Missing invoice {}", id);
+            }
+            for id in report.yank_mismatches.iter() {
+                println!("Yanked status does not match for invoice {}", id);
+            }
+            for (id, parcel) in report.missing_parcels.iter() {
+                println!("Missing parcel {} for invoice {}", parcel, id);
+            }
+            if !report.is_consistent() {
+                anyhow::bail!("Restore finished with errors. If this is an incremental backup, restore the backup it was based on first")
+            }
+            println!("Restore complete. All invoices and parcels are consistent");
+        }
+        Command::UpgradeLayout => {
+            if use_embedded_db {
+                anyhow::bail!("The upgrade-layout command only applies to the file backend")
+            }
+            let report = provider::file::upgrade_layout(bindle_directory).await?;
+            if report.previous == provider::file::Layout::Sharded {
+                println!("Store already uses the sharded layout");
+            } else {
+                println!(
+                    "Moved {} invoices, drafts and parcels into shards. Store now uses the sharded layout",
+                    report.moved
+                );
+            }
+        }
+        Command::Reindex => {
+            let dir = search_index_dir.ok_or_else(|| {
+                anyhow::anyhow!("The reindex command requires --search-index-dir to be set")
+            })?;
+            let index = search::PersistentEngine::open(dir).await.map_err(|e| {
+                anyhow::anyhow!("Failed to open search index {}: {}", dir.display(), e)
+            })?;
+            // Providers rebuild a cleared index from every stored invoice when they are created
+            index.clear().await?;
+            if use_embedded_db {
+                provider::embedded::EmbeddedProvider::new(bindle_directory, index.clone()).await?;
+            } else {
+                provider::file::FileProvider::try_new(bindle_directory, index.clone()).await?;
+            }
+            // The generation is only recorded once every invoice was indexed
+            if index.generation().await?.is_none() {
+                anyhow::bail!("Unable to rebuild the search index. See the log for details")
+            }
+            let matches = index
+                .query(
+                    "",
+                    "",
+                    search::SearchOptions {
+                        yanked: true,
+                        ..Default::default()
+                    },
+                )
+                .await?;
+            println!("Rebuilt search index with {} invoices", matches.total);
+        }
+    }
+    Ok(())
+}
+
+/// Loads the encryption key file at the given path, if one is configured
+async fn load_encryption_keys(path: Option<&Path>) -> anyhow::Result<Option<EncryptionKeyFile>> {
+    let path = match path {
+        Some(p) => p,
+        None => return Ok(None),
+    };
+    let keys = EncryptionKeyFile::load_file(path).await.map_err(|e| {
+        anyhow::anyhow!(
+            "Failed to load encryption key file from {}: {}",
+            path.display(),
+            e
+        )
+    })?;
+    Ok(Some(keys))
+}
+
+/// Spawns a background task that runs the given integrity check every `interval` seconds and logs
+/// any problems found
+fn spawn_fsck<F, Fut>(interval: u64, fsck: F)
+where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = provider::Result<FsckReport>> + Send,
+{
+    info!(interval, "Running integrity checks in the background");
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+        // The first tick completes immediately, so skip it to avoid slowing down startup
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            match fsck().await {
+                Ok(report) if report.is_clean() => {
+                    info!(
+                        checked_invoices = report.checked_invoices,
+                        checked_parcels = report.checked_parcels,
+                        "Integrity check found no problems"
+                    )
+                }
+                Ok(report) => error!(
+                    corrupt_parcels = ?report.corrupt_parcels,
+                    unreadable_invoices = ?report.unreadable_invoices,
+                    misnamed_invoices = ?report.misnamed_invoices,
+                    size_mismatches = ?report.size_mismatches,
+                    "Integrity check found problems. Run the fsck command to quarantine them"
+                ),
+                Err(e) => error!(error = %e, "Unable to run integrity check"),
+            }
+        }
+    });
+}
+
+/// Spawns a background task that removes draft invoices that are more than `ttl` seconds old
+fn spawn_draft_expiry<P>(ttl: u64, store: P)
+where
+    P: provider::Provider + Send + Sync + 'static,
+{
+    let ttl = Duration::from_secs(ttl);
+    tokio::spawn(async move {
+        // Check often enough that drafts don't outlive their TTL by much
+        let mut ticker =
+            tokio::time::interval(ttl.clamp(Duration::from_secs(1), Duration::from_secs(60 * 60)));
+        loop {
+            ticker.tick().await;
+            match store.expire_draft_invoices(ttl).await {
+                Ok(expired) if expired.is_empty() => (),
+                Ok(expired) => info!(?expired, "Removed expired draft invoices"),
+                Err(e) => error!(error = %e, "Unable to remove expired draft invoices"),
+            }
+        }
+    });
+}
+
+/// Spawns a background task that applies the given retention rules every `interval` seconds.
+/// Every yanked and deleted invoice is recorded in the audit log, and deleted invoices are removed
+/// from the storage usage of quotas
+fn spawn_retention<P>(
+    interval: u64,
+    rules: Vec<RetentionRule>,
+    store: P,
+    quotas: QuotaTracker,
+    audit: AuditLog,
+) where
+    P: provider::Provider + Send + Sync + 'static,
+{
+    info!(
+        interval,
+        rules = rules.len(),
+        "Applying retention rules in the background"
+    );
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+        loop {
+            ticker.tick().await;
+            match retention::apply_retention(&store, &rules, RetentionOptions::default()).await {
+                Ok(report) => {
+                    if !report.failures.is_empty() {
+                        error!(failures = ?report.failures, "Unable to apply some retention rules");
+                    }
+                    for inv in report.deleted_invoices.iter() {
+                        quotas.record_deleted_invoice(inv);
+                    }
+                    let expired = report
+                        .yanked
+                        .iter()
+                        .map(|id| (AuditAction::YankInvoice, id))
+                        .chain(
+                            report
+                                .deleted
+                                .iter()
+                                .map(|id| (AuditAction::DeleteInvoice, id)),
+                        );
+                    for (action, id) in expired {
+                        audit
+                            .record(
+                                RETENTION_PRINCIPAL.to_owned(),
+                                action,
+                                Some(id.to_string()),
+                                None,
+                                warp::http::StatusCode::OK,
+                            )
+                            .await;
+                    }
+                }
+                Err(e) => error!(error = %e, "Unable to apply retention rules"),
+            }
+        }
+    });
+}
+
+/// Migrates all bindles from the source to the destination, printing a report of what was copied
+/// and any inconsistencies found afterwards
+async fn migrate<S, D>(source: &S, dest: &D) -> anyhow::Result<()>
+where
+    S: provider::Provider + Sync,
+    D: provider::Provider + Sync,
+{
+    let report = provider::migrate::migrate(source, dest).await?;
+
+    println!(
+        "Copied {} invoices and {} parcels ({} bytes). Skipped {} invoices and {} parcels that already existed",
+        report.copied_invoices,
+        report.copied_parcels,
+        report.copied_bytes,
+        report.skipped_invoices,
+        report.skipped_parcels
+    );
+    if report.absent_parcels > 0 {
+        println!(
+            "{} referenced parcels were never uploaded to the source and were not copied",
+            report.absent_parcels
+        );
+    }
+    for failure in report.failures.iter() {
+        println!("Error: {}", failure);
+    }
+    for id in report.missing_invoices.iter() {
+        println!("Missing invoice {}", id);
+    }
+    for id in report.yank_mismatches.iter() {
+        println!("Yanked status does not match for invoice {}", id);
+    }
+    for (id, parcel) in report.missing_parcels.iter() {
+        println!("Missing parcel {} for invoice {}", parcel, id);
+    }
+
+    if !report.is_consistent() {
+        anyhow::bail!("Migration finished with errors. Fix the errors above and run the migration again to resume")
     }
+    println!("Migration complete. All invoices and parcels are consistent");
+    Ok(())
 }
 
 fn default_config_file() -> Option<PathBuf> {
@@ -486,6 +1370,11 @@ async fn merged_opts() -> anyhow::Result<Opts> {
         config_file: opts.config_file,
         htpasswd_file: opts.htpasswd_file.or(config.htpasswd_file),
         unauthenticated: opts.unauthenticated || config.unauthenticated,
+        admins: if opts.admins.is_empty() {
+            config.admins
+        } else {
+            opts.admins
+        },
         key_path: opts.key_path.or(config.key_path),
         keyring_file: opts.keyring_file.or(config.keyring_file),
         oidc_client_id: opts.oidc_client_id.or(config.oidc_client_id),
@@ -493,7 +1382,28 @@ async fn merged_opts() -> anyhow::Result<Opts> {
         oidc_issuer_url: opts.oidc_issuer_url.or(config.oidc_issuer_url),
         signing_file: opts.signing_file.or(config.signing_file),
         use_embedded_db: opts.use_embedded_db || config.use_embedded_db,
+        large_parcel_threshold: opts
+            .large_parcel_threshold
+            .or(config.large_parcel_threshold),
+        search_index_dir: opts.search_index_dir.or(config.search_index_dir),
+        fsck_interval: opts.fsck_interval.or(config.fsck_interval),
+        compress: opts.compress || config.compress,
+        compress_min_size: opts.compress_min_size.or(config.compress_min_size),
+        compress_media_types: if opts.compress_media_types.is_empty() {
+            config.compress_media_types
+        } else {
+            opts.compress_media_types
+        },
+        compress_level: opts.compress_level.or(config.compress_level),
+        encryption_key_file: opts.encryption_key_file.or(config.encryption_key_file),
+        quota_file: opts.quota_file.or(config.quota_file),
+        audit_log_file: opts.audit_log_file.or(config.audit_log_file),
+        audit_db: opts.audit_db.or(config.audit_db),
+        retention_interval: opts.retention_interval.or(config.retention_interval),
+        draft_ttl: opts.draft_ttl.or(config.draft_ttl),
+        retention: config.retention,
         verification_strategy: opts.verification_strategy.or(config.verification_strategy),
+        command: opts.command,
     })
 }
 
@@ -131,7 +131,7 @@ impl OidcAuthenticator {
                 if !k
                     .common
                     .public_key_use
-                    .map_or(false, |u| matches!(u, PublicKeyUse::Signature))
+                    .is_some_and(|u| matches!(u, PublicKeyUse::Signature))
                 {
                     return None;
                 }
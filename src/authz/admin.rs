@@ -0,0 +1,93 @@
+//! An authorizer that allows a configured list of users to perform administrative operations,
+//! delegating all other authorization decisions to another authorizer
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use warp::http::Method;
+
+use super::{Authorizable, Authorizer};
+
+/// An authorizer that wraps another authorizer and allows the configured admins to perform
+/// administrative operations. An admin can be given as either a principal or a group name
+#[derive(Clone)]
+pub struct AdminAuthorizer<T> {
+    inner: T,
+    admins: Arc<HashSet<String>>,
+}
+
+impl<T> AdminAuthorizer<T> {
+    /// Returns a new authorizer that delegates all non-administrative authorization to the given
+    /// authorizer and allows the given principals or groups to perform administrative operations
+    pub fn new<I: IntoIterator<Item = String>>(inner: T, admins: I) -> Self {
+        AdminAuthorizer {
+            inner,
+            admins: Arc::new(admins.into_iter().collect()),
+        }
+    }
+}
+
+impl<T: Authorizer> Authorizer for AdminAuthorizer<T> {
+    fn authorize<A: Authorizable>(
+        &self,
+        item: A,
+        path: &str,
+        method: &Method,
+    ) -> anyhow::Result<()> {
+        self.inner.authorize(item, path, method)
+    }
+
+    fn authorize_admin<A: Authorizable>(&self, item: A) -> anyhow::Result<()> {
+        let principal = item.principal();
+        // An empty principal would mean this is anonymous
+        if principal.is_empty() {
+            anyhow::bail!("Anonymous users cannot perform administrative operations")
+        }
+        if self.admins.contains(&principal) || item.groups().iter().any(|g| self.admins.contains(g))
+        {
+            Ok(())
+        } else {
+            anyhow::bail!("{} is not an admin", principal)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::authz::always::Anonymous;
+    use crate::authz::anonymous_get::AnonymousGet;
+
+    struct User(&'static str, Vec<String>);
+
+    impl Authorizable for User {
+        fn principal(&self) -> String {
+            self.0.to_owned()
+        }
+
+        fn groups(&self) -> Vec<String> {
+            self.1.clone()
+        }
+    }
+
+    #[test]
+    fn test_authorize_admin() {
+        let authz = AdminAuthorizer::new(
+            AnonymousGet,
+            vec!["alice".to_owned(), "bindle-admins".to_owned()],
+        );
+
+        assert!(authz.authorize_admin(User("alice", vec![])).is_ok());
+        assert!(authz
+            .authorize_admin(User("bob", vec!["bindle-admins".to_owned()]))
+            .is_ok());
+        assert!(authz
+            .authorize_admin(User("mallory", vec!["users".to_owned()]))
+            .is_err());
+        assert!(authz.authorize_admin(Anonymous).is_err());
+
+        // Normal authorization should be delegated
+        assert!(authz.authorize(Anonymous, "/v1/_i", &Method::GET).is_ok());
+        assert!(authz.authorize(Anonymous, "/v1/_i", &Method::POST).is_err());
+    }
+}
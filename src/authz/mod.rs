@@ -1,6 +1,7 @@
 //! Types and traits for use in authorization. This module is only available if the `server` feature
 //! is enabled
 
+pub mod admin;
 pub mod always;
 pub mod anonymous_get;
 
@@ -15,6 +16,17 @@ pub trait Authorizable {
     fn groups(&self) -> Vec<String>;
 }
 
+/// Allows an item to be authorized more than once without taking ownership of it
+impl<T: Authorizable + ?Sized> Authorizable for &T {
+    fn principal(&self) -> String {
+        (**self).principal()
+    }
+
+    fn groups(&self) -> Vec<String> {
+        (**self).groups()
+    }
+}
+
 /// A trait for any system that can authorize any [`Authorizable`](Authorizable) type
 // TODO: Will this need to be async?
 pub trait Authorizer {
@@ -27,4 +39,11 @@ pub trait Authorizer {
         path: &str,
         method: &warp::http::Method,
     ) -> anyhow::Result<()>;
+
+    /// Checks whether or not the given item is allowed to perform administrative operations (such
+    /// as permanently deleting a bindle), returning a failure reason in the case where the item is
+    /// not authorized. By default, no one is allowed to perform administrative operations
+    fn authorize_admin<A: Authorizable>(&self, _item: A) -> anyhow::Result<()> {
+        anyhow::bail!("Administrative operations are not allowed")
+    }
 }
@@ -95,6 +95,25 @@ where
         self.local.yank_invoice(id).await
     }
 
+    #[instrument(level = "trace", skip(self, id))]
+    async fn delete_invoice<I>(&self, id: I) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        // This only removes the invoice from the local cache
+        self.local.delete_invoice(id).await
+    }
+
+    // In a cache implementation, this only lists what has been stored in the local provider
+    #[instrument(level = "trace", skip(self))]
+    async fn list_invoices(
+        &self,
+        include_yanked: bool,
+    ) -> Result<Box<dyn Stream<Item = Result<Id>> + Unpin + Send + Sync>> {
+        self.local.list_invoices(include_yanked).await
+    }
+
     async fn create_parcel<I, R, B>(&self, _: I, _: &str, _: R) -> Result<()>
     where
         I: TryInto<Id> + Send,
@@ -138,7 +157,7 @@ where
                         // This isn't my favorite. Right now we are mapping to an io error which will be mapped back to a storage error
                         .map(|res| {
                             res.map_err(|e| {
-                                std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
+                                std::io::Error::other(e.to_string())
                             })
                         });
                     // Attempt to insert the parcel into the store, if it fails, warn the user and
@@ -156,7 +175,7 @@ where
                             self.remote.get_parcel(&parsed_id, parcel_id).await?
                         }
                     };
-                    Ok(Box::new(stream.map(|res| res.map_err(ProviderError::from))))
+                    Ok(stream)
                 }.instrument(tracing::trace_span!("get_parcel_cache_miss", invoice_id = %parsed_id, parcel_id)).await
             }
         }
@@ -171,4 +190,85 @@ where
     {
         self.local.parcel_exists(bindle_id, parcel_id).await
     }
+
+    #[instrument(level = "trace", skip(self))]
+    async fn has_parcel(&self, parcel_id: &str) -> Result<bool> {
+        match self.local.has_parcel(parcel_id).await {
+            Ok(true) => Ok(true),
+            // Only some parcels are cached, so anything else needs to be checked with the remote
+            _ => self.remote.has_parcel(parcel_id).await,
+        }
+    }
+
+    #[instrument(level = "trace", skip(self, parcel_ids), fields(num_parcels = parcel_ids.len()))]
+    async fn parcels_exist(&self, parcel_ids: &[&str]) -> Result<Vec<bool>> {
+        let mut exists = match self.local.parcels_exist(parcel_ids).await {
+            Ok(e) => e,
+            Err(e) => {
+                debug!(error = %e, "Unable to check local cache for parcels, checking remote");
+                vec![false; parcel_ids.len()]
+            }
+        };
+        crate::provider::fill_parcels_exist(&self.remote, parcel_ids, &mut exists).await?;
+        Ok(exists)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::provider::file::FileProvider;
+    use crate::search::NoopEngine;
+    use crate::testing;
+    use crate::verification::NoopVerified;
+    use crate::NoopSigned;
+
+    use tokio_util::codec::{BytesCodec, FramedRead};
+
+    #[tokio::test]
+    async fn test_parcels_exist() {
+        let remote_dir = tempfile::tempdir().unwrap();
+        let local_dir = tempfile::tempdir().unwrap();
+        let remote = FileProvider::new(remote_dir.path(), NoopEngine::default()).await;
+        let local = FileProvider::new(local_dir.path(), NoopEngine::default()).await;
+
+        let scaffold = testing::Scaffold::load("lotsa_parcels").await;
+        let id = scaffold.invoice.bindle.id.clone();
+        let mut parcels = scaffold.parcel_files.values();
+        let cached = parcels.next().unwrap();
+        let uncached = parcels.next().unwrap();
+        let missing = parcels.next().unwrap();
+
+        // The remote has both parcels, but only one of them has made it into the cache
+        for (store, stored) in [(&remote, vec![cached, uncached]), (&local, vec![cached])] {
+            store
+                .create_invoice(NoopSigned(NoopVerified(scaffold.invoice.clone())))
+                .await
+                .expect("create invoice");
+            for parcel in stored {
+                store
+                    .create_parcel(
+                        &id,
+                        &parcel.sha,
+                        FramedRead::new(
+                            std::io::Cursor::new(parcel.data.clone()),
+                            BytesCodec::new(),
+                        ),
+                    )
+                    .await
+                    .expect("create parcel");
+            }
+        }
+
+        let cache = DumbCache::new(remote, local);
+        let exists = cache
+            .parcels_exist(&[
+                missing.sha.as_str(),
+                cached.sha.as_str(),
+                uncached.sha.as_str(),
+            ])
+            .await
+            .expect("Should be able to check parcels");
+        assert_eq!(exists, vec![false, true, true]);
+    }
 }
@@ -86,7 +86,7 @@ where
         I::Error: Into<ProviderError>,
     {
         let parsed_id = id.try_into().map_err(|e| e.into())?;
-        tracing::span::Span::current().record("invoice_id", &tracing::field::display(&parsed_id));
+        tracing::span::Span::current().record("invoice_id", tracing::field::display(&parsed_id));
         trace!("Checking for invoice in cache");
         let mut invoices = self.invoices.lock().await;
         match invoices.get(&parsed_id) {
@@ -112,12 +112,34 @@ where
     {
         // Delete the invoice from the local cache as it will be no longer valid
         let parsed_id = id.try_into().map_err(|e| e.into())?;
-        tracing::span::Span::current().record("invoice_id", &tracing::field::display(&parsed_id));
+        tracing::span::Span::current().record("invoice_id", tracing::field::display(&parsed_id));
         debug!("Removing local cache entry for yanked invoice");
         self.invoices.lock().await.pop(&parsed_id);
         self.remote.yank_invoice(parsed_id).await
     }
 
+    #[instrument(level = "trace", skip(self, id), fields(invoice_id))]
+    async fn delete_invoice<I>(&self, id: I) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id = id.try_into().map_err(|e| e.into())?;
+        tracing::span::Span::current().record("invoice_id", tracing::field::display(&parsed_id));
+        debug!("Removing local cache entry for deleted invoice");
+        self.invoices.lock().await.pop(&parsed_id);
+        self.remote.delete_invoice(parsed_id).await
+    }
+
+    // The cache only holds a subset of invoices, so this always goes to the remote
+    #[instrument(level = "trace", skip(self))]
+    async fn list_invoices(
+        &self,
+        include_yanked: bool,
+    ) -> Result<Box<dyn Stream<Item = Result<Id>> + Unpin + Send + Sync>> {
+        self.remote.list_invoices(include_yanked).await
+    }
+
     #[instrument(level = "trace", skip(self, bindle_id, data), fields(invoice_id))]
     async fn create_parcel<I, R, B>(&self, bindle_id: I, parcel_id: &str, data: R) -> Result<()>
     where
@@ -127,7 +149,7 @@ where
         B: bytes::Buf + Send,
     {
         let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
-        tracing::span::Span::current().record("invoice_id", &tracing::field::display(&parsed_id));
+        tracing::span::Span::current().record("invoice_id", tracing::field::display(&parsed_id));
         self.validate_parcel(&parsed_id, parcel_id).await?;
         debug!("Passing through create parcel request to remote");
         self.remote.create_parcel(parsed_id, parcel_id, data).await
@@ -144,7 +166,7 @@ where
         I::Error: Into<ProviderError>,
     {
         let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
-        tracing::span::Span::current().record("invoice_id", &tracing::field::display(&parsed_id));
+        tracing::span::Span::current().record("invoice_id", tracing::field::display(&parsed_id));
         // TODO: Should we be worrying about checking the parcel exists in the invoice here? As
         // this is a cache, the remote it fetches from should cover this, but I could also see
         // someone misusing this by fetching from a parcel they have access to and then being
@@ -174,7 +196,7 @@ where
                     let mut file = File::from_std(handle);
                     tokio::io::copy(
                         &mut StreamReader::new(stream.map(|res| {
-                            res.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+                            res.map_err(std::io::Error::other)
                         })),
                         &mut file,
                     )
@@ -207,7 +229,7 @@ where
         I::Error: Into<ProviderError>,
     {
         let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
-        tracing::span::Span::current().record("invoice_id", &tracing::field::display(&parsed_id));
+        tracing::span::Span::current().record("invoice_id", tracing::field::display(&parsed_id));
         self.validate_parcel(&parsed_id, parcel_id).await?;
         let parcels = self.parcels.lock().await;
         // For some reason we can't just used the borrowed string here because I think it is
@@ -220,6 +242,34 @@ where
             self.remote.parcel_exists(&parsed_id, parcel_id).instrument(tracing::trace_span!("parcel_exists_cache_miss", invoice_id = %parsed_id, parcel_id)).await
         }
     }
+
+    #[instrument(level = "trace", skip(self))]
+    async fn has_parcel(&self, parcel_id: &str) -> Result<bool> {
+        if self.parcels.lock().await.contains(&parcel_id.to_owned()) {
+            trace!("Parcel exists in cache, returning");
+            Ok(true)
+        } else {
+            debug!("Parcel does not exist in cache, checking remote");
+            self.remote.has_parcel(parcel_id).await
+        }
+    }
+
+    #[instrument(level = "trace", skip(self, parcel_ids), fields(num_parcels = parcel_ids.len()))]
+    async fn parcels_exist(&self, parcel_ids: &[&str]) -> Result<Vec<bool>> {
+        let mut exists: Vec<bool> = {
+            let parcels = self.parcels.lock().await;
+            parcel_ids
+                .iter()
+                .map(|id| parcels.contains(&id.to_string()))
+                .collect()
+        };
+        trace!(
+            cached = exists.iter().filter(|e| **e).count(),
+            "Checked cache for parcels"
+        );
+        crate::provider::fill_parcels_exist(&self.remote, parcel_ids, &mut exists).await?;
+        Ok(exists)
+    }
 }
 
 #[cfg(test)]
@@ -237,9 +287,14 @@ mod test {
         get_yanked_count: Arc<Mutex<u8>>,
         get_parcel_count: Arc<Mutex<u8>>,
         parcel_exists_count: Arc<Mutex<u8>>,
+        has_parcel_count: Arc<Mutex<u8>>,
+        // Like a provider that only implements the required methods if this isn't set
+        supports_has_parcel: bool,
 
         create_invoice_called: Arc<Mutex<bool>>,
         yank_invoice_called: Arc<Mutex<bool>>,
+        delete_invoice_called: Arc<Mutex<bool>>,
+        list_invoices_called: Arc<Mutex<bool>>,
         create_parcel_called: Arc<Mutex<bool>>,
     }
 
@@ -282,6 +337,25 @@ mod test {
             Ok(())
         }
 
+        async fn delete_invoice<I>(&self, _id: I) -> Result<()>
+        where
+            I: TryInto<Id> + Send,
+            I::Error: Into<ProviderError>,
+        {
+            let mut called = self.delete_invoice_called.lock().await;
+            *called = true;
+            Ok(())
+        }
+
+        async fn list_invoices(
+            &self,
+            _include_yanked: bool,
+        ) -> Result<Box<dyn Stream<Item = Result<Id>> + Unpin + Send + Sync>> {
+            let mut called = self.list_invoices_called.lock().await;
+            *called = true;
+            Ok(Box::new(tokio_stream::empty()))
+        }
+
         async fn create_parcel<I, R, B>(
             &self,
             _bindle_id: I,
@@ -331,6 +405,15 @@ mod test {
             *count += 1;
             Ok(true)
         }
+
+        async fn has_parcel(&self, _parcel_id: &str) -> Result<bool> {
+            if !self.supports_has_parcel {
+                return Err(ProviderError::Unsupported);
+            }
+            let mut count = self.has_parcel_count.lock().await;
+            *count += 1;
+            Ok(false)
+        }
     }
 
     #[tokio::test]
@@ -415,6 +498,55 @@ mod test {
         )
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_parcels_exist() {
+        let provider = TestProvider {
+            supports_has_parcel: true,
+            ..Default::default()
+        };
+        let cache = LruCache::new(10, provider.clone());
+
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let cached = scaffold.parcel_files.get("parcel").unwrap().sha.as_str();
+        let _ = cache
+            .get_parcel(&scaffold.invoice.bindle.id, cached)
+            .await
+            .expect("Should be able to get parcel");
+
+        let exists = cache
+            .parcels_exist(&[cached, "not-a-sha"])
+            .await
+            .expect("Should be able to check parcels");
+        assert_eq!(exists, vec![true, false]);
+
+        let num_called = provider.has_parcel_count.lock().await;
+        assert_eq!(
+            1, *num_called,
+            "Remote store should have only been asked about the uncached parcel"
+        )
+    }
+
+    #[tokio::test]
+    async fn test_missing_parcels_without_has_parcel() {
+        // Providers that only implement the required methods should still be able to find missing
+        // parcels
+        let provider = TestProvider::default();
+        let cache = LruCache::new(10, provider.clone());
+
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let missing = crate::provider::missing_parcels(&cache, &scaffold.invoice)
+            .await
+            .expect("Should be able to find missing parcels");
+        assert!(missing.is_empty());
+
+        let num_called = provider.parcel_exists_count.lock().await;
+        assert_eq!(
+            scaffold.invoice.parcel.unwrap().len(),
+            *num_called as usize,
+            "Remote store should have been asked about each parcel"
+        )
+    }
+
     #[tokio::test]
     async fn test_passthrough() {
         // Make sure all the create operations pass through
@@ -431,6 +563,14 @@ mod test {
             .yank_invoice("enterprise.com/warpcore/1.0.0")
             .await
             .expect("Should be able to yank invoice");
+        cache
+            .delete_invoice("enterprise.com/warpcore/1.0.0")
+            .await
+            .expect("Should be able to delete invoice");
+        let _ = cache
+            .list_invoices(true)
+            .await
+            .expect("Should be able to list invoices");
         let parcel_info = scaffold.parcel_files.get("parcel").unwrap();
         cache
             .create_parcel(
@@ -452,6 +592,14 @@ mod test {
             *provider.yank_invoice_called.lock().await,
             "Remote provider should have been called for yank invoice"
         );
+        assert!(
+            *provider.delete_invoice_called.lock().await,
+            "Remote provider should have been called for delete invoice"
+        );
+        assert!(
+            *provider.list_invoices_called.lock().await,
+            "Remote provider should have been called for list invoices"
+        );
         assert!(
             *provider.create_parcel_called.lock().await,
             "Remote provider should have been called for create parcel"
@@ -27,7 +27,7 @@ pub(crate) type CacheResult<T> = Result<Option<T>, crate::provider::ProviderErro
 pub(crate) fn into_cache_result<T>(res: crate::provider::Result<T>) -> CacheResult<T> {
     match res {
         Ok(val) => Ok(Some(val)),
-        Err(e) if matches!(e, ProviderError::NotFound) => Ok(None),
+        Err(ProviderError::NotFound) => Ok(None),
         Err(e) => Err(e),
     }
 }
@@ -60,6 +60,9 @@ pub enum ClientError {
     /// A server error was encountered. Contains an optional message from the server
     #[error("Error contacting server: {}", .0.clone().unwrap_or_else(||"Protocol error. Verify the Bindle URL".to_owned()))]
     ServerError(Option<String>),
+    /// The server does not support the requested operation
+    #[error("Operation is not supported by the server")]
+    Unsupported,
     /// Invalid credentials were used or user does not have access to the requested resource. This
     /// is only valid if the server supports authentication and/or permissions
     #[error("User has invalid credentials or is not authorized to access the requested resource")]
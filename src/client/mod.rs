@@ -6,14 +6,20 @@ pub mod load;
 pub mod tokens;
 
 use std::convert::TryInto;
+use std::ops::Range;
 use std::path::Path;
 use std::sync::Arc;
 
 use reqwest::header::{self, HeaderMap};
 use reqwest::Client as HttpClient;
 use reqwest::{Body, RequestBuilder, StatusCode};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::{Stream, StreamExt};
+use tokio_util::codec::{BytesCodec, FramedRead};
 use tracing::{debug, info, instrument, trace};
+use tracing_futures::Instrument;
 use url::Url;
 
 use crate::provider::{Provider, ProviderError};
@@ -27,11 +33,20 @@ pub use error::ClientError;
 pub type Result<T> = std::result::Result<T, ClientError>;
 
 pub const INVOICE_ENDPOINT: &str = "_i";
+pub const PARCEL_ENDPOINT: &str = "_p";
 pub const QUERY_ENDPOINT: &str = "_q";
 pub const RELATIONSHIP_ENDPOINT: &str = "_r";
+pub const UPLOAD_ENDPOINT: &str = "_u";
 pub const LOGIN_ENDPOINT: &str = "login";
 pub const BINDLE_KEYS_ENDPOINT: &str = "bindle-keys";
 const TOML_MIME_TYPE: &str = "application/toml";
+/// The number of invoices requested at a time when listing invoices
+const LIST_PAGE_SIZE: u8 = u8::MAX;
+/// The maximum amount of parcel data sent in a single request of a resumable upload
+const UPLOAD_CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+/// The number of times a chunk of a resumable upload is retried without making progress before
+/// giving up
+const MAX_UPLOAD_RETRIES: u32 = 5;
 
 /// A client type for interacting with a Bindle server
 #[derive(Clone)]
@@ -186,7 +201,23 @@ impl<T: tokens::TokenManager> Client<T> {
         inv: crate::Invoice,
     ) -> Result<crate::InvoiceCreateResponse> {
         let req = self
-            .create_invoice_builder()
+            .create_invoice_builder(false)
+            .await?
+            .body(toml::to_vec(&inv)?);
+        self.create_invoice_request(req).await
+    }
+
+    /// Same as [`create_invoice`](Client::create_invoice), but the invoice is staged on the server
+    /// as a draft. A draft can't be fetched or found in queries until the last of its missing
+    /// parcels has been uploaded, at which point it is published automatically. Drafts whose
+    /// parcels are never uploaded are eventually removed by the server
+    #[instrument(level = "trace", skip(self, inv), fields(id = %inv.bindle.id))]
+    pub async fn create_staged_invoice(
+        &self,
+        inv: crate::Invoice,
+    ) -> Result<crate::InvoiceCreateResponse> {
+        let req = self
+            .create_invoice_builder(true)
             .await?
             .body(toml::to_vec(&inv)?);
         self.create_invoice_request(req).await
@@ -205,17 +236,38 @@ impl<T: tokens::TokenManager> Client<T> {
         let inv_stream = load::raw(path).await?;
         debug!("Successfully loaded invoice stream");
         let req = self
-            .create_invoice_builder()
+            .create_invoice_builder(false)
+            .await?
+            .body(Body::wrap_stream(inv_stream));
+        self.create_invoice_request(req).await
+    }
+
+    /// Same as [`create_staged_invoice`](Client::create_staged_invoice), but takes a path to an
+    /// invoice file instead
+    #[instrument(level = "trace", skip(self, file_path), fields(path = %file_path.as_ref().display()))]
+    pub async fn create_staged_invoice_from_file<P: AsRef<Path>>(
+        &self,
+        file_path: P,
+    ) -> Result<crate::InvoiceCreateResponse> {
+        let path = file_path.as_ref().to_owned();
+        debug!("Loading invoice from file");
+        let inv_stream = load::raw(path).await?;
+        let req = self
+            .create_invoice_builder(true)
             .await?
             .body(Body::wrap_stream(inv_stream));
         self.create_invoice_request(req).await
     }
 
-    async fn create_invoice_builder(&self) -> Result<RequestBuilder> {
+    async fn create_invoice_builder(&self, staged: bool) -> Result<RequestBuilder> {
         // We can unwrap here because any URL error would be programmers fault
+        let mut url = self.base_url.join(INVOICE_ENDPOINT).unwrap();
+        if staged {
+            url.set_query(Some("staged=true"));
+        }
         let req = self
             .client
-            .post(self.base_url.join(INVOICE_ENDPOINT).unwrap())
+            .post(url)
             .header(header::CONTENT_TYPE, TOML_MIME_TYPE);
         self.token_manager.apply_auth_header(req).await
     }
@@ -247,7 +299,7 @@ impl<T: tokens::TokenManager> Client<T> {
         I::Error: Into<ClientError>,
     {
         let parsed_id = id.try_into().map_err(|e| e.into())?;
-        tracing::span::Span::current().record("invoice_id", &tracing::field::display(&parsed_id));
+        tracing::span::Span::current().record("invoice_id", tracing::field::display(&parsed_id));
         self.get_invoice_request(
             self.base_url
                 .join(&format!("{}/{}", INVOICE_ENDPOINT, parsed_id))?,
@@ -263,7 +315,7 @@ impl<T: tokens::TokenManager> Client<T> {
         I::Error: Into<ClientError>,
     {
         let parsed_id = id.try_into().map_err(|e| e.into())?;
-        tracing::span::Span::current().record("invoice_id", &tracing::field::display(&parsed_id));
+        tracing::span::Span::current().record("invoice_id", tracing::field::display(&parsed_id));
         let mut url = self
             .base_url
             .join(&format!("{}/{}", INVOICE_ENDPOINT, parsed_id))?;
@@ -300,6 +352,64 @@ impl<T: tokens::TokenManager> Client<T> {
         Ok(toml::from_slice(&resp.bytes().await?)?)
     }
 
+    /// Returns a stream of the IDs of all invoices on the bindle server, optionally including yanked
+    /// invoices. This pages through the query endpoint as the stream is consumed, so it relies on
+    /// the server's search index being up to date. Must be called from within a Tokio runtime
+    pub fn list_invoices(
+        &self,
+        include_yanked: bool,
+    ) -> impl Stream<Item = Result<Id>> + Unpin + Send + Sync + 'static
+    where
+        T: Clone + Send + Sync + 'static,
+    {
+        // The pages are fetched by a separate task, as the request futures aren't `Sync`. It stops
+        // once the channel is full, so it never gets more than a page ahead of the stream
+        let (tx, rx) = mpsc::channel(LIST_PAGE_SIZE as usize);
+        let client = self.clone();
+        tokio::spawn(
+            async move {
+                let mut offset = 0;
+                loop {
+                    let matches = match client
+                        .query_invoices(crate::QueryOptions {
+                            offset: Some(offset),
+                            limit: Some(LIST_PAGE_SIZE),
+                            yanked: Some(include_yanked),
+                            ..Default::default()
+                        })
+                        .await
+                    {
+                        Ok(m) => m,
+                        Err(e) => {
+                            let _ = tx.send(Err(e)).await;
+                            return;
+                        }
+                    };
+                    let page_size = matches.invoices.len();
+                    trace!(offset, page_size, "Listed page of invoices");
+                    offset += page_size as u64;
+                    // Not every server filters out yanked invoices, so double check here
+                    for inv in matches
+                        .invoices
+                        .into_iter()
+                        .filter(|inv| include_yanked || !inv.yanked.unwrap_or(false))
+                    {
+                        if tx.send(Ok(inv.bindle.id)).await.is_err() {
+                            trace!("Invoice stream was dropped, stopping listing");
+                            return;
+                        }
+                    }
+                    if !matches.more || page_size == 0 {
+                        debug!(total = offset, "Listed invoices");
+                        return;
+                    }
+                }
+            }
+            .instrument(tracing::trace_span!("list_invoices", include_yanked)),
+        );
+        ReceiverStream::new(rx)
+    }
+
     //////////////// Yank Invoice ////////////////
 
     /// Yanks the invoice from availability on the bindle server. This can take any form that can
@@ -312,7 +422,7 @@ impl<T: tokens::TokenManager> Client<T> {
         I::Error: Into<ClientError>,
     {
         let parsed_id = id.try_into().map_err(|e| e.into())?;
-        tracing::span::Span::current().record("invoice_id", &tracing::field::display(&parsed_id));
+        tracing::span::Span::current().record("invoice_id", tracing::field::display(&parsed_id));
         let req = self.client.delete(
             self.base_url
                 .join(&format!("{}/{}", INVOICE_ENDPOINT, parsed_id))?,
@@ -324,6 +434,33 @@ impl<T: tokens::TokenManager> Client<T> {
         Ok(())
     }
 
+    //////////////// Delete Invoice ////////////////
+
+    /// Permanently deletes the invoice from the bindle server. Unlike yanking, the invoice will no
+    /// longer be retrievable in any way. This is an administrative operation, so the server will
+    /// only allow it if the current user is an admin. This can take any form that can convert into
+    /// the `Id` type, but generally speaking, this is the canonical name of the bindle (e.g.
+    /// `example.com/foo/1.0.0`)
+    #[instrument(level = "trace", skip(self, id), fields(invoice_id))]
+    pub async fn delete_invoice<I>(&self, id: I) -> Result<()>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+    {
+        let parsed_id = id.try_into().map_err(|e| e.into())?;
+        tracing::span::Span::current().record("invoice_id", tracing::field::display(&parsed_id));
+        let mut url = self
+            .base_url
+            .join(&format!("{}/{}", INVOICE_ENDPOINT, parsed_id))?;
+        url.set_query(Some("purge=true"));
+        let req = self.client.delete(url);
+        let req = self.token_manager.apply_auth_header(req).await?;
+        trace!(?req);
+        let resp = req.send().await?;
+        unwrap_status(resp, Endpoint::Invoice, Operation::Delete).await?;
+        Ok(())
+    }
+
     //////////////// Create Parcel ////////////////
 
     /// Creates the given parcel using the SHA and the raw parcel data to upload to the server.
@@ -341,7 +478,7 @@ impl<T: tokens::TokenManager> Client<T> {
         I::Error: Into<ClientError>,
     {
         let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
-        tracing::span::Span::current().record("invoice_id", &tracing::field::display(&parsed_id));
+        tracing::span::Span::current().record("invoice_id", tracing::field::display(&parsed_id));
         self.create_parcel_request(
             self.create_parcel_builder(&parsed_id, parcel_sha)
                 .await?
@@ -353,6 +490,11 @@ impl<T: tokens::TokenManager> Client<T> {
     /// Same as [`create_parcel`](Client::create_parcel), but takes a path to the parcel
     /// file. This will be more efficient for large files as it will stream the data into the body
     /// rather than taking the intermediate step of loading the bytes into a `Vec`.
+    ///
+    /// If the server supports resumable uploads, the file is sent in chunks and any chunk that
+    /// fails is resent from the last byte the server received. An upload of the same parcel that
+    /// was previously interrupted is picked up where it left off. Otherwise the file is sent in a
+    /// single request
     #[instrument(level = "trace", skip(self, bindle_id, data_path), fields(invoice_id, path = %data_path.as_ref().display()))]
     pub async fn create_parcel_from_file<D, I>(
         &self,
@@ -368,18 +510,82 @@ impl<T: tokens::TokenManager> Client<T> {
         // Copy the path to avoid lifetime issues
         let data = data_path.as_ref().to_owned();
         let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
-        tracing::span::Span::current().record("invoice_id", &tracing::field::display(&parsed_id));
-        debug!("Loading parcel data from file");
-        let stream = load::raw(data).await?;
-        debug!("Successfully loaded parcel stream");
-        let data_body = Body::wrap_stream(stream);
+        tracing::span::Span::current().record("invoice_id", tracing::field::display(&parsed_id));
+        let offset = match self.start_parcel_upload(&parsed_id, parcel_sha).await {
+            Ok(o) => o,
+            // Older servers won't have the upload endpoint at all
+            Err(ClientError::Unsupported) | Err(ClientError::ResourceNotFound) => {
+                debug!("Server does not support resumable uploads, sending parcel in one request");
+                debug!("Loading parcel data from file");
+                let stream = load::raw(data).await?;
+                debug!("Successfully loaded parcel stream");
+                let data_body = Body::wrap_stream(stream);
 
-        self.create_parcel_request(
-            self.create_parcel_builder(&parsed_id, parcel_sha)
-                .await?
-                .body(data_body),
-        )
-        .await
+                return self
+                    .create_parcel_request(
+                        self.create_parcel_builder(&parsed_id, parcel_sha)
+                            .await?
+                            .body(data_body),
+                    )
+                    .await;
+            }
+            Err(e) => return Err(e),
+        };
+        self.upload_file_chunks(&parsed_id, parcel_sha, &data, offset)
+            .await?;
+        self.finish_parcel_upload(&parsed_id, parcel_sha).await
+    }
+
+    /// Sends the parcel data in the given file to an in progress upload, starting at the given
+    /// offset. Failed chunks are retried from whatever offset the server reports
+    async fn upload_file_chunks(
+        &self,
+        bindle_id: &Id,
+        parcel_sha: &str,
+        data_path: &Path,
+        mut offset: u64,
+    ) -> Result<()> {
+        let size = tokio::fs::metadata(data_path).await?.len();
+        let mut failures = 0;
+        while offset < size {
+            let len = std::cmp::min(UPLOAD_CHUNK_SIZE, size - offset);
+            debug!(offset, len, "Uploading parcel chunk");
+            let mut file = tokio::fs::File::open(data_path).await?;
+            file.seek(std::io::SeekFrom::Start(offset)).await?;
+            let body = Body::wrap_stream(FramedRead::new(file.take(len), BytesCodec::new()));
+            let err = match self
+                .upload_chunk_request(bindle_id, parcel_sha, offset, body)
+                .await
+            {
+                Ok(new_offset) if new_offset > offset => {
+                    offset = new_offset;
+                    failures = 0;
+                    continue;
+                }
+                Ok(_) => ClientError::Other("Server did not accept any parcel data".to_owned()),
+                Err(e) => e,
+            };
+            // Connection problems, server errors, and offset conflicts can all be recovered from
+            // by asking the server how much data it has and trying again
+            let retryable = matches!(
+                err,
+                ClientError::HttpClientError(_)
+                    | ClientError::ServerError(_)
+                    | ClientError::Other(_)
+                    | ClientError::InvalidRequest {
+                        status_code: StatusCode::CONFLICT,
+                        ..
+                    }
+            );
+            failures += 1;
+            if !retryable || failures > MAX_UPLOAD_RETRIES {
+                return Err(err);
+            }
+            info!(error = %err, failures, "Parcel chunk failed to upload, retrying");
+            tokio::time::sleep(std::time::Duration::from_secs(failures.into())).await;
+            offset = self.parcel_upload_offset(bindle_id, parcel_sha).await?;
+        }
+        Ok(())
     }
 
     /// Same as [`create_parcel`](Client::create_parcel), but takes a stream of parcel data as bytes
@@ -397,7 +603,7 @@ impl<T: tokens::TokenManager> Client<T> {
         B: bytes::Buf,
     {
         let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
-        tracing::span::Span::current().record("invoice_id", &tracing::field::display(&parsed_id));
+        tracing::span::Span::current().record("invoice_id", tracing::field::display(&parsed_id));
         let map = stream.map(|res| res.map(|mut b| b.copy_to_bytes(b.remaining())));
         let data_body = Body::wrap_stream(map);
         self.create_parcel_request(
@@ -433,6 +639,148 @@ impl<T: tokens::TokenManager> Client<T> {
         Ok(())
     }
 
+    //////////////// Resumable Parcel Upload ////////////////
+
+    /// Starts a resumable upload of the given parcel, returning the number of bytes the server has
+    /// already received. If an upload of the parcel was previously started, it is resumed, so the
+    /// returned offset is where the next chunk should start.
+    ///
+    /// Most users will want [`create_parcel_from_file`](Client::create_parcel_from_file), which
+    /// handles the whole upload. Returns [`ClientError::Unsupported`] if the server does not
+    /// support resumable uploads
+    #[instrument(level = "trace", skip(self, bindle_id), fields(invoice_id))]
+    pub async fn start_parcel_upload<I>(&self, bindle_id: I, parcel_sha: &str) -> Result<u64>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        tracing::span::Span::current().record("invoice_id", tracing::field::display(&parsed_id));
+        let req = self
+            .upload_builder(reqwest::Method::POST, &parsed_id, parcel_sha, None)
+            .await?;
+        trace!(?req);
+        let resp = req.send().await?;
+        let resp = unwrap_status(resp, Endpoint::Upload, Operation::Create).await?;
+        Ok(toml::from_slice::<crate::UploadStatusResponse>(&resp.bytes().await?)?.offset)
+    }
+
+    /// Returns the number of bytes the server has received for an in progress upload of the
+    /// given parcel
+    #[instrument(level = "trace", skip(self, bindle_id), fields(invoice_id))]
+    pub async fn parcel_upload_offset<I>(&self, bindle_id: I, parcel_sha: &str) -> Result<u64>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        tracing::span::Span::current().record("invoice_id", tracing::field::display(&parsed_id));
+        let req = self
+            .upload_builder(reqwest::Method::GET, &parsed_id, parcel_sha, None)
+            .await?;
+        trace!(?req);
+        let resp = req.send().await?;
+        let resp = unwrap_status(resp, Endpoint::Upload, Operation::Get).await?;
+        Ok(toml::from_slice::<crate::UploadStatusResponse>(&resp.bytes().await?)?.offset)
+    }
+
+    /// Sends a chunk of parcel data to an in progress upload, returning the total number of bytes
+    /// the server has received. The offset must be the number of bytes the server has already
+    /// received
+    #[instrument(level = "trace", skip(self, bindle_id, stream), fields(invoice_id))]
+    pub async fn write_parcel_chunk<I, S, B>(
+        &self,
+        bindle_id: I,
+        parcel_sha: &str,
+        offset: u64,
+        stream: S,
+    ) -> Result<u64>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+        S: Stream<Item = std::io::Result<B>> + Unpin + Send + Sync + 'static,
+        B: bytes::Buf,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        tracing::span::Span::current().record("invoice_id", tracing::field::display(&parsed_id));
+        let map = stream.map(|res| res.map(|mut b| b.copy_to_bytes(b.remaining())));
+        self.upload_chunk_request(&parsed_id, parcel_sha, offset, Body::wrap_stream(map))
+            .await
+    }
+
+    /// Completes an in progress upload once all of the parcel data has been sent. The server will
+    /// verify the data against the parcel SHA before storing it
+    #[instrument(level = "trace", skip(self, bindle_id), fields(invoice_id))]
+    pub async fn finish_parcel_upload<I>(&self, bindle_id: I, parcel_sha: &str) -> Result<()>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        tracing::span::Span::current().record("invoice_id", tracing::field::display(&parsed_id));
+        let req = self
+            .upload_builder(reqwest::Method::PUT, &parsed_id, parcel_sha, None)
+            .await?;
+        trace!(?req);
+        let resp = req.send().await?;
+        unwrap_status(resp, Endpoint::Upload, Operation::Create).await?;
+        Ok(())
+    }
+
+    /// Cancels an in progress upload, discarding any data the server has received
+    #[instrument(level = "trace", skip(self, bindle_id), fields(invoice_id))]
+    pub async fn abort_parcel_upload<I>(&self, bindle_id: I, parcel_sha: &str) -> Result<()>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        tracing::span::Span::current().record("invoice_id", tracing::field::display(&parsed_id));
+        let req = self
+            .upload_builder(reqwest::Method::DELETE, &parsed_id, parcel_sha, None)
+            .await?;
+        trace!(?req);
+        let resp = req.send().await?;
+        unwrap_status(resp, Endpoint::Upload, Operation::Delete).await?;
+        Ok(())
+    }
+
+    async fn upload_chunk_request(
+        &self,
+        bindle_id: &Id,
+        parcel_sha: &str,
+        offset: u64,
+        body: Body,
+    ) -> Result<u64> {
+        let req = self
+            .upload_builder(reqwest::Method::PATCH, bindle_id, parcel_sha, Some(offset))
+            .await?
+            .body(body);
+        trace!(?req);
+        let resp = req.send().await?;
+        let resp = unwrap_status(resp, Endpoint::Upload, Operation::Upload).await?;
+        Ok(toml::from_slice::<crate::UploadStatusResponse>(&resp.bytes().await?)?.offset)
+    }
+
+    async fn upload_builder(
+        &self,
+        method: reqwest::Method,
+        bindle_id: &Id,
+        parcel_sha: &str,
+        offset: Option<u64>,
+    ) -> Result<RequestBuilder> {
+        // We can unwrap here because any URL error would be programmers fault
+        let mut url = self
+            .base_url
+            .join(&format!("{}/{}@{}", UPLOAD_ENDPOINT, bindle_id, parcel_sha))
+            .unwrap();
+        if let Some(o) = offset {
+            url.set_query(Some(&format!("offset={}", o)));
+        }
+        let req = self.client.request(method, url);
+        self.token_manager.apply_auth_header(req).await
+    }
+
     //////////////// Get Parcel ////////////////
 
     /// Returns the requested parcel (identified by its Bindle ID and SHA) as a vector of bytes
@@ -443,7 +791,7 @@ impl<T: tokens::TokenManager> Client<T> {
         I::Error: Into<ClientError>,
     {
         let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
-        tracing::span::Span::current().record("invoice_id", &tracing::field::display(&parsed_id));
+        tracing::span::Span::current().record("invoice_id", tracing::field::display(&parsed_id));
         let resp = self.get_parcel_request(&parsed_id, sha).await?;
         Ok(resp.bytes().await?.to_vec())
     }
@@ -462,12 +810,79 @@ impl<T: tokens::TokenManager> Client<T> {
         I::Error: Into<ClientError>,
     {
         let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
-        tracing::span::Span::current().record("invoice_id", &tracing::field::display(&parsed_id));
+        tracing::span::Span::current().record("invoice_id", tracing::field::display(&parsed_id));
         let resp = self.get_parcel_request(&parsed_id, sha).await?;
         Ok(resp.bytes_stream().map(|r| r.map_err(|e| e.into())))
     }
 
+    /// Returns the requested parcel (identified by its Bindle ID and SHA) as a stream of bytes,
+    /// starting at the given byte offset. This is meant for resuming a download of a large parcel
+    /// that was interrupted after `offset` bytes were already written. An offset at or past the end
+    /// of the parcel returns an empty stream.
+    ///
+    /// If the server does not support range requests, the skipped bytes are still downloaded but
+    /// are discarded before being returned
+    #[instrument(level = "trace", skip(self, bindle_id), fields(invoice_id))]
+    pub async fn get_parcel_stream_from<I>(
+        &self,
+        bindle_id: I,
+        sha: &str,
+        offset: u64,
+    ) -> Result<impl Stream<Item = Result<bytes::Bytes>>>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        tracing::span::Span::current().record("invoice_id", tracing::field::display(&parsed_id));
+        self.get_parcel_range_stream(&parsed_id, sha, offset..u64::MAX)
+            .await
+    }
+
     async fn get_parcel_request(&self, bindle_id: &Id, sha: &str) -> Result<reqwest::Response> {
+        let req = self.parcel_request_builder(bindle_id, sha).await?;
+        trace!(?req);
+        let resp = req.send().await?;
+        unwrap_status(resp, Endpoint::Parcel, Operation::Get).await
+    }
+
+    async fn get_parcel_range_stream(
+        &self,
+        bindle_id: &Id,
+        sha: &str,
+        range: Range<u64>,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>> {
+        // An empty range can't be expressed as a range header
+        if range.start >= range.end {
+            return Ok(Box::new(tokio_stream::empty()));
+        }
+        let range_header = if range.end == u64::MAX {
+            format!("bytes={}-", range.start)
+        } else {
+            format!("bytes={}-{}", range.start, range.end - 1)
+        };
+        let req = self
+            .parcel_request_builder(bindle_id, sha)
+            .await?
+            .header(header::RANGE, range_header);
+        trace!(?req);
+        let resp = req.send().await?;
+        // The range starts past the end of the parcel, so there is nothing left to return
+        if resp.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            return Ok(Box::new(tokio_stream::empty()));
+        }
+        let resp = unwrap_status(resp, Endpoint::Parcel, Operation::Get).await?;
+        let partial = resp.status() == StatusCode::PARTIAL_CONTENT;
+        let stream = resp.bytes_stream().map(|r| r.map_err(ClientError::from));
+        if partial {
+            Ok(Box::new(stream))
+        } else {
+            // The server ignored the range and sent the whole parcel
+            Ok(Box::new(crate::provider::slice_stream(stream, range)))
+        }
+    }
+
+    async fn parcel_request_builder(&self, bindle_id: &Id, sha: &str) -> Result<RequestBuilder> {
         // Override the default accept header
         let req = self
             .client
@@ -477,10 +892,55 @@ impl<T: tokens::TokenManager> Client<T> {
                     .unwrap(),
             )
             .header(header::ACCEPT, "*/*");
-        let req = self.token_manager.apply_auth_header(req).await?;
-        trace!(?req);
-        let resp = req.send().await?;
-        unwrap_status(resp, Endpoint::Parcel, Operation::Get).await
+        self.token_manager.apply_auth_header(req).await
+    }
+
+    //////////////// Parcel Existence ////////////////
+
+    /// Checks if the server has the given parcel, regardless of which bindles it belongs to. A
+    /// parcel that already exists doesn't need to be uploaded again for a new bindle
+    #[instrument(level = "trace", skip(self))]
+    pub async fn has_parcel(&self, parcel_sha: &str) -> Result<bool> {
+        let resp = self
+            .raw(
+                reqwest::Method::HEAD,
+                &format!("{}/{}", PARCEL_ENDPOINT, parcel_sha),
+                None::<reqwest::Body>,
+            )
+            .await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        unwrap_status(resp, Endpoint::Parcel, Operation::Query).await?;
+        Ok(true)
+    }
+
+    /// Asks the server which of the given parcels it already has, regardless of which bindles
+    /// they belong to, returning the SHAs of the ones that exist. This does the same as
+    /// [`has_parcel`](Client::has_parcel) for many parcels at once, sending one request for every
+    /// [`MAX_PARCELS`](crate::ParcelExistsRequest::MAX_PARCELS) parcels
+    #[instrument(level = "trace", skip(self, parcel_shas), fields(num_parcels = parcel_shas.len()))]
+    pub async fn existing_parcels(&self, parcel_shas: &[&str]) -> Result<Vec<String>> {
+        let mut existing = Vec::new();
+        for batch in parcel_shas.chunks(crate::ParcelExistsRequest::MAX_PARCELS) {
+            let body = crate::ParcelExistsRequest {
+                parcel: batch.iter().map(|s| s.to_string()).collect(),
+            };
+            // We can unwrap here because any URL error would be programmers fault
+            let req = self
+                .client
+                .post(self.base_url.join(PARCEL_ENDPOINT).unwrap())
+                .header(header::CONTENT_TYPE, TOML_MIME_TYPE)
+                .body(toml::to_vec(&body)?);
+            let req = self.token_manager.apply_auth_header(req).await?;
+            trace!(?req);
+            let resp = req.send().await?;
+            let resp = unwrap_status(resp, Endpoint::Parcel, Operation::Query).await?;
+            existing.extend(
+                toml::from_slice::<crate::ParcelExistsResponse>(&resp.bytes().await?)?.existing,
+            );
+        }
+        Ok(existing)
     }
 
     //////////////// Relationship Endpoints ////////////////
@@ -494,7 +954,7 @@ impl<T: tokens::TokenManager> Client<T> {
         I::Error: Into<ClientError>,
     {
         let parsed_id = id.try_into().map_err(|e| e.into())?;
-        tracing::span::Span::current().record("invoice_id", &tracing::field::display(&parsed_id));
+        tracing::span::Span::current().record("invoice_id", tracing::field::display(&parsed_id));
         let req = self.client.get(self.base_url.join(&format!(
             "{}/{}/{}",
             RELATIONSHIP_ENDPOINT, "missing", parsed_id
@@ -522,7 +982,7 @@ impl<T: tokens::TokenManager> Client<T> {
 // We implement provider for client because often times (such as in the CLI) we are composing the
 // client in to a provider cache. This implementation does not verify or sign anything
 #[async_trait::async_trait]
-impl<T: tokens::TokenManager + Send + Sync + 'static> Provider for Client<T> {
+impl<T: tokens::TokenManager + Clone + Send + Sync + 'static> Provider for Client<T> {
     async fn create_invoice<I>(
         &self,
         invoice: I,
@@ -557,6 +1017,28 @@ impl<T: tokens::TokenManager + Send + Sync + 'static> Provider for Client<T> {
         self.yank_invoice(parsed_id).await.map_err(|e| e.into())
     }
 
+    async fn delete_invoice<I>(&self, id: I) -> crate::provider::Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        // Parse the ID now because the error type constraint doesn't match that of the client
+        let parsed_id = id.try_into().map_err(|e| e.into())?;
+        self.delete_invoice(parsed_id).await.map_err(|e| e.into())
+    }
+
+    async fn list_invoices(
+        &self,
+        include_yanked: bool,
+    ) -> crate::provider::Result<
+        Box<dyn Stream<Item = crate::provider::Result<Id>> + Unpin + Send + Sync>,
+    > {
+        Ok(Box::new(
+            self.list_invoices(include_yanked)
+                .map(|res| res.map_err(ProviderError::from)),
+        ))
+    }
+
     async fn create_parcel<I, R, B>(
         &self,
         bindle_id: I,
@@ -593,6 +1075,98 @@ impl<T: tokens::TokenManager + Send + Sync + 'static> Provider for Client<T> {
         Ok(Box::new(stream.map(|res| res.map_err(|e| e.into()))))
     }
 
+    async fn get_parcel_range<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+        range: Range<u64>,
+    ) -> crate::provider::Result<
+        Box<dyn Stream<Item = crate::provider::Result<bytes::Bytes>> + Unpin + Send + Sync>,
+    >
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        // Parse the ID now because the error type constraint doesn't match that of the client
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        let stream = self
+            .get_parcel_range_stream(&parsed_id, parcel_id, range)
+            .await?;
+        Ok(Box::new(stream.map(|res| res.map_err(|e| e.into()))))
+    }
+
+    async fn start_parcel_upload<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+    ) -> crate::provider::Result<u64>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        // Parse the ID now because the error type constraint doesn't match that of the client
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        Ok(self.start_parcel_upload(parsed_id, parcel_id).await?)
+    }
+
+    async fn parcel_upload_offset<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+    ) -> crate::provider::Result<u64>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        Ok(self.parcel_upload_offset(parsed_id, parcel_id).await?)
+    }
+
+    async fn write_parcel_chunk<I, R, B>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+        offset: u64,
+        data: R,
+    ) -> crate::provider::Result<u64>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+        R: Stream<Item = std::io::Result<B>> + Unpin + Send + Sync + 'static,
+        B: bytes::Buf + Send,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        Ok(self
+            .write_parcel_chunk(parsed_id, parcel_id, offset, data)
+            .await?)
+    }
+
+    async fn finish_parcel_upload<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+    ) -> crate::provider::Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        Ok(self.finish_parcel_upload(parsed_id, parcel_id).await?)
+    }
+
+    async fn abort_parcel_upload<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+    ) -> crate::provider::Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        Ok(self.abort_parcel_upload(parsed_id, parcel_id).await?)
+    }
+
     async fn parcel_exists<I>(&self, bindle_id: I, parcel_id: &str) -> crate::provider::Result<bool>
     where
         I: TryInto<Id> + Send,
@@ -621,6 +1195,21 @@ impl<T: tokens::TokenManager + Send + Sync + 'static> Provider for Client<T> {
             })),
         }
     }
+
+    async fn has_parcel(&self, parcel_id: &str) -> crate::provider::Result<bool> {
+        Client::has_parcel(self, parcel_id)
+            .await
+            .map_err(|e| e.into())
+    }
+
+    async fn parcels_exist(&self, parcel_ids: &[&str]) -> crate::provider::Result<Vec<bool>> {
+        let existing: std::collections::HashSet<String> = self
+            .existing_parcels(parcel_ids)
+            .await?
+            .into_iter()
+            .collect();
+        Ok(parcel_ids.iter().map(|id| existing.contains(*id)).collect())
+    }
 }
 
 // A helper function and related enum to make some reusable code for unwrapping a status code and returning the right error
@@ -629,7 +1218,9 @@ impl<T: tokens::TokenManager + Send + Sync + 'static> Provider for Client<T> {
 /// The operation being performed against a Bindle server.
 enum Operation {
     Create,
+    Upload,
     Yank,
+    Delete,
     Get,
     Query,
     Login,
@@ -638,6 +1229,7 @@ enum Operation {
 enum Endpoint {
     Invoice,
     Parcel,
+    Upload,
     Query,
     // NOTE: This endpoint currently does nothing, but if we need more specific errors, we can use
     // this down the line
@@ -654,6 +1246,12 @@ async fn unwrap_status(
         (StatusCode::OK, _) => Ok(resp),
         (StatusCode::ACCEPTED, Endpoint::Invoice) => Ok(resp),
         (StatusCode::CREATED, Endpoint::Invoice) => Ok(resp),
+        (StatusCode::PARTIAL_CONTENT, Endpoint::Parcel) => Ok(resp),
+        // Deleting is an administrative operation, so a forbidden response means the user is not
+        // an admin rather than the invoice being hidden
+        (StatusCode::FORBIDDEN, Endpoint::Invoice) if matches!(operation, Operation::Delete) => {
+            Err(ClientError::Unauthorized)
+        }
         (StatusCode::NOT_FOUND, Endpoint::Invoice) | (StatusCode::FORBIDDEN, Endpoint::Invoice) => {
             match operation {
                 Operation::Get => Err(ClientError::InvoiceNotFound),
@@ -664,6 +1262,14 @@ async fn unwrap_status(
             Operation::Get => Err(ClientError::ParcelNotFound),
             _ => Err(ClientError::ResourceNotFound),
         },
+        (StatusCode::NOT_FOUND, Endpoint::Upload) => Err(ClientError::ResourceNotFound),
+        (StatusCode::NOT_IMPLEMENTED, Endpoint::Upload)
+        | (StatusCode::NOT_IMPLEMENTED, Endpoint::Parcel) => Err(ClientError::Unsupported),
+        // Conflicts when writing a chunk are offset mismatches, which are returned as invalid
+        // requests below so the message with the current offset isn't lost
+        (StatusCode::CONFLICT, Endpoint::Upload) if matches!(operation, Operation::Create) => {
+            Err(ClientError::ParcelAlreadyExists)
+        }
         (StatusCode::CONFLICT, Endpoint::Invoice) => Err(ClientError::InvoiceAlreadyExists),
         (StatusCode::CONFLICT, Endpoint::Parcel) => Err(ClientError::ParcelAlreadyExists),
         (StatusCode::UNAUTHORIZED, _) => Err(ClientError::Unauthorized),
@@ -25,6 +25,104 @@ pub struct MissingParcelsResponse {
     pub missing: Vec<Label>,
 }
 
+/// A request asking which of the given parcels a server already has. TOML doesn't support top
+/// level arrays, so they must be embedded in a table
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct ParcelExistsRequest {
+    pub parcel: Vec<String>,
+}
+
+impl ParcelExistsRequest {
+    /// The maximum number of parcels that can be checked in a single request
+    pub const MAX_PARCELS: usize = 1000;
+}
+
+/// A response to a [`ParcelExistsRequest`], listing the SHAs of the requested parcels that the
+/// server already has
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct ParcelExistsResponse {
+    pub existing: Vec<String>,
+}
+
+/// A response to a resumable parcel upload request, containing the number of bytes of the parcel
+/// that have been received so far
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct UploadStatusResponse {
+    pub offset: u64,
+}
+
+/// A response to a quota usage request, listing the current usage of every configured quota.
+/// TOML doesn't support top level arrays, so they must be embedded in a table
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct QuotaUsageResponse {
+    pub quota: Vec<QuotaUsage>,
+}
+
+/// The current usage of a single quota, along with its limits. A limit that isn't set is
+/// unlimited
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct QuotaUsage {
+    pub prefix: String,
+    pub invoices: u64,
+    pub bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_invoices: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+}
+
+/// A response to an audit log query, listing the matching entries with the newest first. TOML
+/// doesn't support top level arrays, so they must be embedded in a table
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct AuditLogResponse {
+    pub entry: Vec<AuditEntry>,
+}
+
+/// A single entry in the audit log, recording a mutating operation and who performed it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct AuditEntry {
+    /// The time the operation finished, in seconds since the Unix epoch
+    pub timestamp: u64,
+    /// The principal of the authenticated user. This is empty for anonymous users
+    pub principal: String,
+    pub action: AuditAction,
+    /// The ID of the bindle that was operated on, as given in the request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bindle_id: Option<String>,
+    /// The SHA of the parcel that was operated on, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parcel_sha: Option<String>,
+    pub outcome: AuditOutcome,
+    /// The HTTP status code returned for the operation
+    pub status: u16,
+}
+
+/// The kind of operation recorded in an [`AuditEntry`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AuditAction {
+    CreateInvoice,
+    CreateParcel,
+    YankInvoice,
+    DeleteInvoice,
+    Login,
+}
+
+/// Whether the operation recorded in an [`AuditEntry`] succeeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AuditOutcome {
+    Success,
+    Failure,
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct HealthResponse {
     pub status: String,
@@ -65,6 +163,19 @@ impl From<QueryOptions> for SearchOptions {
     }
 }
 
+/// Available query string options for the audit log API
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct AuditQuery {
+    /// Only return entries for this bindle. This can be a full bindle ID or just the name, which
+    /// matches all versions
+    pub bindle: Option<String>,
+    /// Only return entries for this principal
+    pub principal: Option<String>,
+    /// The maximum number of entries to return. Defaults to 100
+    pub limit: Option<u32>,
+}
+
 /// Available query string options for the keyring API
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -19,8 +19,9 @@ pub(crate) use api::DeviceAuthorizationExtraFields;
 pub(crate) use api::LoginParams;
 #[doc(inline)]
 pub use api::{
-    ErrorResponse, HealthResponse, InvoiceCreateResponse, KeyOptions, MissingParcelsResponse,
-    QueryOptions,
+    AuditAction, AuditEntry, AuditLogResponse, AuditOutcome, AuditQuery, ErrorResponse,
+    HealthResponse, InvoiceCreateResponse, KeyOptions, MissingParcelsResponse, ParcelExistsRequest,
+    ParcelExistsResponse, QueryOptions, QuotaUsage, QuotaUsageResponse, UploadStatusResponse,
 };
 use base64::Engine;
 #[doc(inline)]
@@ -415,7 +416,7 @@ mod test {
     fn test_version_comparisons() {
         // Do not need an exhaustive list of matches -- just a sampling to make sure
         // the outer logic is correct.
-        let reqs = vec!["= 1.2.3", "1.2.3", "1.2.3", "^1.1", "~1.2", ""];
+        let reqs = ["= 1.2.3", "1.2.3", "1.2.3", "^1.1", "~1.2", ""];
         let version = Version::parse("1.2.3").unwrap();
 
         reqs.iter().for_each(|r| {
@@ -426,7 +427,7 @@ mod test {
 
         // Again, we do not need to test the SemVer crate -- just make sure some
         // outliers and obvious cases are covered.
-        let reqs = vec!["2", "%^&%^&%"];
+        let reqs = ["2", "%^&%^&%"];
         reqs.iter()
             .for_each(|r| assert!(!version_compare(&version, r)));
     }
@@ -654,7 +655,7 @@ mod test {
 
     #[test]
     fn test_examples_in_spec_parse() {
-        let test_files = vec![
+        let test_files = [
             "test/data/simple-invoice.toml",
             "test/data/full-invoice.toml",
             "test/data/alt-format-invoice.toml",
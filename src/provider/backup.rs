@@ -0,0 +1,738 @@
+//! Point-in-time backups of a whole store as a tar archive, and restoring them into any provider.
+//!
+//! Everything is read and written through the [`Provider`] interface, so a backup never contains
+//! partially uploaded parcels and can be taken from a [`FileProvider`](super::file::FileProvider)
+//! while the server is running. The list of invoices (along with their yanked status) is read
+//! first, so the backup contains the store as it was when the backup started. Every parcel is
+//! checked against its label before it is added to the archive, and again before it is restored.
+//!
+//! An archive contains every invoice under `invoices/`, every parcel under `parcels/` and a
+//! `manifest.toml` describing its contents. Incremental backups are taken by passing the manifest
+//! of an earlier backup, in which case any parcel listed in it is left out of the new archive. To
+//! restore an incremental backup, first restore the backup it was based on.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio_stream::StreamExt;
+use tokio_util::io::{ReaderStream, StreamReader};
+use tracing::{debug, info, instrument, warn};
+
+use crate::provider::{Provider, ProviderError, Result};
+use crate::verification::NoopVerified;
+use crate::{Id, Invoice, NoopSigned};
+
+/// The version of the archive format written by [`backup`]
+pub const BACKUP_FORMAT_VERSION: u32 = 1;
+/// The path of the manifest in an archive
+pub const MANIFEST_PATH: &str = "manifest.toml";
+const INVOICE_PREFIX: &str = "invoices/";
+const PARCEL_PREFIX: &str = "parcels/";
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// A description of everything in a backup archive
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackupManifest {
+    pub version: u32,
+    /// When the backup was started, in seconds since the Unix epoch
+    pub created: u64,
+    /// Whether this backup leaves out parcels that are in an earlier backup
+    pub incremental: bool,
+    #[serde(default)]
+    pub invoice: Vec<BackupInvoice>,
+    /// Every parcel that was stored when the backup was taken, whether or not it is included in
+    /// this archive
+    #[serde(default)]
+    pub parcel: Vec<BackupParcel>,
+}
+
+/// An invoice in a backup archive
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackupInvoice {
+    pub id: String,
+    pub yanked: bool,
+}
+
+/// A parcel in a backup, along with one of the invoices that references it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackupParcel {
+    pub sha256: String,
+    pub size: u64,
+    pub bindle_id: String,
+    /// False if the parcel was left out because it is in an earlier backup
+    pub included: bool,
+}
+
+/// A report of everything written during a backup
+#[derive(Debug, Default)]
+pub struct BackupReport {
+    /// The number of invoices written to the archive
+    pub invoices: usize,
+    /// The number of parcels written to the archive
+    pub parcels: usize,
+    /// The number of parcels left out because they are in the base backup
+    pub skipped_parcels: usize,
+    /// The number of parcels referenced by an invoice that were never uploaded
+    pub absent_parcels: usize,
+    /// The total size of all parcels written to the archive
+    pub bytes: u64,
+    /// A description of every invoice or parcel that couldn't be backed up. The backup continues
+    /// past errors so that as much as possible is saved
+    pub failures: Vec<String>,
+}
+
+impl BackupReport {
+    /// Returns true if everything in the store was backed up
+    pub fn is_complete(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// A report of everything done during a restore, including the results of checking the store
+/// against the manifest afterwards
+#[derive(Debug, Default)]
+pub struct RestoreReport {
+    /// The number of invoices created in the store
+    pub restored_invoices: usize,
+    /// The number of invoices that already existed in the store
+    pub skipped_invoices: usize,
+    /// The number of parcels created in the store
+    pub restored_parcels: usize,
+    /// The number of parcels that already existed in the store
+    pub skipped_parcels: usize,
+    /// The total size of all restored parcels
+    pub restored_bytes: u64,
+    /// A description of every error that occurred while restoring, such as parcels that don't
+    /// match their digest. The restore continues past errors so that as much as possible is
+    /// restored
+    pub failures: Vec<String>,
+    /// Invoices listed in the manifest that aren't in the store after the restore
+    pub missing_invoices: Vec<String>,
+    /// Invoices whose yanked status in the store doesn't match the manifest
+    pub yank_mismatches: Vec<Id>,
+    /// Parcels (along with an invoice referencing them) listed in the manifest that aren't in the
+    /// store after the restore. For an incremental backup, this usually means the backup it was
+    /// based on hasn't been restored
+    pub missing_parcels: Vec<(String, String)>,
+}
+
+impl RestoreReport {
+    /// Returns true if the store contains everything in the manifest and nothing failed
+    pub fn is_consistent(&self) -> bool {
+        self.failures.is_empty()
+            && self.missing_invoices.is_empty()
+            && self.yank_mismatches.is_empty()
+            && self.missing_parcels.is_empty()
+    }
+}
+
+/// Writes a backup of every invoice and parcel in the store to the given writer as a tar archive.
+/// If a `base` manifest is given, parcels listed in it are left out. See the
+/// [module documentation](self) for more details.
+///
+/// An error is only returned if the invoices in the store could not be listed or the archive
+/// could not be written. All other errors are recorded in the returned report
+#[instrument(level = "trace", skip(store, writer, base))]
+pub async fn backup<P, W>(
+    store: &P,
+    writer: W,
+    base: Option<&BackupManifest>,
+) -> Result<BackupReport>
+where
+    P: Provider + Sync,
+    W: AsyncWrite + Unpin + Send + Sync + 'static,
+{
+    info!(incremental = base.is_some(), "Beginning backup");
+    let mut report = BackupReport::default();
+    let mut manifest = BackupManifest {
+        version: BACKUP_FORMAT_VERSION,
+        created: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+        incremental: base.is_some(),
+        ..Default::default()
+    };
+    let mut builder = tokio_tar::Builder::new(writer);
+
+    // Read all of the invoices up front so the backup is a snapshot of when it started. Parcels
+    // can never change once they are stored, so they can be read afterwards
+    let mut parcels: BTreeMap<String, (Id, u64)> = BTreeMap::new();
+    let mut ids = store.list_invoices(true).await?;
+    while let Some(res) = ids.next().await {
+        let inv = match res {
+            Ok(id) => store.get_yanked_invoice(&id).await,
+            Err(e) => Err(e),
+        };
+        let inv = match inv {
+            Ok(inv) => inv,
+            // The invoice was deleted after it was listed
+            Err(ProviderError::NotFound) => continue,
+            Err(e) => {
+                warn!(error = %e, "Unable to read invoice");
+                report
+                    .failures
+                    .push(format!("Unable to read invoice: {}", e));
+                continue;
+            }
+        };
+        let data = toml::to_vec(&inv)?;
+        append(
+            &mut builder,
+            format!("{}{}.toml", INVOICE_PREFIX, inv.canonical_name()),
+            manifest.created,
+            data.len() as u64,
+            data.as_slice(),
+        )
+        .await?;
+        for label in inv.parcel.iter().flatten().map(|p| &p.label) {
+            parcels
+                .entry(label.sha256.clone())
+                .or_insert_with(|| (inv.bindle.id.clone(), label.size));
+        }
+        manifest.invoice.push(BackupInvoice {
+            id: inv.bindle.id.to_string(),
+            yanked: inv.yanked.unwrap_or(false),
+        });
+        report.invoices += 1;
+    }
+
+    let in_base: HashSet<&str> = base
+        .map(|m| m.parcel.iter().map(|p| p.sha256.as_str()).collect())
+        .unwrap_or_default();
+    for (sha, (id, size)) in parcels {
+        let mut entry = BackupParcel {
+            sha256: sha,
+            size,
+            bindle_id: id.to_string(),
+            included: false,
+        };
+        if in_base.contains(entry.sha256.as_str()) {
+            report.skipped_parcels += 1;
+            manifest.parcel.push(entry);
+            continue;
+        }
+        match backup_parcel(store, &mut builder, &id, &entry, manifest.created).await {
+            Ok(true) => {
+                debug!(%id, parcel_id = %entry.sha256, "Backed up parcel");
+                report.parcels += 1;
+                report.bytes += size;
+                entry.included = true;
+                manifest.parcel.push(entry);
+            }
+            Ok(false) => report.absent_parcels += 1,
+            // Any IO errors with the archive mean it can't be finished
+            Err(BackupError::Archive(e)) => return Err(e.into()),
+            Err(BackupError::Parcel(e)) => {
+                warn!(%id, parcel_id = %entry.sha256, error = %e, "Unable to back up parcel");
+                report.failures.push(format!(
+                    "Unable to back up parcel {} for invoice {}: {}",
+                    entry.sha256, id, e
+                ));
+            }
+        }
+    }
+
+    let data = toml::to_vec(&manifest)?;
+    append(
+        &mut builder,
+        MANIFEST_PATH,
+        manifest.created,
+        data.len() as u64,
+        data.as_slice(),
+    )
+    .await?;
+    let mut writer = builder.into_inner().await?;
+    writer.flush().await?;
+    writer.shutdown().await?;
+    info!(
+        invoices = report.invoices,
+        parcels = report.parcels,
+        failures = report.failures.len(),
+        "Finished backup"
+    );
+    Ok(report)
+}
+
+enum BackupError {
+    Archive(std::io::Error),
+    Parcel(ProviderError),
+}
+
+/// Adds a parcel to the archive. Returns false if the parcel was never uploaded
+async fn backup_parcel<P, W>(
+    store: &P,
+    builder: &mut tokio_tar::Builder<W>,
+    id: &Id,
+    parcel: &BackupParcel,
+    mtime: u64,
+) -> std::result::Result<bool, BackupError>
+where
+    P: Provider + Sync,
+    W: AsyncWrite + Unpin + Send + Sync + 'static,
+{
+    if !store
+        .parcel_exists(id, &parcel.sha256)
+        .await
+        .map_err(BackupError::Parcel)?
+    {
+        return Ok(false);
+    }
+    let stream = store
+        .get_parcel(id, &parcel.sha256)
+        .await
+        .map_err(BackupError::Parcel)?;
+    // The size has to be known before anything is written to the archive, so the parcel is
+    // checked in a temporary file first
+    let mut reader = StreamReader::new(stream.map(|res| res.map_err(std::io::Error::other)));
+    let mut file = spool_verified(&mut reader, &parcel.sha256, parcel.size)
+        .await
+        .map_err(BackupError::Parcel)?;
+    append(
+        builder,
+        format!("{}{}", PARCEL_PREFIX, parcel.sha256),
+        mtime,
+        parcel.size,
+        &mut file,
+    )
+    .await
+    .map_err(BackupError::Archive)?;
+    Ok(true)
+}
+
+async fn append<W, R>(
+    builder: &mut tokio_tar::Builder<W>,
+    path: impl AsRef<std::path::Path>,
+    mtime: u64,
+    size: u64,
+    data: R,
+) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin + Send + Sync + 'static,
+    R: AsyncRead + Unpin,
+{
+    let mut header = tokio_tar::Header::new_gnu();
+    header.set_entry_type(tokio_tar::EntryType::Regular);
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(mtime);
+    builder.append_data(&mut header, path, data).await
+}
+
+/// Copies the reader to a temporary file, checking that the data matches the given size and
+/// SHA-256 digest. Returns the file, ready to be read from the beginning
+async fn spool_verified<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    sha: &str,
+    size: u64,
+) -> Result<tokio::fs::File> {
+    let mut file = tokio::fs::File::from_std(tempfile::tempfile()?);
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    let mut total = 0u64;
+    loop {
+        let read = reader.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+        file.write_all(&buf[..read]).await?;
+        total += read as u64;
+    }
+    if total != size {
+        return Err(ProviderError::SizeMismatch);
+    }
+    if format!("{:x}", hasher.finalize()) != sha {
+        return Err(ProviderError::DigestMismatch);
+    }
+    file.flush().await?;
+    file.rewind().await?;
+    Ok(file)
+}
+
+/// Reads the manifest from a backup archive, such as to use it as the base of an incremental
+/// backup
+pub async fn read_manifest<R>(reader: R) -> Result<BackupManifest>
+where
+    R: AsyncRead + Unpin + Send + Sync,
+{
+    let mut archive = tokio_tar::Archive::new(reader);
+    let mut entries = archive.entries()?;
+    while let Some(entry) = entries.next().await {
+        let mut entry = entry?;
+        if entry.path()?.to_str() == Some(MANIFEST_PATH) {
+            return parse_manifest(&mut entry).await;
+        }
+    }
+    Err(ProviderError::Other(
+        "archive does not contain a manifest. It may be truncated".to_owned(),
+    ))
+}
+
+async fn parse_manifest<R: AsyncRead + Unpin>(reader: &mut R) -> Result<BackupManifest> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data).await?;
+    let manifest: BackupManifest = toml::from_slice(&data)?;
+    if manifest.version > BACKUP_FORMAT_VERSION {
+        return Err(ProviderError::Other(format!(
+            "backup format version {} is newer than the supported version {}",
+            manifest.version, BACKUP_FORMAT_VERSION
+        )));
+    }
+    Ok(manifest)
+}
+
+/// Restores every invoice and parcel in a backup archive into the store, checking every parcel
+/// against its digest before it is stored. Anything that already exists in the store is skipped,
+/// so an interrupted restore can be run again. See the [module documentation](self) for more
+/// details.
+///
+/// An error is only returned if the archive could not be read. All other errors are recorded in
+/// the returned report
+#[instrument(level = "trace", skip(store, reader))]
+pub async fn restore<P, R>(store: &P, reader: R) -> Result<RestoreReport>
+where
+    P: Provider + Sync,
+    R: AsyncRead + Unpin + Send + Sync,
+{
+    info!("Beginning restore");
+    let mut report = RestoreReport::default();
+    // Parcels can only be stored for an invoice that references them, so keep track of an
+    // invoice and the expected size for each one
+    let mut owners: HashMap<String, (Id, u64)> = HashMap::new();
+    let mut to_yank = Vec::new();
+    let mut manifest = None;
+
+    let mut archive = tokio_tar::Archive::new(reader);
+    let mut entries = archive.entries()?;
+    while let Some(entry) = entries.next().await {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().into_owned();
+        if path == MANIFEST_PATH {
+            manifest = Some(parse_manifest(&mut entry).await?);
+        } else if path.starts_with(INVOICE_PREFIX) {
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).await?;
+            let inv: Invoice = match toml::from_slice(&data) {
+                Ok(inv) => inv,
+                Err(e) => {
+                    report
+                        .failures
+                        .push(format!("Unable to parse invoice {}: {}", path, e));
+                    continue;
+                }
+            };
+            for label in inv.parcel.iter().flatten().map(|p| &p.label) {
+                owners
+                    .entry(label.sha256.clone())
+                    .or_insert_with(|| (inv.bindle.id.clone(), label.size));
+            }
+            let id = inv.bindle.id.clone();
+            match restore_invoice(store, inv, &mut report).await {
+                Ok(true) => to_yank.push(id),
+                Ok(false) => {}
+                Err(e) => {
+                    warn!(%id, error = %e, "Unable to restore invoice");
+                    report
+                        .failures
+                        .push(format!("Unable to restore invoice {}: {}", id, e));
+                }
+            }
+        } else if let Some(sha) = path.strip_prefix(PARCEL_PREFIX) {
+            let sha = sha.to_owned();
+            let (id, size) = match owners.get(&sha) {
+                Some(owner) => owner.clone(),
+                None => {
+                    report.failures.push(format!(
+                        "Parcel {} is not referenced by any invoice in the archive",
+                        sha
+                    ));
+                    continue;
+                }
+            };
+            if let Err(e) = restore_parcel(store, &id, &sha, size, &mut entry, &mut report).await {
+                warn!(%id, parcel_id = %sha, error = %e, "Unable to restore parcel");
+                report.failures.push(format!(
+                    "Unable to restore parcel {} for invoice {}: {}",
+                    sha, id, e
+                ));
+            }
+        } else {
+            warn!(%path, "Skipping unknown entry in backup archive");
+        }
+    }
+
+    // Yanked invoices can't be created, so they are yanked once all of their parcels are stored
+    for id in to_yank {
+        if let Err(e) = store.yank_invoice(&id).await {
+            report
+                .failures
+                .push(format!("Unable to yank invoice {}: {}", id, e));
+        }
+    }
+
+    let manifest = manifest.ok_or_else(|| {
+        ProviderError::Other("archive does not contain a manifest. It may be truncated".to_owned())
+    })?;
+    info!(
+        restored_invoices = report.restored_invoices,
+        restored_parcels = report.restored_parcels,
+        failures = report.failures.len(),
+        "Finished restoring, checking consistency"
+    );
+    check_manifest(store, &manifest, &mut report).await;
+    Ok(report)
+}
+
+/// Creates the invoice in the store. Returns true if the invoice needs to be yanked afterwards
+async fn restore_invoice<P: Provider + Sync>(
+    store: &P,
+    mut inv: Invoice,
+    report: &mut RestoreReport,
+) -> Result<bool> {
+    let yanked = inv.yanked.take().unwrap_or(false);
+    let id = inv.bindle.id.clone();
+    let stored_yanked = match store.create_invoice(NoopSigned(NoopVerified(inv))).await {
+        Ok(_) => {
+            debug!(%id, "Restored invoice");
+            report.restored_invoices += 1;
+            false
+        }
+        Err(ProviderError::Exists) => {
+            debug!(%id, "Invoice already exists, skipping");
+            report.skipped_invoices += 1;
+            store.get_yanked_invoice(&id).await?.yanked.unwrap_or(false)
+        }
+        Err(e) => return Err(e),
+    };
+    Ok(yanked && !stored_yanked)
+}
+
+async fn restore_parcel<P, R>(
+    store: &P,
+    id: &Id,
+    sha: &str,
+    size: u64,
+    data: &mut R,
+    report: &mut RestoreReport,
+) -> Result<()>
+where
+    P: Provider + Sync,
+    R: AsyncRead + Unpin,
+{
+    if store.parcel_exists(id, sha).await? {
+        report.skipped_parcels += 1;
+        return Ok(());
+    }
+    // Providers only have to check the size of a parcel, so check the digest before storing it
+    let file = spool_verified(data, sha, size).await?;
+    match store.create_parcel(id, sha, ReaderStream::new(file)).await {
+        Ok(_) => {
+            debug!(%id, parcel_id = %sha, "Restored parcel");
+            report.restored_parcels += 1;
+            report.restored_bytes += size;
+            Ok(())
+        }
+        Err(ProviderError::Exists) => {
+            report.skipped_parcels += 1;
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Checks that every invoice and parcel in the manifest exists in the store, recording any
+/// differences in the report
+async fn check_manifest<P: Provider + Sync>(
+    store: &P,
+    manifest: &BackupManifest,
+    report: &mut RestoreReport,
+) {
+    for invoice in manifest.invoice.iter() {
+        let id = match Id::try_from(invoice.id.as_str()) {
+            Ok(id) => id,
+            Err(_) => {
+                report.missing_invoices.push(invoice.id.clone());
+                continue;
+            }
+        };
+        match store.get_yanked_invoice(&id).await {
+            Ok(inv) if inv.yanked.unwrap_or(false) != invoice.yanked => {
+                report.yank_mismatches.push(id)
+            }
+            Ok(_) => {}
+            Err(_) => report.missing_invoices.push(invoice.id.clone()),
+        }
+    }
+    for parcel in manifest.parcel.iter() {
+        let exists = store
+            .parcel_exists(parcel.bindle_id.as_str(), &parcel.sha256)
+            .await
+            .unwrap_or(false);
+        if !exists {
+            report
+                .missing_parcels
+                .push((parcel.bindle_id.clone(), parcel.sha256.clone()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::provider::{embedded::EmbeddedProvider, file::FileProvider};
+    use crate::search::NoopEngine;
+    use crate::testing;
+
+    use tokio_util::codec::{BytesCodec, FramedRead};
+
+    async fn store_bindle(store: &FileProvider<NoopEngine>, scaffold: &testing::Scaffold) {
+        store
+            .create_invoice(NoopSigned(NoopVerified(scaffold.invoice.clone())))
+            .await
+            .unwrap();
+        for parcel in scaffold.parcel_files.values() {
+            match store
+                .create_parcel(
+                    &scaffold.invoice.bindle.id,
+                    &parcel.sha,
+                    FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+                )
+                .await
+            {
+                Ok(_) | Err(ProviderError::Exists) => {}
+                Err(e) => panic!("Unable to create parcel: {}", e),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_incremental_backup_and_restore() {
+        let source_dir = tempfile::tempdir().unwrap();
+        let dest_dir = tempfile::tempdir().unwrap();
+        let source = FileProvider::new(source_dir.path(), NoopEngine::default()).await;
+        let dest = EmbeddedProvider::new(dest_dir.path(), NoopEngine::default())
+            .await
+            .unwrap();
+
+        let v1 = testing::Scaffold::load("valid_v1").await;
+        let v2 = testing::Scaffold::load("valid_v2").await;
+        store_bindle(&source, &v1).await;
+        source.yank_invoice(&v1.invoice.bindle.id).await.unwrap();
+
+        let archive_dir = tempfile::tempdir().unwrap();
+        let full_path = archive_dir.path().join("full.tar");
+        let report = backup(
+            &source,
+            tokio::fs::File::create(&full_path).await.unwrap(),
+            None,
+        )
+        .await
+        .expect("Backup should succeed");
+        let full = std::fs::read(&full_path).unwrap();
+        assert!(report.is_complete(), "Report: {:?}", report);
+        assert_eq!(1, report.invoices);
+        assert_eq!(1, report.parcels);
+
+        // The incremental backup should only contain the parcels that are new in v2
+        store_bindle(&source, &v2).await;
+        let base = read_manifest(full.as_slice())
+            .await
+            .expect("Archive should have a manifest");
+        let incremental_path = archive_dir.path().join("incremental.tar");
+        let report = backup(
+            &source,
+            tokio::fs::File::create(&incremental_path).await.unwrap(),
+            Some(&base),
+        )
+        .await
+        .expect("Backup should succeed");
+        let incremental = std::fs::read(&incremental_path).unwrap();
+        assert!(report.is_complete(), "Report: {:?}", report);
+        assert_eq!(2, report.invoices);
+        assert_eq!(1, report.skipped_parcels);
+        assert_eq!(v2.parcel_files.len() - 1, report.parcels);
+
+        // Restoring the incremental backup on its own should report the parcels it depends on
+        let report = restore(&dest, incremental.as_slice())
+            .await
+            .expect("Restore should succeed");
+        assert!(!report.is_consistent());
+        assert_eq!(1, report.missing_parcels.len());
+
+        let report = restore(&dest, full.as_slice())
+            .await
+            .expect("Restore should succeed");
+        assert!(report.is_consistent(), "Report: {:?}", report);
+        assert_eq!(1, report.restored_parcels);
+
+        // Everything should now be restored, so running it again should only skip things
+        let report = restore(&dest, incremental.as_slice())
+            .await
+            .expect("Restore should succeed");
+        assert!(report.is_consistent(), "Report: {:?}", report);
+        assert_eq!(0, report.restored_invoices);
+        assert_eq!(2, report.skipped_invoices);
+
+        let inv = dest
+            .get_yanked_invoice(&v1.invoice.bindle.id)
+            .await
+            .expect("Invoice should have been restored");
+        assert!(
+            inv.yanked.unwrap_or(false),
+            "Yanked status should be restored"
+        );
+        for parcel in v2.parcel_files.values() {
+            assert!(dest
+                .parcel_exists(&v2.invoice.bindle.id, &parcel.sha)
+                .await
+                .unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_restore_corrupted_parcel() {
+        let source_dir = tempfile::tempdir().unwrap();
+        let dest_dir = tempfile::tempdir().unwrap();
+        let source = FileProvider::new(source_dir.path(), NoopEngine::default()).await;
+        let dest = FileProvider::new(dest_dir.path(), NoopEngine::default()).await;
+
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        store_bindle(&source, &scaffold).await;
+        let archive_dir = tempfile::tempdir().unwrap();
+        let archive_path = archive_dir.path().join("backup.tar");
+        backup(
+            &source,
+            tokio::fs::File::create(&archive_path).await.unwrap(),
+            None,
+        )
+        .await
+        .expect("Backup should succeed");
+        let mut archive = std::fs::read(&archive_path).unwrap();
+
+        // Corrupt the parcel in the archive, keeping the same size
+        let parcel = scaffold.parcel_files.get("parcel").unwrap();
+        let offset = archive
+            .windows(parcel.data.len())
+            .position(|w| w == parcel.data.as_slice())
+            .expect("Parcel should be in the archive");
+        archive[offset] = archive[offset].wrapping_add(1);
+
+        let report = restore(&dest, archive.as_slice())
+            .await
+            .expect("Restore should succeed");
+        assert!(!report.is_consistent());
+        assert_eq!(1, report.failures.len());
+        assert_eq!(1, report.missing_parcels.len());
+        assert!(!dest
+            .parcel_exists(&scaffold.invoice.bindle.id, &parcel.sha)
+            .await
+            .unwrap());
+    }
+}
@@ -0,0 +1,158 @@
+//! Types used for configuring transparent at-rest compression of parcels.
+//!
+//! Terminal providers that support compression (currently the
+//! [`FileProvider`](crate::provider::file::FileProvider) and the
+//! [`EmbeddedProvider`](crate::provider::embedded::EmbeddedProvider)) can be configured with a
+//! [`CompressionPolicy`] using their `with_compression` method. Parcels matching the policy are
+//! stored compressed with [zstd](https://facebook.github.io/zstd/).
+//!
+//! Parcels are still addressed by the SHA-256 of their uncompressed data and are decompressed
+//! transparently when fetched. Each provider marks compressed parcels in storage, so compressed
+//! and uncompressed parcels can coexist in the same store. Changing the policy only affects
+//! parcels stored from then on
+
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+
+use async_compression::Level;
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::Label;
+
+/// The zstd compression level used by default
+pub const DEFAULT_COMPRESSION_LEVEL: u32 = 3;
+/// The size (in bytes) below which parcels are not compressed by default. Very small parcels
+/// rarely get smaller and aren't worth the overhead
+pub const DEFAULT_MIN_SIZE: u64 = 4096;
+
+/// A policy that determines which parcels are compressed when stored
+#[derive(Debug, Clone)]
+pub struct CompressionPolicy {
+    /// Parcels smaller than this many bytes are stored uncompressed
+    pub min_size: u64,
+    /// The media types of parcels that should be compressed. A type can end in a wildcard
+    /// subtype (e.g. `text/*`) to match all of its subtypes. If empty, parcels of any media type
+    /// are compressed
+    pub media_types: Vec<String>,
+    /// The zstd compression level to use. Higher levels compress better but are slower
+    pub level: u32,
+}
+
+impl Default for CompressionPolicy {
+    fn default() -> Self {
+        CompressionPolicy {
+            min_size: DEFAULT_MIN_SIZE,
+            media_types: Vec::new(),
+            level: DEFAULT_COMPRESSION_LEVEL,
+        }
+    }
+}
+
+impl CompressionPolicy {
+    /// Returns whether or not the parcel with the given label should be stored compressed
+    pub fn should_compress(&self, label: &Label) -> bool {
+        if label.size < self.min_size {
+            return false;
+        }
+        if self.media_types.is_empty() {
+            return true;
+        }
+        // Ignore any parameters (such as a charset) on the media type
+        let media_type = label
+            .media_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase();
+        self.media_types.iter().any(|pattern| {
+            let pattern = pattern.trim().to_lowercase();
+            if pattern == "*" || pattern == "*/*" {
+                return true;
+            }
+            match pattern.strip_suffix("/*") {
+                Some(prefix) => media_type
+                    .split_once('/')
+                    .map(|(main, _)| main == prefix)
+                    .unwrap_or(false),
+                None => media_type == pattern,
+            }
+        })
+    }
+
+    /// Returns the compression level for use with an async encoder
+    pub(crate) fn encoder_level(&self) -> Level {
+        Level::Precise(self.level)
+    }
+
+    /// Returns the compression level for use with the synchronous zstd functions. This is capped
+    /// at the same maximum level as the async encoder
+    pub(crate) fn zstd_level(&self) -> i32 {
+        self.level.min(21) as i32
+    }
+}
+
+/// Wraps a reader that is `Send` but not `Sync` (such as a zstd decoder) so it can be used in the
+/// `Sync` streams returned by providers. Reading requires a mutable reference, so the mutex is
+/// never actually locked
+pub(crate) struct SyncReader<R> {
+    inner: Mutex<R>,
+}
+
+impl<R> SyncReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        SyncReader {
+            inner: Mutex::new(inner),
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for SyncReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let inner = self
+            .get_mut()
+            .inner
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner());
+        Pin::new(inner).poll_read(cx, buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn label(size: u64, media_type: &str) -> Label {
+        Label {
+            size,
+            media_type: media_type.to_owned(),
+            ..Label::default()
+        }
+    }
+
+    #[test]
+    fn test_should_compress() {
+        let policy = CompressionPolicy {
+            min_size: 10,
+            media_types: vec!["text/*".to_owned(), "application/wasm".to_owned()],
+            ..Default::default()
+        };
+        assert!(policy.should_compress(&label(10, "text/plain")));
+        assert!(policy.should_compress(&label(100, "text/html; charset=utf-8")));
+        assert!(policy.should_compress(&label(100, "Application/Wasm")));
+        assert!(!policy.should_compress(&label(9, "text/plain")));
+        assert!(!policy.should_compress(&label(100, "image/png")));
+        assert!(!policy.should_compress(&label(100, "application/wasm-extra")));
+
+        let policy = CompressionPolicy {
+            min_size: 0,
+            ..Default::default()
+        };
+        assert!(policy.should_compress(&label(0, "image/png")));
+    }
+}
@@ -0,0 +1,568 @@
+//! An object safe version of the [`Provider`] trait, so that providers can be chosen at runtime.
+//!
+//! The `Provider` trait is generic over ID and stream types, which means it can't be used as a
+//! trait object. [`DynProvider`] has the same methods with concrete types instead and is
+//! implemented for every `Provider`. [`DynamicProvider`] goes the other way, wrapping any
+//! `DynProvider` trait object so that it can be used anywhere a `Provider` is expected (such as the
+//! server or as the inner provider of a cache). Put together, this allows picking a backend from
+//! configuration without having to repeat everything that uses it for each possible type:
+//!
+//! ```no_run
+//! # async fn example(use_embedded: bool) -> anyhow::Result<()> {
+//! use bindle::provider::dynamic::DynamicProvider;
+//! use bindle::provider::{embedded::EmbeddedProvider, file::FileProvider};
+//! use bindle::search::NoopEngine;
+//!
+//! let store = if use_embedded {
+//!     DynamicProvider::new(EmbeddedProvider::new("/tmp/bindles", NoopEngine::default()).await?)
+//! } else {
+//!     DynamicProvider::new(FileProvider::new("/tmp/bindles", NoopEngine::default()).await)
+//! };
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Every call through a `DynamicProvider` allocates a boxed future, and parcel data that is passed
+//! in is converted to [`Bytes`](bytes::Bytes) chunks, so there is a small amount of overhead
+//! compared to using a provider directly
+
+use std::convert::TryInto;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio_stream::{Stream, StreamExt};
+
+use crate::provider::{Provider, ProviderError, Result};
+use crate::verification::{NoopVerified, Verified};
+use crate::{Id, Invoice, Label, NoopSigned, Signed};
+
+/// A stream of parcel data returned from a provider
+pub type ParcelStream = Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>;
+
+/// A stream of parcel data to be stored by a provider
+pub type ParcelData = Box<dyn Stream<Item = std::io::Result<bytes::Bytes>> + Unpin + Send + Sync>;
+
+/// A stream of invoice IDs returned from a provider
+pub type IdStream = Box<dyn Stream<Item = Result<Id>> + Unpin + Send + Sync>;
+
+/// An invoice that has been signed and verified, for passing to a [`DynProvider`]. It can only be
+/// constructed from a type that is both [`Signed`] and [`Verified`], so it can't be used to skip
+/// either step
+pub struct CheckedInvoice(Invoice);
+
+impl CheckedInvoice {
+    /// Wraps the given signed and verified invoice
+    pub fn new<I: Signed + Verified>(inv: I) -> Self {
+        CheckedInvoice(inv.signed())
+    }
+}
+
+/// The object safe counterpart of [`Provider`]. All of the methods behave exactly like the
+/// `Provider` methods with the same name.
+///
+/// This is implemented for all providers, so it shouldn't need to be implemented directly. See the
+/// [module documentation](self) for more details
+#[async_trait::async_trait]
+pub trait DynProvider: Send + Sync {
+    /// See [`Provider::create_invoice`]
+    async fn create_invoice(&self, inv: CheckedInvoice) -> Result<(Invoice, Vec<Label>)>;
+
+    /// See [`Provider::create_draft_invoice`]
+    async fn create_draft_invoice(&self, inv: CheckedInvoice) -> Result<(Invoice, Vec<Label>)>;
+
+    /// See [`Provider::get_draft_invoice`]
+    async fn get_draft_invoice(&self, id: &Id) -> Result<Invoice>;
+
+    /// See [`Provider::publish_draft_invoice`]
+    async fn publish_draft_invoice(&self, id: &Id) -> Result<Vec<Label>>;
+
+    /// See [`Provider::expire_draft_invoices`]
+    async fn expire_draft_invoices(&self, ttl: Duration) -> Result<Vec<Id>>;
+
+    /// See [`Provider::get_invoice`]
+    async fn get_invoice(&self, id: &Id) -> Result<Invoice>;
+
+    /// See [`Provider::get_yanked_invoice`]
+    async fn get_yanked_invoice(&self, id: &Id) -> Result<Invoice>;
+
+    /// See [`Provider::yank_invoice`]
+    async fn yank_invoice(&self, id: &Id) -> Result<()>;
+
+    /// See [`Provider::delete_invoice`]
+    async fn delete_invoice(&self, id: &Id) -> Result<()>;
+
+    /// See [`Provider::list_invoices`]
+    async fn list_invoices(&self, include_yanked: bool) -> Result<IdStream>;
+
+    /// See [`Provider::validate_parcel`]
+    async fn validate_parcel(&self, bindle_id: &Id, parcel_id: &str) -> Result<Label>;
+
+    /// See [`Provider::create_parcel`]
+    async fn create_parcel(&self, bindle_id: &Id, parcel_id: &str, data: ParcelData) -> Result<()>;
+
+    /// See [`Provider::get_parcel`]
+    async fn get_parcel(&self, bindle_id: &Id, parcel_id: &str) -> Result<ParcelStream>;
+
+    /// See [`Provider::get_parcel_range`]
+    async fn get_parcel_range(
+        &self,
+        bindle_id: &Id,
+        parcel_id: &str,
+        range: Range<u64>,
+    ) -> Result<ParcelStream>;
+
+    /// See [`Provider::start_parcel_upload`]
+    async fn start_parcel_upload(&self, bindle_id: &Id, parcel_id: &str) -> Result<u64>;
+
+    /// See [`Provider::parcel_upload_offset`]
+    async fn parcel_upload_offset(&self, bindle_id: &Id, parcel_id: &str) -> Result<u64>;
+
+    /// See [`Provider::write_parcel_chunk`]
+    async fn write_parcel_chunk(
+        &self,
+        bindle_id: &Id,
+        parcel_id: &str,
+        offset: u64,
+        data: ParcelData,
+    ) -> Result<u64>;
+
+    /// See [`Provider::finish_parcel_upload`]
+    async fn finish_parcel_upload(&self, bindle_id: &Id, parcel_id: &str) -> Result<()>;
+
+    /// See [`Provider::abort_parcel_upload`]
+    async fn abort_parcel_upload(&self, bindle_id: &Id, parcel_id: &str) -> Result<()>;
+
+    /// See [`Provider::parcel_exists`]
+    async fn parcel_exists(&self, bindle_id: &Id, parcel_id: &str) -> Result<bool>;
+
+    /// See [`Provider::has_parcel`]
+    async fn has_parcel(&self, parcel_id: &str) -> Result<bool>;
+
+    /// See [`Provider::parcels_exist`]
+    async fn parcels_exist(&self, parcel_ids: &[&str]) -> Result<Vec<bool>>;
+}
+
+#[async_trait::async_trait]
+impl<P: Provider + Send + Sync> DynProvider for P {
+    async fn create_invoice(&self, inv: CheckedInvoice) -> Result<(Invoice, Vec<Label>)> {
+        Provider::create_invoice(self, NoopSigned(NoopVerified(inv.0))).await
+    }
+
+    async fn create_draft_invoice(&self, inv: CheckedInvoice) -> Result<(Invoice, Vec<Label>)> {
+        Provider::create_draft_invoice(self, NoopSigned(NoopVerified(inv.0))).await
+    }
+
+    async fn get_draft_invoice(&self, id: &Id) -> Result<Invoice> {
+        Provider::get_draft_invoice(self, id).await
+    }
+
+    async fn publish_draft_invoice(&self, id: &Id) -> Result<Vec<Label>> {
+        Provider::publish_draft_invoice(self, id).await
+    }
+
+    async fn expire_draft_invoices(&self, ttl: Duration) -> Result<Vec<Id>> {
+        Provider::expire_draft_invoices(self, ttl).await
+    }
+
+    async fn get_invoice(&self, id: &Id) -> Result<Invoice> {
+        Provider::get_invoice(self, id).await
+    }
+
+    async fn get_yanked_invoice(&self, id: &Id) -> Result<Invoice> {
+        Provider::get_yanked_invoice(self, id).await
+    }
+
+    async fn yank_invoice(&self, id: &Id) -> Result<()> {
+        Provider::yank_invoice(self, id).await
+    }
+
+    async fn delete_invoice(&self, id: &Id) -> Result<()> {
+        Provider::delete_invoice(self, id).await
+    }
+
+    async fn list_invoices(&self, include_yanked: bool) -> Result<IdStream> {
+        Provider::list_invoices(self, include_yanked).await
+    }
+
+    async fn validate_parcel(&self, bindle_id: &Id, parcel_id: &str) -> Result<Label> {
+        Provider::validate_parcel(self, bindle_id, parcel_id).await
+    }
+
+    async fn create_parcel(&self, bindle_id: &Id, parcel_id: &str, data: ParcelData) -> Result<()> {
+        Provider::create_parcel(self, bindle_id, parcel_id, data).await
+    }
+
+    async fn get_parcel(&self, bindle_id: &Id, parcel_id: &str) -> Result<ParcelStream> {
+        Provider::get_parcel(self, bindle_id, parcel_id).await
+    }
+
+    async fn get_parcel_range(
+        &self,
+        bindle_id: &Id,
+        parcel_id: &str,
+        range: Range<u64>,
+    ) -> Result<ParcelStream> {
+        Provider::get_parcel_range(self, bindle_id, parcel_id, range).await
+    }
+
+    async fn start_parcel_upload(&self, bindle_id: &Id, parcel_id: &str) -> Result<u64> {
+        Provider::start_parcel_upload(self, bindle_id, parcel_id).await
+    }
+
+    async fn parcel_upload_offset(&self, bindle_id: &Id, parcel_id: &str) -> Result<u64> {
+        Provider::parcel_upload_offset(self, bindle_id, parcel_id).await
+    }
+
+    async fn write_parcel_chunk(
+        &self,
+        bindle_id: &Id,
+        parcel_id: &str,
+        offset: u64,
+        data: ParcelData,
+    ) -> Result<u64> {
+        Provider::write_parcel_chunk(self, bindle_id, parcel_id, offset, data).await
+    }
+
+    async fn finish_parcel_upload(&self, bindle_id: &Id, parcel_id: &str) -> Result<()> {
+        Provider::finish_parcel_upload(self, bindle_id, parcel_id).await
+    }
+
+    async fn abort_parcel_upload(&self, bindle_id: &Id, parcel_id: &str) -> Result<()> {
+        Provider::abort_parcel_upload(self, bindle_id, parcel_id).await
+    }
+
+    async fn parcel_exists(&self, bindle_id: &Id, parcel_id: &str) -> Result<bool> {
+        Provider::parcel_exists(self, bindle_id, parcel_id).await
+    }
+
+    async fn has_parcel(&self, parcel_id: &str) -> Result<bool> {
+        Provider::has_parcel(self, parcel_id).await
+    }
+
+    async fn parcels_exist(&self, parcel_ids: &[&str]) -> Result<Vec<bool>> {
+        Provider::parcels_exist(self, parcel_ids).await
+    }
+}
+
+/// A [`Provider`] that forwards everything to a [`DynProvider`] trait object. Cloning is cheap and
+/// all clones share the same underlying provider
+#[derive(Clone)]
+pub struct DynamicProvider {
+    inner: Arc<dyn DynProvider>,
+}
+
+impl DynamicProvider {
+    /// Wraps the given provider
+    pub fn new<P: Provider + Send + Sync + 'static>(provider: P) -> Self {
+        DynamicProvider {
+            inner: Arc::new(provider),
+        }
+    }
+
+    /// Wraps a provider that is already a trait object
+    pub fn from_dyn(provider: Arc<dyn DynProvider>) -> Self {
+        DynamicProvider { inner: provider }
+    }
+}
+
+impl std::fmt::Debug for DynamicProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DynamicProvider").finish_non_exhaustive()
+    }
+}
+
+fn to_id<I>(id: I) -> Result<Id>
+where
+    I: TryInto<Id>,
+    I::Error: Into<ProviderError>,
+{
+    id.try_into().map_err(|e| e.into())
+}
+
+fn to_parcel_data<R, B>(data: R) -> ParcelData
+where
+    R: Stream<Item = std::io::Result<B>> + Unpin + Send + Sync + 'static,
+    B: bytes::Buf + Send,
+{
+    Box::new(data.map(|res| res.map(|mut b| b.copy_to_bytes(b.remaining()))))
+}
+
+#[async_trait::async_trait]
+impl Provider for DynamicProvider {
+    async fn create_invoice<I>(&self, inv: I) -> Result<(Invoice, Vec<Label>)>
+    where
+        I: Signed + Verified + Send + Sync,
+    {
+        self.inner.create_invoice(CheckedInvoice::new(inv)).await
+    }
+
+    async fn create_draft_invoice<I>(&self, inv: I) -> Result<(Invoice, Vec<Label>)>
+    where
+        I: Signed + Verified + Send + Sync,
+    {
+        self.inner
+            .create_draft_invoice(CheckedInvoice::new(inv))
+            .await
+    }
+
+    async fn get_draft_invoice<I>(&self, id: I) -> Result<Invoice>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner.get_draft_invoice(&to_id(id)?).await
+    }
+
+    async fn publish_draft_invoice<I>(&self, id: I) -> Result<Vec<Label>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner.publish_draft_invoice(&to_id(id)?).await
+    }
+
+    async fn expire_draft_invoices(&self, ttl: Duration) -> Result<Vec<Id>> {
+        self.inner.expire_draft_invoices(ttl).await
+    }
+
+    async fn get_invoice<I>(&self, id: I) -> Result<Invoice>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner.get_invoice(&to_id(id)?).await
+    }
+
+    async fn get_yanked_invoice<I>(&self, id: I) -> Result<Invoice>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner.get_yanked_invoice(&to_id(id)?).await
+    }
+
+    async fn yank_invoice<I>(&self, id: I) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner.yank_invoice(&to_id(id)?).await
+    }
+
+    async fn delete_invoice<I>(&self, id: I) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner.delete_invoice(&to_id(id)?).await
+    }
+
+    async fn list_invoices(&self, include_yanked: bool) -> Result<IdStream> {
+        self.inner.list_invoices(include_yanked).await
+    }
+
+    async fn validate_parcel<I>(&self, bindle_id: I, parcel_id: &str) -> Result<Label>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner
+            .validate_parcel(&to_id(bindle_id)?, parcel_id)
+            .await
+    }
+
+    async fn create_parcel<I, R, B>(&self, bindle_id: I, parcel_id: &str, data: R) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+        R: Stream<Item = std::io::Result<B>> + Unpin + Send + Sync + 'static,
+        B: bytes::Buf + Send,
+    {
+        self.inner
+            .create_parcel(&to_id(bindle_id)?, parcel_id, to_parcel_data(data))
+            .await
+    }
+
+    async fn get_parcel<I>(&self, bindle_id: I, parcel_id: &str) -> Result<ParcelStream>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner.get_parcel(&to_id(bindle_id)?, parcel_id).await
+    }
+
+    async fn get_parcel_range<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+        range: Range<u64>,
+    ) -> Result<ParcelStream>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner
+            .get_parcel_range(&to_id(bindle_id)?, parcel_id, range)
+            .await
+    }
+
+    async fn start_parcel_upload<I>(&self, bindle_id: I, parcel_id: &str) -> Result<u64>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner
+            .start_parcel_upload(&to_id(bindle_id)?, parcel_id)
+            .await
+    }
+
+    async fn parcel_upload_offset<I>(&self, bindle_id: I, parcel_id: &str) -> Result<u64>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner
+            .parcel_upload_offset(&to_id(bindle_id)?, parcel_id)
+            .await
+    }
+
+    async fn write_parcel_chunk<I, R, B>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+        offset: u64,
+        data: R,
+    ) -> Result<u64>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+        R: Stream<Item = std::io::Result<B>> + Unpin + Send + Sync + 'static,
+        B: bytes::Buf + Send,
+    {
+        self.inner
+            .write_parcel_chunk(&to_id(bindle_id)?, parcel_id, offset, to_parcel_data(data))
+            .await
+    }
+
+    async fn finish_parcel_upload<I>(&self, bindle_id: I, parcel_id: &str) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner
+            .finish_parcel_upload(&to_id(bindle_id)?, parcel_id)
+            .await
+    }
+
+    async fn abort_parcel_upload<I>(&self, bindle_id: I, parcel_id: &str) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner
+            .abort_parcel_upload(&to_id(bindle_id)?, parcel_id)
+            .await
+    }
+
+    async fn parcel_exists<I>(&self, bindle_id: I, parcel_id: &str) -> Result<bool>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner
+            .parcel_exists(&to_id(bindle_id)?, parcel_id)
+            .await
+    }
+
+    async fn has_parcel(&self, parcel_id: &str) -> Result<bool> {
+        self.inner.has_parcel(parcel_id).await
+    }
+
+    async fn parcels_exist(&self, parcel_ids: &[&str]) -> Result<Vec<bool>> {
+        self.inner.parcels_exist(parcel_ids).await
+    }
+}
+
+#[cfg(all(test, feature = "providers"))]
+mod test {
+    // `DynProvider` isn't imported so that the `Provider` methods are called
+    use super::DynamicProvider;
+    use crate::provider::{Provider, ProviderError};
+    use crate::verification::NoopVerified;
+    use crate::{testing, Id, NoopSigned};
+
+    use rstest::rstest;
+    use tokio::io::AsyncReadExt;
+    use tokio_stream::StreamExt;
+    use tokio_util::codec::{BytesCodec, FramedRead};
+    use tokio_util::io::StreamReader;
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_should_forward_to_provider(
+        #[values(
+            DynamicProvider::new(testing::setup().await.0),
+            DynamicProvider::new(testing::setup_embedded().await.0)
+        )]
+        store: DynamicProvider,
+    ) {
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let id = scaffold.invoice.bindle.id.clone();
+        let (_, missing) = store
+            .create_invoice(NoopSigned(NoopVerified(scaffold.invoice.clone())))
+            .await
+            .expect("should create invoice");
+        assert_eq!(missing.len(), scaffold.parcel_files.len());
+
+        let parcel = scaffold.parcel_files.get("parcel").expect("missing parcel");
+        store
+            .create_parcel(
+                &id,
+                &parcel.sha,
+                FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+            )
+            .await
+            .expect("should create parcel");
+        assert!(store.parcel_exists(&id, &parcel.sha).await.unwrap());
+        assert_eq!(
+            store.parcels_exist(&[&parcel.sha, "nope"]).await.unwrap(),
+            vec![true, false]
+        );
+
+        let stream = store
+            .get_parcel(id.to_string(), &parcel.sha)
+            .await
+            .expect("should get parcel");
+        let mut data = Vec::new();
+        StreamReader::new(stream.map(|res| res.map_err(std::io::Error::other)))
+            .read_to_end(&mut data)
+            .await
+            .expect("should read parcel");
+        assert_eq!(data, parcel.data);
+
+        let fetched = store
+            .get_invoice("enterprise.com/warpcore/1.0.0")
+            .await
+            .expect("should get invoice with a string ID");
+        assert_eq!(fetched.bindle.id, id);
+        assert!(matches!(
+            store.get_invoice("not a valid id!").await,
+            Err(ProviderError::InvalidId(_))
+        ));
+
+        store.yank_invoice(&id).await.expect("should yank invoice");
+        assert!(matches!(
+            store.get_invoice(&id).await,
+            Err(ProviderError::Yanked)
+        ));
+        let listed: Vec<Id> = store
+            .list_invoices(true)
+            .await
+            .expect("should list invoices")
+            .map(|res| res.expect("should be a valid ID"))
+            .collect()
+            .await;
+        assert_eq!(listed, vec![id]);
+    }
+}
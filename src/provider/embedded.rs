@@ -6,37 +6,98 @@
 //! [CBOR](https://github.com/pyfisch/cbor) format for efficient serialization/deserialization from
 //! the database.
 //!
+//! Parcels larger than a configurable threshold (see
+//! [`EmbeddedProvider::with_blob_threshold`]) are not stored in the database itself, as very large
+//! values lead to write amplification and memory spikes. Instead, they are streamed to
+//! content-addressed files in a directory beside the database, and the database only stores where
+//! to find them. A file is always fully written and synced to disk before the database points at
+//! it, and the database entry is always removed before the file. Anything left over on only one
+//! side by a crash is cleaned up the next time the provider is opened.
+//!
 //! This provider is currently experimental, with the goal of replacing the `FileProvider` as the
 //! default provider in the future.
 //!
 //! This will only be available if the `provider` feature is enabled
 
+use std::collections::HashMap;
 use std::convert::TryInto;
-use std::path::Path;
+use std::io::SeekFrom;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use sled::Error as SledError;
-use tokio::io::AsyncReadExt;
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::{Error as SledError, Transactional};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
 use tokio::sync::Semaphore;
 use tokio_stream::{Stream, StreamExt};
 use tokio_util::codec::{BytesCodec, FramedRead};
 use tokio_util::io::StreamReader;
 use tracing::{debug, error, info, instrument, trace, warn};
-use tracing_futures::Instrument;
 
-use crate::provider::{Provider, ProviderError, Result};
+use crate::provider::compression::{CompressionPolicy, SyncReader};
+use crate::provider::encryption::{self, EncryptionKeyFile};
+use crate::provider::file::{
+    decode_reader, validate_sha256, Encoding, PartFile, WriteOptions, PART_EXTENSION,
+};
+use crate::provider::fsck::{FsckOptions, FsckReport, QUARANTINE_NAME};
+use crate::provider::gc::{GcOptions, GcReport};
+use crate::provider::generation::{Change, GenerationTracker};
+use crate::provider::tiered::Evictable;
+use crate::provider::{is_parcel_id, parcel_label, Provider, ProviderError, Result};
 use crate::search::Search;
 use crate::verification::Verified;
 use crate::{Id, Signed};
 
 const INVOICE_DB_NAME: &str = "invoices";
+/// The tree that draft invoices are stored in until all of their parcels have been created
+const DRAFT_DB_NAME: &str = "drafts";
 const PARCEL_DB_NAME: &str = "parcels";
+/// The tree that parcels compressed with zstd are stored in. A parcel being stored in this tree
+/// rather than the main parcel tree marks it as compressed
+const COMPRESSED_PARCEL_DB_NAME: &str = "parcels.zst";
+/// The tree that encrypted (and possibly compressed) parcels are stored in
+const ENCRYPTED_PARCEL_DB_NAME: &str = "parcels.enc";
+/// The tree that holds the metadata of parcels that are stored as files outside of the database
+const BLOB_PARCEL_DB_NAME: &str = "parcels.blob";
+/// The tree that holds metadata about the database itself
+const METADATA_DB_NAME: &str = "metadata";
+/// The key (in the metadata tree) of the generation of the database, which is incremented before
+/// every change to its invoices
+const GENERATION_KEY: &str = "generation";
+/// The directory (within the storage directory) that large parcels are stored in. Note that sled
+/// already uses a `blobs` directory of its own
+const BLOB_DIRECTORY: &str = "large_parcels";
+/// The directory (within the blob directory) that fsck moves corrupt large parcels to
+const BLOB_QUARANTINE_DIRECTORY: &str = "quarantine";
+/// The size (in bytes) above which parcels are stored as files outside of the database by default
+pub const DEFAULT_BLOB_THRESHOLD: u64 = 4 * 1024 * 1024;
 // TODO: This number should be equal to the number of threads configured for blocking. We could
 // expose this value in the constructor, but that feels too much like a low-level detail to expose
 // in the API. But I also can't find a way to fetch this configured value
 const BLOCKING_THREAD_COUNT: usize = 512;
 
+/// A draft invoice as it is stored in the draft tree
+#[derive(Serialize, Deserialize)]
+struct Draft {
+    /// When the draft was created, in seconds since the Unix epoch
+    created: u64,
+    invoice: crate::Invoice,
+}
+
+/// Where to find a parcel that is stored outside of the database, as stored in the blob tree
+#[derive(Serialize, Deserialize)]
+struct BlobMetadata {
+    /// The name of the file within the blob directory. Its extension marks how the data is encoded
+    file: String,
+    /// The size of the file, which is different from the size of the parcel if it is compressed
+    /// or encrypted
+    stored_size: u64,
+}
+
 /// An embedded database backend for storing and retrieving bindles and parcels.
 ///
 /// Given a storage directory, EmbeddedProvider brings its own storage layout for keeping track of
@@ -46,18 +107,40 @@ const BLOCKING_THREAD_COUNT: usize = 512;
 /// the index will be updated.
 pub struct EmbeddedProvider<T> {
     invoices: sled::Tree,
+    drafts: sled::Tree,
     parcels: sled::Tree,
+    compressed_parcels: sled::Tree,
+    encrypted_parcels: sled::Tree,
+    blob_parcels: sled::Tree,
+    quarantine: sled::Tree,
+    metadata: sled::Tree,
     index: T,
+    generation: Arc<GenerationTracker>,
     semaphore: Arc<Semaphore>,
+    compression: Option<CompressionPolicy>,
+    encryption: Option<Arc<EncryptionKeyFile>>,
+    blob_dir: PathBuf,
+    blob_threshold: u64,
 }
 
 impl<T: Clone> Clone for EmbeddedProvider<T> {
     fn clone(&self) -> Self {
         EmbeddedProvider {
             invoices: self.invoices.clone(),
+            drafts: self.drafts.clone(),
             parcels: self.parcels.clone(),
+            compressed_parcels: self.compressed_parcels.clone(),
+            encrypted_parcels: self.encrypted_parcels.clone(),
+            blob_parcels: self.blob_parcels.clone(),
+            quarantine: self.quarantine.clone(),
+            metadata: self.metadata.clone(),
             index: self.index.clone(),
+            generation: self.generation.clone(),
             semaphore: self.semaphore.clone(),
+            compression: self.compression.clone(),
+            encryption: self.encryption.clone(),
+            blob_dir: self.blob_dir.clone(),
+            blob_threshold: self.blob_threshold,
         }
     }
 }
@@ -66,24 +149,352 @@ impl<T: Search + Send + Sync> EmbeddedProvider<T> {
     pub async fn new<P: AsRef<Path>>(storage_path: P, index: T) -> anyhow::Result<Self> {
         debug!(storage_path = %storage_path.as_ref().display(), "Creating new embedded provider");
         let sp = storage_path.as_ref().to_owned();
+        let blob_dir = storage_path.as_ref().join(BLOB_DIRECTORY);
         let db = tokio::task::spawn_blocking(|| sled::open(sp)).await??;
         let owned = db.clone();
         let invoices =
             tokio::task::spawn_blocking(move || owned.open_tree(INVOICE_DB_NAME)).await??;
-        let parcels = tokio::task::spawn_blocking(move || db.open_tree(PARCEL_DB_NAME)).await??;
+        let owned = db.clone();
+        let drafts = tokio::task::spawn_blocking(move || owned.open_tree(DRAFT_DB_NAME)).await??;
+        let owned = db.clone();
+        let parcels =
+            tokio::task::spawn_blocking(move || owned.open_tree(PARCEL_DB_NAME)).await??;
+        let owned = db.clone();
+        let compressed_parcels =
+            tokio::task::spawn_blocking(move || owned.open_tree(COMPRESSED_PARCEL_DB_NAME))
+                .await??;
+        let owned = db.clone();
+        let encrypted_parcels =
+            tokio::task::spawn_blocking(move || owned.open_tree(ENCRYPTED_PARCEL_DB_NAME))
+                .await??;
+        let owned = db.clone();
+        let blob_parcels =
+            tokio::task::spawn_blocking(move || owned.open_tree(BLOB_PARCEL_DB_NAME)).await??;
+        let owned = db.clone();
+        let quarantine =
+            tokio::task::spawn_blocking(move || owned.open_tree(QUARANTINE_NAME)).await??;
+        let metadata =
+            tokio::task::spawn_blocking(move || db.open_tree(METADATA_DB_NAME)).await??;
+        let owned = blob_parcels.clone();
+        let dir = blob_dir.clone();
+        tokio::task::spawn_blocking(move || recover_blobs(&owned, &dir)).await??;
         let emb = EmbeddedProvider {
             invoices,
+            drafts,
             parcels,
+            compressed_parcels,
+            encrypted_parcels,
+            blob_parcels,
+            quarantine,
+            metadata,
             index,
+            generation: Arc::new(GenerationTracker::default()),
             semaphore: Arc::new(Semaphore::new(BLOCKING_THREAD_COUNT)),
+            compression: None,
+            encryption: None,
+            blob_dir,
+            blob_threshold: DEFAULT_BLOB_THRESHOLD,
         };
         debug!("warming index");
-        if let Err(e) = emb.warm_index().await {
+        if let Err(e) = emb.sync_index().await {
             warn!(error = %e, "Error warming index");
         }
         Ok(emb)
     }
 
+    /// Compresses newly stored parcels that match the given policy. Parcels that are already
+    /// stored are left as they are
+    pub fn with_compression(mut self, policy: CompressionPolicy) -> Self {
+        self.compression = Some(policy);
+        self
+    }
+
+    /// Encrypts newly stored parcels with the active key in the given key file. The other keys in
+    /// the file are only used to read parcels that were encrypted with them. Parcels that are
+    /// already stored are left as they are
+    pub fn with_encryption(mut self, keys: EncryptionKeyFile) -> Self {
+        self.encryption = Some(Arc::new(keys));
+        self
+    }
+
+    /// Stores parcels larger than the given size (in bytes) as files outside of the database.
+    /// Defaults to [`DEFAULT_BLOB_THRESHOLD`]. Setting it to `u64::MAX` keeps all parcels in the
+    /// database. Parcels that are already stored are left where they are
+    pub fn with_blob_threshold(mut self, threshold: u64) -> Self {
+        self.blob_threshold = threshold;
+        self
+    }
+
+    /// Returns all trees that parcels (or the metadata of large parcels) can be stored in
+    fn parcel_trees(&self) -> [sled::Tree; 4] {
+        [
+            self.parcels.clone(),
+            self.compressed_parcels.clone(),
+            self.encrypted_parcels.clone(),
+            self.blob_parcels.clone(),
+        ]
+    }
+
+    /// Loads the full data for the given parcel, decrypting and decompressing it if needed.
+    /// Returns `ProviderError::NotFound` if the parcel isn't stored
+    async fn load_parcel(&self, parcel_id: &str) -> Result<sled::IVec> {
+        let parcels = self.parcels.clone();
+        let compressed_parcels = self.compressed_parcels.clone();
+        let encrypted_parcels = self.encrypted_parcels.clone();
+        let keys = self.encryption.clone();
+        let pid = parcel_id.to_owned();
+        spawn_lock(self.semaphore.clone(), move || {
+            if let Some(data) = parcels.get(&pid).map_err(map_sled_error)? {
+                return Ok(data);
+            }
+            if let Some(data) = compressed_parcels.get(&pid).map_err(map_sled_error)? {
+                return Ok(decode_parcel(COMPRESSED_PARCEL_DB_NAME, &data, None)?.into());
+            }
+            match encrypted_parcels.get(&pid).map_err(map_sled_error)? {
+                Some(data) => {
+                    Ok(decode_parcel(ENCRYPTED_PARCEL_DB_NAME, &data, keys.as_deref())?.into())
+                }
+                None => Err(ProviderError::NotFound),
+            }
+        })
+        .await?
+    }
+
+    /// Opens the data for the given parcel, starting at the given offset into the plaintext. Large
+    /// parcels are streamed from their file, while all other parcels are loaded into memory.
+    /// Returns `ProviderError::NotFound` if the parcel isn't stored
+    async fn open_parcel(
+        &self,
+        parcel_id: &str,
+        offset: u64,
+    ) -> Result<Box<dyn AsyncRead + Unpin + Send + Sync>> {
+        let blob_parcels = self.blob_parcels.clone();
+        let pid = parcel_id.to_owned();
+        let metadata: BlobMetadata =
+            match spawn_lock(self.semaphore.clone(), move || blob_parcels.get(pid))
+                .await?
+                .map_err(map_sled_error)?
+            {
+                Some(raw) => serde_cbor::from_slice(raw.as_ref())?,
+                None => {
+                    let mut data = std::io::Cursor::new(self.load_parcel(parcel_id).await?);
+                    // A position past the end of the cursor is allowed and will just result in an
+                    // empty read
+                    data.set_position(offset);
+                    return Ok(Box::new(data));
+                }
+            };
+
+        let path = self.blob_dir.join(&metadata.file);
+        let encoding = blob_encoding(&metadata.file);
+        trace!(path = %path.display(), ?encoding, offset, "Opening large parcel data");
+        let mut file = tokio::fs::File::open(&path).await.map_err(map_io_error)?;
+        if encoding == Encoding::Plain {
+            file.seek(SeekFrom::Start(offset))
+                .await
+                .map_err(map_io_error)?;
+            return Ok(Box::new(file));
+        }
+        let mut reader =
+            SyncReader::new(decode_reader(file, encoding, self.encryption.as_deref(), true).await?);
+        // Encoded data can't be seeked, so anything before the offset has to be decoded and thrown
+        // away
+        if offset > 0 {
+            tokio::io::copy(&mut (&mut reader).take(offset), &mut tokio::io::sink())
+                .await
+                .map_err(map_io_error)?;
+        }
+        Ok(Box::new(reader))
+    }
+
+    /// Streams the given parcel data to a file outside of the database and then inserts the
+    /// metadata pointing at it. The file is synced to disk before the metadata is inserted, so the
+    /// metadata never points at incomplete data
+    async fn create_blob<R, B>(&self, parcel_id: &str, label: &crate::Label, data: R) -> Result<()>
+    where
+        R: Stream<Item = std::io::Result<B>> + Unpin + Send + Sync + 'static,
+        B: bytes::Buf + Send,
+    {
+        if self.has_parcel(parcel_id).await? {
+            return Err(ProviderError::Exists);
+        }
+        let options = WriteOptions {
+            compression: self
+                .compression
+                .as_ref()
+                .filter(|policy| policy.should_compress(label))
+                .map(|policy| policy.encoder_level()),
+            encryption: self.encryption.clone(),
+        };
+        let file = blob_file_name(parcel_id, options.encoding());
+        let path = self.blob_dir.join(&file);
+
+        debug!(path = %path.display(), "Writing parcel data to file");
+        let mut part = PartFile::new(path.clone()).await?;
+        part.write_parcel(data, parcel_id, label.size, &options)
+            .await?;
+        part.finalize().await?;
+        let metadata = serde_cbor::to_vec(&BlobMetadata {
+            file,
+            stored_size: tokio::fs::metadata(&path).await?.len(),
+        })?;
+
+        debug!("Inserting parcel metadata into database");
+        let parcel_trees = self.parcel_trees();
+        let blob_parcels = self.blob_parcels.clone();
+        let pid = parcel_id.to_owned();
+        let res = spawn_lock(self.semaphore.clone(), move || {
+            if contains_parcel(&parcel_trees, &pid).map_err(map_sled_error)? {
+                return Err(ProviderError::Exists);
+            }
+            match blob_parcels.compare_and_swap(&pid, None as Option<&[u8]>, Some(metadata)) {
+                Ok(Ok(())) => Ok(()),
+                Err(e) => Err(map_sled_error(e)),
+                // This error is only possible if the parcel already exists
+                Ok(Err(_)) => Err(ProviderError::Exists),
+            }
+        })
+        .await?;
+        if res.is_err() {
+            // Nothing points at the file we just wrote, so it has to go
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                warn!(path = %path.display(), error = %e, "Unable to remove unused parcel file");
+            }
+        }
+        res
+    }
+
+    /// Checks the integrity of all large parcels, moving offenders to the quarantine tree and
+    /// directory if requested. Returns the report so far and the size of every intact parcel
+    async fn fsck_blobs(
+        &self,
+        should_quarantine: bool,
+    ) -> Result<(FsckReport, HashMap<String, u64>)> {
+        let mut report = FsckReport {
+            quarantined: should_quarantine,
+            ..Default::default()
+        };
+        let mut parcel_sizes = HashMap::new();
+        let blob_parcels = self.blob_parcels.clone();
+        let entries = spawn_lock(self.semaphore.clone(), move || {
+            blob_parcels
+                .iter()
+                .collect::<std::result::Result<Vec<_>, _>>()
+        })
+        .await?
+        .map_err(map_sled_error)?;
+
+        for (key, raw) in entries {
+            let parcel_id = String::from_utf8_lossy(key.as_ref()).into_owned();
+            report.checked_parcels += 1;
+            // Unreadable metadata is just as corrupt as data that doesn't match its SHA
+            let metadata = serde_cbor::from_slice::<BlobMetadata>(raw.as_ref()).ok();
+            let size = match metadata.as_ref() {
+                Some(metadata) => self.check_blob(&parcel_id, metadata).await?,
+                None => None,
+            };
+            if let Some(size) = size {
+                parcel_sizes.insert(parcel_id, size);
+                continue;
+            }
+            warn!(%parcel_id, "Large parcel data does not match its SHA");
+            if should_quarantine {
+                let quarantine = self.quarantine.clone();
+                let blob_parcels = self.blob_parcels.clone();
+                let quarantine_key = format!("{}/{}", BLOB_PARCEL_DB_NAME, parcel_id);
+                spawn_lock(self.semaphore.clone(), move || {
+                    quarantine
+                        .insert(quarantine_key, raw)
+                        .and_then(|_| blob_parcels.remove(key))
+                })
+                .await?
+                .map_err(map_sled_error)?;
+                // The metadata is gone, so the file can be moved out of the way
+                if let Some(metadata) = metadata {
+                    let dir = self.blob_dir.join(BLOB_QUARANTINE_DIRECTORY);
+                    tokio::fs::create_dir_all(&dir).await?;
+                    match tokio::fs::rename(
+                        self.blob_dir.join(&metadata.file),
+                        dir.join(&metadata.file),
+                    )
+                    .await
+                    {
+                        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                        _ => (),
+                    }
+                }
+            }
+            report.corrupt_parcels.push(parcel_id);
+        }
+        Ok((report, parcel_sizes))
+    }
+
+    /// Decodes and hashes the file of the given large parcel, returning its size if it matches the
+    /// parcel's SHA. Data that is missing or can't be decoded returns `None`, while a missing
+    /// encryption key returns an error
+    async fn check_blob(&self, parcel_id: &str, metadata: &BlobMetadata) -> Result<Option<u64>> {
+        let file = match tokio::fs::File::open(self.blob_dir.join(&metadata.file)).await {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let encoding = blob_encoding(&metadata.file);
+        let res = match decode_reader(file, encoding, self.encryption.as_deref(), true).await {
+            Ok(mut reader) => validate_sha256(&mut reader, parcel_id).await,
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok(size) => Ok(Some(size)),
+            Err(ProviderError::DigestMismatch) => Ok(None),
+            Err(ProviderError::Io(e)) => {
+                debug!(%parcel_id, error = %e, "Unable to decode large parcel data");
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Brings the index in sync with the invoices in the DB. An index that recorded the current
+    /// generation of the DB (because it persists across restarts) is already in sync, while any
+    /// other index is cleared and warmed
+    async fn sync_index(&self) -> anyhow::Result<()> {
+        let snapshot = self.generation.snapshot();
+        let metadata = self.metadata.clone();
+        let generation = spawn_lock(self.semaphore.clone(), move || metadata.get(GENERATION_KEY))
+            .await?
+            .map_err(map_sled_error)?;
+        let generation = decode_generation(generation.as_deref());
+        if self.index.generation().await? == Some(generation) {
+            info!(generation, "Search index is in sync with the database");
+        } else {
+            self.index.clear().await?;
+            self.warm_index().await?;
+        }
+        self.generation
+            .synced(&self.index, snapshot, generation)
+            .await
+    }
+
+    /// Increments the generation of the DB. This MUST be called before changing any invoice, and
+    /// the returned change finished once the index has been updated
+    async fn begin_change(&self) -> Result<Change<'_>> {
+        let metadata = self.metadata.clone();
+        let semaphore = self.semaphore.clone();
+        self.generation
+            .begin(|| async move {
+                let current = spawn_lock(semaphore, move || {
+                    metadata.update_and_fetch(GENERATION_KEY, |old| {
+                        Some((decode_generation(old) + 1).to_be_bytes().to_vec())
+                    })
+                })
+                .await?
+                .map_err(map_sled_error)?;
+                let current = decode_generation(current.as_deref());
+                Ok((current - 1, current))
+            })
+            .await
+    }
+
     /// This warms the index by loading all of the invoices currently in the DB
     ///
     /// Warming the index is something that the storage backend should do, though I am
@@ -97,6 +508,7 @@ impl<T: Search + Send + Sync> EmbeddedProvider<T> {
         // Read all invoices
         info!("Beginning index warm");
         let mut total_indexed: u64 = 0;
+        let mut failed: u64 = 0;
         // NOTE(thomastaylor312): Trying to do this async and spawn blocking is impossible unless we
         // add a clone constraint to T. So technically this could cause a blocking issue depending
         // on the cache size and if there are other IO operations (though it does have the advantage
@@ -117,12 +529,101 @@ impl<T: Search + Send + Sync> EmbeddedProvider<T> {
 
             if let Err(e) = self.index.index(&invoice).await {
                 error!(invoice_id = %invoice.bindle.id, error = %e, "Error indexing invoice");
+                failed += 1;
+                continue;
             }
             total_indexed += 1;
         }
         debug!(total_indexed, "Warmed index");
+        if failed > 0 {
+            anyhow::bail!("{} invoices could not be indexed", failed);
+        }
         Ok(())
     }
+
+    /// Removes all parcels that are not referenced by any invoice (including yanked invoices).
+    ///
+    /// Parcels are written to the database atomically, so there are never any partially written
+    /// parcels to clean up and the grace period in the options is ignored (partially written large
+    /// parcels are instead cleaned up when the provider is opened). If `dry_run` is set in
+    /// the options, nothing will be removed, but the returned report will contain everything that
+    /// would have been removed
+    #[instrument(level = "trace", skip(self))]
+    pub async fn gc(&self, options: GcOptions) -> Result<GcReport> {
+        info!(dry_run = options.dry_run, "Beginning garbage collection");
+        let drafts = self.drafts.clone();
+        let invoices = self.invoices.clone();
+        let parcel_trees = self.parcel_trees();
+        let blob_dir = self.blob_dir.clone();
+        let report = spawn_lock(self.semaphore.clone(), move || {
+            gc_trees(
+                &drafts,
+                &invoices,
+                &parcel_trees,
+                &blob_dir,
+                options.dry_run,
+            )
+        })
+        .await??;
+        info!(
+            removed_parcels = report.removed_parcels.len(),
+            reclaimed_bytes = report.reclaimed_bytes,
+            "Finished garbage collection"
+        );
+        Ok(report)
+    }
+
+    /// Checks the integrity of all stored invoices and parcels. Every parcel is decrypted and
+    /// decompressed (if needed) and re-hashed against the SHA it is stored under, every invoice is checked to be stored under the SHA of its
+    /// canonical name, and every parcel referenced by an invoice is checked to exist with the size
+    /// given in its label.
+    ///
+    /// If `quarantine` is set in the options, offending parcels and invoices are moved into a
+    /// separate quarantine tree in the database. The files of offending large parcels are moved
+    /// into a `quarantine` directory within the directory they are stored in. Encrypted parcels whose key is not configured
+    /// can't be checked, so an error is returned rather than reporting them as corrupt
+    #[instrument(level = "trace", skip(self))]
+    pub async fn fsck(&self, options: FsckOptions) -> Result<FsckReport> {
+        info!(quarantine = options.quarantine, "Beginning integrity check");
+        let (report, parcel_sizes) = self.fsck_blobs(options.quarantine).await?;
+        let invoices = self.invoices.clone();
+        let parcels = self.parcels.clone();
+        let compressed_parcels = self.compressed_parcels.clone();
+        let encrypted_parcels = self.encrypted_parcels.clone();
+        let quarantine = self.quarantine.clone();
+        let keys = self.encryption.clone();
+        // Quarantining invoices changes them
+        let change = match options.quarantine {
+            true => Some(self.begin_change().await?),
+            false => None,
+        };
+        let report = spawn_lock(self.semaphore.clone(), move || {
+            fsck_trees(
+                report,
+                parcel_sizes,
+                &invoices,
+                &[&parcels, &compressed_parcels, &encrypted_parcels],
+                &quarantine,
+                keys.as_deref(),
+                options.quarantine,
+            )
+        })
+        .await??;
+        if let Some(change) = change {
+            // Quarantined invoices aren't removed from the index, so it has to be rebuilt the next
+            // time the provider is created
+            let unchanged =
+                report.unreadable_invoices.is_empty() && report.misnamed_invoices.is_empty();
+            change.finish(&self.index, unchanged).await;
+        }
+        info!(
+            checked_invoices = report.checked_invoices,
+            checked_parcels = report.checked_parcels,
+            clean = report.is_clean(),
+            "Finished integrity check"
+        );
+        Ok(report)
+    }
 }
 
 #[async_trait::async_trait]
@@ -134,7 +635,7 @@ impl<T: crate::search::Search + Send + Sync> Provider for EmbeddedProvider<T> {
     {
         let inv = invoice.signed();
         tracing::span::Span::current()
-            .record("invoice_id", &tracing::field::display(&inv.bindle.id));
+            .record("invoice_id", tracing::field::display(&inv.bindle.id));
         // It is illegal to create a yanked invoice.
         if inv.yanked.unwrap_or(false) {
             debug!(id = %inv.bindle.id, "Invoice being created is set to yanked");
@@ -143,11 +644,22 @@ impl<T: crate::search::Search + Send + Sync> Provider for EmbeddedProvider<T> {
 
         let invoice_id = inv.canonical_name();
 
+        let drafts = self.drafts.clone();
+        let key = invoice_id.clone();
+        if spawn_lock(self.semaphore.clone(), move || drafts.contains_key(key))
+            .await?
+            .map_err(map_sled_error)?
+        {
+            debug!("Invoice being created already exists in storage as a draft");
+            return Err(ProviderError::Exists);
+        }
+
         let invoices = self.invoices.clone();
 
         let serialized = serde_cbor::to_vec(&inv)?;
 
         debug!("Inserting invoice into database");
+        let change = self.begin_change().await?;
         let res = spawn_lock(self.semaphore.clone(), move || {
             invoices.compare_and_swap(&invoice_id, None as Option<&[u8]>, Some(serialized))
         })
@@ -157,53 +669,186 @@ impl<T: crate::search::Search + Send + Sync> Provider for EmbeddedProvider<T> {
             Ok(Ok(())) => (),
             Err(e) => return Err(map_sled_error(e)),
             // We'll only get a compare and swap error if it already exists
-            Ok(Err(_)) => return Err(ProviderError::Exists),
+            Ok(Err(_)) => {
+                change.finish(&self.index, true).await;
+                return Err(ProviderError::Exists);
+            }
         }
 
         // Attempt to update the index. Right now, we log an error if the index update
         // fails.
-        if let Err(e) = self.index.index(&inv).await {
+        let indexed = self.index.index(&inv).await;
+        if let Err(e) = &indexed {
             error!(error = %e, "Error indexing new invoice");
         }
+        change.finish(&self.index, indexed.is_ok()).await;
+
+        let labels = crate::provider::missing_parcels(self, &inv).await?;
+        Ok((inv, labels))
+    }
 
-        // if there are no parcels, bail early
-        if inv.parcel.is_none() {
-            return Ok((inv, Vec::with_capacity(0)));
+    #[instrument(level = "trace", skip(self, invoice), fields(invoice_id = tracing::field::Empty))]
+    async fn create_draft_invoice<I>(
+        &self,
+        invoice: I,
+    ) -> Result<(crate::Invoice, Vec<crate::Label>)>
+    where
+        I: Signed + Verified + Send + Sync,
+    {
+        let inv = invoice.signed();
+        tracing::span::Span::current()
+            .record("invoice_id", tracing::field::display(&inv.bindle.id));
+        // It is illegal to create a yanked invoice.
+        if inv.yanked.unwrap_or(false) {
+            debug!(id = %inv.bindle.id, "Invoice being created is set to yanked");
+            return Err(ProviderError::CreateYanked);
         }
 
-        trace!("Checking for missing parcels listed in newly created invoice");
-        let s = self.semaphore.clone();
-        let parcels = self.parcels.clone();
-        // Loop through the boxes and see what exists
-        let missing = inv
-            .parcel
-            // Need to clone so we can move into the spawn_lock
-            .clone()
-            .unwrap_or_default()
-            .into_iter()
-            .map(|k| (s.clone(), parcels.clone(), k.label))
-            .map(|(s, parcels, label)| async move {
-                // Check if the parcel exists in the database
-                let sha = label.sha256.to_owned();
-                let found = spawn_lock(s, move || parcels.contains_key(&sha).unwrap_or(false))
-                    .await
-                    .unwrap_or(false);
-                if found {
-                    None
-                } else {
-                    Some(label)
-                }
-            });
+        let invoice_id = inv.canonical_name();
+        let serialized = serde_cbor::to_vec(&Draft {
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            invoice: inv.clone(),
+        })?;
 
-        let labels = futures::future::join_all(missing)
-            .instrument(tracing::trace_span!("lookup_missing"))
-            .await
-            .into_iter()
-            .flatten()
-            .collect();
+        debug!("Inserting draft invoice into database");
+        let invoices = self.invoices.clone();
+        let drafts = self.drafts.clone();
+        let inserted = spawn_lock(self.semaphore.clone(), move || {
+            if invoices.contains_key(&invoice_id)? {
+                return Ok(false);
+            }
+            // We'll only get a compare and swap error if the draft already exists
+            drafts
+                .compare_and_swap(&invoice_id, None as Option<&[u8]>, Some(serialized))
+                .map(|res| res.is_ok())
+        })
+        .await?
+        .map_err(map_sled_error)?;
+        if !inserted {
+            return Err(ProviderError::Exists);
+        }
+
+        // If nothing is missing, this publishes the draft right away
+        let labels = self.publish_draft_invoice(&inv.bindle.id).await?;
         Ok((inv, labels))
     }
 
+    #[instrument(level = "trace", skip(self, id), fields(id))]
+    async fn get_draft_invoice<I>(&self, id: I) -> Result<crate::Invoice>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id: Id = id.try_into().map_err(|e| e.into())?;
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
+
+        debug!("Getting draft invoice from database");
+        let invoice_id = parsed_id.sha();
+        let drafts = self.drafts.clone();
+        let data = match spawn_lock(self.semaphore.clone(), move || drafts.get(&invoice_id))
+            .await?
+            .map_err(map_sled_error)?
+        {
+            Some(d) => d,
+            None => return Err(ProviderError::NotFound),
+        };
+        let draft: Draft = serde_cbor::from_slice(data.as_ref())?;
+        Ok(draft.invoice)
+    }
+
+    #[instrument(level = "trace", skip(self, id), fields(id))]
+    async fn publish_draft_invoice<I>(&self, id: I) -> Result<Vec<crate::Label>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id: Id = id.try_into().map_err(|e| e.into())?;
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
+
+        let inv = self.get_draft_invoice(&parsed_id).await?;
+        let missing = crate::provider::missing_parcels(self, &inv).await?;
+        if !missing.is_empty() {
+            debug!(
+                missing = missing.len(),
+                "Draft invoice is still missing parcels"
+            );
+            return Ok(missing);
+        }
+
+        debug!("Publishing draft invoice");
+        let serialized = serde_cbor::to_vec(&inv)?;
+        let invoice_id = parsed_id.sha();
+        let drafts = self.drafts.clone();
+        let invoices = self.invoices.clone();
+        let change = self.begin_change().await?;
+        let res = spawn_lock(self.semaphore.clone(), move || {
+            // Moving the invoice between trees in a single transaction makes it visible all at
+            // once. If two requests try to publish the same draft, only one of them will find it
+            (&drafts, &invoices).transaction(|(drafts, invoices)| {
+                if drafts.remove(invoice_id.as_bytes())?.is_none() {
+                    return Err(ConflictableTransactionError::Abort(ProviderError::NotFound));
+                }
+                if invoices.get(invoice_id.as_bytes())?.is_some() {
+                    return Err(ConflictableTransactionError::Abort(ProviderError::Exists));
+                }
+                invoices.insert(invoice_id.as_bytes(), serialized.as_slice())?;
+                Ok(())
+            })
+        })
+        .await?;
+        match res {
+            Ok(()) => (),
+            // Nothing was changed if the transaction was aborted
+            Err(TransactionError::Abort(e)) => {
+                change.finish(&self.index, true).await;
+                return Err(e);
+            }
+            Err(TransactionError::Storage(e)) => return Err(map_sled_error(e)),
+        }
+
+        // Attempt to update the index. Right now, we log an error if the index update
+        // fails.
+        let indexed = self.index.index(&inv).await;
+        if let Err(e) = &indexed {
+            error!(error = %e, "Error indexing published invoice");
+        }
+        change.finish(&self.index, indexed.is_ok()).await;
+        Ok(missing)
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    async fn expire_draft_invoices(&self, ttl: Duration) -> Result<Vec<Id>> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let drafts = self.drafts.clone();
+        spawn_lock(self.semaphore.clone(), move || {
+            let mut expired = Vec::new();
+            for res in drafts.iter() {
+                let (key, data) = res.map_err(map_sled_error)?;
+                let draft: Draft = serde_cbor::from_slice(data.as_ref())?;
+                if now.saturating_sub(draft.created) < ttl.as_secs() {
+                    continue;
+                }
+                debug!(id = %draft.invoice.bindle.id, "Removing expired draft invoice");
+                // Only remove the draft if it wasn't published in the meantime
+                if drafts
+                    .compare_and_swap(&key, Some(&data), None as Option<&[u8]>)
+                    .map_err(map_sled_error)?
+                    .is_ok()
+                {
+                    expired.push(draft.invoice.bindle.id);
+                }
+            }
+            Ok(expired)
+        })
+        .await?
+    }
+
     #[instrument(level = "trace", skip(self, id), fields(id))]
     async fn get_yanked_invoice<I>(&self, id: I) -> Result<crate::Invoice>
     where
@@ -211,7 +856,7 @@ impl<T: crate::search::Search + Send + Sync> Provider for EmbeddedProvider<T> {
         I::Error: Into<ProviderError>,
     {
         let parsed_id: Id = id.try_into().map_err(|e| e.into())?;
-        tracing::Span::current().record("id", &tracing::field::display(&parsed_id));
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
 
         // NOTE: sled has its own caching, so we don't need to worry about manually implementing
         // here
@@ -242,7 +887,7 @@ impl<T: crate::search::Search + Send + Sync> Provider for EmbeddedProvider<T> {
         I::Error: Into<ProviderError>,
     {
         let parsed_id = id.try_into().map_err(|e| e.into())?;
-        tracing::Span::current().record("id", &tracing::field::display(&parsed_id));
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
         trace!("Fetching invoice from storage");
         let mut inv = self.get_yanked_invoice(&parsed_id).await?;
         inv.yanked = Some(true);
@@ -254,10 +899,12 @@ impl<T: crate::search::Search + Send + Sync> Provider for EmbeddedProvider<T> {
         // value from the DB right before we mutate, but the consequences of this are likely small
         // or non-existent, so we aren't worrying about wrapping in a transaction
 
+        let change = self.begin_change().await?;
         // Attempt to update the index. Right now, we log an error if the index update
         // fails.
         trace!("Indexing yanked invoice");
-        if let Err(e) = self.index.index(&inv).await {
+        let indexed = self.index.index(&inv).await;
+        if let Err(e) = &indexed {
             error!(error = %e, "Error indexing yanked invoice");
         }
 
@@ -272,10 +919,105 @@ impl<T: crate::search::Search + Send + Sync> Provider for EmbeddedProvider<T> {
         })
         .await?
         .map_err(map_sled_error)?;
+        change.finish(&self.index, indexed.is_ok()).await;
 
         Ok(())
     }
 
+    #[instrument(level = "trace", skip(self, id), fields(id))]
+    async fn delete_invoice<I>(&self, id: I) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id: Id = id.try_into().map_err(|e| e.into())?;
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
+
+        debug!("Deleting invoice from database");
+        let invoice_id = parsed_id.sha();
+        let invoices = self.invoices.clone();
+        let change = self.begin_change().await?;
+        if spawn_lock(self.semaphore.clone(), move || invoices.remove(&invoice_id))
+            .await?
+            .map_err(map_sled_error)?
+            .is_none()
+        {
+            change.finish(&self.index, true).await;
+            return Err(ProviderError::NotFound);
+        }
+
+        // Attempt to update the index. Right now, we log an error if the index update
+        // fails.
+        trace!("Removing deleted invoice from index");
+        let removed = self.index.remove(&parsed_id).await;
+        if let Err(e) = &removed {
+            error!(error = %e, "Error removing deleted invoice from index");
+        }
+        change.finish(&self.index, removed.is_ok()).await;
+        Ok(())
+    }
+
+    #[instrument(level = "trace", skip(self, bindle_id), fields(id))]
+    async fn validate_parcel<I>(&self, bindle_id: I, parcel_id: &str) -> Result<crate::Label>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id: Id = bindle_id.try_into().map_err(|e| e.into())?;
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
+        // Parcels can also be created for drafts, which are waiting on exactly that
+        let inv = match self.get_yanked_invoice(&parsed_id).await {
+            Err(ProviderError::NotFound) => self.get_draft_invoice(&parsed_id).await?,
+            res => res?,
+        };
+        parcel_label(inv, parcel_id)
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    async fn list_invoices(
+        &self,
+        include_yanked: bool,
+    ) -> Result<Box<dyn Stream<Item = Result<Id>> + Unpin + Send + Sync>> {
+        // Only the keys are read up front. Each invoice is loaded as the stream is consumed
+        let invoices = self.invoices.clone();
+        let keys = spawn_lock(self.semaphore.clone(), move || {
+            invoices
+                .iter()
+                .keys()
+                .collect::<std::result::Result<Vec<_>, _>>()
+        })
+        .await?
+        .map_err(map_sled_error)?;
+        debug!(total = keys.len(), "Listing invoices");
+
+        let invoices = self.invoices.clone();
+        let semaphore = self.semaphore.clone();
+        let stream = tokio_stream::iter(keys)
+            .then(move |key| {
+                let invoices = invoices.clone();
+                let semaphore = semaphore.clone();
+                async move {
+                    let data = match spawn_lock(semaphore, move || invoices.get(key)).await {
+                        Ok(Ok(Some(d))) => d,
+                        // The invoice was deleted after we listed it
+                        Ok(Ok(None)) => return None,
+                        Ok(Err(e)) => return Some(Err(map_sled_error(e))),
+                        Err(e) => return Some(Err(e)),
+                    };
+                    Some(
+                        serde_cbor::from_slice::<crate::Invoice>(data.as_ref())
+                            .map_err(ProviderError::from),
+                    )
+                }
+            })
+            .filter_map(move |res| match res? {
+                Ok(inv) if !include_yanked && inv.yanked.unwrap_or(false) => None,
+                Ok(inv) => Some(Ok(inv.bindle.id)),
+                Err(e) => Some(Err(e)),
+            });
+        Ok(Box::new(Box::pin(stream)))
+    }
+
     #[instrument(level = "trace", skip(self, bindle_id, data), fields(id))]
     async fn create_parcel<I, R, B>(&self, bindle_id: I, parcel_id: &str, data: R) -> Result<()>
     where
@@ -286,19 +1028,22 @@ impl<T: crate::search::Search + Send + Sync> Provider for EmbeddedProvider<T> {
     {
         debug!("Validating bindle -> parcel relationship");
         let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
-        tracing::Span::current().record("id", &tracing::field::display(&parsed_id));
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
         let label = self.validate_parcel(parsed_id, parcel_id).await?;
 
+        // Only SHAs are used as file names, so that a label can't point anywhere else on disk
+        if label.size > self.blob_threshold && is_parcel_id(parcel_id) {
+            return self.create_blob(parcel_id, &label, data).await;
+        }
+
         debug!("Reading data from stream");
 
         // Read the data into memory (it is going to start there anyway in the database before
         // getting flushed to disk)
         let mut parcel_data: Vec<u8> = Vec::with_capacity(label.size as usize);
-        StreamReader::new(
-            data.map(|res| res.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))),
-        )
-        .read_to_end(&mut parcel_data)
-        .await?;
+        StreamReader::new(data.map(|res| res.map_err(std::io::Error::other)))
+            .read_to_end(&mut parcel_data)
+            .await?;
 
         debug!("Validating size");
         if parcel_data.len() as u64 != label.size {
@@ -318,19 +1063,57 @@ impl<T: crate::search::Search + Send + Sync> Provider for EmbeddedProvider<T> {
         }
 
         debug!("Inserting parcel into database");
-        let parcels = self.parcels.clone();
+        let [parcels, compressed_parcels, encrypted_parcels, blob_parcels] = self.parcel_trees();
+        let keys = self.encryption.clone();
+        let level = self
+            .compression
+            .as_ref()
+            .filter(|policy| policy.should_compress(&label))
+            .map(|policy| policy.zstd_level());
         let pid = parcel_id.to_owned();
-        let res = spawn_lock(self.semaphore.clone(), move || {
-            parcels.compare_and_swap(&pid, None as Option<&[u8]>, Some(parcel_data))
+        spawn_lock(self.semaphore.clone(), move || {
+            if contains_parcel(
+                &[
+                    parcels.clone(),
+                    compressed_parcels.clone(),
+                    encrypted_parcels.clone(),
+                    blob_parcels,
+                ],
+                &pid,
+            )
+            .map_err(map_sled_error)?
+            {
+                return Err(ProviderError::Exists);
+            }
+            let (tree, data) = match level {
+                Some(level) => {
+                    trace!(level, "Compressing parcel data");
+                    (
+                        compressed_parcels,
+                        zstd::stream::encode_all(parcel_data.as_slice(), level)?,
+                    )
+                }
+                None => (parcels, parcel_data),
+            };
+            // Data is compressed before it is encrypted, as encrypted data doesn't compress
+            let (tree, data) = match keys {
+                Some(keys) => {
+                    trace!("Encrypting parcel data");
+                    (
+                        encrypted_parcels,
+                        encryption::encrypt_bytes(&keys, level.is_some(), &data)?,
+                    )
+                }
+                None => (tree, data),
+            };
+            match tree.compare_and_swap(&pid, None as Option<&[u8]>, Some(data)) {
+                Ok(Ok(())) => Ok(()),
+                Err(e) => Err(map_sled_error(e)),
+                // This error is only possible if the parcel already exists
+                Ok(Err(_)) => Err(ProviderError::Exists),
+            }
         })
-        .await?;
-
-        match res {
-            Ok(Ok(())) => Ok(()),
-            Err(e) => Err(map_sled_error(e)),
-            // This error is only possible if the parcel already exists
-            Ok(Err(_)) => Err(ProviderError::Exists),
-        }
+        .await?
     }
 
     #[instrument(level = "trace", skip(self, bindle_id), fields(id))]
@@ -345,20 +1128,43 @@ impl<T: crate::search::Search + Send + Sync> Provider for EmbeddedProvider<T> {
     {
         debug!("Validating bindle -> parcel relationship");
         let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
-        tracing::Span::current().record("id", &tracing::field::display(&parsed_id));
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
         self.validate_parcel(parsed_id, parcel_id).await?;
 
         debug!("Getting parcel from storage");
-        let parcels = self.parcels.clone();
-        let pid = parcel_id.to_owned();
-        let data = match spawn_lock(self.semaphore.clone(), move || parcels.get(&pid))
+        let data = self.open_parcel(parcel_id, 0).await?;
+
+        Ok::<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>, _>(Box::new(
+            FramedRead::new(data, BytesCodec::new())
+                .map(|res| res.map_err(map_io_error).map(|b| b.freeze())),
+        ))
+    }
+
+    #[instrument(level = "trace", skip(self, bindle_id), fields(id))]
+    async fn get_parcel_range<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+        range: Range<u64>,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        debug!("Validating bindle -> parcel relationship");
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
+        self.validate_parcel(parsed_id, parcel_id).await?;
+
+        debug!(
+            start = range.start,
+            end = range.end,
+            "Getting parcel range from storage"
+        );
+        let data = self
+            .open_parcel(parcel_id, range.start)
             .await?
-            .map_err(map_sled_error)?
-        {
-            // Wrap the data in a cursor so it implements AsyncRead and can be streamed
-            Some(d) => std::io::Cursor::new(d),
-            None => return Err(ProviderError::NotFound),
-        };
+            .take(range.end.saturating_sub(range.start));
 
         Ok::<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>, _>(Box::new(
             FramedRead::new(data, BytesCodec::new())
@@ -374,15 +1180,59 @@ impl<T: crate::search::Search + Send + Sync> Provider for EmbeddedProvider<T> {
     {
         debug!("Validating bindle -> parcel relationship");
         let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
-        tracing::Span::current().record("id", &tracing::field::display(&parsed_id));
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
         self.validate_parcel(parsed_id, parcel_id).await?;
 
         debug!("Checking if parcel exists in storage");
+        self.has_parcel(parcel_id).await
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    async fn has_parcel(&self, parcel_id: &str) -> Result<bool> {
         let pid = parcel_id.to_owned();
-        let parcels = self.parcels.clone();
-        spawn_lock(self.semaphore.clone(), move || parcels.contains_key(&pid))
-            .await?
-            .map_err(map_sled_error)
+        let parcel_trees = self.parcel_trees();
+        spawn_lock(self.semaphore.clone(), move || {
+            contains_parcel(&parcel_trees, &pid)
+        })
+        .await?
+        .map_err(map_sled_error)
+    }
+
+    #[instrument(level = "trace", skip(self, parcel_ids), fields(num_parcels = parcel_ids.len()))]
+    async fn parcels_exist(&self, parcel_ids: &[&str]) -> Result<Vec<bool>> {
+        let ids: Vec<String> = parcel_ids.iter().map(|id| id.to_string()).collect();
+        let parcel_trees = self.parcel_trees();
+        // Checking all of the parcels in a single blocking task avoids a thread hop per parcel
+        spawn_lock(self.semaphore.clone(), move || {
+            ids.iter()
+                .map(|id| contains_parcel(&parcel_trees, id))
+                .collect::<sled::Result<Vec<bool>>>()
+        })
+        .await?
+        .map_err(map_sled_error)
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: Search + Send + Sync> Evictable for EmbeddedProvider<T> {
+    #[instrument(level = "trace", skip(self))]
+    async fn evict_parcel(&self, parcel_id: &str) -> Result<()> {
+        debug!(%parcel_id, "Evicting parcel");
+        let pid = parcel_id.to_owned();
+        let parcel_trees = self.parcel_trees();
+        let blob_dir = self.blob_dir.clone();
+        let removed = spawn_lock(self.semaphore.clone(), move || {
+            let mut removed = false;
+            for tree in parcel_trees.iter() {
+                removed |= remove_parcel(tree, &pid, &blob_dir, false)?.is_some();
+            }
+            Ok::<_, ProviderError>(removed)
+        })
+        .await??;
+        if !removed {
+            return Err(ProviderError::NotFound);
+        }
+        Ok(())
     }
 }
 
@@ -393,7 +1243,14 @@ fn map_io_error(e: std::io::Error) -> ProviderError {
     ProviderError::from(e)
 }
 
-fn map_sled_error(e: SledError) -> ProviderError {
+/// Decodes a generation stored in the metadata tree. A missing (or invalid) generation is 0
+fn decode_generation(raw: Option<&[u8]>) -> u64 {
+    raw.and_then(|raw| raw.try_into().ok())
+        .map(u64::from_be_bytes)
+        .unwrap_or_default()
+}
+
+pub(crate) fn map_sled_error(e: SledError) -> ProviderError {
     match &e {
         // This is a panicable error because if the collection is somehow gone, we can't keep
         // continuing
@@ -426,6 +1283,301 @@ fn map_sled_error(e: SledError) -> ProviderError {
     }
 }
 
+/// Returns whether the given parcel is stored in any of the given parcel trees
+fn contains_parcel(parcel_trees: &[sled::Tree], parcel_id: &str) -> sled::Result<bool> {
+    for tree in parcel_trees {
+        if tree.contains_key(parcel_id)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Returns the plaintext of parcel data stored in the tree with the given name. Data that can't be
+/// decrypted or decompressed returns an IO error, while a missing encryption key returns any other
+/// error
+fn decode_parcel(
+    tree_name: &str,
+    data: &[u8],
+    keys: Option<&EncryptionKeyFile>,
+) -> Result<Vec<u8>> {
+    match tree_name {
+        COMPRESSED_PARCEL_DB_NAME => Ok(zstd::stream::decode_all(data)?),
+        ENCRYPTED_PARCEL_DB_NAME => {
+            let keys = keys.ok_or_else(|| {
+                ProviderError::Other(
+                    "Parcel data is encrypted, but no encryption keys are configured".to_owned(),
+                )
+            })?;
+            let (header, plaintext) = encryption::decrypt_bytes(keys, data)?;
+            if header.compressed {
+                Ok(zstd::stream::decode_all(plaintext.as_slice())?)
+            } else {
+                Ok(plaintext)
+            }
+        }
+        _ => Ok(data.to_vec()),
+    }
+}
+
+/// Removes all parcels in the given parcel trees that are not referenced by an invoice in the
+/// draft or invoice trees
+fn gc_trees(
+    drafts: &sled::Tree,
+    invoices: &sled::Tree,
+    parcel_trees: &[sled::Tree],
+    blob_dir: &Path,
+    dry_run: bool,
+) -> Result<GcReport> {
+    let mut report = GcReport {
+        dry_run,
+        ..Default::default()
+    };
+
+    // NOTE: The parcels MUST be listed before the invoices are read. A parcel can only be created
+    // once its invoice exists, so every parcel we see here is guaranteed to have its invoice
+    // included in the live set, even if both were created while we were running
+    let mut parcel_ids = Vec::new();
+    for parcels in parcel_trees {
+        for res in parcels.iter().keys() {
+            let key = res.map_err(map_sled_error)?;
+            parcel_ids.push((parcels, String::from_utf8_lossy(key.as_ref()).into_owned()));
+        }
+    }
+
+    trace!("Building live set of parcels");
+    let mut live = std::collections::HashSet::new();
+    // Parcels can be created for drafts too. Drafts MUST be read before invoices, as a draft that
+    // is published while we are running is moved into the invoice tree
+    for res in drafts.iter().values() {
+        let raw = res.map_err(map_sled_error)?;
+        let draft: Draft = serde_cbor::from_slice(raw.as_ref())?;
+        live.extend(
+            draft
+                .invoice
+                .parcel
+                .unwrap_or_default()
+                .into_iter()
+                .map(|p| p.label.sha256),
+        );
+    }
+    for res in invoices.iter().values() {
+        let raw = res.map_err(map_sled_error)?;
+        // If we can't read an invoice, we can't know which parcels it references, so we bail out
+        // rather than risk deleting live data
+        let invoice: crate::Invoice = serde_cbor::from_slice(raw.as_ref())?;
+        live.extend(
+            invoice
+                .parcel
+                .unwrap_or_default()
+                .into_iter()
+                .map(|p| p.label.sha256),
+        );
+    }
+    report.live_parcels = live.len();
+    debug!(live_parcels = report.live_parcels, "Built live set");
+
+    for (parcels, parcel_id) in parcel_ids.into_iter().filter(|(_, id)| !live.contains(id)) {
+        debug!(%parcel_id, "Removing unreferenced parcel");
+        let size = remove_parcel(parcels, &parcel_id, blob_dir, dry_run)?;
+        report.reclaimed_bytes += size.unwrap_or_default();
+        report.removed_parcels.push(parcel_id);
+    }
+    Ok(report)
+}
+
+/// Checks the integrity of the invoice and parcel trees, moving offenders to the quarantine tree
+/// (keyed by the name of their original tree and their key) if requested. The given report and
+/// parcel sizes contain the results of checking the large parcels
+fn fsck_trees(
+    mut report: FsckReport,
+    mut parcel_sizes: HashMap<String, u64>,
+    invoices: &sled::Tree,
+    parcel_trees: &[&sled::Tree],
+    quarantine: &sled::Tree,
+    keys: Option<&EncryptionKeyFile>,
+    should_quarantine: bool,
+) -> Result<FsckReport> {
+    let move_to_quarantine = |tree: &sled::Tree, tree_name: &str, key: &str, data: sled::IVec| {
+        if !should_quarantine {
+            return Ok(());
+        }
+        quarantine
+            .insert(format!("{}/{}", tree_name, key), data)
+            .and_then(|_| tree.remove(key))
+            .map(|_| ())
+            .map_err(map_sled_error)
+    };
+
+    trace!("Checking parcels");
+    for tree in parcel_trees {
+        let tree_name = String::from_utf8_lossy(&tree.name()).into_owned();
+        for res in tree.iter() {
+            let (key, data) = res.map_err(map_sled_error)?;
+            let parcel_id = String::from_utf8_lossy(key.as_ref()).into_owned();
+            report.checked_parcels += 1;
+            // Data that can't be decrypted or decompressed is just as corrupt as data that doesn't
+            // match its SHA
+            let decoded = match decode_parcel(&tree_name, &data, keys) {
+                Ok(d) => Some(d),
+                Err(ProviderError::Io(e)) => {
+                    debug!(%parcel_id, error = %e, "Unable to decode parcel data");
+                    None
+                }
+                Err(e) => return Err(e),
+            };
+            match decoded {
+                Some(d) if format!("{:x}", Sha256::digest(&d)) == parcel_id => {
+                    parcel_sizes.insert(parcel_id, d.len() as u64);
+                }
+                _ => {
+                    warn!(%parcel_id, "Parcel data does not match its SHA");
+                    move_to_quarantine(tree, &tree_name, &parcel_id, data)?;
+                    report.corrupt_parcels.push(parcel_id);
+                }
+            }
+        }
+    }
+
+    trace!("Checking invoices");
+    for res in invoices.iter() {
+        let (key, data) = res.map_err(map_sled_error)?;
+        let invoice_id = String::from_utf8_lossy(key.as_ref()).into_owned();
+        report.checked_invoices += 1;
+        let invoice: crate::Invoice = match serde_cbor::from_slice(data.as_ref()) {
+            Ok(inv) => inv,
+            Err(e) => {
+                warn!(%invoice_id, error = %e, "Unable to parse invoice");
+                move_to_quarantine(invoices, INVOICE_DB_NAME, &invoice_id, data)?;
+                report.unreadable_invoices.push(invoice_id);
+                continue;
+            }
+        };
+        if invoice.canonical_name() != invoice_id {
+            warn!(%invoice_id, bindle_id = %invoice.bindle.id, "Invoice is not stored under its canonical name");
+            move_to_quarantine(invoices, INVOICE_DB_NAME, &invoice_id, data)?;
+            report.misnamed_invoices.push(invoice_id);
+            continue;
+        }
+
+        for label in invoice
+            .parcel
+            .unwrap_or_default()
+            .into_iter()
+            .map(|p| p.label)
+        {
+            // Corrupt parcels have already been reported
+            if report.corrupt_parcels.contains(&label.sha256) {
+                continue;
+            }
+            match parcel_sizes.get(&label.sha256) {
+                None => report
+                    .missing_parcels
+                    .push((invoice.bindle.id.clone(), label.sha256)),
+                Some(size) if *size != label.size => {
+                    warn!(bindle_id = %invoice.bindle.id, parcel_id = %label.sha256, "Parcel size does not match label");
+                    report
+                        .size_mismatches
+                        .push((invoice.bindle.id.clone(), label.sha256))
+                }
+                Some(_) => (),
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Returns the name of the file a large parcel with the given encoding is stored in
+fn blob_file_name(parcel_id: &str, encoding: Encoding) -> String {
+    match encoding {
+        Encoding::Plain => parcel_id.to_owned(),
+        Encoding::Compressed => format!("{}.zst", parcel_id),
+        Encoding::Encrypted => format!("{}.enc", parcel_id),
+    }
+}
+
+/// Returns how the data in the given large parcel file is encoded
+fn blob_encoding(file: &str) -> Encoding {
+    match Path::new(file).extension().and_then(|ext| ext.to_str()) {
+        Some("zst") => Encoding::Compressed,
+        Some("enc") => Encoding::Encrypted,
+        _ => Encoding::Plain,
+    }
+}
+
+/// Removes the given parcel from the given parcel tree, returning the number of bytes it took up
+/// in storage, or `None` if it isn't stored in the tree. Nothing is removed for dry runs. For large
+/// parcels, the metadata is removed before the file so that it never points at a missing file
+fn remove_parcel(
+    tree: &sled::Tree,
+    parcel_id: &str,
+    blob_dir: &Path,
+    dry_run: bool,
+) -> Result<Option<u64>> {
+    let data = if dry_run {
+        tree.get(parcel_id)
+    } else {
+        tree.remove(parcel_id)
+    }
+    .map_err(map_sled_error)?;
+    let data = match data {
+        Some(d) => d,
+        None => return Ok(None),
+    };
+    if tree.name().as_ref() != BLOB_PARCEL_DB_NAME.as_bytes() {
+        return Ok(Some(data.len() as u64));
+    }
+    let metadata: BlobMetadata = serde_cbor::from_slice(data.as_ref())?;
+    if !dry_run {
+        match std::fs::remove_file(blob_dir.join(&metadata.file)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+    }
+    Ok(Some(metadata.stored_size))
+}
+
+/// Brings the blob directory and tree back in sync after a crash. Files are always written before
+/// their metadata is inserted and metadata is always removed before its file, so anything that
+/// only exists on one side was left behind by an interrupted write or removal and is removed
+fn recover_blobs(blob_parcels: &sled::Tree, blob_dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(blob_dir)?;
+    let mut stored = std::collections::HashSet::new();
+    for res in blob_parcels.iter() {
+        let (key, raw) = res.map_err(map_sled_error)?;
+        let parcel_id = String::from_utf8_lossy(key.as_ref()).into_owned();
+        // Metadata that can't be read is left for fsck to report
+        if let Ok(metadata) = serde_cbor::from_slice::<BlobMetadata>(raw.as_ref()) {
+            if !blob_dir.join(&metadata.file).is_file() {
+                warn!(%parcel_id, "Removing metadata of large parcel whose file is missing");
+                blob_parcels.remove(key).map_err(map_sled_error)?;
+                continue;
+            }
+        }
+        stored.insert(parcel_id);
+    }
+
+    for entry in std::fs::read_dir(blob_dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let path = entry.path();
+        let is_part = path
+            .extension()
+            .map(|ext| ext == PART_EXTENSION)
+            .unwrap_or(false);
+        let name = entry.file_name().to_string_lossy().into_owned();
+        // File names are the parcel SHA followed by the extensions for their encoding
+        let parcel_id = name.split('.').next().unwrap_or_default();
+        if is_part || !stored.contains(parcel_id) {
+            warn!(path = %path.display(), "Removing large parcel file left over from an interrupted write");
+            std::fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
 /// A helper function that wraps `spawn_blocking` with a semaphore permit acquisition
 async fn spawn_lock<F, R>(semaphore: Arc<Semaphore>, f: F) -> Result<R>
 where
@@ -446,3 +1598,527 @@ where
         .await
         .map_err(|_| ProviderError::Other("Internal error: unable to lock task".into()))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::verification::NoopVerified;
+    use crate::{testing, NoopSigned};
+
+    #[tokio::test]
+    async fn test_should_delete_invoice() {
+        let root = tempfile::tempdir().unwrap();
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let store = EmbeddedProvider::new(root.path(), crate::search::StrictEngine::default())
+            .await
+            .unwrap();
+
+        let signed = NoopSigned(NoopVerified(scaffold.invoice.clone()));
+        store.create_invoice(signed).await.unwrap();
+        store
+            .yank_invoice(&scaffold.invoice.bindle.id)
+            .await
+            .unwrap();
+
+        // Yanked invoices can still be deleted
+        store
+            .delete_invoice(&scaffold.invoice.bindle.id)
+            .await
+            .expect("Should be able to delete invoice");
+        assert!(matches!(
+            store.get_yanked_invoice(&scaffold.invoice.bindle.id).await,
+            Err(ProviderError::NotFound)
+        ));
+        assert!(matches!(
+            store.delete_invoice(&scaffold.invoice.bindle.id).await,
+            Err(ProviderError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_should_check_parcels_exist() {
+        let root = tempfile::tempdir().unwrap();
+        let scaffold = testing::Scaffold::load("lotsa_parcels").await;
+        let store = EmbeddedProvider::new(root.path(), crate::search::StrictEngine::default())
+            .await
+            .unwrap();
+
+        let signed = NoopSigned(NoopVerified(scaffold.invoice.clone()));
+        let (_, missing) = store.create_invoice(signed).await.unwrap();
+        assert_eq!(missing.len(), scaffold.parcel_files.len());
+
+        let mut parcels = scaffold.parcel_files.values();
+        let stored = parcels.next().unwrap();
+        let other = parcels.next().unwrap();
+        store
+            .create_parcel(
+                &scaffold.invoice.bindle.id,
+                &stored.sha,
+                FramedRead::new(std::io::Cursor::new(stored.data.clone()), BytesCodec::new()),
+            )
+            .await
+            .expect("create parcel");
+
+        let exists = store
+            .parcels_exist(&[other.sha.as_str(), stored.sha.as_str(), "not-a-sha"])
+            .await
+            .expect("Should be able to check parcels");
+        assert_eq!(exists, vec![false, true, false]);
+
+        let missing = crate::provider::missing_parcels(&store, &scaffold.invoice)
+            .await
+            .unwrap();
+        assert_eq!(missing.len(), scaffold.parcel_files.len() - 1);
+        assert!(missing.iter().all(|label| label.sha256 != stored.sha));
+    }
+
+    #[tokio::test]
+    async fn test_should_list_invoices() {
+        let root = tempfile::tempdir().unwrap();
+        let store = EmbeddedProvider::new(root.path(), crate::search::StrictEngine::default())
+            .await
+            .unwrap();
+        let v1 = testing::Scaffold::load("valid_v1").await;
+        let v2 = testing::Scaffold::load("valid_v2").await;
+        for inv in [&v1.invoice, &v2.invoice] {
+            store
+                .create_invoice(NoopSigned(NoopVerified(inv.clone())))
+                .await
+                .expect("Should be able to create invoice");
+        }
+        store.yank_invoice(&v1.invoice.bindle.id).await.unwrap();
+
+        let ids: Vec<Id> = store
+            .list_invoices(false)
+            .await
+            .expect("Should be able to list invoices")
+            .collect::<Result<_>>()
+            .await
+            .expect("All invoices should be readable");
+        assert_eq!(vec![v2.invoice.bindle.id.clone()], ids);
+
+        let mut ids: Vec<Id> = store
+            .list_invoices(true)
+            .await
+            .expect("Should be able to list invoices")
+            .collect::<Result<_>>()
+            .await
+            .expect("All invoices should be readable");
+        ids.sort_by_key(|id| id.to_string());
+        assert_eq!(vec![v1.invoice.bindle.id, v2.invoice.bindle.id], ids);
+    }
+
+    #[tokio::test]
+    async fn test_should_fsck() {
+        let root = tempfile::tempdir().unwrap();
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let parcel = scaffold.parcel_files.get("parcel").unwrap();
+        let store = EmbeddedProvider::new(root.path(), crate::search::StrictEngine::default())
+            .await
+            .unwrap();
+
+        let signed = NoopSigned(NoopVerified(scaffold.invoice.clone()));
+        store.create_invoice(signed).await.unwrap();
+        store
+            .create_parcel(
+                &scaffold.invoice.bindle.id,
+                &parcel.sha,
+                FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+            )
+            .await
+            .expect("create parcel");
+
+        let report = store.fsck(FsckOptions::default()).await.unwrap();
+        assert!(report.is_clean(), "Report: {:?}", report);
+        assert_eq!(1, report.checked_invoices);
+        assert_eq!(1, report.checked_parcels);
+
+        // Corrupt the parcel and store invoices under the wrong name and with bad data
+        let mut corrupted = parcel.data.clone();
+        corrupted[0] = corrupted[0].wrapping_add(1);
+        store.parcels.insert(&parcel.sha, corrupted).unwrap();
+        let raw = store
+            .invoices
+            .get(scaffold.invoice.canonical_name())
+            .unwrap()
+            .unwrap();
+        store.invoices.insert("misnamed", raw).unwrap();
+        store
+            .invoices
+            .insert("unreadable", b"not an invoice".to_vec())
+            .unwrap();
+
+        let report = store.fsck(FsckOptions::default()).await.unwrap();
+        assert!(!report.is_clean());
+        assert_eq!(vec![parcel.sha.clone()], report.corrupt_parcels);
+        assert_eq!(vec!["misnamed".to_owned()], report.misnamed_invoices);
+        assert_eq!(vec!["unreadable".to_owned()], report.unreadable_invoices);
+        assert!(store.quarantine.is_empty());
+
+        store.fsck(FsckOptions { quarantine: true }).await.unwrap();
+        assert_eq!(3, store.quarantine.len());
+        assert!(!store.parcels.contains_key(&parcel.sha).unwrap());
+
+        let report = store.fsck(FsckOptions::default()).await.unwrap();
+        assert!(report.is_clean(), "Report: {:?}", report);
+        assert_eq!(
+            vec![(scaffold.invoice.bindle.id.clone(), parcel.sha.clone())],
+            report.missing_parcels
+        );
+    }
+
+    #[tokio::test]
+    async fn test_should_store_compressed_parcels() {
+        let root = tempfile::tempdir().unwrap();
+        let scaffold = testing::Scaffold::load("valid_v2").await;
+        let id = &scaffold.invoice.bindle.id;
+        // Only one of the parcels is large enough to be compressed
+        let policy = CompressionPolicy {
+            min_size: 10,
+            ..Default::default()
+        };
+        let store = EmbeddedProvider::new(root.path(), crate::search::StrictEngine::default())
+            .await
+            .unwrap()
+            .with_compression(policy.clone());
+
+        let signed = NoopSigned(NoopVerified(scaffold.invoice.clone()));
+        store.create_invoice(signed).await.unwrap();
+        for parcel in scaffold.parcel_files.values() {
+            store
+                .create_parcel(
+                    id,
+                    &parcel.sha,
+                    FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+                )
+                .await
+                .expect("create parcel");
+        }
+
+        for parcel in scaffold.parcel_files.values() {
+            let compressed = policy.should_compress(&crate::Label {
+                size: parcel.data.len() as u64,
+                ..Default::default()
+            });
+            assert_eq!(
+                compressed,
+                store.compressed_parcels.contains_key(&parcel.sha).unwrap()
+            );
+            assert_eq!(
+                !compressed,
+                store.parcels.contains_key(&parcel.sha).unwrap()
+            );
+            assert!(store.parcel_exists(id, &parcel.sha).await.unwrap());
+
+            let mut data = Vec::new();
+            let stream = store.get_parcel(id, &parcel.sha).await.unwrap();
+            StreamReader::new(stream.map(|res| res.map_err(std::io::Error::other)))
+                .read_to_end(&mut data)
+                .await
+                .unwrap();
+            assert_eq!(data, parcel.data);
+
+            let mut data = Vec::new();
+            let stream = store.get_parcel_range(id, &parcel.sha, 2..5).await.unwrap();
+            StreamReader::new(stream.map(|res| res.map_err(std::io::Error::other)))
+                .read_to_end(&mut data)
+                .await
+                .unwrap();
+            assert_eq!(data, parcel.data[2..5]);
+        }
+
+        let report = store.fsck(FsckOptions::default()).await.unwrap();
+        assert!(report.is_clean(), "Report: {:?}", report);
+        assert_eq!(2, report.checked_parcels);
+
+        // Parcels that can't be decompressed are corrupt
+        let (key, _) = store.compressed_parcels.first().unwrap().unwrap();
+        store
+            .compressed_parcels
+            .insert(&key, b"not zstd".to_vec())
+            .unwrap();
+        let report = store.fsck(FsckOptions { quarantine: true }).await.unwrap();
+        assert_eq!(
+            vec![String::from_utf8_lossy(&key).into_owned()],
+            report.corrupt_parcels
+        );
+        assert!(store.compressed_parcels.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_should_store_encrypted_parcels() {
+        let root = tempfile::tempdir().unwrap();
+        let scaffold = testing::Scaffold::load("valid_v2").await;
+        let id = &scaffold.invoice.bindle.id;
+        let mut keys = EncryptionKeyFile::default();
+        keys.rotate("first").unwrap();
+        let store = EmbeddedProvider::new(root.path(), crate::search::StrictEngine::default())
+            .await
+            .unwrap()
+            .with_compression(CompressionPolicy {
+                min_size: 10,
+                ..Default::default()
+            })
+            .with_encryption(keys);
+
+        let signed = NoopSigned(NoopVerified(scaffold.invoice.clone()));
+        store.create_invoice(signed).await.unwrap();
+        for parcel in scaffold.parcel_files.values() {
+            store
+                .create_parcel(
+                    id,
+                    &parcel.sha,
+                    FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+                )
+                .await
+                .expect("create parcel");
+        }
+
+        assert!(store.parcels.is_empty());
+        assert!(store.compressed_parcels.is_empty());
+        for parcel in scaffold.parcel_files.values() {
+            assert!(store.encrypted_parcels.contains_key(&parcel.sha).unwrap());
+            assert!(store.parcel_exists(id, &parcel.sha).await.unwrap());
+
+            let mut data = Vec::new();
+            let stream = store.get_parcel(id, &parcel.sha).await.unwrap();
+            StreamReader::new(stream.map(|res| res.map_err(std::io::Error::other)))
+                .read_to_end(&mut data)
+                .await
+                .unwrap();
+            assert_eq!(data, parcel.data);
+        }
+
+        let report = store.fsck(FsckOptions::default()).await.unwrap();
+        assert!(report.is_clean(), "Report: {:?}", report);
+        assert_eq!(2, report.checked_parcels);
+
+        // Parcels that fail authentication are corrupt
+        let (key, data) = store.encrypted_parcels.first().unwrap().unwrap();
+        let mut raw = data.to_vec();
+        let last = raw.len() - 1;
+        raw[last] ^= 1;
+        store.encrypted_parcels.insert(&key, raw).unwrap();
+        let report = store.fsck(FsckOptions { quarantine: true }).await.unwrap();
+        assert_eq!(
+            vec![String::from_utf8_lossy(&key).into_owned()],
+            report.corrupt_parcels
+        );
+        assert_eq!(1, store.encrypted_parcels.len());
+
+        // Without the keys, the remaining parcel can neither be read nor checked
+        let mut unencrypted = store.clone();
+        unencrypted.encryption = None;
+        let (key, _) = store.encrypted_parcels.first().unwrap().unwrap();
+        assert!(unencrypted
+            .get_parcel(id, &String::from_utf8_lossy(&key))
+            .await
+            .is_err());
+        assert!(unencrypted.fsck(FsckOptions::default()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_should_gc_orphaned_parcels() {
+        let root = tempfile::tempdir().unwrap();
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let parcel = scaffold.parcel_files.get("parcel").unwrap();
+        let store = EmbeddedProvider::new(root.path(), crate::search::StrictEngine::default())
+            .await
+            .unwrap();
+
+        let signed = NoopSigned(NoopVerified(scaffold.invoice.clone()));
+        store.create_invoice(signed).await.unwrap();
+        store
+            .create_parcel(
+                &scaffold.invoice.bindle.id,
+                &parcel.sha,
+                FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+            )
+            .await
+            .expect("create parcel");
+        // Insert an orphaned parcel out of band
+        store
+            .parcels
+            .insert("abc123", b"orphaned".to_vec())
+            .unwrap();
+
+        let report = store
+            .gc(GcOptions {
+                dry_run: true,
+                ..Default::default()
+            })
+            .await
+            .expect("dry run should succeed");
+        assert_eq!(1, report.live_parcels);
+        assert_eq!(vec!["abc123".to_owned()], report.removed_parcels);
+        assert!(store.parcels.contains_key("abc123").unwrap());
+
+        let report = store
+            .gc(GcOptions::default())
+            .await
+            .expect("gc should succeed");
+        assert_eq!(vec!["abc123".to_owned()], report.removed_parcels);
+        assert_eq!(b"orphaned".len() as u64, report.reclaimed_bytes);
+        assert!(!store.parcels.contains_key("abc123").unwrap());
+        assert!(store.parcels.contains_key(&parcel.sha).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_should_store_large_parcels_in_files() {
+        let root = tempfile::tempdir().unwrap();
+        let blob_dir = root.path().join(BLOB_DIRECTORY);
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let id = &scaffold.invoice.bindle.id;
+        let parcel = scaffold.parcel_files.get("parcel").unwrap();
+        let store = EmbeddedProvider::new(root.path(), crate::search::StrictEngine::default())
+            .await
+            .unwrap()
+            .with_blob_threshold(0);
+
+        let signed = NoopSigned(NoopVerified(scaffold.invoice.clone()));
+        store.create_invoice(signed).await.unwrap();
+        store
+            .create_parcel(
+                id,
+                &parcel.sha,
+                FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+            )
+            .await
+            .expect("create parcel");
+
+        assert!(store.parcels.is_empty());
+        assert!(store.blob_parcels.contains_key(&parcel.sha).unwrap());
+        let path = blob_dir.join(&parcel.sha);
+        assert_eq!(parcel.data, std::fs::read(&path).unwrap());
+        assert!(store.parcel_exists(id, &parcel.sha).await.unwrap());
+        assert!(matches!(
+            store
+                .create_parcel(
+                    id,
+                    &parcel.sha,
+                    FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+                )
+                .await,
+            Err(ProviderError::Exists)
+        ));
+
+        let mut data = Vec::new();
+        let stream = store.get_parcel(id, &parcel.sha).await.unwrap();
+        StreamReader::new(stream.map(|res| res.map_err(std::io::Error::other)))
+            .read_to_end(&mut data)
+            .await
+            .unwrap();
+        assert_eq!(data, parcel.data);
+
+        let mut data = Vec::new();
+        let stream = store.get_parcel_range(id, &parcel.sha, 2..5).await.unwrap();
+        StreamReader::new(stream.map(|res| res.map_err(std::io::Error::other)))
+            .read_to_end(&mut data)
+            .await
+            .unwrap();
+        assert_eq!(data, parcel.data[2..5]);
+
+        let report = store.fsck(FsckOptions::default()).await.unwrap();
+        assert!(report.is_clean(), "Report: {:?}", report);
+        assert_eq!(1, report.checked_parcels);
+
+        // Leftovers from interrupted writes are cleaned up when the provider is opened
+        drop(store);
+        let part = blob_dir.join(format!("{}.part", "f".repeat(64)));
+        let orphan = blob_dir.join("f".repeat(64));
+        std::fs::write(&part, b"partial").unwrap();
+        std::fs::write(&orphan, b"orphaned").unwrap();
+        let store = EmbeddedProvider::new(root.path(), crate::search::StrictEngine::default())
+            .await
+            .unwrap();
+        assert!(!part.exists());
+        assert!(!orphan.exists());
+        assert!(store.has_parcel(&parcel.sha).await.unwrap());
+
+        // As is metadata that points at a file that was never written
+        drop(store);
+        std::fs::remove_file(&path).unwrap();
+        let store = EmbeddedProvider::new(root.path(), crate::search::StrictEngine::default())
+            .await
+            .unwrap();
+        assert!(!store.has_parcel(&parcel.sha).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_should_check_and_gc_large_parcels() {
+        let root = tempfile::tempdir().unwrap();
+        let blob_dir = root.path().join(BLOB_DIRECTORY);
+        let scaffold = testing::Scaffold::load("valid_v2").await;
+        let id = &scaffold.invoice.bindle.id;
+        let mut keys = EncryptionKeyFile::default();
+        keys.rotate("first").unwrap();
+        let store = EmbeddedProvider::new(root.path(), crate::search::StrictEngine::default())
+            .await
+            .unwrap()
+            .with_compression(CompressionPolicy {
+                min_size: 10,
+                ..Default::default()
+            })
+            .with_encryption(keys)
+            .with_blob_threshold(0);
+
+        let signed = NoopSigned(NoopVerified(scaffold.invoice.clone()));
+        store.create_invoice(signed).await.unwrap();
+        for parcel in scaffold.parcel_files.values() {
+            store
+                .create_parcel(
+                    id,
+                    &parcel.sha,
+                    FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+                )
+                .await
+                .expect("create parcel");
+        }
+
+        assert!(store.encrypted_parcels.is_empty());
+        for parcel in scaffold.parcel_files.values() {
+            let path = blob_dir.join(format!("{}.enc", parcel.sha));
+            assert_ne!(parcel.data, std::fs::read(&path).unwrap());
+
+            let mut data = Vec::new();
+            let stream = store.get_parcel(id, &parcel.sha).await.unwrap();
+            StreamReader::new(stream.map(|res| res.map_err(std::io::Error::other)))
+                .read_to_end(&mut data)
+                .await
+                .unwrap();
+            assert_eq!(data, parcel.data);
+        }
+
+        let report = store.fsck(FsckOptions::default()).await.unwrap();
+        assert!(report.is_clean(), "Report: {:?}", report);
+        assert_eq!(2, report.checked_parcels);
+
+        // Files that fail authentication are corrupt
+        let mut parcels = scaffold.parcel_files.values();
+        let corrupt = parcels.next().unwrap();
+        let intact = parcels.next().unwrap();
+        let file_name = format!("{}.enc", corrupt.sha);
+        let mut raw = std::fs::read(blob_dir.join(&file_name)).unwrap();
+        let last = raw.len() - 1;
+        raw[last] ^= 1;
+        std::fs::write(blob_dir.join(&file_name), raw).unwrap();
+        let report = store.fsck(FsckOptions { quarantine: true }).await.unwrap();
+        assert_eq!(vec![corrupt.sha.clone()], report.corrupt_parcels);
+        assert_eq!(1, store.blob_parcels.len());
+        assert_eq!(1, store.quarantine.len());
+        assert!(blob_dir
+            .join(BLOB_QUARANTINE_DIRECTORY)
+            .join(&file_name)
+            .is_file());
+
+        // Deleting the invoice leaves the remaining file to be collected
+        let intact_path = blob_dir.join(format!("{}.enc", intact.sha));
+        let stored_size = std::fs::metadata(&intact_path).unwrap().len();
+        store.delete_invoice(id).await.unwrap();
+        let report = store.gc(GcOptions::default()).await.unwrap();
+        assert_eq!(vec![intact.sha.clone()], report.removed_parcels);
+        assert_eq!(stored_size, report.reclaimed_bytes);
+        assert!(store.blob_parcels.is_empty());
+        assert!(!intact_path.exists());
+    }
+}
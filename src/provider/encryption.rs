@@ -0,0 +1,612 @@
+//! Types used for configuring encryption of parcels at rest.
+//!
+//! Terminal providers that support encryption (currently the
+//! [`FileProvider`](crate::provider::file::FileProvider) and the
+//! [`EmbeddedProvider`](crate::provider::embedded::EmbeddedProvider)) can be configured with an
+//! [`EncryptionKeyFile`] using their `with_encryption` method. Parcels are then encrypted with
+//! ChaCha20-Poly1305 using the first key in the file before they are written to storage.
+//!
+//! Every encrypted parcel records the ID of the key it was encrypted with, and any key in the file
+//! can be used to decrypt. Keys can therefore be rotated by adding a new key to the front of the
+//! file. Parcels encrypted with the older keys stay readable for as long as those keys are kept
+//! in the file.
+//!
+//! Parcels are still addressed and verified by the SHA-256 of their plaintext, and providers
+//! always return plaintext when parcels are fetched. Encrypted data is stored as a short header
+//! followed by a series of independently authenticated records, so large parcels can be streamed
+//! without buffering them in memory. The last record is marked so that truncated data is detected
+
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::io::{Error as IoError, ErrorKind};
+use std::path::Path;
+
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio_stream::Stream;
+
+use super::{ProviderError, Result};
+
+/// The maximum amount of plaintext (in bytes) stored in a single encrypted record
+pub(crate) const RECORD_SIZE: usize = 64 * 1024;
+
+const MAGIC: &[u8; 8] = b"BINDLE\x00\x01";
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+const FINAL_RECORD: u32 = 1 << 31;
+const FLAG_COMPRESSED: u8 = 1;
+
+/// A key file containing the symmetric keys used for encrypting parcels. It is stored as TOML
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct EncryptionKeyFile {
+    /// The available keys. The first key is used to encrypt all newly stored parcels, while any
+    /// key can be used for decryption
+    pub key: Vec<EncryptionKeyEntry>,
+}
+
+/// A single encryption key
+#[derive(Serialize, Deserialize, Clone)]
+pub struct EncryptionKeyEntry {
+    /// A unique identifier for this key. It is stored alongside every parcel encrypted with the
+    /// key so the right key can be found when decrypting
+    pub id: String,
+    /// The base64 encoded 32 byte key
+    pub key: String,
+}
+
+// Implemented by hand so keys don't end up in logs
+impl std::fmt::Debug for EncryptionKeyEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptionKeyEntry")
+            .field("id", &self.id)
+            .field("key", &"<redacted>")
+            .finish()
+    }
+}
+
+impl EncryptionKeyEntry {
+    /// Generates a new random key with the given ID
+    pub fn generate(id: impl Into<String>) -> Self {
+        let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+        EncryptionKeyEntry {
+            id: id.into(),
+            key: base64::engine::general_purpose::STANDARD.encode(key),
+        }
+    }
+
+    fn cipher(&self) -> anyhow::Result<ChaCha20Poly1305> {
+        let raw = base64::engine::general_purpose::STANDARD.decode(&self.key)?;
+        if raw.len() != KEY_SIZE {
+            anyhow::bail!(
+                "Key {} must be {} bytes long, found {} bytes",
+                self.id,
+                KEY_SIZE,
+                raw.len()
+            );
+        }
+        Ok(ChaCha20Poly1305::new_from_slice(&raw)?)
+    }
+}
+
+impl EncryptionKeyFile {
+    /// Loads and validates a key file from the given path
+    pub async fn load_file(path: impl AsRef<Path>) -> anyhow::Result<EncryptionKeyFile> {
+        let raw = tokio::fs::read(path).await?;
+        let keys: EncryptionKeyFile = toml::from_slice(&raw)?;
+        keys.validate()?;
+        Ok(keys)
+    }
+
+    /// Save the present keyfile to the named path.
+    pub async fn save_file(&self, dest: impl AsRef<Path>) -> anyhow::Result<()> {
+        let out = toml::to_vec(self)?;
+        let mut opts = tokio::fs::OpenOptions::new();
+        opts.create(true).write(true).truncate(true);
+
+        #[cfg(target_family = "unix")]
+        opts.mode(0o600);
+
+        let mut file = opts.open(dest).await?;
+        file.write_all(&out).await?;
+        file.flush().await?;
+        Ok(())
+    }
+
+    /// Adds a newly generated key with the given ID to the front of the file, making it the key
+    /// used for encrypting new parcels. Returns an error if a key with the same ID already exists
+    pub fn rotate(&mut self, id: impl Into<String>) -> anyhow::Result<()> {
+        let entry = EncryptionKeyEntry::generate(id);
+        if self.get(&entry.id).is_some() {
+            anyhow::bail!("A key with ID {} already exists", entry.id);
+        }
+        self.key.insert(0, entry);
+        self.validate()
+    }
+
+    /// Checks that the file contains at least one key, that all keys are valid, and that no two
+    /// keys share an ID
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.key.is_empty() {
+            anyhow::bail!("Encryption key file does not contain any keys");
+        }
+        let mut seen = HashSet::new();
+        for entry in self.key.iter() {
+            if entry.id.is_empty() || entry.id.len() > u8::MAX as usize {
+                anyhow::bail!("Key IDs must be between 1 and 255 bytes long");
+            }
+            if !seen.insert(entry.id.as_str()) {
+                anyhow::bail!("Found more than one key with ID {}", entry.id);
+            }
+            entry.cipher()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the key used to encrypt new parcels
+    pub fn active(&self) -> Option<&EncryptionKeyEntry> {
+        self.key.first()
+    }
+
+    /// Returns the key with the given ID, if it exists
+    pub fn get(&self, id: &str) -> Option<&EncryptionKeyEntry> {
+        self.key.iter().find(|k| k.id == id)
+    }
+
+    fn cipher_for(&self, id: &str) -> Result<ChaCha20Poly1305> {
+        self.get(id)
+            .ok_or_else(|| {
+                ProviderError::Other(format!(
+                    "Parcel is encrypted with key {}, which is not in the key file",
+                    id
+                ))
+            })?
+            .cipher()
+            .map_err(|e| ProviderError::Other(e.to_string()))
+    }
+}
+
+/// The header stored at the start of encrypted data
+#[derive(Debug, Clone)]
+pub(crate) struct Header {
+    /// The ID of the key used to encrypt the data
+    pub(crate) key_id: String,
+    /// Whether the plaintext was compressed before it was encrypted
+    pub(crate) compressed: bool,
+}
+
+impl Header {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut raw = Vec::with_capacity(MAGIC.len() + 2 + self.key_id.len());
+        raw.extend_from_slice(MAGIC);
+        raw.push(if self.compressed { FLAG_COMPRESSED } else { 0 });
+        // Key IDs are validated to fit in a u8 when the key file is loaded
+        raw.push(self.key_id.len() as u8);
+        raw.extend_from_slice(self.key_id.as_bytes());
+        raw
+    }
+}
+
+/// Reads and parses the header at the start of encrypted data
+pub(crate) async fn read_header<R: AsyncRead + Unpin + ?Sized>(
+    reader: &mut R,
+) -> std::io::Result<Header> {
+    let mut start = [0u8; 10];
+    reader.read_exact(&mut start).await?;
+    if &start[..MAGIC.len()] != MAGIC {
+        return Err(invalid_data(
+            "Data does not start with an encryption header",
+        ));
+    }
+    let mut key_id = vec![0u8; start[9] as usize];
+    reader.read_exact(&mut key_id).await?;
+    Ok(Header {
+        key_id: String::from_utf8(key_id)
+            .map_err(|_| invalid_data("Encryption header contains an invalid key ID"))?,
+        compressed: start[8] & FLAG_COMPRESSED != 0,
+    })
+}
+
+/// Encrypts data into records. Each record is authenticated together with the header, its
+/// position, and whether or not it is the last record, so records can't be reordered, moved
+/// between parcels, or dropped from the end without detection
+pub(crate) struct Sealer {
+    cipher: ChaCha20Poly1305,
+    header: Vec<u8>,
+    index: u64,
+}
+
+impl Sealer {
+    /// Returns a sealer for new data using the active key in the given file
+    pub(crate) fn new(keys: &EncryptionKeyFile, compressed: bool) -> Result<Self> {
+        let active = keys.active().ok_or_else(|| {
+            ProviderError::Other("Encryption key file does not contain any keys".into())
+        })?;
+        Self::resume(
+            keys,
+            &Header {
+                key_id: active.id.clone(),
+                compressed,
+            },
+            0,
+        )
+    }
+
+    /// Returns a sealer that appends to existing data with the given header, starting with the
+    /// record at the given index
+    pub(crate) fn resume(keys: &EncryptionKeyFile, header: &Header, index: u64) -> Result<Self> {
+        Ok(Sealer {
+            cipher: keys.cipher_for(&header.key_id)?,
+            header: header.to_bytes(),
+            index,
+        })
+    }
+
+    /// The raw header that must be written before the first record
+    pub(crate) fn header(&self) -> &[u8] {
+        &self.header
+    }
+
+    fn seal(&mut self, plaintext: &[u8], last: bool) -> std::io::Result<Vec<u8>> {
+        let record_header = record_header(plaintext.len(), last);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = aad(&self.header, self.index, record_header);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| IoError::other("Unable to encrypt data"))?;
+        self.index += 1;
+        let mut record = Vec::with_capacity(4 + NONCE_SIZE + ciphertext.len());
+        record.extend_from_slice(&record_header.to_be_bytes());
+        record.extend_from_slice(&nonce);
+        record.extend_from_slice(&ciphertext);
+        Ok(record)
+    }
+
+    /// Encrypts everything read from the reader and writes the records to the writer, returning
+    /// the number of plaintext bytes read. If `finish` is true, the final record is written
+    /// afterwards and no more data can be appended
+    pub(crate) async fn seal_to<R, W>(
+        &mut self,
+        reader: &mut R,
+        writer: &mut W,
+        finish: bool,
+    ) -> std::io::Result<u64>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        let mut total = 0u64;
+        let mut buf = vec![0u8; RECORD_SIZE];
+        loop {
+            // Fill up the whole buffer so records are as large as possible
+            let mut filled = 0;
+            while filled < buf.len() {
+                let n = reader.read(&mut buf[filled..]).await?;
+                if n == 0 {
+                    break;
+                }
+                filled += n;
+            }
+            if filled == 0 {
+                break;
+            }
+            total += filled as u64;
+            writer.write_all(&self.seal(&buf[..filled], false)?).await?;
+        }
+        if finish {
+            writer.write_all(&self.seal(&[], true)?).await?;
+        }
+        writer.flush().await?;
+        Ok(total)
+    }
+
+    /// Encrypts the given data in memory, returning the header followed by all records
+    pub(crate) fn seal_bytes(mut self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut out = self.header.clone();
+        for chunk in data.chunks(RECORD_SIZE) {
+            out.extend(self.seal(chunk, false)?);
+        }
+        out.extend(self.seal(&[], true)?);
+        Ok(out)
+    }
+}
+
+/// Decrypts records written by a [`Sealer`]
+pub(crate) struct Opener {
+    cipher: ChaCha20Poly1305,
+    header: Vec<u8>,
+    index: u64,
+    finished: bool,
+    require_final: bool,
+}
+
+impl Opener {
+    /// Returns an opener for data with the given header. If `require_final` is false, the data
+    /// may end without a final record (as is the case for uploads that are still in progress)
+    pub(crate) fn new(
+        keys: &EncryptionKeyFile,
+        header: &Header,
+        require_final: bool,
+    ) -> Result<Self> {
+        Ok(Opener {
+            cipher: keys.cipher_for(&header.key_id)?,
+            header: header.to_bytes(),
+            index: 0,
+            finished: false,
+            require_final,
+        })
+    }
+
+    fn open(&mut self, record_header: u32, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        let (nonce, ciphertext) = body.split_at(NONCE_SIZE);
+        let aad = aad(&self.header, self.index, record_header);
+        let plaintext = self
+            .cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| invalid_data("Encrypted data failed authentication"))?;
+        self.index += 1;
+        self.finished = record_header & FINAL_RECORD != 0;
+        Ok(plaintext)
+    }
+
+    fn check_end(&self) -> std::io::Result<()> {
+        if self.require_final && !self.finished {
+            return Err(invalid_data("Encrypted data is truncated"));
+        }
+        Ok(())
+    }
+
+    /// Reads and decrypts the next record with data from the reader, returning `None` once all
+    /// records have been read
+    async fn next_record<R: AsyncRead + Unpin>(
+        &mut self,
+        reader: &mut R,
+    ) -> std::io::Result<Option<bytes::Bytes>> {
+        loop {
+            let record_header = match read_record_header(reader).await? {
+                Some(h) => h,
+                None => {
+                    self.check_end()?;
+                    return Ok(None);
+                }
+            };
+            if self.finished {
+                return Err(invalid_data("Found data after the end of encrypted data"));
+            }
+            let mut body = vec![0u8; record_body_len(record_header)?];
+            reader.read_exact(&mut body).await?;
+            let plaintext = self.open(record_header, &body)?;
+            // The final record is always empty, so keep going to make sure nothing follows it
+            if !plaintext.is_empty() {
+                return Ok(Some(plaintext.into()));
+            }
+        }
+    }
+
+    /// Decrypts all records in the given data, which must not include the header
+    pub(crate) fn open_bytes(mut self, mut data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(data.len());
+        while !data.is_empty() {
+            if self.finished {
+                return Err(invalid_data("Found data after the end of encrypted data"));
+            }
+            if data.len() < 4 {
+                return Err(invalid_data("Encrypted data is truncated"));
+            }
+            let (raw_header, rest) = data.split_at(4);
+            let record_header = u32::from_be_bytes(raw_header.try_into().unwrap());
+            let len = record_body_len(record_header)?;
+            if rest.len() < len {
+                return Err(invalid_data("Encrypted data is truncated"));
+            }
+            let (body, rest) = rest.split_at(len);
+            out.extend(self.open(record_header, body)?);
+            data = rest;
+        }
+        self.check_end()?;
+        Ok(out)
+    }
+
+    /// Returns a stream of decrypted data read from the given reader, which must be positioned
+    /// right after the header
+    pub(crate) fn into_stream<R: AsyncRead + Unpin + Send>(
+        self,
+        reader: R,
+    ) -> impl Stream<Item = std::io::Result<bytes::Bytes>> + Send {
+        futures::stream::unfold(Some((self, reader)), |state| async move {
+            let (mut opener, mut reader) = state?;
+            match opener.next_record(&mut reader).await {
+                Ok(Some(data)) => Some((Ok(data), Some((opener, reader)))),
+                Ok(None) => None,
+                Err(e) => Some((Err(e), None)),
+            }
+        })
+    }
+}
+
+/// Encrypts the given data in memory using the active key
+pub(crate) fn encrypt_bytes(
+    keys: &EncryptionKeyFile,
+    compressed: bool,
+    data: &[u8],
+) -> Result<Vec<u8>> {
+    Ok(Sealer::new(keys, compressed)?.seal_bytes(data)?)
+}
+
+/// Decrypts data encrypted with [`encrypt_bytes`], returning the header and plaintext. Data that is
+/// malformed or fails authentication returns an IO error with a kind of `InvalidData`, while a
+/// missing key returns any other error
+pub(crate) fn decrypt_bytes(keys: &EncryptionKeyFile, data: &[u8]) -> Result<(Header, Vec<u8>)> {
+    let mut reader = data;
+    let header = futures::executor::block_on(read_header(&mut reader)).map_err(|e| {
+        if e.kind() == ErrorKind::UnexpectedEof {
+            invalid_data("Encrypted data is truncated")
+        } else {
+            e
+        }
+    })?;
+    let plaintext = Opener::new(keys, &header, true)?.open_bytes(reader)?;
+    Ok((header, plaintext))
+}
+
+/// A summary of the encrypted records in an upload that is still in progress
+pub(crate) struct RecordScan {
+    /// The header of the upload
+    pub(crate) header: Header,
+    /// The total number of plaintext bytes in all complete records
+    pub(crate) plaintext_len: u64,
+    /// The number of complete records
+    pub(crate) records: u64,
+    /// The number of bytes taken up by the header and all complete records. Anything past this is
+    /// a partially written record that should be discarded
+    pub(crate) valid_len: u64,
+}
+
+/// Walks the record headers in the given data without decrypting anything. Records are only
+/// authenticated when the data is read back
+pub(crate) async fn scan_records<R: AsyncRead + AsyncSeek + Unpin>(
+    reader: &mut R,
+) -> std::io::Result<RecordScan> {
+    let total_len = reader.seek(std::io::SeekFrom::End(0)).await?;
+    reader.seek(std::io::SeekFrom::Start(0)).await?;
+    let header = read_header(reader).await?;
+    let mut scan = RecordScan {
+        valid_len: header.to_bytes().len() as u64,
+        header,
+        plaintext_len: 0,
+        records: 0,
+    };
+    loop {
+        let record_header = match read_record_header(reader).await {
+            Ok(Some(h)) => h,
+            Ok(None) => break,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        };
+        let body_len = record_body_len(record_header)? as u64;
+        let end = scan.valid_len + 4 + body_len;
+        if end > total_len {
+            break;
+        }
+        reader.seek(std::io::SeekFrom::Start(end)).await?;
+        scan.plaintext_len += body_len - (NONCE_SIZE + TAG_SIZE) as u64;
+        scan.records += 1;
+        scan.valid_len = end;
+    }
+    Ok(scan)
+}
+
+fn record_header(len: usize, last: bool) -> u32 {
+    // Records are never larger than RECORD_SIZE, so this can't collide with the final flag
+    let len = len as u32;
+    if last {
+        len | FINAL_RECORD
+    } else {
+        len
+    }
+}
+
+fn record_body_len(record_header: u32) -> std::io::Result<usize> {
+    let len = (record_header & !FINAL_RECORD) as usize;
+    if len > RECORD_SIZE {
+        return Err(invalid_data("Encrypted record is too large"));
+    }
+    Ok(NONCE_SIZE + len + TAG_SIZE)
+}
+
+fn aad(header: &[u8], index: u64, record_header: u32) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(header.len() + 12);
+    aad.extend_from_slice(header);
+    aad.extend_from_slice(&index.to_be_bytes());
+    aad.extend_from_slice(&record_header.to_be_bytes());
+    aad
+}
+
+/// Reads a record header, returning `None` if the reader is already at the end of the data
+async fn read_record_header<R: AsyncRead + Unpin + ?Sized>(
+    reader: &mut R,
+) -> std::io::Result<Option<u32>> {
+    let mut raw = [0u8; 4];
+    let n = reader.read(&mut raw).await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut raw[n..]).await?;
+    Ok(Some(u32::from_be_bytes(raw)))
+}
+
+fn invalid_data(msg: &str) -> IoError {
+    IoError::new(ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_encryption_roundtrip() {
+        let mut keys = EncryptionKeyFile::default();
+        keys.rotate("old").unwrap();
+        let data: Vec<u8> = (0..(RECORD_SIZE * 2 + 10)).map(|i| i as u8).collect();
+        let old = encrypt_bytes(&keys, false, &data).unwrap();
+
+        // Rotating keys should still allow decrypting data encrypted with the old key
+        keys.rotate("new").unwrap();
+        let (header, plaintext) = decrypt_bytes(&keys, &old).unwrap();
+        assert_eq!(header.key_id, "old");
+        assert_eq!(plaintext, data);
+
+        let new = encrypt_bytes(&keys, true, &data).unwrap();
+        let (header, plaintext) = decrypt_bytes(&keys, &new).unwrap();
+        assert_eq!(header.key_id, "new");
+        assert!(header.compressed);
+        assert_eq!(plaintext, data);
+
+        // The streaming reader should agree with the in memory version
+        let mut reader = new.as_slice();
+        let header = read_header(&mut reader).await.unwrap();
+        let stream = Opener::new(&keys, &header, true)
+            .unwrap()
+            .into_stream(reader);
+        let mut decrypted = Vec::new();
+        tokio_util::io::StreamReader::new(Box::pin(stream))
+            .read_to_end(&mut decrypted)
+            .await
+            .unwrap();
+        assert_eq!(decrypted, data);
+
+        // Tampering with, truncating, or extending the data should all be detected
+        let mut tampered = new.clone();
+        let idx = tampered.len() / 2;
+        tampered[idx] ^= 1;
+        assert!(decrypt_bytes(&keys, &tampered).is_err());
+        let final_record_len = 4 + NONCE_SIZE + TAG_SIZE;
+        assert!(decrypt_bytes(&keys, &new[..new.len() - final_record_len]).is_err());
+        let mut extended = new.clone();
+        extended.extend_from_slice(&new[new.len() - final_record_len..]);
+        assert!(decrypt_bytes(&keys, &extended).is_err());
+
+        // Data encrypted with a key that is no longer in the file can't be decrypted
+        keys.key.retain(|k| k.id != "old");
+        match decrypt_bytes(&keys, &old) {
+            Err(ProviderError::Other(_)) => (),
+            _ => panic!("Expected an error about a missing key"),
+        }
+    }
+}
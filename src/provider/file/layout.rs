@@ -0,0 +1,202 @@
+//! Versioning of the directory layout used by the [`FileProvider`](super::FileProvider).
+//!
+//! Stores created before the layout was versioned keep every invoice, draft and parcel directory
+//! directly within `invoices/`, `drafts/` and `parcels/` ([`Layout::Flat`]). With tens of thousands
+//! of entries, listing those directories and looking things up in them gets slow on a number of
+//! filesystems, so [`Layout::Sharded`] puts every entry into a subdirectory named after the first
+//! two characters of its SHA instead.
+//!
+//! The layout of a store is recorded in a `layout.toml` file in its root directory, and a store
+//! without one is flat. New stores are created with the sharded layout, while a flat store can be
+//! converted in place with [`upgrade_layout`]
+
+use std::collections::HashSet;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, info, instrument, warn};
+
+use super::{
+    list_dir_names, part_path, sync_dir, DRAFT_DIRECTORY, INVOICE_DIRECTORY, PARCEL_DIRECTORY,
+};
+use crate::provider::{ProviderError, Result};
+
+/// The name of the file in the root of a store that records its layout version
+pub const LAYOUT_FILE: &str = "layout.toml";
+/// The number of characters of a SHA used as the name of its shard directory
+const SHARD_LENGTH: usize = 2;
+/// The shard directory for IDs that are too short to be sharded. Invoice and parcel IDs are SHAs,
+/// so this should only ever be used by stores that were written by hand
+const SHORT_ID_SHARD: &str = "_";
+/// The directories whose entries are sharded
+const SHARDED_DIRECTORIES: [&str; 3] = [INVOICE_DIRECTORY, DRAFT_DIRECTORY, PARCEL_DIRECTORY];
+
+/// The directory layouts a [`FileProvider`](super::FileProvider) can store bindles in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layout {
+    /// Version 1. Every entry is stored directly within its directory (e.g. `parcels/SHA`)
+    Flat,
+    /// Version 2. Every entry is stored within a subdirectory named after the first two characters
+    /// of its SHA (e.g. `parcels/ab/abcdef...`)
+    Sharded,
+}
+
+impl Layout {
+    /// Returns the version number recorded in the layout file
+    pub fn version(&self) -> u32 {
+        match self {
+            Layout::Flat => 1,
+            Layout::Sharded => 2,
+        }
+    }
+
+    fn from_version(version: u32) -> Option<Self> {
+        match version {
+            1 => Some(Layout::Flat),
+            2 => Some(Layout::Sharded),
+            _ => None,
+        }
+    }
+}
+
+/// The contents of the layout file
+#[derive(Serialize, Deserialize)]
+struct LayoutFile {
+    version: u32,
+}
+
+/// A report of everything changed by [`upgrade_layout`]
+#[derive(Debug)]
+pub struct LayoutUpgradeReport {
+    /// The layout the store was in before the upgrade
+    pub previous: Layout,
+    /// The number of invoice, draft and parcel directories that were moved into shards
+    pub moved: usize,
+}
+
+/// Returns the name of the shard directory for the entry with the given ID
+pub(crate) fn shard_name(id: &str) -> &str {
+    match id.get(..SHARD_LENGTH) {
+        Some(prefix) if id.len() > SHARD_LENGTH => prefix,
+        _ => SHORT_ID_SHARD,
+    }
+}
+
+/// Reads the layout of the store in the given root directory. A store without a layout file
+/// (including one that doesn't exist yet) is flat
+pub(crate) async fn read_layout(root: &Path) -> Result<Layout> {
+    let raw = match tokio::fs::read(root.join(LAYOUT_FILE)).await {
+        Ok(raw) => raw,
+        Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => return Ok(Layout::Flat),
+        Err(e) => return Err(e.into()),
+    };
+    let file: LayoutFile = toml::from_slice(&raw)?;
+    Layout::from_version(file.version).ok_or_else(|| {
+        ProviderError::Other(format!(
+            "Storage layout version {} is not supported by this version of bindle",
+            file.version
+        ))
+    })
+}
+
+/// Reads the layout of the store in the given root directory like [`read_layout`], except that a new
+/// store (one whose directory doesn't exist yet or is empty) is created with the sharded layout
+pub(crate) async fn init_layout(root: &Path) -> Result<Layout> {
+    if !is_new_store(root).await? {
+        return read_layout(root).await;
+    }
+    info!("Creating new store with the sharded layout");
+    tokio::fs::create_dir_all(root).await?;
+    write_layout(root, Layout::Sharded).await?;
+    Ok(Layout::Sharded)
+}
+
+/// Returns whether the given root directory doesn't exist or is empty
+async fn is_new_store(root: &Path) -> Result<bool> {
+    match tokio::fs::read_dir(root).await {
+        Ok(mut entries) => Ok(entries.next_entry().await?.is_none()),
+        Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => Ok(true),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Atomically writes the layout file for the store in the given root directory
+async fn write_layout(root: &Path, layout: Layout) -> Result<()> {
+    let path = root.join(LAYOUT_FILE);
+    let part = part_path(&path);
+    let data = toml::to_vec(&LayoutFile {
+        version: layout.version(),
+    })?;
+    let mut file = tokio::fs::File::create(&part).await?;
+    file.write_all(&data).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&part, &path).await?;
+    sync_dir(root).await
+}
+
+/// Converts the store in the given root directory to the sharded layout by moving every invoice,
+/// draft and parcel directory into its shard, and then records the new layout. A store that is
+/// already sharded is left as it is.
+///
+/// Nothing may be using the store while it is upgraded. Each directory is moved atomically, so an
+/// interrupted upgrade never loses anything, but the store can't be used until the upgrade has been
+/// run again to finish it
+#[instrument(level = "trace", skip(root), fields(root = %root.as_ref().display()))]
+pub async fn upgrade_layout(root: impl AsRef<Path>) -> Result<LayoutUpgradeReport> {
+    let root = root.as_ref();
+    let previous = read_layout(root).await?;
+    let mut report = LayoutUpgradeReport { previous, moved: 0 };
+    if previous == Layout::Sharded {
+        info!("Store already uses the sharded layout");
+        return Ok(report);
+    }
+
+    info!("Upgrading store to the sharded layout");
+    tokio::fs::create_dir_all(root).await?;
+    for dir in SHARDED_DIRECTORIES {
+        let dir = root.join(dir);
+        let mut shards = HashSet::new();
+        for name in list_dir_names(&dir).await? {
+            let source = dir.join(&name);
+            if !tokio::fs::metadata(&source).await?.is_dir() {
+                warn!(path = %source.display(), "Skipping unexpected file");
+                continue;
+            }
+            // Invoice and parcel directories are named after SHAs and never contain other
+            // directories, so anything else is a shard left over from an interrupted upgrade
+            if name.len() <= SHARD_LENGTH && is_shard(&source).await? {
+                continue;
+            }
+            let shard = dir.join(shard_name(&name));
+            tokio::fs::create_dir_all(&shard).await?;
+            let dest = shard.join(&name);
+            debug!(source = %source.display(), dest = %dest.display(), "Moving directory into shard");
+            tokio::fs::rename(&source, &dest).await?;
+            shards.insert(shard);
+            report.moved += 1;
+        }
+        // Every move MUST be on disk before the new layout is recorded. Otherwise a crash could
+        // leave entries where the sharded layout won't find them
+        for shard in shards {
+            sync_dir(&shard).await?;
+        }
+        if report.moved > 0 {
+            sync_dir(&dir).await?;
+        }
+    }
+    write_layout(root, Layout::Sharded).await?;
+    info!(moved = report.moved, "Finished upgrading store");
+    Ok(report)
+}
+
+/// Returns whether the given directory only contains other directories
+async fn is_shard(dir: &Path) -> Result<bool> {
+    let mut readdir = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = readdir.next_entry().await? {
+        if !entry.file_type().await?.is_dir() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
@@ -6,18 +6,26 @@
 //!
 //! This will only be available if the `provider` feature is enabled
 
+mod layout;
+
+use std::collections::HashSet;
 use std::io::Write;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::task::{Context, Poll};
+use std::time::Duration;
 use std::{convert::TryInto, ffi::OsString};
 
 use ::lru::LruCache;
+use async_compression::tokio::bufread::{ZstdDecoder, ZstdEncoder as ZstdReadEncoder};
+use async_compression::tokio::write::ZstdEncoder;
+use async_compression::Level;
 use sha2::{Digest, Sha256};
 use tokio::fs::{create_dir_all, File, OpenOptions};
-use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, ReadBuf};
 use tokio::sync::Mutex as TokioMutex;
 use tokio_stream::{Stream, StreamExt};
 use tokio_util::codec::{BytesCodec, FramedRead};
@@ -25,20 +33,48 @@ use tokio_util::io::StreamReader;
 use tracing::{debug, error, info, instrument, trace, warn};
 use tracing_futures::Instrument;
 
-use crate::provider::{Provider, ProviderError, Result};
+use crate::provider::compression::{CompressionPolicy, SyncReader};
+use crate::provider::encryption::{self, EncryptionKeyFile, Opener, Sealer};
+use crate::provider::fsck::{FsckOptions, FsckReport, QUARANTINE_NAME};
+use crate::provider::gc::{GcOptions, GcReport, DEFAULT_GRACE_PERIOD};
+use crate::provider::generation::{Change, GenerationTracker};
+use crate::provider::tiered::Evictable;
+use crate::provider::{parcel_label, Provider, ProviderError, Result};
 use crate::search::Search;
 use crate::verification::Verified;
 use crate::{Id, Signed};
 
+pub use layout::{upgrade_layout, Layout, LayoutUpgradeReport, LAYOUT_FILE};
+
 /// The folder name for the invoices directory
 const INVOICE_DIRECTORY: &str = "invoices";
 /// The folder name for the parcels directory
 pub const PARCEL_DIRECTORY: &str = "parcels";
 const INVOICE_TOML: &str = "invoice.toml";
+/// The folder name for draft invoices that are waiting for their parcels to be created
+const DRAFT_DIRECTORY: &str = "drafts";
 pub const PARCEL_DAT: &str = "parcel.dat";
+/// The file name for parcel data that is stored compressed with zstd. Its presence instead of a
+/// `parcel.dat` file marks the parcel as compressed
+pub const PARCEL_DAT_ZST: &str = "parcel.dat.zst";
+/// The file name for parcel data that is stored encrypted (and possibly compressed before that).
+/// Its presence instead of a `parcel.dat` file marks the parcel as encrypted
+pub const PARCEL_DAT_ENC: &str = "parcel.dat.enc";
 // SAFETY: We control this number since it is a constant
-const CACHE_SIZE: std::num::NonZeroUsize = unsafe { std::num::NonZeroUsize::new_unchecked(50) };
-const PART_EXTENSION: &str = "part";
+const CACHE_SIZE: std::num::NonZeroUsize = std::num::NonZeroUsize::new(50).unwrap();
+pub(crate) const PART_EXTENSION: &str = "part";
+/// The folder name for the part files of in progress resumable uploads
+const UPLOAD_DIRECTORY: &str = "uploads";
+/// The extension added to the part files of resumable uploads that are stored encrypted
+const ENCRYPTED_UPLOAD_EXTENSION: &str = "enc";
+/// The name of the file in the root of a store that records its generation, which is incremented
+/// before every change to its invoices
+const GENERATION_FILE: &str = "generation";
+/// The folder that garbage collection moves unreferenced parcels into while it checks that no
+/// invoice created in the meantime references them
+const GC_DIRECTORY: &str = "gc";
+/// The SHA of an empty parcel, the only one that can legitimately be stored in an empty file
+const EMPTY_PARCEL_SHA: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
 
 /// A file system backend for storing and retrieving bindles and parcles.
 ///
@@ -51,6 +87,12 @@ pub struct FileProvider<T> {
     root: PathBuf,
     index: T,
     invoice_cache: Arc<TokioMutex<LruCache<Id, crate::Invoice>>>,
+    // The parcel IDs of resumable uploads that currently have a request operating on them
+    active_uploads: Arc<Mutex<HashSet<String>>>,
+    compression: Option<CompressionPolicy>,
+    encryption: Option<Arc<EncryptionKeyFile>>,
+    layout: Layout,
+    generation: Arc<GenerationTracker>,
 }
 
 impl<T: Clone> Clone for FileProvider<T> {
@@ -59,23 +101,91 @@ impl<T: Clone> Clone for FileProvider<T> {
             root: self.root.clone(),
             index: self.index.clone(),
             invoice_cache: Arc::clone(&self.invoice_cache),
+            active_uploads: Arc::clone(&self.active_uploads),
+            compression: self.compression.clone(),
+            encryption: self.encryption.clone(),
+            layout: self.layout,
+            generation: Arc::clone(&self.generation),
         }
     }
 }
 
 impl<T: Search + Send + Sync> FileProvider<T> {
+    /// Creates a provider for the store in the given directory, using the layout recorded in it.
+    /// New stores (directories that don't exist yet or are empty) are created with
+    /// [`Layout::Sharded`], while existing stores without a recorded layout use [`Layout::Flat`].
+    ///
+    /// # Panics
+    ///
+    /// This will panic if the layout file in the directory can't be read, is malformed or records a
+    /// layout that this version doesn't know about. Use [`try_new`](Self::try_new) to handle that
+    /// instead
     pub async fn new<P: AsRef<Path>>(path: P, index: T) -> Self {
+        match Self::try_new(path, index).await {
+            Ok(fs) => fs,
+            Err(e) => panic!("Unable to read storage layout: {}", e),
+        }
+    }
+
+    /// Same as [`new`](Self::new), but returns an error if the layout file in the directory can't
+    /// be read, is malformed or records a layout that this version doesn't know about, as the store
+    /// can't be safely used in that case
+    pub async fn try_new<P: AsRef<Path>>(path: P, index: T) -> Result<Self> {
         debug!(path = %path.as_ref().display(), cache_size = CACHE_SIZE, "Creating new file provider");
+        let layout = layout::init_layout(path.as_ref()).await?;
+        debug!(?layout, "Detected storage layout");
         let fs = FileProvider {
             root: path.as_ref().to_owned(),
             index,
             invoice_cache: Arc::new(TokioMutex::new(LruCache::new(CACHE_SIZE))),
+            active_uploads: Arc::new(Mutex::new(HashSet::new())),
+            compression: None,
+            encryption: None,
+            layout,
+            generation: Arc::new(GenerationTracker::default()),
         };
         debug!("warming index");
-        if let Err(e) = fs.warm_index().await {
+        if let Err(e) = fs.sync_index().await {
             warn!(error = %e, "Error warming index");
         }
-        fs
+        Ok(fs)
+    }
+
+    /// Compresses newly stored parcels that match the given policy. Parcels that are already
+    /// stored are left as they are
+    pub fn with_compression(mut self, policy: CompressionPolicy) -> Self {
+        self.compression = Some(policy);
+        self
+    }
+
+    /// Encrypts newly stored parcels (and in progress uploads) with the active key in the given
+    /// key file. The other keys in the file are only used to read parcels that were encrypted with
+    /// them. Parcels that are already stored are left as they are
+    pub fn with_encryption(mut self, keys: EncryptionKeyFile) -> Self {
+        self.encryption = Some(Arc::new(keys));
+        self
+    }
+
+    /// Returns the layout the store is using
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    /// Brings the index in sync with the invoices on disk. An index that recorded the current
+    /// generation of the store (because it persists across restarts) is already in sync, while
+    /// any other index is cleared and warmed
+    async fn sync_index(&self) -> anyhow::Result<()> {
+        let snapshot = self.generation.snapshot();
+        let generation = read_generation(&self.root).await?;
+        if self.index.generation().await? == Some(generation) {
+            info!(generation, "Search index is in sync with the store");
+        } else {
+            self.index.clear().await?;
+            self.warm_index().await?;
+        }
+        self.generation
+            .synced(&self.index, snapshot, generation)
+            .await
     }
 
     /// This warms the index by loading all of the invoices currently on disk.
@@ -91,67 +201,675 @@ impl<T: Search + Send + Sync> FileProvider<T> {
         // Read all invoices
         info!(path = %self.root.display(), "Beginning index warm");
         let mut total_indexed: u64 = 0;
-        // Check if the invoice directory exists. If it doesn't, this is likely the first time and
-        // we should just return
-        let invoice_path = self.invoice_path("");
-        match tokio::fs::metadata(&invoice_path).await {
-            Ok(_) => (),
-            Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => return Ok(()),
-            Err(e) => return Err(e.into()),
-        };
-        let mut readdir = tokio::fs::read_dir(invoice_path).await?;
-        while let Some(e) = readdir.next_entry().await? {
-            let p = e.path();
-            let sha = match p.file_name().map(|f| f.to_string_lossy()) {
-                Some(sha_opt) => sha_opt,
-                None => continue,
-            };
-            // Load invoice
+        let mut failed: u64 = 0;
+        // If the invoice directory doesn't exist, this is likely the first time and there is
+        // nothing to load
+        for sha in self.list_ids(INVOICE_DIRECTORY).await? {
+            // Load invoice. A single bad record shouldn't keep every other invoice out of the
+            // index, so anything that can't be loaded is skipped. Running fsck will find it
             let inv_path = self.invoice_toml_path(&sha);
             info!(path = %inv_path.display(), "Loading invoice into search index");
             // Open file
-            let inv_toml = tokio::fs::read(inv_path).await?;
+            let inv_toml = match tokio::fs::read(&inv_path).await {
+                Ok(data) => data,
+                // The invoice is still being written or was abandoned
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => continue,
+                Err(e) => {
+                    error!(path = %inv_path.display(), error = %e, "Unable to read invoice, skipping");
+                    continue;
+                }
+            };
 
             // Parse
-            let invoice: crate::Invoice = toml::from_slice(&inv_toml)?;
+            let invoice: crate::Invoice = match toml::from_slice(&inv_toml) {
+                Ok(inv) => inv,
+                Err(e) => {
+                    error!(path = %inv_path.display(), error = %e, "Unable to parse invoice, skipping");
+                    continue;
+                }
+            };
             let digest = invoice.canonical_name();
             if sha != digest {
-                anyhow::bail!(
-                    "SHA {} did not match computed digest {}. Delete this record.",
-                    sha,
-                    digest
+                error!(
+                    %sha,
+                    %digest,
+                    "Invoice SHA did not match computed digest, skipping. Delete this record"
                 );
+                continue;
             }
 
             if let Err(e) = self.index.index(&invoice).await {
                 error!(invoice_id = %invoice.bindle.id, error = %e, "Error indexing invoice");
+                failed += 1;
+                continue;
             }
             total_indexed += 1;
         }
+        // Failing here would keep the generation from being recorded, which would mean rebuilding
+        // the whole index on every start because of a single invoice
+        if failed > 0 {
+            warn!(
+                failed,
+                "Some invoices could not be indexed and won't show up in search results"
+            );
+        }
         debug!(total_indexed, "Warmed index");
         Ok(())
     }
 
+    /// Cleans up after writes that were interrupted by a crash. Part files that haven't been
+    /// modified within the default garbage collection grace period are removed, and invoice, draft
+    /// and parcel directories containing an empty file are quarantined, as data that never made it
+    /// to disk can leave an empty file behind after a power loss.
+    ///
+    /// This must only be run when nothing else is writing to the store (such as when the server
+    /// starts), as a write that is still in progress also leaves an empty file behind for a moment.
+    /// Newer part files are left alone in any case
+    #[instrument(level = "trace", skip(self))]
+    pub async fn recover(&self) -> Result<()> {
+        for dir in [INVOICE_DIRECTORY, DRAFT_DIRECTORY, PARCEL_DIRECTORY] {
+            for id in self.list_ids(dir).await? {
+                let entry = self.entry_path(dir, &id);
+                let mut corrupt = false;
+                for name in list_dir_names(&entry).await? {
+                    let path = entry.join(&name);
+                    let metadata = match tokio::fs::metadata(&path).await {
+                        Ok(m) => m,
+                        // The write finished or was cleaned up while we were looking
+                        Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => continue,
+                        Err(e) => return Err(e.into()),
+                    };
+                    if path.extension().and_then(|ext| ext.to_str()) == Some(PART_EXTENSION) {
+                        if is_stale(&path, DEFAULT_GRACE_PERIOD).await? {
+                            warn!(path = %path.display(), "Removing part file left by an interrupted write");
+                            match tokio::fs::remove_file(&path).await {
+                                Ok(_) => (),
+                                Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => (),
+                                Err(e) => return Err(e.into()),
+                            }
+                        }
+                    } else if metadata.len() == 0 && id != EMPTY_PARCEL_SHA {
+                        corrupt = true;
+                    }
+                }
+                if corrupt {
+                    warn!(path = %entry.display(), "Quarantining directory with an empty file left by an interrupted write");
+                    self.quarantine(dir, &id).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes all parcels that are not referenced by any invoice (including yanked invoices), as
+    /// well as any part files that have not been modified within the configured grace period.
+    ///
+    /// This is safe to run while the store is in use. Unreferenced parcels are first moved out of
+    /// the way and the invoices are checked again before they are removed, so a parcel that a new
+    /// invoice started referencing in the meantime is put back. A push that checks for the parcel
+    /// after it was moved will upload it again.
+    ///
+    /// If `dry_run` is set in the options, nothing will be removed, but the returned report will
+    /// contain everything that would have been removed
+    #[instrument(level = "trace", skip(self))]
+    pub async fn gc(&self, options: GcOptions) -> Result<GcReport> {
+        info!(path = %self.root.display(), dry_run = options.dry_run, "Beginning garbage collection");
+        let mut report = GcReport {
+            dry_run: options.dry_run,
+            ..Default::default()
+        };
+
+        // Put back anything left behind by a garbage collection that was interrupted, so it is
+        // checked again below
+        if !options.dry_run {
+            for parcel_id in list_dir_names(&self.root.join(GC_DIRECTORY)).await? {
+                self.restore_collected_parcel(&parcel_id).await?;
+            }
+        }
+
+        // NOTE: The parcels MUST be listed before the invoices are read. A parcel can only be
+        // created once its invoice exists, so every parcel we see here is guaranteed to have its
+        // invoice included in the live set, even if both were created while we were running
+        let parcel_ids = self.list_ids(PARCEL_DIRECTORY).await?;
+
+        let live = self.live_parcels(&options, &mut report).await?;
+        report.live_parcels = live.len();
+        debug!(live_parcels = report.live_parcels, "Built live set");
+
+        let mut collected = Vec::new();
+        for parcel_id in parcel_ids {
+            let data_paths = [
+                self.parcel_data_path(&parcel_id),
+                self.parcel_compressed_path(&parcel_id),
+                self.parcel_encrypted_path(&parcel_id),
+            ];
+            if live.contains(&parcel_id) {
+                // Even referenced parcels can have an abandoned upload. Once that is removed, an
+                // empty directory would block anyone from uploading the parcel again
+                for path in data_paths.iter() {
+                    self.gc_part_file(path, &options, &mut report).await?;
+                }
+                remove_empty_dir(&self.parcel_path(&parcel_id), options.dry_run).await?;
+                continue;
+            }
+
+            // Don't pull the rug out from under an upload that is still in progress
+            let mut in_progress = false;
+            for part_path in data_paths.iter().map(|p| part_path(p)) {
+                if tokio::fs::metadata(&part_path).await.is_ok()
+                    && !is_stale(&part_path, options.grace_period).await?
+                {
+                    in_progress = true;
+                }
+            }
+            if in_progress {
+                trace!(%parcel_id, "Skipping unreferenced parcel with a write in progress");
+                continue;
+            }
+
+            let parcel_path = self.parcel_path(&parcel_id);
+            let size = dir_size(&parcel_path).await?;
+            if options.dry_run {
+                report.reclaimed_bytes += size;
+                report.removed_parcels.push(parcel_id);
+                continue;
+            }
+            // An invoice created since the live set was built could reference the parcel, and a
+            // push for it wouldn't upload the parcel again as it still exists. Moving it aside first
+            // means any push from now on will upload it instead
+            let dest = self.root.join(GC_DIRECTORY).join(&parcel_id);
+            create_dir_all(self.root.join(GC_DIRECTORY)).await?;
+            tokio::fs::rename(&parcel_path, &dest).await?;
+            collected.push((parcel_id, size));
+        }
+
+        if !collected.is_empty() {
+            trace!("Checking collected parcels against invoices created in the meantime");
+            let live = match self.live_parcels(&options, &mut report).await {
+                Ok(live) => live,
+                Err(e) => {
+                    for (parcel_id, _) in collected.iter() {
+                        self.restore_collected_parcel(parcel_id).await?;
+                    }
+                    return Err(e);
+                }
+            };
+            for (parcel_id, size) in collected {
+                if live.contains(&parcel_id) {
+                    debug!(%parcel_id, "Restoring parcel that is referenced again");
+                    self.restore_collected_parcel(&parcel_id).await?;
+                    continue;
+                }
+                debug!(%parcel_id, "Removing unreferenced parcel");
+                tokio::fs::remove_dir_all(self.root.join(GC_DIRECTORY).join(&parcel_id)).await?;
+                report.reclaimed_bytes += size;
+                report.removed_parcels.push(parcel_id);
+            }
+        }
+
+        // Resumable uploads that haven't been touched within the grace period were abandoned
+        let upload_dir = self.root.join(UPLOAD_DIRECTORY);
+        for name in list_dir_names(&upload_dir).await? {
+            if let Some(parcel_id) = Path::new(&name).file_stem() {
+                self.gc_part_file(&upload_dir.join(parcel_id), &options, &mut report)
+                    .await?;
+            }
+        }
+
+        info!(
+            removed_parcels = report.removed_parcels.len(),
+            removed_part_files = report.removed_part_files.len(),
+            reclaimed_bytes = report.reclaimed_bytes,
+            "Finished garbage collection"
+        );
+        Ok(report)
+    }
+
+    /// Returns the IDs of every parcel referenced by a draft or invoice (including yanked
+    /// invoices). Invoices that were never finished have their stale part files cleaned up
+    async fn live_parcels(
+        &self,
+        options: &GcOptions,
+        report: &mut GcReport,
+    ) -> Result<std::collections::HashSet<String>> {
+        trace!("Building live set of parcels");
+        let mut live = std::collections::HashSet::new();
+        // Parcels can be created for drafts too. Drafts MUST be read before invoices, as a draft
+        // that is published while we are running is moved into the invoices directory
+        for invoice_id in self.list_ids(DRAFT_DIRECTORY).await? {
+            let inv_toml = match tokio::fs::read(self.draft_toml_path(&invoice_id)).await {
+                Ok(data) => data,
+                // The draft was published, expired or never finished
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let invoice: crate::Invoice = toml::from_slice(&inv_toml)?;
+            live.extend(
+                invoice
+                    .parcel
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|p| p.label.sha256),
+            );
+        }
+        for invoice_id in self.list_ids(INVOICE_DIRECTORY).await? {
+            let inv_path = self.invoice_toml_path(&invoice_id);
+            let inv_toml = match tokio::fs::read(&inv_path).await {
+                Ok(data) => data,
+                // A missing invoice.toml means the invoice was never finished. Clean up its part
+                // file if it is stale
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => {
+                    self.gc_part_file(&inv_path, options, report).await?;
+                    remove_empty_dir(&self.invoice_path(&invoice_id), options.dry_run).await?;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            // If we can't read an invoice, we can't know which parcels it references, so we
+            // bail out rather than risk deleting live data
+            let invoice: crate::Invoice = toml::from_slice(&inv_toml)?;
+            live.extend(
+                invoice
+                    .parcel
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|p| p.label.sha256),
+            );
+        }
+        Ok(live)
+    }
+
+    /// Moves a parcel that garbage collection moved aside back into place. If the parcel was
+    /// uploaded again in the meantime, the collected copy is removed instead
+    async fn restore_collected_parcel(&self, parcel_id: &str) -> Result<()> {
+        let collected = self.root.join(GC_DIRECTORY).join(parcel_id);
+        let parcel_path = self.parcel_path(parcel_id);
+        if tokio::fs::metadata(&parcel_path).await.is_ok() {
+            tokio::fs::remove_dir_all(&collected).await?;
+            return Ok(());
+        }
+        if let Some(parent) = parcel_path.parent() {
+            create_dir_all(parent).await?;
+        }
+        match tokio::fs::rename(&collected, &parcel_path).await {
+            Ok(_) => Ok(()),
+            // An upload of the parcel started since we checked
+            Err(_) if tokio::fs::metadata(&parcel_path).await.is_ok() => {
+                tokio::fs::remove_dir_all(&collected).await?;
+                Ok(())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Removes the part file for the given final location if it exists and is older than the
+    /// grace period, recording it in the report
+    async fn gc_part_file(
+        &self,
+        final_location: &Path,
+        options: &GcOptions,
+        report: &mut GcReport,
+    ) -> Result<()> {
+        let part = part_path(final_location);
+        let metadata = match tokio::fs::metadata(&part).await {
+            Ok(m) => m,
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        if !is_stale(&part, options.grace_period).await? {
+            return Ok(());
+        }
+        debug!(path = %part.display(), "Removing stale part file");
+        if !options.dry_run {
+            tokio::fs::remove_file(&part).await?;
+        }
+        report.reclaimed_bytes += metadata.len();
+        report.removed_part_files.push(part);
+        Ok(())
+    }
+
+    /// Checks the integrity of all stored invoices and parcels. Every parcel is decrypted and
+    /// decompressed (if needed) and re-hashed against the SHA it is stored under, every invoice is checked to be stored under the SHA of
+    /// its canonical name, and every parcel referenced by an invoice is checked to exist with the
+    /// size given in its label.
+    ///
+    /// If `quarantine` is set in the options, offending parcel and invoice directories are moved
+    /// into a `quarantine` directory inside of the root directory. Anything that is still being
+    /// written is skipped. Encrypted parcels whose key is not configured can't be checked, so an
+    /// error is returned rather than reporting them as corrupt
+    #[instrument(level = "trace", skip(self))]
+    pub async fn fsck(&self, options: FsckOptions) -> Result<FsckReport> {
+        info!(path = %self.root.display(), quarantine = options.quarantine, "Beginning integrity check");
+        let mut report = FsckReport {
+            quarantined: options.quarantine,
+            ..Default::default()
+        };
+
+        trace!("Checking parcels");
+        let mut parcel_sizes = std::collections::HashMap::new();
+        for parcel_id in self.list_ids(PARCEL_DIRECTORY).await? {
+            let encoding = match self.stored_parcel_path(&parcel_id).await {
+                Ok((_, encoding)) => encoding,
+                // There is no data if the parcel is still being written or was abandoned
+                Err(ProviderError::NotFound) => continue,
+                Err(e) => return Err(e),
+            };
+            report.checked_parcels += 1;
+            let res = match self.open_parcel_data(&parcel_id, 0).await {
+                Ok(mut reader) => validate_sha256(&mut reader, &parcel_id).await,
+                Err(e) => Err(e),
+            };
+            let res = match res {
+                // Data that can't be decrypted or decompressed is just as corrupt as data that
+                // doesn't match its SHA
+                Err(ProviderError::Io(e)) if encoding != Encoding::Plain => {
+                    debug!(%parcel_id, error = %e, "Unable to decode parcel data");
+                    Err(ProviderError::DigestMismatch)
+                }
+                res => res,
+            };
+            match res {
+                Ok(size) => {
+                    parcel_sizes.insert(parcel_id, size);
+                }
+                Err(ProviderError::DigestMismatch) => {
+                    warn!(%parcel_id, "Parcel data does not match its SHA");
+                    if options.quarantine {
+                        self.quarantine(PARCEL_DIRECTORY, &parcel_id).await?;
+                    }
+                    report.corrupt_parcels.push(parcel_id);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        trace!("Checking invoices");
+        for invoice_id in self.list_ids(INVOICE_DIRECTORY).await? {
+            let inv_toml = match tokio::fs::read(self.invoice_toml_path(&invoice_id)).await {
+                Ok(data) => data,
+                // The invoice is still being written or was abandoned
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            report.checked_invoices += 1;
+            let invoice: crate::Invoice = match toml::from_slice(&inv_toml) {
+                Ok(inv) => inv,
+                Err(e) => {
+                    warn!(%invoice_id, error = %e, "Unable to parse invoice");
+                    if options.quarantine {
+                        self.quarantine(INVOICE_DIRECTORY, &invoice_id).await?;
+                    }
+                    report.unreadable_invoices.push(invoice_id);
+                    continue;
+                }
+            };
+            if invoice.canonical_name() != invoice_id {
+                warn!(%invoice_id, bindle_id = %invoice.bindle.id, "Invoice is not stored under its canonical name");
+                if options.quarantine {
+                    self.quarantine(INVOICE_DIRECTORY, &invoice_id).await?;
+                }
+                report.misnamed_invoices.push(invoice_id);
+                continue;
+            }
+
+            for label in invoice
+                .parcel
+                .unwrap_or_default()
+                .into_iter()
+                .map(|p| p.label)
+            {
+                // Corrupt parcels have already been reported
+                if report.corrupt_parcels.contains(&label.sha256) {
+                    continue;
+                }
+                match parcel_sizes.get(&label.sha256) {
+                    None => report
+                        .missing_parcels
+                        .push((invoice.bindle.id.clone(), label.sha256)),
+                    Some(size) if *size != label.size => {
+                        warn!(bindle_id = %invoice.bindle.id, parcel_id = %label.sha256, "Parcel size does not match label");
+                        report
+                            .size_mismatches
+                            .push((invoice.bindle.id.clone(), label.sha256))
+                    }
+                    Some(_) => (),
+                }
+            }
+        }
+
+        info!(
+            checked_invoices = report.checked_invoices,
+            checked_parcels = report.checked_parcels,
+            clean = report.is_clean(),
+            "Finished integrity check"
+        );
+        Ok(report)
+    }
+
+    /// Moves the given invoice or parcel directory (as given by the directory name of its type)
+    /// into the quarantine directory so it is no longer served
+    async fn quarantine(&self, kind: &str, name: &str) -> Result<()> {
+        let dest_dir = self.root.join(QUARANTINE_NAME).join(kind);
+        create_dir_all(&dest_dir).await?;
+        // Something with the same name may have already been quarantined, so add a timestamp
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let dest = dest_dir.join(format!("{}-{}", name, timestamp));
+        let source = self.entry_path(kind, name);
+        debug!(source = %source.display(), dest = %dest.display(), "Quarantining directory");
+        if kind != INVOICE_DIRECTORY {
+            tokio::fs::rename(source, dest).await?;
+            return Ok(());
+        }
+        let change = self.begin_change().await?;
+        tokio::fs::rename(source, dest).await?;
+        // Quarantined invoices aren't removed from the index, so it has to be rebuilt the next time
+        // the provider is created
+        change.finish(&self.index, false).await;
+        Ok(())
+    }
+
+    /// Increments the generation of the store. This MUST be called before changing any invoice,
+    /// and the returned change finished once the index has been updated
+    async fn begin_change(&self) -> Result<Change<'_>> {
+        self.generation
+            .begin(|| async {
+                let previous = read_generation(&self.root).await?;
+                write_generation(&self.root, previous + 1).await?;
+                Ok((previous, previous + 1))
+            })
+            .await
+    }
+
+    /// Returns the IDs of all entries in the given invoice, draft or parcel directory, looking
+    /// through the shards if the store is sharded
+    async fn list_ids(&self, dir: &str) -> Result<Vec<String>> {
+        let dir = self.root.join(dir);
+        if self.layout == Layout::Flat {
+            return list_dir_names(&dir).await;
+        }
+        let mut ids = Vec::new();
+        for shard in list_dir_names(&dir).await? {
+            for id in list_dir_names(&dir.join(&shard)).await? {
+                // Anything in the wrong shard can't be found by its ID anyway
+                if layout::shard_name(&id) != shard {
+                    warn!(path = %dir.join(&shard).join(&id).display(), "Skipping entry stored in the wrong shard");
+                    continue;
+                }
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Return the path to the directory for the given ID within the given invoice, draft or parcel
+    /// directory
+    fn entry_path(&self, dir: &str, id: &str) -> PathBuf {
+        let mut path = self.root.join(dir);
+        if self.layout == Layout::Sharded {
+            path.push(layout::shard_name(id));
+        }
+        path.push(id);
+        path
+    }
+
     /// Return the path to the invoice directory for a particular bindle.
     fn invoice_path(&self, invoice_id: &str) -> PathBuf {
-        let mut path = self.root.join(INVOICE_DIRECTORY);
-        path.push(invoice_id);
-        path
+        self.entry_path(INVOICE_DIRECTORY, invoice_id)
     }
     /// Return the path for an invoice.toml for a particular bindle.
     fn invoice_toml_path(&self, invoice_id: &str) -> PathBuf {
         self.invoice_path(invoice_id).join(INVOICE_TOML)
     }
+    /// Return the path to the draft directory for a particular bindle.
+    fn draft_path(&self, invoice_id: &str) -> PathBuf {
+        self.entry_path(DRAFT_DIRECTORY, invoice_id)
+    }
+    /// Return the path for the invoice.toml of a draft of a particular bindle.
+    fn draft_toml_path(&self, invoice_id: &str) -> PathBuf {
+        self.draft_path(invoice_id).join(INVOICE_TOML)
+    }
     /// Return the parcel-specific path for storing a parcel.
-    fn parcel_path(&self, parcel_id: &str) -> PathBuf {
-        let mut path = self.root.join(PARCEL_DIRECTORY);
-        path.push(parcel_id);
-        path
+    pub(crate) fn parcel_path(&self, parcel_id: &str) -> PathBuf {
+        self.entry_path(PARCEL_DIRECTORY, parcel_id)
     }
     /// Return the path to the parcel.dat file for the given box ID
-    fn parcel_data_path(&self, parcel_id: &str) -> PathBuf {
+    pub(crate) fn parcel_data_path(&self, parcel_id: &str) -> PathBuf {
         self.parcel_path(parcel_id).join(PARCEL_DAT)
     }
+    /// Return the path to the compressed parcel.dat.zst file for the given box ID
+    fn parcel_compressed_path(&self, parcel_id: &str) -> PathBuf {
+        self.parcel_path(parcel_id).join(PARCEL_DAT_ZST)
+    }
+
+    /// Return the path to the encrypted parcel.dat.enc file for the given box ID
+    fn parcel_encrypted_path(&self, parcel_id: &str) -> PathBuf {
+        self.parcel_path(parcel_id).join(PARCEL_DAT_ENC)
+    }
+    /// Return the path parcel data with the given encoding is stored at
+    fn parcel_location(&self, parcel_id: &str, encoding: Encoding) -> PathBuf {
+        match encoding {
+            Encoding::Plain => self.parcel_data_path(parcel_id),
+            Encoding::Compressed => self.parcel_compressed_path(parcel_id),
+            Encoding::Encrypted => self.parcel_encrypted_path(parcel_id),
+        }
+    }
+
+    /// Returns the path to the stored data for the given parcel along with how it is encoded.
+    /// Returns `ProviderError::NotFound` if the parcel isn't stored
+    async fn stored_parcel_path(&self, parcel_id: &str) -> Result<(PathBuf, Encoding)> {
+        for encoding in [Encoding::Plain, Encoding::Compressed, Encoding::Encrypted] {
+            let path = self.parcel_location(parcel_id, encoding);
+            match tokio::fs::metadata(&path).await {
+                Ok(m) if m.is_file() => return Ok((path, encoding)),
+                Ok(_) => (),
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err(ProviderError::NotFound)
+    }
+
+    /// Opens the stored data for the given parcel, starting at the given offset into the
+    /// plaintext. Encrypted and compressed parcels are transparently decrypted and decompressed
+    async fn open_parcel_data(
+        &self,
+        parcel_id: &str,
+        offset: u64,
+    ) -> Result<Box<dyn AsyncRead + Unpin + Send + Sync>> {
+        let (path, encoding) = self.stored_parcel_path(parcel_id).await?;
+        trace!(path = %path.display(), ?encoding, offset, "Opening parcel data");
+        let mut file = File::open(path).await.map_err(map_io_error)?;
+        if encoding == Encoding::Plain {
+            // Seeking past the end of the file is allowed and will just result in an empty read
+            file.seek(std::io::SeekFrom::Start(offset))
+                .await
+                .map_err(map_io_error)?;
+            return Ok(Box::new(file));
+        }
+        let mut reader =
+            SyncReader::new(decode_reader(file, encoding, self.encryption.as_deref(), true).await?);
+        // Encoded data can't be seeked, so anything before the offset has to be decoded and thrown
+        // away
+        if offset > 0 {
+            tokio::io::copy(&mut (&mut reader).take(offset), &mut tokio::io::sink())
+                .await
+                .map_err(map_io_error)?;
+        }
+        Ok(Box::new(reader))
+    }
+
+    /// Returns the compression level to store the given parcel with, or `None` if it should be
+    /// stored uncompressed
+    fn compression_for(&self, label: &crate::Label) -> Option<Level> {
+        self.compression
+            .as_ref()
+            .filter(|policy| policy.should_compress(label))
+            .map(|policy| policy.encoder_level())
+    }
+
+    /// Returns how the given parcel should be written to disk
+    fn write_options(&self, label: &crate::Label) -> WriteOptions {
+        WriteOptions {
+            compression: self.compression_for(label),
+            encryption: self.encryption.clone(),
+        }
+    }
+
+    /// Returns the configured encryption keys or an error if encryption isn't configured
+    fn encryption_keys(&self) -> Result<&EncryptionKeyFile> {
+        self.encryption.as_deref().ok_or_else(missing_keys_error)
+    }
+
+    /// Return the path to the part file for a resumable upload of the given parcel. These are kept
+    /// outside of the parcel directory so an unfinished upload isn't mistaken for a stored parcel
+    fn upload_path(&self, parcel_id: &str) -> PathBuf {
+        part_path(&self.root.join(UPLOAD_DIRECTORY).join(parcel_id))
+    }
+    /// Return the path to the part file for an encrypted resumable upload of the given parcel
+    fn encrypted_upload_path(&self, parcel_id: &str) -> PathBuf {
+        part_path(
+            &self
+                .root
+                .join(UPLOAD_DIRECTORY)
+                .join(parcel_id)
+                .with_extension(ENCRYPTED_UPLOAD_EXTENSION),
+        )
+    }
+
+    /// Returns the path to the part file of the existing upload for the given parcel along with
+    /// whether or not it is encrypted. Returns `ProviderError::NotFound` if there is no upload
+    async fn find_upload(&self, parcel_id: &str) -> Result<(PathBuf, bool)> {
+        for (path, encrypted) in [
+            (self.upload_path(parcel_id), false),
+            (self.encrypted_upload_path(parcel_id), true),
+        ] {
+            match tokio::fs::metadata(&path).await {
+                Ok(_) => return Ok((path, encrypted)),
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err(ProviderError::NotFound)
+    }
+
+    /// Marks a resumable upload as busy until the returned guard is dropped. Returns
+    /// `ProviderError::WriteInProgress` if another request is already operating on the upload
+    fn lock_upload(&self, parcel_id: &str) -> Result<UploadGuard> {
+        let mut active = self
+            .active_uploads
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if !active.insert(parcel_id.to_owned()) {
+            return Err(ProviderError::WriteInProgress);
+        }
+        Ok(UploadGuard {
+            active_uploads: Arc::clone(&self.active_uploads),
+            parcel_id: parcel_id.to_owned(),
+        })
+    }
 }
 
 #[async_trait::async_trait]
@@ -163,7 +881,7 @@ impl<T: crate::search::Search + Send + Sync> Provider for FileProvider<T> {
     {
         let inv = invoice.signed();
         tracing::span::Span::current()
-            .record("invoice_id", &tracing::field::display(&inv.bindle.id));
+            .record("invoice_id", tracing::field::display(&inv.bindle.id));
         // It is illegal to create a yanked invoice.
         if inv.yanked.unwrap_or(false) {
             debug!(id = %inv.bindle.id, "Invoice being created is set to yanked");
@@ -184,9 +902,9 @@ impl<T: crate::search::Search + Send + Sync> Provider for FileProvider<T> {
                 return Err(ProviderError::Exists);
             }
             trace!(path = %inv_path.display(), "Base path doesn't exist, creating");
-            if let Err(e) = create_dir_all(inv_path).await {
+            if let Err(e) = create_dirs(&inv_path).await {
                 error!(error = %e, "Unable to create invoice storage directory");
-                return Err(e.into());
+                return Err(e);
             }
         }
 
@@ -202,52 +920,167 @@ impl<T: crate::search::Search + Send + Sync> Provider for FileProvider<T> {
             debug!("Invoice being created already exists in storage");
             return Err(ProviderError::Exists);
         }
+        if tokio::fs::metadata(self.draft_toml_path(&invoice_id))
+            .await
+            .is_ok()
+        {
+            debug!("Invoice being created already exists in storage as a draft");
+            return Err(ProviderError::Exists);
+        }
 
         // Create the part file to indicate that we are currently writing
+        let change = self.begin_change().await?;
         let mut part = PartFile::new(dest).await?;
         part.write_invoice(&inv).await?;
         part.finalize().await?;
 
         // Attempt to update the index. Right now, we log an error if the index update
         // fails.
-        if let Err(e) = self.index.index(&inv).await {
+        let indexed = self.index.index(&inv).await;
+        if let Err(e) = &indexed {
             error!(error = %e, "Error indexing new invoice");
         }
+        change.finish(&self.index, indexed.is_ok()).await;
 
-        // if there are no parcels, bail early
-        if inv.parcel.is_none() {
-            return Ok((inv, Vec::with_capacity(0)));
-        }
+        let labels = crate::provider::missing_parcels(self, &inv).await?;
+        Ok((inv, labels))
+    }
 
-        trace!("Checking for missing parcels listed in newly created invoice");
-        // Note: this will not allocate
-        let zero_vec = Vec::with_capacity(0);
-        // Loop through the boxes and see what exists
-        let missing = inv
-            .parcel
-            .as_ref()
-            .unwrap_or(&zero_vec)
-            .iter()
-            .map(|k| async move {
-                let parcel_path = self.parcel_path(k.label.sha256.as_str());
-                // Stat k to see if it exists. If it does not exist or is not a directory, add it.
-                let res = tokio::fs::metadata(parcel_path).await;
-                match res {
-                    Ok(stat) if !stat.is_dir() => Some(k.label.clone()),
-                    Err(_e) => Some(k.label.clone()),
-                    _ => None,
-                }
-            });
+    #[instrument(level = "trace", skip(self, invoice), fields(invoice_id = tracing::field::Empty))]
+    async fn create_draft_invoice<I>(
+        &self,
+        invoice: I,
+    ) -> Result<(crate::Invoice, Vec<crate::Label>)>
+    where
+        I: Signed + Verified + Send + Sync,
+    {
+        let inv = invoice.signed();
+        tracing::span::Span::current()
+            .record("invoice_id", tracing::field::display(&inv.bindle.id));
+        // It is illegal to create a yanked invoice.
+        if inv.yanked.unwrap_or(false) {
+            debug!(id = %inv.bindle.id, "Invoice being created is set to yanked");
+            return Err(ProviderError::CreateYanked);
+        }
 
-        let labels = futures::future::join_all(missing)
-            .instrument(tracing::trace_span!("lookup_missing"))
+        let invoice_id = inv.canonical_name();
+        if tokio::fs::metadata(self.invoice_toml_path(&invoice_id))
             .await
-            .into_iter()
-            .flatten()
-            .collect();
+            .is_ok()
+        {
+            debug!("Invoice being created as a draft already exists in storage");
+            return Err(ProviderError::Exists);
+        }
+
+        let draft_path = self.draft_path(&invoice_id);
+        trace!(path = %draft_path.display(), "Creating draft directory");
+        if let Err(e) = create_dirs(&draft_path).await {
+            error!(error = %e, "Unable to create draft storage directory");
+            return Err(e);
+        }
+        // Creating the part file fails if the draft already exists
+        let mut part = PartFile::new(self.draft_toml_path(&invoice_id)).await?;
+        part.write_invoice(&inv).await?;
+        part.finalize().await?;
+
+        // If nothing is missing, this publishes the draft right away
+        let labels = self.publish_draft_invoice(&inv.bindle.id).await?;
         Ok((inv, labels))
     }
 
+    #[instrument(level = "trace", skip(self, id), fields(id))]
+    async fn get_draft_invoice<I>(&self, id: I) -> Result<crate::Invoice>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id: Id = id.try_into().map_err(|e| e.into())?;
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
+
+        let draft_path = self.draft_toml_path(&parsed_id.sha());
+        debug!(path = %draft_path.display(), "Reading draft invoice");
+        let inv_toml = tokio::fs::read(draft_path).await.map_err(map_io_error)?;
+        Ok(toml::from_slice(&inv_toml)?)
+    }
+
+    #[instrument(level = "trace", skip(self, id), fields(id))]
+    async fn publish_draft_invoice<I>(&self, id: I) -> Result<Vec<crate::Label>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id: Id = id.try_into().map_err(|e| e.into())?;
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
+
+        let inv = self.get_draft_invoice(&parsed_id).await?;
+        let missing = crate::provider::missing_parcels(self, &inv).await?;
+        if !missing.is_empty() {
+            debug!(
+                missing = missing.len(),
+                "Draft invoice is still missing parcels"
+            );
+            return Ok(missing);
+        }
+
+        let invoice_id = parsed_id.sha();
+        let dest = self.invoice_toml_path(&invoice_id);
+        if tokio::fs::metadata(&dest).await.is_ok() {
+            debug!("Invoice being published already exists in storage");
+            return Err(ProviderError::Exists);
+        }
+        create_dirs(&self.invoice_path(&invoice_id)).await?;
+        // Renaming is atomic, so the whole invoice becomes visible at once. If two requests try
+        // to publish the same draft, only one of them will still find it
+        debug!(path = %dest.display(), "Publishing draft invoice");
+        let change = self.begin_change().await?;
+        if let Err(e) = tokio::fs::rename(self.draft_toml_path(&invoice_id), &dest).await {
+            let e = map_io_error(e);
+            // Someone else published the draft, so nothing was changed
+            if matches!(e, ProviderError::NotFound) {
+                change.finish(&self.index, true).await;
+            }
+            return Err(e);
+        }
+        sync_parent_dirs(&dest).await?;
+
+        // Attempt to update the index. Right now, we log an error if the index update
+        // fails.
+        let indexed = self.index.index(&inv).await;
+        if let Err(e) = &indexed {
+            error!(error = %e, "Error indexing published invoice");
+        }
+        change.finish(&self.index, indexed.is_ok()).await;
+        remove_empty_dir(&self.draft_path(&invoice_id), false).await?;
+        Ok(missing)
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    async fn expire_draft_invoices(&self, ttl: Duration) -> Result<Vec<Id>> {
+        let mut expired = Vec::new();
+        for invoice_id in self.list_ids(DRAFT_DIRECTORY).await? {
+            let draft_path = self.draft_toml_path(&invoice_id);
+            let inv_toml = match tokio::fs::read(&draft_path).await {
+                Ok(data) => data,
+                // The draft was published or never finished
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if !is_stale(&draft_path, ttl).await? {
+                continue;
+            }
+            let invoice: crate::Invoice = toml::from_slice(&inv_toml)?;
+            debug!(id = %invoice.bindle.id, "Removing expired draft invoice");
+            match tokio::fs::remove_file(&draft_path).await {
+                Ok(_) => (),
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => continue,
+                Err(e) => return Err(e.into()),
+            }
+            remove_empty_dir(&self.draft_path(&invoice_id), false).await?;
+            expired.push(invoice.bindle.id);
+        }
+        Ok(expired)
+    }
+
     #[instrument(level = "trace", skip(self, id), fields(id))]
     async fn get_yanked_invoice<I>(&self, id: I) -> Result<crate::Invoice>
     where
@@ -255,7 +1088,7 @@ impl<T: crate::search::Search + Send + Sync> Provider for FileProvider<T> {
         I::Error: Into<ProviderError>,
     {
         let parsed_id: Id = id.try_into().map_err(|e| e.into())?;
-        tracing::Span::current().record("id", &tracing::field::display(&parsed_id));
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
 
         if let Some(inv) = self.invoice_cache.lock().await.get(&parsed_id) {
             debug!("Found invoice in cache, returning");
@@ -297,33 +1130,35 @@ impl<T: crate::search::Search + Send + Sync> Provider for FileProvider<T> {
         I::Error: Into<ProviderError>,
     {
         let parsed_id = id.try_into().map_err(|e| e.into())?;
-        tracing::Span::current().record("id", &tracing::field::display(&parsed_id));
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
         trace!("Fetching invoice from storage");
         let mut inv = self.get_yanked_invoice(&parsed_id).await?;
         inv.yanked = Some(true);
 
         debug!("Yanking invoice");
+        let change = self.begin_change().await?;
 
         // Attempt to update the index. Right now, we log an error if the index update
         // fails.
         trace!("Indexing yanked invoice");
-        if let Err(e) = self.index.index(&inv).await {
+        let indexed = self.index.index(&inv).await;
+        if let Err(e) = &indexed {
             error!(error = %e, "Error indexing yanked invoice");
         }
 
         // Open the destination or error out if it already exists.
         let dest = self.invoice_toml_path(&inv.canonical_name());
 
-        // Encode the invoice into a TOML object
-        trace!("Encoding invoice to TOML");
-        let data = toml::to_vec(&inv)?;
         // NOTE: Right now, this just force-overwites the existing invoice. We are assuming
         // that the bindle has already been confirmed to be present. However, we have not
         // ensured that here. So it is theoretically possible (if get_invoice was not used
-        // to build the invoice) that this could _create_ a new file. We could probably change
-        // this behavior with OpenOptions.
+        // to build the invoice) that this could _create_ a new file. The invoice is replaced
+        // through a part file so a crash can't leave it truncated
         debug!(path = %dest.display(), "Writing yanked invoice to disk");
-        tokio::fs::write(dest, data).await?;
+        let mut part = PartFile::replace(dest).await?;
+        part.write_invoice(&inv).await?;
+        part.finalize().await?;
+        change.finish(&self.index, indexed.is_ok()).await;
 
         // Drop the invoice from the cache (as it is unlikely that someone will want to fetch it
         // right after yanking it)
@@ -332,6 +1167,91 @@ impl<T: crate::search::Search + Send + Sync> Provider for FileProvider<T> {
         Ok(())
     }
 
+    #[instrument(level = "trace", skip(self, id), fields(id))]
+    async fn delete_invoice<I>(&self, id: I) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id: Id = id.try_into().map_err(|e| e.into())?;
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
+
+        let invoice_id = parsed_id.sha();
+        let dest = self.invoice_toml_path(&invoice_id);
+        debug!(path = %dest.display(), "Deleting invoice from disk");
+        let change = self.begin_change().await?;
+        if let Err(e) = tokio::fs::remove_file(dest).await {
+            let e = map_io_error(e);
+            // Nothing was deleted, so the index is still in sync
+            if matches!(e, ProviderError::NotFound) {
+                change.finish(&self.index, true).await;
+            }
+            return Err(e);
+        }
+        // The directory only held the invoice, so clean it up as well
+        remove_empty_dir(&self.invoice_path(&invoice_id), false).await?;
+
+        trace!("Dropping deleted invoice from cache");
+        self.invoice_cache.lock().await.pop(&parsed_id);
+
+        // Attempt to update the index. Right now, we log an error if the index update
+        // fails.
+        trace!("Removing deleted invoice from index");
+        let removed = self.index.remove(&parsed_id).await;
+        if let Err(e) = &removed {
+            error!(error = %e, "Error removing deleted invoice from index");
+        }
+        change.finish(&self.index, removed.is_ok()).await;
+        Ok(())
+    }
+
+    #[instrument(level = "trace", skip(self, bindle_id), fields(id))]
+    async fn validate_parcel<I>(&self, bindle_id: I, parcel_id: &str) -> Result<crate::Label>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id: Id = bindle_id.try_into().map_err(|e| e.into())?;
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
+        // Parcels can also be created for drafts, which are waiting on exactly that
+        let inv = match self.get_yanked_invoice(&parsed_id).await {
+            Err(ProviderError::NotFound) => self.get_draft_invoice(&parsed_id).await?,
+            res => res?,
+        };
+        parcel_label(inv, parcel_id)
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    async fn list_invoices(
+        &self,
+        include_yanked: bool,
+    ) -> Result<Box<dyn Stream<Item = Result<Id>> + Unpin + Send + Sync>> {
+        // Only the directory names are read up front. Each invoice is loaded as the stream is
+        // consumed
+        let invoice_ids = self.list_ids(INVOICE_DIRECTORY).await?;
+        debug!(total = invoice_ids.len(), "Listing invoices");
+        let inv_paths: Vec<PathBuf> = invoice_ids
+            .iter()
+            .map(|invoice_id| self.invoice_toml_path(invoice_id))
+            .collect();
+        let stream = tokio_stream::iter(inv_paths)
+            .then(|inv_path| async move {
+                let inv_toml = match tokio::fs::read(&inv_path).await {
+                    Ok(data) => data,
+                    // The invoice was either never finished or deleted after we listed it
+                    Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => return None,
+                    Err(e) => return Some(Err(e.into())),
+                };
+                Some(toml::from_slice::<crate::Invoice>(&inv_toml).map_err(ProviderError::from))
+            })
+            .filter_map(move |res| match res? {
+                Ok(inv) if !include_yanked && inv.yanked.unwrap_or(false) => None,
+                Ok(inv) => Some(Ok(inv.bindle.id)),
+                Err(e) => Some(Err(e)),
+            });
+        Ok(Box::new(Box::pin(stream)))
+    }
+
     #[instrument(level = "trace", skip(self, bindle_id, data), fields(id))]
     async fn create_parcel<I, R, B>(&self, bindle_id: I, parcel_id: &str, data: R) -> Result<()>
     where
@@ -342,7 +1262,7 @@ impl<T: crate::search::Search + Send + Sync> Provider for FileProvider<T> {
     {
         debug!("Validating bindle -> parcel relationship");
         let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
-        tracing::Span::current().record("id", &tracing::field::display(&parsed_id));
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
         let label = self.validate_parcel(parsed_id, parcel_id).await?;
 
         // Test if a dir with that SHA exists. If so, this is an error.
@@ -357,17 +1277,240 @@ impl<T: crate::search::Search + Send + Sync> Provider for FileProvider<T> {
         }
         // Create box dir
         trace!(path = %par_path.display(), "Creating parcel directory");
-        if let Err(e) = create_dir_all(par_path).await {
+        if let Err(e) = create_dirs(&par_path).await {
             error!(error = %e, "Unable to create parcel storage directory");
-            return Err(e.into());
+            return Err(e);
         }
 
         // Write data
-        let mut part = PartFile::new(self.parcel_data_path(parcel_id)).await?;
-        part.write_parcel(data, parcel_id, label.size).await?;
+        let options = self.write_options(&label);
+        let mut part = PartFile::new(self.parcel_location(parcel_id, options.encoding())).await?;
+        part.write_parcel(data, parcel_id, label.size, &options)
+            .await?;
         part.finalize().await
     }
 
+    #[instrument(level = "trace", skip(self, bindle_id), fields(id))]
+    async fn start_parcel_upload<I>(&self, bindle_id: I, parcel_id: &str) -> Result<u64>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        debug!("Validating bindle -> parcel relationship");
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
+        self.validate_parcel(parsed_id, parcel_id).await?;
+
+        if self.stored_parcel_path(parcel_id).await.is_ok() {
+            debug!("Parcel being uploaded already exists in storage");
+            return Err(ProviderError::Exists);
+        }
+
+        let _guard = self.lock_upload(parcel_id)?;
+        match self.find_upload(parcel_id).await {
+            Ok((path, encrypted)) => {
+                let offset = scan_upload(&path, encrypted).await?.received;
+                debug!(path = %path.display(), offset, "Resuming existing upload");
+                return Ok(offset);
+            }
+            Err(ProviderError::NotFound) => (),
+            Err(e) => return Err(e),
+        }
+
+        create_dirs(&self.root.join(UPLOAD_DIRECTORY)).await?;
+        // Encrypted uploads are stored as encrypted records so that no plaintext ever touches the
+        // disk. Plain uploads are left as is so they can be resumed with a different configuration
+        let (path, header) = match self.encryption.as_deref() {
+            Some(keys) => (
+                self.encrypted_upload_path(parcel_id),
+                Some(Sealer::new(keys, false)?.header().to_vec()),
+            ),
+            None => (self.upload_path(parcel_id), None),
+        };
+        let mut file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(&path)
+            .await?;
+        if let Some(header) = header {
+            file.write_all(&header).await?;
+            file.flush().await?;
+        }
+        debug!(path = %path.display(), "Started new upload");
+        Ok(0)
+    }
+
+    #[instrument(level = "trace", skip(self, bindle_id), fields(id))]
+    async fn parcel_upload_offset<I>(&self, bindle_id: I, parcel_id: &str) -> Result<u64>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        debug!("Validating bindle -> parcel relationship");
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
+        self.validate_parcel(parsed_id, parcel_id).await?;
+
+        let (path, encrypted) = self.find_upload(parcel_id).await?;
+        Ok(scan_upload(&path, encrypted).await?.received)
+    }
+
+    #[instrument(level = "trace", skip(self, bindle_id, data), fields(id))]
+    async fn write_parcel_chunk<I, R, B>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+        offset: u64,
+        data: R,
+    ) -> Result<u64>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+        R: Stream<Item = std::io::Result<B>> + Unpin + Send + Sync + 'static,
+        B: bytes::Buf + Send,
+    {
+        debug!("Validating bindle -> parcel relationship");
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
+        let label = self.validate_parcel(parsed_id, parcel_id).await?;
+
+        let _guard = self.lock_upload(parcel_id)?;
+        let (path, encrypted) = self.find_upload(parcel_id).await?;
+        let state = scan_upload(&path, encrypted).await?;
+        if state.received != offset {
+            debug!(
+                received = state.received,
+                offset, "Chunk offset does not match upload"
+            );
+            return Err(ProviderError::OffsetMismatch(state.received));
+        }
+        let mut sealer = match state.header.as_ref() {
+            Some(header) => Some(Sealer::resume(
+                self.encryption_keys()?,
+                header,
+                state.records,
+            )?),
+            None => None,
+        };
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .await
+            .map_err(map_io_error)?;
+        // Drop anything left over from a record that was only partially written
+        file.set_len(state.valid_len).await?;
+        file.seek(std::io::SeekFrom::Start(state.valid_len)).await?;
+
+        // Read at most one byte more than the parcel can hold so we can tell if too much was sent
+        let remaining = label.size.saturating_sub(offset);
+        let mut reader = StreamReader::new(data.map(|res| res.map_err(std::io::Error::other)))
+            .take(remaining + 1);
+        trace!(path = %path.display(), offset, "Appending chunk to upload");
+        let res = match sealer.as_mut() {
+            Some(sealer) => sealer.seal_to(&mut reader, &mut file, false).await,
+            None => tokio::io::copy(&mut reader, &mut file).await,
+        };
+        // Whatever made it to disk is kept, even on error, so the upload can be resumed from there
+        file.flush().await?;
+        let written = res?;
+
+        if written > remaining {
+            debug!("Chunk would exceed parcel size, discarding it");
+            file.set_len(state.valid_len).await?;
+            return Err(ProviderError::SizeMismatch);
+        }
+        trace!(bytes_written = written, "Wrote chunk to upload");
+        Ok(offset + written)
+    }
+
+    #[instrument(level = "trace", skip(self, bindle_id), fields(id))]
+    async fn finish_parcel_upload<I>(&self, bindle_id: I, parcel_id: &str) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        debug!("Validating bindle -> parcel relationship");
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
+        let label = self.validate_parcel(parsed_id, parcel_id).await?;
+
+        let _guard = self.lock_upload(parcel_id)?;
+        let (path, encrypted) = self.find_upload(parcel_id).await?;
+        let state = scan_upload(&path, encrypted).await?;
+        // Leave the upload in place so the missing data can still be sent
+        if state.received != label.size {
+            debug!(
+                received = state.received,
+                expected = label.size,
+                "Upload is incomplete"
+            );
+            return Err(ProviderError::SizeMismatch);
+        }
+        // Uploads are stored so that chunks can be appended to them, so unless the parcel is
+        // stored as is, the data is compressed and encrypted into place. This is set up before
+        // touching anything so a missing key doesn't discard the upload
+        let options = self.write_options(&label);
+        let reader = if encrypted || options.encoding() != Encoding::Plain {
+            let upload_encoding = if encrypted {
+                Encoding::Encrypted
+            } else {
+                Encoding::Plain
+            };
+            let upload_file = File::open(&path).await.map_err(map_io_error)?;
+            Some(
+                decode_reader(
+                    upload_file.take(state.valid_len),
+                    upload_encoding,
+                    self.encryption.as_deref(),
+                    false,
+                )
+                .await?,
+            )
+        } else {
+            None
+        };
+
+        let par_path = self.parcel_path(parcel_id);
+        trace!(path = %par_path.display(), "Creating parcel directory");
+        if let Err(e) = create_dirs(&par_path).await {
+            error!(error = %e, "Unable to create parcel storage directory");
+            return Err(e);
+        }
+        // If the data doesn't match the SHA, the part file is removed when dropped as the upload
+        // can never succeed
+        let mut upload = PartFile::resume(path, self.parcel_data_path(parcel_id)).await?;
+        match reader {
+            None => {
+                upload.validate(parcel_id).await?;
+                upload.finalize().await
+            }
+            Some(mut reader) => {
+                let mut part =
+                    PartFile::new(self.parcel_location(parcel_id, options.encoding())).await?;
+                part.write_data(&mut reader, &options).await?;
+                part.validate(parcel_id).await?;
+                part.finalize().await
+            }
+        }
+    }
+
+    #[instrument(level = "trace", skip(self, bindle_id), fields(id))]
+    async fn abort_parcel_upload<I>(&self, bindle_id: I, parcel_id: &str) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        debug!("Validating bindle -> parcel relationship");
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
+        self.validate_parcel(parsed_id, parcel_id).await?;
+
+        let _guard = self.lock_upload(parcel_id)?;
+        let (path, _) = self.find_upload(parcel_id).await?;
+        debug!(path = %path.display(), "Removing upload");
+        tokio::fs::remove_file(path).await.map_err(map_io_error)
+    }
+
     #[instrument(level = "trace", skip(self, bindle_id), fields(id))]
     async fn get_parcel<I>(
         &self,
@@ -380,12 +1523,42 @@ impl<T: crate::search::Search + Send + Sync> Provider for FileProvider<T> {
     {
         debug!("Validating bindle -> parcel relationship");
         let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
-        tracing::Span::current().record("id", &tracing::field::display(&parsed_id));
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
+        self.validate_parcel(parsed_id, parcel_id).await?;
+
+        debug!("Getting parcel from storage");
+        let reader = self.open_parcel_data(parcel_id, 0).await?;
+        Ok::<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>, _>(Box::new(
+            FramedRead::new(reader, BytesCodec::new())
+                .map(|res| res.map_err(map_io_error).map(|b| b.freeze())),
+        ))
+    }
+
+    #[instrument(level = "trace", skip(self, bindle_id), fields(id))]
+    async fn get_parcel_range<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+        range: Range<u64>,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        debug!("Validating bindle -> parcel relationship");
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
         self.validate_parcel(parsed_id, parcel_id).await?;
 
-        let name = self.parcel_data_path(parcel_id);
-        debug!(path = %name.display(), "Getting parcel from storage");
-        let reader = File::open(name).await.map_err(map_io_error)?;
+        debug!(
+            start = range.start,
+            end = range.end,
+            "Getting parcel range from storage"
+        );
+        let reader = self
+            .open_parcel_data(parcel_id, range.start)
+            .await?
+            .take(range.end.saturating_sub(range.start));
         Ok::<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>, _>(Box::new(
             FramedRead::new(reader, BytesCodec::new())
                 .map(|res| res.map_err(map_io_error).map(|b| b.freeze())),
@@ -400,19 +1573,155 @@ impl<T: crate::search::Search + Send + Sync> Provider for FileProvider<T> {
     {
         debug!("Validating bindle -> parcel relationship");
         let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
-        tracing::Span::current().record("id", &tracing::field::display(&parsed_id));
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
         self.validate_parcel(parsed_id, parcel_id).await?;
 
-        let label_path = self.parcel_data_path(parcel_id);
-        debug!(path = %label_path.display(), "Checking if parcel exists in storage");
-        match tokio::fs::metadata(label_path).await {
-            Ok(m) => Ok(m.is_file()),
-            Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => Ok(false),
-            Err(e) => Err(e.into()),
+        debug!("Checking if parcel exists in storage");
+        self.has_parcel(parcel_id).await
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    async fn has_parcel(&self, parcel_id: &str) -> Result<bool> {
+        // The ID is used to build a path, so anything that isn't a SHA can't be let through
+        if !super::is_parcel_id(parcel_id) {
+            return Ok(false);
+        }
+        match self.stored_parcel_path(parcel_id).await {
+            Ok(_) => Ok(true),
+            Err(ProviderError::NotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    #[instrument(level = "trace", skip(self, parcel_ids), fields(num_parcels = parcel_ids.len()))]
+    async fn parcels_exist(&self, parcel_ids: &[&str]) -> Result<Vec<bool>> {
+        // Each check is a few stats, so they can all be done at once
+        futures::future::join_all(parcel_ids.iter().map(|id| self.has_parcel(id)))
+            .instrument(tracing::trace_span!("lookup_parcels"))
+            .await
+            .into_iter()
+            .collect()
+    }
+}
+
+/// How parcel data is encoded on disk
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Encoding {
+    Plain,
+    Compressed,
+    /// The data is encrypted. Whether it was compressed first is stored in its header
+    Encrypted,
+}
+
+/// Describes how parcel data should be written to disk
+#[derive(Default)]
+pub(crate) struct WriteOptions {
+    pub(crate) compression: Option<Level>,
+    pub(crate) encryption: Option<Arc<EncryptionKeyFile>>,
+}
+
+impl WriteOptions {
+    pub(crate) fn encoding(&self) -> Encoding {
+        match (&self.encryption, self.compression) {
+            (Some(_), _) => Encoding::Encrypted,
+            (None, Some(_)) => Encoding::Compressed,
+            (None, None) => Encoding::Plain,
+        }
+    }
+}
+
+/// The state of a resumable upload as found on disk
+struct UploadState {
+    /// The number of plaintext bytes received so far
+    received: u64,
+    /// The length of the part file that holds complete data
+    valid_len: u64,
+    /// The number of complete encrypted records
+    records: u64,
+    /// The header of the upload if it is encrypted
+    header: Option<encryption::Header>,
+}
+
+/// Reads the state of the resumable upload stored at the given path
+async fn scan_upload(path: &Path, encrypted: bool) -> Result<UploadState> {
+    let mut file = File::open(path).await.map_err(map_io_error)?;
+    if !encrypted {
+        let len = file.metadata().await?.len();
+        return Ok(UploadState {
+            received: len,
+            valid_len: len,
+            records: 0,
+            header: None,
+        });
+    }
+    let scan = encryption::scan_records(&mut file).await?;
+    Ok(UploadState {
+        received: scan.plaintext_len,
+        valid_len: scan.valid_len,
+        records: scan.records,
+        header: Some(scan.header),
+    })
+}
+
+#[async_trait::async_trait]
+impl<T: Search + Send + Sync> Evictable for FileProvider<T> {
+    #[instrument(level = "trace", skip(self))]
+    async fn evict_parcel(&self, parcel_id: &str) -> Result<()> {
+        let data_paths = [
+            self.parcel_data_path(parcel_id),
+            self.parcel_compressed_path(parcel_id),
+            self.parcel_encrypted_path(parcel_id),
+        ];
+        for path in data_paths.iter() {
+            if tokio::fs::metadata(part_path(path)).await.is_ok() {
+                return Err(ProviderError::WriteInProgress);
+            }
         }
+        debug!(%parcel_id, "Evicting parcel");
+        tokio::fs::remove_dir_all(self.parcel_path(parcel_id))
+            .await
+            .map_err(map_io_error)
     }
 }
 
+/// Returns a reader over the plaintext of data stored with the given encoding. If `require_final`
+/// is false, encrypted data may end early (as is the case for uploads)
+pub(crate) async fn decode_reader<'a, R>(
+    mut reader: R,
+    encoding: Encoding,
+    keys: Option<&EncryptionKeyFile>,
+    require_final: bool,
+) -> Result<Box<dyn AsyncRead + Unpin + Send + 'a>>
+where
+    R: AsyncRead + Unpin + Send + 'a,
+{
+    Ok(match encoding {
+        Encoding::Plain => Box::new(reader),
+        Encoding::Compressed => Box::new(ZstdDecoder::new(BufReader::new(reader))),
+        Encoding::Encrypted => {
+            let keys = keys.ok_or_else(missing_keys_error)?;
+            let header = encryption::read_header(&mut reader).await?;
+            let opener = Opener::new(keys, &header, require_final)?;
+            let plaintext = StreamReader::new(Box::pin(opener.into_stream(reader)));
+            if header.compressed {
+                let mut decoder = ZstdDecoder::new(BufReader::new(plaintext));
+                // Keep reading after the end of the compressed data so the final record is
+                // always checked
+                decoder.multiple_members(true);
+                Box::new(decoder)
+            } else {
+                Box::new(plaintext)
+            }
+        }
+    })
+}
+
+fn missing_keys_error() -> ProviderError {
+    ProviderError::Other(
+        "Parcel data is encrypted, but no encryption keys are configured".to_owned(),
+    )
+}
+
 fn map_io_error(e: std::io::Error) -> ProviderError {
     if matches!(e.kind(), std::io::ErrorKind::NotFound) {
         return ProviderError::NotFound;
@@ -420,6 +1729,144 @@ fn map_io_error(e: std::io::Error) -> ProviderError {
     ProviderError::from(e)
 }
 
+/// Reads the generation of the store in the given root directory. A store without a generation
+/// file (including one that doesn't exist yet) is at generation 0
+async fn read_generation(root: &Path) -> Result<u64> {
+    match tokio::fs::read_to_string(root.join(GENERATION_FILE)).await {
+        Ok(raw) => raw.trim().parse().map_err(|e| {
+            ProviderError::Other(format!("Unable to parse generation of the store: {}", e))
+        }),
+        Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Atomically writes the generation of the store in the given root directory. The new generation
+/// is synced to disk before returning, so it is never lost if the following change makes it
+async fn write_generation(root: &Path, generation: u64) -> Result<()> {
+    create_dir_all(root).await?;
+    let path = root.join(GENERATION_FILE);
+    let part = part_path(&path);
+    let mut file = File::create(&part).await?;
+    file.write_all(generation.to_string().as_bytes()).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&part, &path).await?;
+    sync_dir(root).await
+}
+
+/// Returns the path of the part file used while writing to the given final location
+fn part_path(final_location: &Path) -> PathBuf {
+    let extension = match final_location.extension() {
+        Some(s) => {
+            let mut ext = s.to_owned();
+            ext.push(".");
+            ext.push(PART_EXTENSION);
+            ext
+        }
+        None => OsString::from(PART_EXTENSION),
+    };
+    final_location.with_extension(extension)
+}
+
+/// Returns the names of all entries in the given directory. A nonexistent directory is treated as
+/// empty
+async fn list_dir_names(dir: &Path) -> Result<Vec<String>> {
+    let mut readdir = match tokio::fs::read_dir(dir).await {
+        Ok(r) => r,
+        Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut names = Vec::new();
+    while let Some(entry) = readdir.next_entry().await? {
+        names.push(entry.file_name().to_string_lossy().into_owned());
+    }
+    Ok(names)
+}
+
+/// Returns whether the file at the given path has not been modified within the grace period
+async fn is_stale(path: &Path, grace_period: std::time::Duration) -> Result<bool> {
+    let modified = tokio::fs::metadata(path).await?.modified()?;
+    // If the clock has gone backwards, the file is very much not stale
+    Ok(modified
+        .elapsed()
+        .map(|elapsed| elapsed >= grace_period)
+        .unwrap_or(false))
+}
+
+/// Returns the total size of all files directly within the given directory
+async fn dir_size(dir: &Path) -> Result<u64> {
+    let mut readdir = tokio::fs::read_dir(dir).await?;
+    let mut total = 0;
+    while let Some(entry) = readdir.next_entry().await? {
+        total += entry.metadata().await?.len();
+    }
+    Ok(total)
+}
+
+/// Removes the given directory if it exists and is empty. This is a no-op for dry runs
+async fn remove_empty_dir(dir: &Path, dry_run: bool) -> Result<()> {
+    if dry_run {
+        return Ok(());
+    }
+    let mut readdir = match tokio::fs::read_dir(dir).await {
+        Ok(r) => r,
+        Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if readdir.next_entry().await?.is_none() {
+        trace!(path = %dir.display(), "Removing empty directory");
+        tokio::fs::remove_dir(dir).await?;
+    }
+    Ok(())
+}
+
+/// Flushes the entries of the given directory to disk, so that files that were just created in
+/// or renamed into it survive a crash. Directories can only be synced on Unix, so this does nothing
+/// on other platforms
+pub(crate) async fn sync_dir(dir: &Path) -> Result<()> {
+    #[cfg(target_family = "unix")]
+    File::open(dir).await?.sync_all().await?;
+    #[cfg(not(target_family = "unix"))]
+    let _ = dir;
+    Ok(())
+}
+
+/// Creates the given directory along with any missing parents, like `create_dir_all`. Every
+/// directory that gained a new entry is synced, so that none of the new directories (such as the
+/// shard and entry directories of a new parcel) can be lost in a crash
+async fn create_dirs(path: &Path) -> Result<()> {
+    let mut created = Vec::new();
+    for dir in path.ancestors() {
+        // A relative path can run out of ancestors
+        if dir.as_os_str().is_empty() || tokio::fs::metadata(dir).await.is_ok() {
+            break;
+        }
+        created.push(dir);
+    }
+    create_dir_all(path).await?;
+    // Sync from the top down, so a directory is only recorded once its parent is
+    for dir in created.into_iter().rev() {
+        if let Some(parent) = dir.parent().filter(|p| !p.as_os_str().is_empty()) {
+            sync_dir(parent).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Syncs the directory containing the given path as well as the directory above it, as the
+/// directory containing the path may have just been created for it. Directories created with
+/// [`create_dirs`] are already synced, no matter how many of them there are
+async fn sync_parent_dirs(path: &Path) -> Result<()> {
+    for dir in path.ancestors().skip(1).take(2) {
+        // A relative path can run out of ancestors
+        if dir.as_os_str().is_empty() {
+            break;
+        }
+        sync_dir(dir).await?;
+    }
+    Ok(())
+}
+
 /// An internal wrapper to implement `AsyncWrite` on Sha256
 pub(crate) struct AsyncSha256 {
     inner: Mutex<Sha256>,
@@ -478,15 +1925,18 @@ impl tokio::io::AsyncWrite for AsyncSha256 {
     }
 }
 
-/// Validate that the File path matches the given SHA256
-async fn validate_sha256(file: &mut File, sha: &str) -> Result<()> {
+/// Validate that the data read from the given reader matches the given SHA256, returning the number
+/// of bytes read
+pub(crate) async fn validate_sha256<R: AsyncRead + Unpin + ?Sized>(
+    reader: &mut R,
+    sha: &str,
+) -> Result<u64> {
     let mut hasher = AsyncSha256::new();
-    tokio::io::copy(file, &mut hasher).await?;
+    let size = tokio::io::copy(reader, &mut hasher).await?;
     let hasher = match hasher.into_inner() {
         Ok(h) => h,
         Err(_) => {
-            return Err(ProviderError::Io(std::io::Error::new(
-                std::io::ErrorKind::Other,
+            return Err(ProviderError::Io(std::io::Error::other(
                 "data write corruption, mutex poisoned",
             )))
         }
@@ -497,32 +1947,62 @@ async fn validate_sha256(file: &mut File, sha: &str) -> Result<()> {
         return Err(ProviderError::DigestMismatch);
     }
 
-    Ok(())
+    Ok(size)
+}
+
+/// A reader that counts the number of bytes read through it
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CountingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.count += (buf.filled().len() - before) as u64;
+        res
+    }
 }
 
 /// A helper struct for a part file that will clean up the file on drop if it still exists. Also
 /// contains functionality for writing to the file and finalizing it (i.e moving it to the correct
 /// location)
-struct PartFile {
+pub(crate) struct PartFile {
     path: PathBuf,
     final_location: PathBuf,
     file: File,
+    // How the data written to the file was encoded
+    encoding: Encoding,
+    encryption: Option<Arc<EncryptionKeyFile>>,
 }
 
 impl PartFile {
     /// Creates a new PartFile that will eventually be located at the given `final_location`. This
     /// will attempt to create a new part file and return an error if one already exists
-    async fn new(final_location: PathBuf) -> Result<Self> {
-        let extension = match final_location.extension() {
-            Some(s) => {
-                let mut ext = s.to_owned();
-                ext.push(".");
-                ext.push(PART_EXTENSION);
-                ext
-            }
-            None => OsString::from(PART_EXTENSION),
-        };
-        let part = final_location.with_extension(extension);
+    pub(crate) async fn new(final_location: PathBuf) -> Result<Self> {
+        let part_file = PartFile::create(final_location).await?;
+        // Another write could have finished between the caller checking the final location and
+        // us creating the part file, so check again now that we hold the part file. Dropping the
+        // part file on return cleans it up
+        if tokio::fs::metadata(&part_file.final_location).await.is_ok() {
+            return Err(ProviderError::Exists);
+        }
+        Ok(part_file)
+    }
+
+    /// Creates a new PartFile that will replace the file at the given `final_location` once it is
+    /// finalized. The file is replaced atomically, so it is never seen partially written
+    async fn replace(final_location: PathBuf) -> Result<Self> {
+        PartFile::create(final_location).await
+    }
+
+    async fn create(final_location: PathBuf) -> Result<Self> {
+        let part = part_path(&final_location);
         trace!(path = %part.display(), "Checking that a write is not currently in progress");
         // Make sure we aren't already writing
         if tokio::fs::metadata(&part)
@@ -565,6 +2045,27 @@ impl PartFile {
             path: part,
             final_location,
             file,
+            encoding: Encoding::Plain,
+            encryption: None,
+        })
+    }
+
+    /// Opens an existing part file at the given path (such as the data of a resumable upload) that
+    /// will eventually be located at the given `final_location`
+    async fn resume(path: PathBuf, final_location: PathBuf) -> Result<Self> {
+        trace!(path = %path.display(), "Opening existing part file");
+        let file = OpenOptions::new()
+            .write(true)
+            .read(true)
+            .open(&path)
+            .await
+            .map_err(map_io_error)?;
+        Ok(PartFile {
+            path,
+            final_location,
+            file,
+            encoding: Encoding::Plain,
+            encryption: None,
         })
     }
 
@@ -583,11 +2084,12 @@ impl PartFile {
             .map_err(|e| e.into())
     }
 
-    async fn write_parcel<R, B>(
+    pub(crate) async fn write_parcel<R, B>(
         &mut self,
         data: R,
         parcel_id: &str,
         expected_length: u64,
+        options: &WriteOptions,
     ) -> Result<()>
     where
         R: Stream<Item = std::io::Result<B>> + Unpin + Send + Sync + 'static,
@@ -599,47 +2101,115 @@ impl PartFile {
             parcel_id,
             "Storing parcel data in part file"
         );
-        trace!("Copying data to open file");
-        let written = tokio::io::copy(
-            &mut StreamReader::new(
-                data.map(|res| res.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))),
-            ),
-            &mut self.file,
-        )
-        .instrument(tracing::trace_span!("parcel_data_write"))
-        .await?;
+        let written = self
+            .write_data(
+                &mut StreamReader::new(data.map(|res| res.map_err(std::io::Error::other))),
+                options,
+            )
+            .await?;
 
         // Make sure the right amount of data was sent
         trace!(bytes_written = written, "Wrote data to part file");
         if written != expected_length {
             return Err(ProviderError::SizeMismatch);
         }
+        self.validate(parcel_id).await
+    }
+
+    /// Copies all data from the given reader into the part file, compressing and encrypting it as
+    /// set in the options. Returns the number of (plaintext) bytes copied
+    async fn write_data<R: AsyncRead + Unpin + ?Sized>(
+        &mut self,
+        reader: &mut R,
+        options: &WriteOptions,
+    ) -> Result<u64> {
+        self.encoding = options.encoding();
+        self.encryption = options.encryption.clone();
+        trace!(encoding = ?self.encoding, "Copying data to open file");
+        let written = match (options.encryption.as_deref(), options.compression) {
+            (None, None) => {
+                tokio::io::copy(reader, &mut self.file)
+                    .instrument(tracing::trace_span!("parcel_data_write"))
+                    .await?
+            }
+            (None, Some(level)) => {
+                let mut encoder = ZstdEncoder::with_quality(&mut self.file, level);
+                let written = tokio::io::copy(reader, &mut encoder)
+                    .instrument(tracing::trace_span!("parcel_data_write"))
+                    .await?;
+                // Shutting down the encoder writes out the end of the compressed data
+                encoder.shutdown().await?;
+                written
+            }
+            (Some(keys), compression) => {
+                let mut sealer = Sealer::new(keys, compression.is_some())?;
+                self.file.write_all(sealer.header()).await?;
+                // Data is compressed before it is encrypted, as encrypted data doesn't compress
+                let mut counter = CountingReader {
+                    inner: reader,
+                    count: 0,
+                };
+                match compression {
+                    None => {
+                        sealer
+                            .seal_to(&mut counter, &mut self.file, true)
+                            .instrument(tracing::trace_span!("parcel_data_write"))
+                            .await?;
+                    }
+                    Some(level) => {
+                        let mut encoder =
+                            ZstdReadEncoder::with_quality(BufReader::new(&mut counter), level);
+                        sealer
+                            .seal_to(&mut encoder, &mut self.file, true)
+                            .instrument(tracing::trace_span!("parcel_data_write"))
+                            .await?;
+                    }
+                }
+                counter.count
+            }
+        };
+        Ok(written)
+    }
+
+    /// Validates that the data in the part file matches the given parcel SHA
+    async fn validate(&mut self, parcel_id: &str) -> Result<()> {
         // Verify parcel by rewinding the parcel and then hashing it.
         // This MUST be after the last write to out, otherwise the results will
         // not be correct.
         self.file.flush().await?;
         self.file.seek(std::io::SeekFrom::Start(0)).await?;
         trace!("Validating data for parcel");
-        validate_sha256(&mut self.file, parcel_id)
+        let mut reader = decode_reader(
+            &mut self.file,
+            self.encoding,
+            self.encryption.as_deref(),
+            true,
+        )
+        .await?;
+        validate_sha256(&mut reader, parcel_id)
             .instrument(tracing::trace_span!("parcel_data_validation"))
             .await?;
         trace!("SHA data validated");
         Ok(())
     }
 
-    /// Moves the file to the configured final location, consuming the part file
-    async fn finalize(mut self) -> Result<()> {
+    /// Moves the file to the configured final location, consuming the part file. Once this
+    /// returns, the file survives a crash
+    pub(crate) async fn finalize(mut self) -> Result<()> {
         debug!(
             renamed_path = %self.final_location.display(),
             "Renaming part file for parcel"
         );
 
+        // All data MUST be on disk before the rename. Otherwise a crash could leave an empty or
+        // truncated file at the final location
+        self.file.flush().await?;
+        self.file.sync_all().await?;
         // Close the file handle to avoid any problems with unfinished IO operations
         self.file.shutdown().await?;
 
-        tokio::fs::rename(&self.path, &self.final_location)
-            .await
-            .map_err(|e| e.into())
+        tokio::fs::rename(&self.path, &self.final_location).await?;
+        sync_parent_dirs(&self.final_location).await
     }
 }
 
@@ -655,6 +2225,21 @@ impl Drop for PartFile {
     }
 }
 
+/// A guard that marks a resumable upload as busy while a request is operating on it
+struct UploadGuard {
+    active_uploads: Arc<Mutex<HashSet<String>>>,
+    parcel_id: String,
+}
+
+impl Drop for UploadGuard {
+    fn drop(&mut self) {
+        self.active_uploads
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.parcel_id);
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -711,6 +2296,92 @@ mod test {
         assert!(store.get_invoice(scaffold.invoice.bindle.id).await.is_err());
     }
 
+    #[tokio::test]
+    async fn test_should_delete_invoice() {
+        let root = tempdir().unwrap();
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let index = crate::search::StrictEngine::default();
+        let store = FileProvider::new(root.path(), index.clone()).await;
+        let inv_name = scaffold.invoice.canonical_name();
+
+        let signed = NoopSigned(NoopVerified(scaffold.invoice.clone()));
+        store.create_invoice(signed).await.unwrap();
+        // Make sure the invoice is cached before deleting
+        store
+            .get_invoice(&scaffold.invoice.bindle.id)
+            .await
+            .unwrap();
+
+        store
+            .delete_invoice(&scaffold.invoice.bindle.id)
+            .await
+            .expect("Should be able to delete invoice");
+
+        assert!(!store.invoice_path(&inv_name).exists());
+        assert!(matches!(
+            store.get_yanked_invoice(&scaffold.invoice.bindle.id).await,
+            Err(ProviderError::NotFound)
+        ));
+        let matches = index
+            .query(
+                scaffold.invoice.bindle.id.name(),
+                "",
+                crate::search::SearchOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(0, matches.total, "Invoice should be removed from the index");
+
+        // Deleting something that doesn't exist should be a not found error
+        assert!(matches!(
+            store.delete_invoice(&scaffold.invoice.bindle.id).await,
+            Err(ProviderError::NotFound)
+        ));
+
+        // And the invoice can be recreated once deleted
+        let signed = NoopSigned(NoopVerified(scaffold.invoice.clone()));
+        store
+            .create_invoice(signed)
+            .await
+            .expect("Should be able to recreate a deleted invoice");
+    }
+
+    #[tokio::test]
+    async fn test_should_list_invoices() {
+        let root = tempdir().unwrap();
+        let store = FileProvider::new(root.path(), crate::search::StrictEngine::default()).await;
+        let v1 = testing::Scaffold::load("valid_v1").await;
+        let v2 = testing::Scaffold::load("valid_v2").await;
+        for inv in [&v1.invoice, &v2.invoice] {
+            store
+                .create_invoice(NoopSigned(NoopVerified(inv.clone())))
+                .await
+                .expect("Should be able to create invoice");
+        }
+        store.yank_invoice(&v1.invoice.bindle.id).await.unwrap();
+        // An unfinished invoice directory should be skipped
+        std::fs::create_dir_all(store.invoice_path("unfinished")).unwrap();
+
+        let ids: Vec<Id> = store
+            .list_invoices(false)
+            .await
+            .expect("Should be able to list invoices")
+            .collect::<Result<_>>()
+            .await
+            .expect("All invoices should be readable");
+        assert_eq!(vec![v2.invoice.bindle.id.clone()], ids);
+
+        let mut ids: Vec<Id> = store
+            .list_invoices(true)
+            .await
+            .expect("Should be able to list invoices")
+            .collect::<Result<_>>()
+            .await
+            .expect("All invoices should be readable");
+        ids.sort_by_key(|id| id.to_string());
+        assert_eq!(vec![v1.invoice.bindle.id, v2.invoice.bindle.id], ids);
+    }
+
     #[tokio::test]
     async fn test_should_reject_yanked_invoice() {
         // Create a temporary directory
@@ -761,9 +2432,7 @@ mod test {
             .get_parcel(&scaffold.invoice.bindle.id, &parcel.sha)
             .await
             .expect("load parcel data");
-        let mut reader = StreamReader::new(
-            stream.map(|res| res.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))),
-        );
+        let mut reader = StreamReader::new(stream.map(|res| res.map_err(std::io::Error::other)));
         reader
             .read_to_end(&mut data)
             .await
@@ -771,6 +2440,334 @@ mod test {
         assert_eq!(data, parcel.data);
     }
 
+    #[tokio::test]
+    async fn test_should_store_compressed_parcels() {
+        let scaffold = testing::Scaffold::load("valid_v2").await;
+        let parcel = scaffold.parcel_files.get("parcel").unwrap();
+        let uploaded = scaffold.parcel_files.get("other").unwrap();
+        let id = &scaffold.invoice.bindle.id;
+        let root = tempdir().expect("create tempdir");
+        let store = FileProvider::new(root.path(), crate::search::StrictEngine::default())
+            .await
+            .with_compression(CompressionPolicy {
+                min_size: 0,
+                media_types: vec!["text/*".to_owned()],
+                ..Default::default()
+            });
+
+        store
+            .create_invoice(NoopSigned(NoopVerified(scaffold.invoice.clone())))
+            .await
+            .expect("should be able to create invoice");
+        store
+            .create_parcel(
+                id,
+                &parcel.sha,
+                FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+            )
+            .await
+            .expect("create parcel");
+        // Parcels finished through a resumable upload should be compressed as well
+        store
+            .start_parcel_upload(id, &uploaded.sha)
+            .await
+            .expect("start upload");
+        store
+            .write_parcel_chunk(
+                id,
+                &uploaded.sha,
+                0,
+                FramedRead::new(
+                    std::io::Cursor::new(uploaded.data.clone()),
+                    BytesCodec::new(),
+                ),
+            )
+            .await
+            .expect("write chunk");
+        store
+            .finish_parcel_upload(id, &uploaded.sha)
+            .await
+            .expect("finish upload");
+
+        for p in [parcel, uploaded] {
+            assert!(store.parcel_compressed_path(&p.sha).exists());
+            assert!(!store.parcel_data_path(&p.sha).exists());
+            assert!(store
+                .parcel_exists(id, &p.sha)
+                .await
+                .expect("Shouldn't get an error while checking for parcel existence"));
+        }
+
+        // A store without compression enabled should still be able to read compressed parcels
+        let uncompressed =
+            FileProvider::new(root.path(), crate::search::StrictEngine::default()).await;
+        for store in [&store, &uncompressed] {
+            let mut data = Vec::new();
+            let stream = store
+                .get_parcel(id, &parcel.sha)
+                .await
+                .expect("load parcel");
+            StreamReader::new(stream.map(|res| res.map_err(std::io::Error::other)))
+                .read_to_end(&mut data)
+                .await
+                .expect("read parcel");
+            assert_eq!(data, parcel.data);
+
+            let mut data = Vec::new();
+            let stream = store
+                .get_parcel_range(id, &parcel.sha, 2..5)
+                .await
+                .expect("load parcel range");
+            StreamReader::new(stream.map(|res| res.map_err(std::io::Error::other)))
+                .read_to_end(&mut data)
+                .await
+                .expect("read parcel range");
+            assert_eq!(data, parcel.data[2..5]);
+        }
+
+        let report = store
+            .fsck(FsckOptions::default())
+            .await
+            .expect("fsck should succeed");
+        assert!(report.is_clean(), "Report should be clean: {:?}", report);
+
+        std::fs::write(store.parcel_compressed_path(&parcel.sha), b"not zstd").unwrap();
+        let report = store
+            .fsck(FsckOptions::default())
+            .await
+            .expect("fsck should succeed");
+        assert_eq!(report.corrupt_parcels, vec![parcel.sha.clone()]);
+    }
+
+    #[tokio::test]
+    async fn test_should_store_encrypted_parcels() {
+        let scaffold = testing::Scaffold::load("valid_v2").await;
+        let parcel = scaffold.parcel_files.get("parcel").unwrap();
+        let uploaded = scaffold.parcel_files.get("other").unwrap();
+        let id = &scaffold.invoice.bindle.id;
+        let root = tempdir().expect("create tempdir");
+        let mut keys = EncryptionKeyFile::default();
+        keys.rotate("first").unwrap();
+        let store = FileProvider::new(root.path(), crate::search::StrictEngine::default())
+            .await
+            .with_compression(CompressionPolicy {
+                min_size: 0,
+                ..Default::default()
+            })
+            .with_encryption(keys.clone());
+
+        store
+            .create_invoice(NoopSigned(NoopVerified(scaffold.invoice.clone())))
+            .await
+            .expect("should be able to create invoice");
+        store
+            .create_parcel(
+                id,
+                &parcel.sha,
+                FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+            )
+            .await
+            .expect("create parcel");
+        // Resumable uploads are encrypted while in progress and should still track their offset
+        store
+            .start_parcel_upload(id, &uploaded.sha)
+            .await
+            .expect("start upload");
+        for (offset, chunk) in [(0, &uploaded.data[..2]), (2, &uploaded.data[2..])] {
+            store
+                .write_parcel_chunk(
+                    id,
+                    &uploaded.sha,
+                    offset,
+                    tokio_stream::once(Ok::<_, std::io::Error>(bytes::Bytes::from(chunk.to_vec()))),
+                )
+                .await
+                .expect("write chunk");
+        }
+        assert!(store.encrypted_upload_path(&uploaded.sha).exists());
+        assert_eq!(
+            store
+                .parcel_upload_offset(id, &uploaded.sha)
+                .await
+                .expect("get offset"),
+            uploaded.data.len() as u64
+        );
+        store
+            .finish_parcel_upload(id, &uploaded.sha)
+            .await
+            .expect("finish upload");
+
+        for p in [parcel, uploaded] {
+            assert!(store.parcel_encrypted_path(&p.sha).exists());
+            assert!(!store.parcel_data_path(&p.sha).exists());
+            assert!(!store.parcel_compressed_path(&p.sha).exists());
+        }
+
+        // Rotating the keys should still allow reading parcels encrypted with the old key
+        keys.rotate("second").unwrap();
+        let rotated = FileProvider::new(root.path(), crate::search::StrictEngine::default())
+            .await
+            .with_encryption(keys);
+        for store in [&store, &rotated] {
+            let mut data = Vec::new();
+            let stream = store
+                .get_parcel(id, &parcel.sha)
+                .await
+                .expect("load parcel");
+            StreamReader::new(stream.map(|res| res.map_err(std::io::Error::other)))
+                .read_to_end(&mut data)
+                .await
+                .expect("read parcel");
+            assert_eq!(data, parcel.data);
+
+            let mut data = Vec::new();
+            let stream = store
+                .get_parcel_range(id, &uploaded.sha, 1..3)
+                .await
+                .expect("load parcel range");
+            StreamReader::new(stream.map(|res| res.map_err(std::io::Error::other)))
+                .read_to_end(&mut data)
+                .await
+                .expect("read parcel range");
+            assert_eq!(data, uploaded.data[1..3]);
+        }
+
+        // Without the keys, the parcels can neither be read nor checked
+        let unencrypted =
+            FileProvider::new(root.path(), crate::search::StrictEngine::default()).await;
+        assert!(unencrypted.get_parcel(id, &parcel.sha).await.is_err());
+        assert!(unencrypted.fsck(FsckOptions::default()).await.is_err());
+
+        let report = rotated
+            .fsck(FsckOptions::default())
+            .await
+            .expect("fsck should succeed");
+        assert!(report.is_clean(), "Report should be clean: {:?}", report);
+
+        let path = store.parcel_encrypted_path(&parcel.sha);
+        let mut raw = std::fs::read(&path).unwrap();
+        let last = raw.len() - 1;
+        raw[last] ^= 1;
+        std::fs::write(&path, raw).unwrap();
+        let report = rotated
+            .fsck(FsckOptions::default())
+            .await
+            .expect("fsck should succeed");
+        assert_eq!(report.corrupt_parcels, vec![parcel.sha.clone()]);
+    }
+
+    #[tokio::test]
+    async fn test_should_resume_parcel_upload() {
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let parcel = scaffold.parcel_files.get("parcel").unwrap();
+        let id = &scaffold.invoice.bindle.id;
+        let root = tempdir().expect("create tempdir");
+        let store = FileProvider::new(root.path(), crate::search::StrictEngine::default()).await;
+        let chunk =
+            |data: &[u8]| FramedRead::new(std::io::Cursor::new(data.to_vec()), BytesCodec::new());
+
+        store
+            .create_invoice(NoopSigned(NoopVerified(scaffold.invoice.clone())))
+            .await
+            .expect("should be able to create invoice");
+
+        // Data that doesn't match the SHA should be thrown away when finishing
+        assert_eq!(0, store.start_parcel_upload(id, &parcel.sha).await.unwrap());
+        let bad_data = vec![0; parcel.data.len()];
+        store
+            .write_parcel_chunk(id, &parcel.sha, 0, chunk(&bad_data))
+            .await
+            .expect("should be able to write chunk");
+        assert!(matches!(
+            store.finish_parcel_upload(id, &parcel.sha).await,
+            Err(ProviderError::DigestMismatch)
+        ));
+        assert!(matches!(
+            store.parcel_upload_offset(id, &parcel.sha).await,
+            Err(ProviderError::NotFound)
+        ));
+
+        // An aborted upload should start back at the beginning
+        store.start_parcel_upload(id, &parcel.sha).await.unwrap();
+        store
+            .write_parcel_chunk(id, &parcel.sha, 0, chunk(&parcel.data[..2]))
+            .await
+            .unwrap();
+        store
+            .abort_parcel_upload(id, &parcel.sha)
+            .await
+            .expect("should be able to abort upload");
+        assert_eq!(0, store.start_parcel_upload(id, &parcel.sha).await.unwrap());
+
+        // Write part of the parcel and make sure the upload can be picked back up
+        let split = 4;
+        assert_eq!(
+            split as u64,
+            store
+                .write_parcel_chunk(id, &parcel.sha, 0, chunk(&parcel.data[..split]))
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            split as u64,
+            store.start_parcel_upload(id, &parcel.sha).await.unwrap()
+        );
+        assert_eq!(
+            split as u64,
+            store.parcel_upload_offset(id, &parcel.sha).await.unwrap()
+        );
+        assert!(matches!(
+            store
+                .write_parcel_chunk(id, &parcel.sha, 0, chunk(&parcel.data))
+                .await,
+            Err(ProviderError::OffsetMismatch(4))
+        ));
+        assert!(
+            matches!(
+                store.finish_parcel_upload(id, &parcel.sha).await,
+                Err(ProviderError::SizeMismatch)
+            ),
+            "An incomplete upload should not be finished"
+        );
+
+        // Sending more data than the parcel holds should be rejected without losing the upload
+        let mut too_much = parcel.data[split..].to_vec();
+        too_much.push(0);
+        assert!(matches!(
+            store
+                .write_parcel_chunk(id, &parcel.sha, split as u64, chunk(&too_much))
+                .await,
+            Err(ProviderError::SizeMismatch)
+        ));
+        assert_eq!(
+            parcel.data.len() as u64,
+            store
+                .write_parcel_chunk(id, &parcel.sha, split as u64, chunk(&parcel.data[split..]))
+                .await
+                .unwrap()
+        );
+        store
+            .finish_parcel_upload(id, &parcel.sha)
+            .await
+            .expect("should be able to finish upload");
+
+        let mut data = Vec::new();
+        let stream = store
+            .get_parcel(id, &parcel.sha)
+            .await
+            .expect("load parcel data");
+        StreamReader::new(stream.map(|res| res.map_err(std::io::Error::other)))
+            .read_to_end(&mut data)
+            .await
+            .expect("read parcel data");
+        assert_eq!(data, parcel.data);
+
+        assert!(matches!(
+            store.start_parcel_upload(id, &parcel.sha).await,
+            Err(ProviderError::Exists)
+        ));
+    }
+
     #[tokio::test]
     async fn test_should_store_and_retrieve_bindle() {
         let root = tempdir().expect("create tempdir");
@@ -842,6 +2839,452 @@ mod test {
         )
     }
 
+    #[tokio::test]
+    async fn test_should_fsck() {
+        let root = tempdir().unwrap();
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let parcel = scaffold.parcel_files.get("parcel").unwrap();
+        let store = FileProvider::new(root.path(), crate::search::StrictEngine::default()).await;
+
+        let signed = NoopSigned(NoopVerified(scaffold.invoice.clone()));
+        store.create_invoice(signed).await.unwrap();
+        store
+            .create_parcel(
+                &scaffold.invoice.bindle.id,
+                &parcel.sha,
+                FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+            )
+            .await
+            .expect("create parcel");
+
+        let report = store.fsck(FsckOptions::default()).await.unwrap();
+        assert!(report.is_clean(), "Report: {:?}", report);
+        assert_eq!(1, report.checked_invoices);
+        assert_eq!(1, report.checked_parcels);
+        assert!(report.missing_parcels.is_empty());
+
+        // Corrupt the parcel, keeping the same size
+        let mut corrupted = parcel.data.clone();
+        corrupted[0] = corrupted[0].wrapping_add(1);
+        std::fs::write(store.parcel_data_path(&parcel.sha), corrupted).unwrap();
+        // Store a copy of the invoice under the wrong name and an invoice that can't be parsed
+        std::fs::create_dir_all(store.invoice_path("misnamed")).unwrap();
+        std::fs::copy(
+            store.invoice_toml_path(&scaffold.invoice.canonical_name()),
+            store.invoice_toml_path("misnamed"),
+        )
+        .unwrap();
+        std::fs::create_dir_all(store.invoice_path("unreadable")).unwrap();
+        std::fs::write(store.invoice_toml_path("unreadable"), b"not an invoice").unwrap();
+
+        let report = store.fsck(FsckOptions::default()).await.unwrap();
+        assert!(!report.is_clean());
+        assert_eq!(vec![parcel.sha.clone()], report.corrupt_parcels);
+        assert_eq!(vec!["misnamed".to_owned()], report.misnamed_invoices);
+        assert_eq!(vec!["unreadable".to_owned()], report.unreadable_invoices);
+        assert!(
+            store.parcel_data_path(&parcel.sha).exists(),
+            "Nothing should be moved without quarantine enabled"
+        );
+
+        let report = store.fsck(FsckOptions { quarantine: true }).await.unwrap();
+        assert!(!report.is_clean());
+        assert!(!store.parcel_path(&parcel.sha).exists());
+        assert!(!store.invoice_path("misnamed").exists());
+        assert!(!store.invoice_path("unreadable").exists());
+        assert_eq!(
+            3,
+            list_dir_names(&root.path().join(QUARANTINE_NAME).join(INVOICE_DIRECTORY))
+                .await
+                .unwrap()
+                .len()
+                + list_dir_names(&root.path().join(QUARANTINE_NAME).join(PARCEL_DIRECTORY))
+                    .await
+                    .unwrap()
+                    .len()
+        );
+
+        // Everything left should be clean, with the quarantined parcel now missing
+        let report = store.fsck(FsckOptions::default()).await.unwrap();
+        assert!(report.is_clean(), "Report: {:?}", report);
+        assert_eq!(
+            vec![(scaffold.invoice.bindle.id.clone(), parcel.sha.clone())],
+            report.missing_parcels
+        );
+
+        // The quarantined parcel can be uploaded again
+        store
+            .create_parcel(
+                &scaffold.invoice.bindle.id,
+                &parcel.sha,
+                FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+            )
+            .await
+            .expect("Should be able to upload a quarantined parcel again");
+    }
+
+    #[tokio::test]
+    async fn test_should_recover_from_interrupted_writes() {
+        let root = tempdir().unwrap();
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let parcel = scaffold.parcel_files.get("parcel").unwrap();
+        let store = FileProvider::new(root.path(), crate::search::StrictEngine::default()).await;
+
+        let signed = NoopSigned(NoopVerified(scaffold.invoice.clone()));
+        store.create_invoice(signed).await.unwrap();
+        store
+            .create_parcel(
+                &scaffold.invoice.bindle.id,
+                &parcel.sha,
+                FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+            )
+            .await
+            .expect("create parcel");
+
+        // Leave behind what a power loss can: empty files that were never synced and part files
+        // of writes that never finished
+        std::fs::create_dir_all(store.invoice_path("empty")).unwrap();
+        std::fs::write(store.invoice_toml_path("empty"), b"").unwrap();
+        std::fs::create_dir_all(store.parcel_path("abc123")).unwrap();
+        std::fs::write(store.parcel_data_path("abc123"), b"").unwrap();
+        // An empty parcel is supposed to be stored in an empty file
+        std::fs::create_dir_all(store.parcel_path(EMPTY_PARCEL_SHA)).unwrap();
+        std::fs::write(store.parcel_data_path(EMPTY_PARCEL_SHA), b"").unwrap();
+        let stale = part_path(&store.parcel_data_path("def456"));
+        std::fs::create_dir_all(store.parcel_path("def456")).unwrap();
+        std::fs::write(&stale, b"partial")
+            .and_then(|_| std::fs::File::options().write(true).open(&stale))
+            .and_then(|f| f.set_modified(std::time::SystemTime::now() - DEFAULT_GRACE_PERIOD * 2))
+            .unwrap();
+        let fresh = part_path(&store.parcel_data_path("fed654"));
+        std::fs::create_dir_all(store.parcel_path("fed654")).unwrap();
+        std::fs::write(&fresh, b"partial").unwrap();
+        // Unreadable invoices shouldn't stop the index from being warmed
+        std::fs::create_dir_all(store.invoice_path("garbage")).unwrap();
+        std::fs::write(store.invoice_toml_path("garbage"), b"not an invoice").unwrap();
+
+        let store = FileProvider::new(root.path(), crate::search::StrictEngine::default()).await;
+        assert!(
+            store.invoice_path("empty").exists(),
+            "Creating a provider shouldn't recover on its own"
+        );
+        store.recover().await.expect("Should be able to recover");
+        assert!(!store.invoice_path("empty").exists());
+        assert!(!store.parcel_path("abc123").exists());
+        assert_eq!(
+            2,
+            list_dir_names(&root.path().join(QUARANTINE_NAME).join(INVOICE_DIRECTORY))
+                .await
+                .unwrap()
+                .len()
+                + list_dir_names(&root.path().join(QUARANTINE_NAME).join(PARCEL_DIRECTORY))
+                    .await
+                    .unwrap()
+                    .len()
+        );
+        assert!(store.parcel_data_path(EMPTY_PARCEL_SHA).exists());
+        assert!(!stale.exists(), "Stale part file should be removed");
+        assert!(
+            fresh.exists(),
+            "Part file that could still be written to should be kept"
+        );
+
+        store
+            .warm_index()
+            .await
+            .expect("Corrupt invoices should be skipped when warming the index");
+        store
+            .get_invoice(&scaffold.invoice.bindle.id)
+            .await
+            .expect("Intact invoice should still be readable");
+        assert!(store.parcel_data_path(&parcel.sha).exists());
+    }
+
+    #[tokio::test]
+    async fn test_should_gc_orphaned_parcels() {
+        let root = tempdir().unwrap();
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let parcel = scaffold.parcel_files.get("parcel").unwrap();
+        let store = FileProvider::new(root.path(), crate::search::StrictEngine::default()).await;
+
+        let signed = NoopSigned(NoopVerified(scaffold.invoice.clone()));
+        store.create_invoice(signed).await.unwrap();
+        store
+            .create_parcel(
+                &scaffold.invoice.bindle.id,
+                &parcel.sha,
+                FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+            )
+            .await
+            .expect("create parcel");
+
+        // Write an orphaned parcel and an abandoned upload out of band
+        let orphan = "abc123";
+        std::fs::create_dir_all(store.parcel_path(orphan)).unwrap();
+        std::fs::write(store.parcel_data_path(orphan), b"orphaned").unwrap();
+        let abandoned = "def456";
+        std::fs::create_dir_all(store.parcel_path(abandoned)).unwrap();
+        let abandoned_part = part_path(&store.parcel_data_path(abandoned));
+        std::fs::write(&abandoned_part, b"partial").unwrap();
+
+        // With the default grace period, the abandoned upload is considered in progress
+        let report = store
+            .gc(GcOptions {
+                dry_run: true,
+                ..Default::default()
+            })
+            .await
+            .expect("dry run should succeed");
+        assert_eq!(1, report.live_parcels);
+        assert_eq!(vec![orphan.to_owned()], report.removed_parcels);
+        assert!(report.removed_part_files.is_empty());
+        assert!(
+            store.parcel_data_path(orphan).exists(),
+            "Dry run should not remove anything"
+        );
+
+        let report = store
+            .gc(GcOptions {
+                dry_run: false,
+                grace_period: std::time::Duration::ZERO,
+            })
+            .await
+            .expect("gc should succeed");
+        let mut removed = report.removed_parcels.clone();
+        removed.sort();
+        assert_eq!(vec![orphan.to_owned(), abandoned.to_owned()], removed);
+        assert_eq!(
+            (b"orphaned".len() + b"partial".len()) as u64,
+            report.reclaimed_bytes
+        );
+        assert!(!store.parcel_path(orphan).exists());
+        assert!(!store.parcel_path(abandoned).exists());
+        assert!(
+            store.parcel_data_path(&parcel.sha).exists(),
+            "Referenced parcel should not be removed"
+        );
+        assert!(list_dir_names(&root.path().join(GC_DIRECTORY))
+            .await
+            .unwrap()
+            .is_empty());
+
+        // A parcel left behind by an interrupted run is put back if it is still referenced
+        std::fs::rename(
+            store.parcel_path(&parcel.sha),
+            root.path().join(GC_DIRECTORY).join(&parcel.sha),
+        )
+        .unwrap();
+        let report = store
+            .gc(GcOptions {
+                dry_run: false,
+                grace_period: std::time::Duration::ZERO,
+            })
+            .await
+            .expect("gc should succeed");
+        assert!(report.removed_parcels.is_empty());
+        assert!(
+            store.parcel_data_path(&parcel.sha).exists(),
+            "Referenced parcel should be restored"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_should_keep_parcels_of_drafts() {
+        let root = tempdir().unwrap();
+        let scaffold = testing::Scaffold::load("lotsa_parcels").await;
+        let parcel = scaffold.parcel_files.get("parcel").unwrap();
+        let store = FileProvider::new(root.path(), crate::search::StrictEngine::default()).await;
+
+        let signed = NoopSigned(NoopVerified(scaffold.invoice.clone()));
+        store.create_draft_invoice(signed).await.unwrap();
+        store
+            .create_parcel(
+                &scaffold.invoice.bindle.id,
+                &parcel.sha,
+                FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+            )
+            .await
+            .expect("Parcels should be creatable for drafts");
+        assert!(matches!(
+            store.get_invoice(&scaffold.invoice.bindle.id).await,
+            Err(ProviderError::NotFound)
+        ));
+
+        let report = store
+            .gc(GcOptions {
+                dry_run: false,
+                grace_period: std::time::Duration::ZERO,
+            })
+            .await
+            .expect("gc should succeed");
+        assert_eq!(scaffold.parcel_files.len(), report.live_parcels);
+        assert!(report.removed_parcels.is_empty());
+        assert!(
+            store.parcel_data_path(&parcel.sha).exists(),
+            "Parcels of drafts should not be removed"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_should_detect_layout() {
+        // New stores are sharded right away
+        let root = tempdir().unwrap();
+        let new_root = root.path().join("new");
+        let store = FileProvider::try_new(&new_root, crate::search::StrictEngine::default())
+            .await
+            .expect("Should be able to create a new store");
+        assert_eq!(Layout::Sharded, store.layout());
+        assert!(new_root.join(layout::LAYOUT_FILE).exists());
+
+        // Layouts that aren't known can't be used
+        std::fs::write(root.path().join(layout::LAYOUT_FILE), "version = 99").unwrap();
+        assert!(
+            FileProvider::try_new(root.path(), crate::search::StrictEngine::default())
+                .await
+                .is_err(),
+            "An unknown layout should be an error"
+        );
+        std::fs::write(root.path().join(layout::LAYOUT_FILE), "not toml").unwrap();
+        assert!(
+            FileProvider::try_new(root.path(), crate::search::StrictEngine::default())
+                .await
+                .is_err(),
+            "A malformed layout file should be an error"
+        );
+
+        // Neither can a store whose layout file can't be read, instead of falling back to flat
+        std::fs::remove_file(root.path().join(layout::LAYOUT_FILE)).unwrap();
+        std::fs::create_dir(root.path().join(layout::LAYOUT_FILE)).unwrap();
+        assert!(
+            matches!(
+                FileProvider::try_new(root.path(), crate::search::StrictEngine::default()).await,
+                Err(ProviderError::Io(_))
+            ),
+            "An unreadable layout file should be an error"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_should_upgrade_to_sharded_layout() {
+        let root = tempdir().unwrap();
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let parcel = scaffold.parcel_files.get("parcel").unwrap();
+        let draft = testing::Scaffold::load("lotsa_parcels").await;
+        // Stores from before the layout was versioned don't have a layout file
+        std::fs::create_dir(root.path().join(INVOICE_DIRECTORY)).unwrap();
+        let store = FileProvider::new(root.path(), crate::search::StrictEngine::default()).await;
+        assert_eq!(Layout::Flat, store.layout());
+
+        let signed = NoopSigned(NoopVerified(scaffold.invoice.clone()));
+        store.create_invoice(signed).await.unwrap();
+        store
+            .create_parcel(
+                &scaffold.invoice.bindle.id,
+                &parcel.sha,
+                FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+            )
+            .await
+            .expect("create parcel");
+        let signed = NoopSigned(NoopVerified(draft.invoice.clone()));
+        store.create_draft_invoice(signed).await.unwrap();
+
+        // Pretend a previous upgrade was interrupted after moving the parcel
+        let shard = root.path().join(PARCEL_DIRECTORY).join(&parcel.sha[..2]);
+        std::fs::create_dir_all(&shard).unwrap();
+        std::fs::rename(store.parcel_path(&parcel.sha), shard.join(&parcel.sha)).unwrap();
+
+        let report = upgrade_layout(root.path())
+            .await
+            .expect("upgrade should succeed");
+        assert_eq!(Layout::Flat, report.previous);
+        assert_eq!(
+            2, report.moved,
+            "Only the invoice and draft should be moved"
+        );
+        assert!(root.path().join(LAYOUT_FILE).exists());
+
+        let store = FileProvider::new(root.path(), crate::search::StrictEngine::default()).await;
+        assert_eq!(Layout::Sharded, store.layout());
+        let inv_name = scaffold.invoice.canonical_name();
+        assert_eq!(
+            root.path()
+                .join(INVOICE_DIRECTORY)
+                .join(&inv_name[..2])
+                .join(&inv_name)
+                .join(INVOICE_TOML),
+            store.invoice_toml_path(&inv_name)
+        );
+        assert!(store.invoice_toml_path(&inv_name).exists());
+        assert_eq!(shard.join(&parcel.sha), store.parcel_path(&parcel.sha));
+
+        store
+            .get_invoice(&scaffold.invoice.bindle.id)
+            .await
+            .expect("invoice should be readable after the upgrade");
+        store
+            .get_draft_invoice(&draft.invoice.bindle.id)
+            .await
+            .expect("draft should be readable after the upgrade");
+        assert!(store
+            .parcel_exists(&scaffold.invoice.bindle.id, &parcel.sha)
+            .await
+            .unwrap());
+        let ids: Vec<Id> = store
+            .list_invoices(false)
+            .await
+            .unwrap()
+            .map(|res| res.unwrap())
+            .collect()
+            .await;
+        assert_eq!(vec![scaffold.invoice.bindle.id.clone()], ids);
+
+        let report = store
+            .fsck(FsckOptions::default())
+            .await
+            .expect("fsck should succeed");
+        assert!(report.is_clean());
+        assert_eq!(1, report.checked_parcels);
+        let report = store
+            .gc(GcOptions {
+                dry_run: false,
+                grace_period: std::time::Duration::ZERO,
+            })
+            .await
+            .expect("gc should succeed");
+        assert!(report.removed_parcels.is_empty());
+
+        // New entries also go into their shards
+        let draft_parcel = draft
+            .parcel_files
+            .values()
+            .find(|p| p.sha != parcel.sha)
+            .unwrap();
+        store
+            .create_parcel(
+                &draft.invoice.bindle.id,
+                &draft_parcel.sha,
+                FramedRead::new(
+                    std::io::Cursor::new(draft_parcel.data.clone()),
+                    BytesCodec::new(),
+                ),
+            )
+            .await
+            .expect("create parcel");
+        assert!(root
+            .path()
+            .join(PARCEL_DIRECTORY)
+            .join(&draft_parcel.sha[..2])
+            .join(&draft_parcel.sha)
+            .join(PARCEL_DAT)
+            .exists());
+
+        let report = upgrade_layout(root.path())
+            .await
+            .expect("upgrading again should succeed");
+        assert_eq!(Layout::Sharded, report.previous);
+        assert_eq!(0, report.moved);
+    }
+
     // Running this as multi thread to make sure both processes run simultaneously
     #[tokio::test(flavor = "multi_thread")]
     async fn test_double_write() {
@@ -893,4 +3336,142 @@ mod test {
             "One of the create parcel tasks should succeed"
         );
     }
+
+    #[tokio::test]
+    async fn test_should_only_rebuild_persistent_index_when_out_of_sync() {
+        let root = tempdir().unwrap();
+        let index_dir = tempdir().unwrap();
+        let first = testing::Scaffold::load("valid_v1").await.invoice;
+        let second = testing::Scaffold::load("lotsa_parcels").await.invoice;
+
+        let index = crate::search::PersistentEngine::open(index_dir.path())
+            .await
+            .unwrap();
+        let store = FileProvider::new(root.path(), index.clone()).await;
+        store
+            .create_invoice(NoopSigned(NoopVerified(first.clone())))
+            .await
+            .unwrap();
+        assert_eq!(
+            Some(read_generation(root.path()).await.unwrap()),
+            index.generation().await.unwrap(),
+            "The index should be in sync once the invoice is indexed"
+        );
+        // An invoice that is only on disk wasn't created through the provider, so it is only found
+        // if the index was rebuilt
+        let stray = store.invoice_toml_path(&second.canonical_name());
+        drop((store, index));
+        std::fs::create_dir_all(stray.parent().unwrap()).unwrap();
+        std::fs::write(stray, toml::to_vec(&second).unwrap()).unwrap();
+        let index = crate::search::PersistentEngine::open(index_dir.path())
+            .await
+            .unwrap();
+        let store = FileProvider::new(root.path(), index.clone()).await;
+        let matches = index
+            .query("", "", crate::search::SearchOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(1, matches.total, "An index in sync should not be rebuilt");
+        drop((store, index));
+
+        // Changes by another process (like an administrative command) make the index out of sync
+        let other = FileProvider::new(root.path(), crate::search::NoopEngine::default()).await;
+        other.yank_invoice(&first.bindle.id).await.unwrap();
+        let index = crate::search::PersistentEngine::open(index_dir.path())
+            .await
+            .unwrap();
+        let _store = FileProvider::new(root.path(), index.clone()).await;
+        let matches = index
+            .query(
+                "",
+                "",
+                crate::search::SearchOptions {
+                    yanked: true,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(2, matches.total, "The index should be rebuilt");
+        assert!(matches
+            .invoices
+            .iter()
+            .any(|inv| inv.bindle.id == first.bindle.id && inv.yanked == Some(true)));
+        assert_eq!(
+            Some(read_generation(root.path()).await.unwrap()),
+            index.generation().await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_should_record_generation_when_an_invoice_cant_be_indexed() {
+        let root = tempdir().unwrap();
+        let first = testing::Scaffold::load("valid_v1").await.invoice;
+        let second = testing::Scaffold::load("lotsa_parcels").await.invoice;
+        let store = FileProvider::new(root.path(), crate::search::NoopEngine::default()).await;
+        for inv in [&first, &second] {
+            store
+                .create_invoice(NoopSigned(NoopVerified(inv.clone())))
+                .await
+                .unwrap();
+        }
+
+        let index = RejectingEngine {
+            inner: Default::default(),
+            reject: second.bindle.id.clone(),
+            generation: Default::default(),
+        };
+        let store = FileProvider::new(root.path(), index).await;
+        assert_eq!(
+            Some(read_generation(root.path()).await.unwrap()),
+            store.index.generation().await.unwrap(),
+            "A single invoice that can't be indexed shouldn't force a rebuild on every start"
+        );
+        let matches = store
+            .index
+            .query("", "", crate::search::SearchOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(1, matches.total);
+        assert_eq!(first.bindle.id, matches.invoices[0].bindle.id);
+    }
+
+    /// A search engine that fails to index the invoice with the given ID
+    struct RejectingEngine {
+        inner: crate::search::StrictEngine,
+        reject: Id,
+        generation: std::sync::Mutex<Option<u64>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Search for RejectingEngine {
+        async fn query(
+            &self,
+            term: &str,
+            filter: &str,
+            options: crate::search::SearchOptions,
+        ) -> anyhow::Result<crate::search::Matches> {
+            self.inner.query(term, filter, options).await
+        }
+
+        async fn index(&self, invoice: &crate::Invoice) -> anyhow::Result<()> {
+            if invoice.bindle.id == self.reject {
+                anyhow::bail!("Unable to index invoice");
+            }
+            self.inner.index(invoice).await
+        }
+
+        async fn remove(&self, id: &Id) -> anyhow::Result<()> {
+            self.inner.remove(id).await
+        }
+
+        async fn generation(&self) -> anyhow::Result<Option<u64>> {
+            Ok(*self.generation.lock().unwrap())
+        }
+
+        async fn set_generation(&self, generation: u64) -> anyhow::Result<()> {
+            *self.generation.lock().unwrap() = Some(generation);
+            Ok(())
+        }
+    }
 }
@@ -0,0 +1,58 @@
+//! Types used for checking the integrity of stored invoices and parcels.
+//!
+//! Terminal providers that support integrity checks (currently the
+//! [`FileProvider`](crate::provider::file::FileProvider) and the
+//! [`EmbeddedProvider`](crate::provider::embedded::EmbeddedProvider)) expose an `fsck` method that
+//! takes [`FsckOptions`] and returns an [`FsckReport`]. A check re-hashes every stored parcel
+//! against its SHA, makes sure every invoice is stored under its canonical name, and confirms that
+//! every parcel referenced by an invoice exists with the size given in its label.
+//!
+//! Offending invoices and parcels can optionally be quarantined, which moves them out of the way
+//! so they are no longer served. Because parcels are content addressed, a quarantined parcel can
+//! then be uploaded again by a client that has the correct data
+
+use crate::Id;
+
+/// The name of the directory (or database tree) that quarantined data is moved to
+pub const QUARANTINE_NAME: &str = "quarantine";
+
+/// Options for an integrity check
+#[derive(Debug, Clone, Default)]
+pub struct FsckOptions {
+    /// If true, corrupted parcels, unreadable invoices, and invoices not stored under their
+    /// canonical name are quarantined. Otherwise they are only reported
+    pub quarantine: bool,
+}
+
+/// A report of all problems found during an integrity check
+#[derive(Debug, Clone, Default)]
+pub struct FsckReport {
+    /// Whether or not the offending items in this report were quarantined
+    pub quarantined: bool,
+    /// The number of invoices that were checked
+    pub checked_invoices: usize,
+    /// The number of parcels that were checked
+    pub checked_parcels: usize,
+    /// The SHAs of all parcels whose data does not match their SHA
+    pub corrupt_parcels: Vec<String>,
+    /// The names (i.e. the SHA of the canonical name) of all invoices that could not be parsed
+    pub unreadable_invoices: Vec<String>,
+    /// The names of all invoices that are not stored under the SHA of their canonical name
+    pub misnamed_invoices: Vec<String>,
+    /// Parcels (along with the invoice referencing them) that do not exist in storage. This is
+    /// not necessarily a problem, as the parcel may not have been uploaded yet
+    pub missing_parcels: Vec<(Id, String)>,
+    /// Parcels (along with the invoice referencing them) whose stored size does not match the size
+    /// in the invoice's label
+    pub size_mismatches: Vec<(Id, String)>,
+}
+
+impl FsckReport {
+    /// Returns true if no corruption was found. Missing parcels are not considered corruption
+    pub fn is_clean(&self) -> bool {
+        self.corrupt_parcels.is_empty()
+            && self.unreadable_invoices.is_empty()
+            && self.misnamed_invoices.is_empty()
+            && self.size_mismatches.is_empty()
+    }
+}
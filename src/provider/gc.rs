@@ -0,0 +1,52 @@
+//! Types used for garbage collecting parcels that are no longer referenced by any invoice.
+//!
+//! Parcels are content addressed and can be shared by many bindles, so they are never removed
+//! when a bindle is. Terminal providers that support garbage collection (currently the
+//! [`FileProvider`](crate::provider::file::FileProvider) and the
+//! [`EmbeddedProvider`](crate::provider::embedded::EmbeddedProvider)) expose a `gc` method that
+//! takes [`GcOptions`] and returns a [`GcReport`] describing what was (or, in dry run mode, would
+//! be) removed.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// The default amount of time a partially written file must be untouched before it is considered
+/// abandoned
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// Options for a garbage collection run
+#[derive(Debug, Clone)]
+pub struct GcOptions {
+    /// If true, nothing will be removed, but the returned report will contain everything that
+    /// would have been removed
+    pub dry_run: bool,
+    /// How long a partially written file must be untouched before it is removed. This avoids
+    /// removing files that are part of an in progress upload
+    pub grace_period: Duration,
+}
+
+impl Default for GcOptions {
+    fn default() -> Self {
+        GcOptions {
+            dry_run: false,
+            grace_period: DEFAULT_GRACE_PERIOD,
+        }
+    }
+}
+
+/// A report of everything removed during a garbage collection run
+#[derive(Debug, Clone, Default)]
+pub struct GcReport {
+    /// Whether or not this was a dry run. If true, nothing listed in this report was actually
+    /// removed
+    pub dry_run: bool,
+    /// The number of distinct parcels referenced by at least one invoice (including yanked
+    /// invoices)
+    pub live_parcels: usize,
+    /// The SHAs of all parcels that were removed because no invoice references them
+    pub removed_parcels: Vec<String>,
+    /// The paths to any stale partially written files that were removed
+    pub removed_part_files: Vec<PathBuf>,
+    /// The total number of bytes freed
+    pub reclaimed_bytes: u64,
+}
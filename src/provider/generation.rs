@@ -0,0 +1,233 @@
+//! Tracking of the generation of a store, which lets a search index that persists across restarts
+//! (like [`PersistentEngine`](crate::search::PersistentEngine)) tell whether it is still in sync
+//! with the invoices in the store.
+//!
+//! A provider keeps a counter in its store that is incremented, and written to disk, _before_ each
+//! change to its invoices. Once a change has also been applied to the index and no other change is
+//! in flight, the generation is recorded in the index. When the provider is created again, the
+//! index only has to be rebuilt if the generation it recorded doesn't match the one of the store.
+//! That happens when a change might not have made it into the index, like after a crash or when
+//! another process changed the store
+
+use std::future::Future;
+use std::sync::Mutex;
+
+use tokio::sync::Mutex as TokioMutex;
+use tracing::warn;
+
+use crate::search::Search;
+
+/// Keeps track of the changes a provider makes to the invoices in its store
+#[derive(Default)]
+pub(crate) struct GenerationTracker {
+    state: Mutex<State>,
+    // Serializes reading and incrementing the generation for stores that can't do it atomically
+    bump: TokioMutex<()>,
+}
+
+struct State {
+    /// The generation of the store as of the last change made by this process
+    current: u64,
+    /// The number of changes that have been started but haven't been applied to the index yet
+    in_flight: usize,
+    /// The number of changes that have been started by this process
+    changes: u64,
+    /// Whether the index may be missing a change, in which case no generation is recorded in it
+    /// until it has been rebuilt
+    stale: bool,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State {
+            current: 0,
+            in_flight: 0,
+            changes: 0,
+            // Nothing is known about the index until the provider has synced it
+            stale: true,
+        }
+    }
+}
+
+impl GenerationTracker {
+    /// Starts a change to the invoices in the store. The given function must increment the
+    /// generation of the store and return the generation before and after doing so.
+    ///
+    /// If the previous generation isn't the one left by the last change of this process, another
+    /// process changed the store without updating the index, so no generation is recorded in it
+    /// again until it has been rebuilt
+    pub(crate) async fn begin<F, Fut, E>(&self, bump: F) -> Result<Change<'_>, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<(u64, u64), E>>,
+    {
+        let _guard = self.bump.lock().await;
+        let (previous, current) = bump().await?;
+        let mut state = self.state.lock().unwrap();
+        if previous != state.current && !state.stale {
+            warn!(
+                expected = state.current,
+                found = previous,
+                "Invoices were changed by another process, the search index will be rebuilt on the next start"
+            );
+            state.stale = true;
+        }
+        state.current = current;
+        state.in_flight += 1;
+        state.changes += 1;
+        Ok(Change {
+            tracker: self,
+            finished: false,
+        })
+    }
+
+    /// Returns a snapshot of the changes made by this process to pass to
+    /// [`synced`](Self::synced), or `None` if a change is in flight
+    pub(crate) fn snapshot(&self) -> Option<u64> {
+        let state = self.state.lock().unwrap();
+        (state.in_flight == 0).then_some(state.changes)
+    }
+
+    /// Records that the index contains exactly the invoices of the given generation of the store,
+    /// which must have been read after the snapshot was taken. Nothing is recorded if this process
+    /// changed the store since then, as the index may have missed the change
+    pub(crate) async fn synced<S: Search + Sync + ?Sized>(
+        &self,
+        index: &S,
+        snapshot: Option<u64>,
+        generation: u64,
+    ) -> anyhow::Result<()> {
+        {
+            let mut state = self.state.lock().unwrap();
+            if snapshot != Some(state.changes) {
+                return Ok(());
+            }
+            state.current = generation;
+            state.stale = false;
+        }
+        index.set_generation(generation).await
+    }
+}
+
+/// A change to the invoices in a store that was started with [`GenerationTracker::begin`]. A
+/// change that is dropped without being finished is assumed to have been interrupted, which means
+/// the index may be missing it
+pub(crate) struct Change<'a> {
+    tracker: &'a GenerationTracker,
+    finished: bool,
+}
+
+impl Change<'_> {
+    /// Finishes the change. `indexed` is whether the index was successfully updated (or didn't need
+    /// to be, because nothing was changed after all). If no other change is in flight, the current
+    /// generation is recorded in the index
+    pub(crate) async fn finish<S: Search + Sync + ?Sized>(mut self, index: &S, indexed: bool) {
+        self.finished = true;
+        let generation = {
+            let mut state = self.tracker.state.lock().unwrap();
+            state.in_flight -= 1;
+            state.stale |= !indexed;
+            (state.in_flight == 0 && !state.stale).then_some(state.current)
+        };
+        // If another change starts in the meantime, this records an older generation than the
+        // store has. That only means the index is rebuilt if the process stops right now
+        if let Some(generation) = generation {
+            if let Err(e) = index.set_generation(generation).await {
+                warn!(error = %e, "Unable to record generation in the search index");
+            }
+        }
+    }
+}
+
+impl Drop for Change<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let mut state = self.tracker.state.lock().unwrap();
+            state.in_flight -= 1;
+            state.stale = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Option<u64>>);
+
+    #[async_trait::async_trait]
+    impl Search for Recorder {
+        async fn query(
+            &self,
+            term: &str,
+            _: &str,
+            options: crate::search::SearchOptions,
+        ) -> anyhow::Result<crate::search::Matches> {
+            crate::search::NoopEngine::default()
+                .query(term, "", options)
+                .await
+        }
+
+        async fn index(&self, _: &crate::Invoice) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn remove(&self, _: &crate::Id) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn generation(&self) -> anyhow::Result<Option<u64>> {
+            Ok(*self.0.lock().unwrap())
+        }
+
+        async fn set_generation(&self, generation: u64) -> anyhow::Result<()> {
+            *self.0.lock().unwrap() = Some(generation);
+            Ok(())
+        }
+    }
+
+    async fn bump(tracker: &GenerationTracker, previous: u64) -> Change<'_> {
+        tracker
+            .begin(|| async move { Ok::<_, ()>((previous, previous + 1)) })
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_should_only_record_generation_when_in_sync() {
+        let tracker = GenerationTracker::default();
+        let index = Recorder::default();
+
+        // Nothing is recorded until the index has been synced
+        bump(&tracker, 0).await.finish(&index, true).await;
+        assert_eq!(None, index.generation().await.unwrap());
+        let snapshot = tracker.snapshot();
+        tracker.synced(&index, snapshot, 1).await.unwrap();
+        assert_eq!(Some(1), index.generation().await.unwrap());
+
+        // Concurrent changes are only recorded once both are done
+        let first = bump(&tracker, 1).await;
+        let second = bump(&tracker, 2).await;
+        assert!(tracker.snapshot().is_none());
+        second.finish(&index, true).await;
+        assert_eq!(Some(1), index.generation().await.unwrap());
+        first.finish(&index, true).await;
+        assert_eq!(Some(3), index.generation().await.unwrap());
+
+        // Changes by another process (and interrupted changes) stop anything from being recorded
+        bump(&tracker, 5).await.finish(&index, true).await;
+        assert_eq!(Some(3), index.generation().await.unwrap());
+        let snapshot = tracker.snapshot();
+        tracker.synced(&index, snapshot, 6).await.unwrap();
+        drop(bump(&tracker, 6).await);
+        bump(&tracker, 7).await.finish(&index, true).await;
+        assert_eq!(Some(6), index.generation().await.unwrap());
+
+        // A sync doesn't count if a change was made while it was running
+        let snapshot = tracker.snapshot();
+        bump(&tracker, 8).await.finish(&index, true).await;
+        tracker.synced(&index, snapshot, 8).await.unwrap();
+        assert_eq!(Some(6), index.generation().await.unwrap());
+    }
+}
@@ -0,0 +1,362 @@
+//! Copies all bindles from one provider to another, such as when moving from the
+//! [`FileProvider`](crate::provider::file::FileProvider) to the
+//! [`EmbeddedProvider`](crate::provider::embedded::EmbeddedProvider).
+//!
+//! A migration copies every invoice (keeping its yanked status) along with every parcel it
+//! references. Parcels are validated against their labels by the destination provider as they are
+//! written, so a corrupted parcel is reported as a failure rather than copied. Anything that
+//! already exists in the destination is skipped, which means an interrupted migration can be
+//! resumed by running it again with the same arguments. Once everything has been copied, the
+//! destination is checked against the source and any differences are included in the
+//! [`MigrationReport`]
+
+use tokio_stream::StreamExt;
+use tracing::{debug, info, instrument, warn};
+
+use crate::provider::{Provider, ProviderError, Result};
+use crate::verification::NoopVerified;
+use crate::{Id, NoopSigned};
+
+/// A report of everything done during a migration, including the results of the final
+/// consistency check
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    /// The number of invoices copied to the destination
+    pub copied_invoices: usize,
+    /// The number of invoices that already existed in the destination (e.g. from a previous run)
+    pub skipped_invoices: usize,
+    /// The number of parcels copied to the destination
+    pub copied_parcels: usize,
+    /// The number of parcels that already existed in the destination
+    pub skipped_parcels: usize,
+    /// The number of parcels referenced by an invoice that were never uploaded to the source.
+    /// These cannot be copied and are not considered an inconsistency
+    pub absent_parcels: usize,
+    /// The total size of all copied parcels
+    pub copied_bytes: u64,
+    /// A description of every error that occurred while copying. The migration continues past
+    /// errors so that as much as possible is copied
+    pub failures: Vec<String>,
+    /// Invoices that exist in the source but not in the destination after the migration
+    pub missing_invoices: Vec<Id>,
+    /// Invoices whose yanked status in the destination doesn't match the source
+    pub yank_mismatches: Vec<Id>,
+    /// Parcels (along with the invoice referencing them) that exist in the source but not in the
+    /// destination after the migration
+    pub missing_parcels: Vec<(Id, String)>,
+}
+
+impl MigrationReport {
+    /// Returns true if the destination contains everything in the source and nothing failed
+    pub fn is_consistent(&self) -> bool {
+        self.failures.is_empty()
+            && self.missing_invoices.is_empty()
+            && self.yank_mismatches.is_empty()
+            && self.missing_parcels.is_empty()
+    }
+}
+
+/// Copies all invoices and parcels from the source provider to the destination provider. See the
+/// [module documentation](self) for more details.
+///
+/// An error is only returned if the invoices in the source could not be listed. All other errors
+/// are recorded in the returned report
+#[instrument(level = "trace", skip(source, dest))]
+pub async fn migrate<S, D>(source: &S, dest: &D) -> Result<MigrationReport>
+where
+    S: Provider + Sync,
+    D: Provider + Sync,
+{
+    info!("Beginning migration");
+    let mut report = MigrationReport::default();
+
+    let mut ids = source.list_invoices(true).await?;
+    while let Some(res) = ids.next().await {
+        let id = match res {
+            Ok(id) => id,
+            Err(e) => {
+                report
+                    .failures
+                    .push(format!("Unable to read invoice from source: {}", e));
+                continue;
+            }
+        };
+        if let Err(e) = migrate_invoice(source, dest, &id, &mut report).await {
+            warn!(%id, error = %e, "Unable to migrate invoice");
+            report
+                .failures
+                .push(format!("Unable to migrate invoice {}: {}", id, e));
+        }
+    }
+    info!(
+        copied_invoices = report.copied_invoices,
+        copied_parcels = report.copied_parcels,
+        failures = report.failures.len(),
+        "Finished copying, checking consistency"
+    );
+
+    check_consistency(source, dest, &mut report).await?;
+    Ok(report)
+}
+
+async fn migrate_invoice<S, D>(
+    source: &S,
+    dest: &D,
+    id: &Id,
+    report: &mut MigrationReport,
+) -> Result<()>
+where
+    S: Provider + Sync,
+    D: Provider + Sync,
+{
+    let inv = source.get_yanked_invoice(id).await?;
+    let yanked = inv.yanked.unwrap_or(false);
+
+    // Yanked invoices can't be created, so the invoice is created as is and then yanked once all
+    // of its parcels are copied
+    let mut to_create = inv.clone();
+    to_create.yanked = None;
+    let dest_yanked = match dest
+        .create_invoice(NoopSigned(NoopVerified(to_create)))
+        .await
+    {
+        Ok(_) => {
+            debug!(%id, "Copied invoice");
+            report.copied_invoices += 1;
+            false
+        }
+        Err(ProviderError::Exists) => {
+            debug!(%id, "Invoice already exists in destination, skipping");
+            report.skipped_invoices += 1;
+            dest.get_yanked_invoice(id).await?.yanked.unwrap_or(false)
+        }
+        Err(e) => return Err(e),
+    };
+
+    for label in inv.parcel.unwrap_or_default().into_iter().map(|p| p.label) {
+        if let Err(e) = migrate_parcel(source, dest, id, &label, report).await {
+            warn!(%id, parcel_id = %label.sha256, error = %e, "Unable to migrate parcel");
+            report.failures.push(format!(
+                "Unable to migrate parcel {} for invoice {}: {}",
+                label.sha256, id, e
+            ));
+        }
+    }
+
+    if yanked && !dest_yanked {
+        debug!(%id, "Yanking invoice in destination");
+        dest.yank_invoice(id).await?;
+    }
+    Ok(())
+}
+
+async fn migrate_parcel<S, D>(
+    source: &S,
+    dest: &D,
+    id: &Id,
+    label: &crate::Label,
+    report: &mut MigrationReport,
+) -> Result<()>
+where
+    S: Provider + Sync,
+    D: Provider + Sync,
+{
+    // Parcels can be shared between invoices, so this also skips parcels we copied for an
+    // earlier invoice
+    if dest.parcel_exists(id, &label.sha256).await? {
+        report.skipped_parcels += 1;
+        return Ok(());
+    }
+    if !source.parcel_exists(id, &label.sha256).await? {
+        debug!(%id, parcel_id = %label.sha256, "Parcel was never uploaded to source, skipping");
+        report.absent_parcels += 1;
+        return Ok(());
+    }
+
+    let stream = source
+        .get_parcel(id, &label.sha256)
+        .await?
+        .map(|res| res.map_err(|e| std::io::Error::other(e.to_string())));
+    match dest.create_parcel(id, &label.sha256, stream).await {
+        Ok(_) => {
+            debug!(%id, parcel_id = %label.sha256, "Copied parcel");
+            report.copied_parcels += 1;
+            report.copied_bytes += label.size;
+            Ok(())
+        }
+        // Another invoice referencing the same parcel could have created it in the meantime
+        Err(ProviderError::Exists) => {
+            report.skipped_parcels += 1;
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Checks that every invoice and parcel in the source exists in the destination, recording any
+/// differences in the report
+async fn check_consistency<S, D>(source: &S, dest: &D, report: &mut MigrationReport) -> Result<()>
+where
+    S: Provider + Sync,
+    D: Provider + Sync,
+{
+    let mut ids = source.list_invoices(true).await?;
+    while let Some(res) = ids.next().await {
+        // Unreadable invoices were already recorded as failures during the copy
+        let id = match res {
+            Ok(id) => id,
+            Err(_) => continue,
+        };
+        let inv = match source.get_yanked_invoice(&id).await {
+            Ok(inv) => inv,
+            Err(_) => continue,
+        };
+        let copied = match dest.get_yanked_invoice(&id).await {
+            Ok(inv) => inv,
+            Err(ProviderError::NotFound) => {
+                report.missing_invoices.push(id);
+                continue;
+            }
+            Err(e) => {
+                report
+                    .failures
+                    .push(format!("Unable to check invoice {}: {}", id, e));
+                continue;
+            }
+        };
+        if inv.yanked.unwrap_or(false) != copied.yanked.unwrap_or(false) {
+            report.yank_mismatches.push(id.clone());
+        }
+
+        for label in inv.parcel.unwrap_or_default().into_iter().map(|p| p.label) {
+            let exists = dest
+                .parcel_exists(&id, &label.sha256)
+                .await
+                .unwrap_or(false);
+            if !exists
+                && source
+                    .parcel_exists(&id, &label.sha256)
+                    .await
+                    .unwrap_or(false)
+            {
+                report.missing_parcels.push((id.clone(), label.sha256));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "providers"))]
+mod test {
+    use super::*;
+    use crate::provider::{embedded::EmbeddedProvider, file::FileProvider};
+    use crate::search::NoopEngine;
+    use crate::testing;
+
+    use tokio_util::codec::{BytesCodec, FramedRead};
+
+    #[tokio::test]
+    async fn test_migrate_file_to_embedded() {
+        let source_dir = tempfile::tempdir().unwrap();
+        let dest_dir = tempfile::tempdir().unwrap();
+        let source = FileProvider::new(source_dir.path(), NoopEngine::default()).await;
+        let dest = EmbeddedProvider::new(dest_dir.path(), NoopEngine::default())
+            .await
+            .unwrap();
+
+        let v1 = testing::Scaffold::load("valid_v1").await;
+        let v2 = testing::Scaffold::load("valid_v2").await;
+        for inv in [&v1.invoice, &v2.invoice] {
+            source
+                .create_invoice(NoopSigned(NoopVerified(inv.clone())))
+                .await
+                .unwrap();
+        }
+        // Only upload the parcels for v1. The extra parcel in v2 is never uploaded
+        for parcel in v1.parcel_files.values() {
+            source
+                .create_parcel(
+                    &v1.invoice.bindle.id,
+                    &parcel.sha,
+                    FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+                )
+                .await
+                .unwrap();
+        }
+        source.yank_invoice(&v1.invoice.bindle.id).await.unwrap();
+
+        let report = migrate(&source, &dest)
+            .await
+            .expect("Migration should succeed");
+        assert!(report.is_consistent(), "Report: {:?}", report);
+        assert_eq!(2, report.copied_invoices);
+        assert_eq!(1, report.copied_parcels);
+        // v2 shares a parcel with v1
+        assert_eq!(1, report.skipped_parcels);
+        assert_eq!(1, report.absent_parcels);
+
+        let inv = dest
+            .get_yanked_invoice(&v1.invoice.bindle.id)
+            .await
+            .expect("Invoice should have been copied");
+        assert!(
+            inv.yanked.unwrap_or(false),
+            "Yanked status should be copied"
+        );
+        dest.get_invoice(&v2.invoice.bindle.id)
+            .await
+            .expect("Invoice should have been copied");
+
+        // Running it again should skip everything
+        let report = migrate(&source, &dest)
+            .await
+            .expect("Migration should succeed");
+        assert!(report.is_consistent(), "Report: {:?}", report);
+        assert_eq!(0, report.copied_invoices);
+        assert_eq!(2, report.skipped_invoices);
+        assert_eq!(0, report.copied_parcels);
+    }
+
+    #[tokio::test]
+    async fn test_migrate_corrupted_parcel() {
+        let source_dir = tempfile::tempdir().unwrap();
+        let dest_dir = tempfile::tempdir().unwrap();
+        let source = FileProvider::new(source_dir.path(), NoopEngine::default()).await;
+        let dest = EmbeddedProvider::new(dest_dir.path(), NoopEngine::default())
+            .await
+            .unwrap();
+
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        source
+            .create_invoice(NoopSigned(NoopVerified(scaffold.invoice.clone())))
+            .await
+            .unwrap();
+        let parcel = scaffold.parcel_files.get("parcel").unwrap();
+        source
+            .create_parcel(
+                &scaffold.invoice.bindle.id,
+                &parcel.sha,
+                FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+            )
+            .await
+            .unwrap();
+        // Corrupt the parcel on disk, keeping the same size
+        let mut corrupted = parcel.data.clone();
+        corrupted[0] = corrupted[0].wrapping_add(1);
+        std::fs::write(source.parcel_data_path(&parcel.sha), corrupted).unwrap();
+
+        let report = migrate(&source, &dest)
+            .await
+            .expect("Migration should succeed");
+        assert!(!report.is_consistent());
+        assert_eq!(1, report.failures.len());
+        assert_eq!(
+            vec![(scaffold.invoice.bindle.id.clone(), parcel.sha.clone())],
+            report.missing_parcels
+        );
+        assert!(!dest
+            .parcel_exists(&scaffold.invoice.bindle.id, &parcel.sha)
+            .await
+            .unwrap());
+    }
+}
@@ -21,12 +21,32 @@
 //! will generally contain another Provider implementation or an HTTP client to talk to another
 //! server upstream
 
+#[cfg(feature = "server")]
+pub mod backup;
+#[cfg(feature = "providers")]
+pub mod compression;
+pub mod dynamic;
 #[cfg(feature = "providers")]
 pub mod embedded;
 #[cfg(feature = "providers")]
+pub mod encryption;
+#[cfg(feature = "providers")]
 pub mod file;
+pub mod fsck;
+pub mod gc;
+#[cfg(feature = "providers")]
+mod generation;
+pub mod migrate;
+pub mod replicated;
+pub mod retention;
+#[cfg(feature = "s3")]
+pub mod s3;
+#[cfg(feature = "providers")]
+pub mod tiered;
 
 use std::convert::TryInto;
+use std::ops::Range;
+use std::time::Duration;
 
 use thiserror::Error;
 use tokio_stream::Stream;
@@ -60,6 +80,58 @@ pub trait Provider {
     where
         I: Signed + Verified + Send + Sync;
 
+    /// Stores an invoice as a draft that stays hidden until all of its parcels have been created.
+    /// Returns the stored invoice and a list of missing parcels, just like `create_invoice`.
+    ///
+    /// A draft is not returned by `get_invoice`, `get_yanked_invoice` or `list_invoices` and is not
+    /// added to the search index, but parcels can still be created for it. Once none of its parcels
+    /// are missing, `publish_draft_invoice` turns it into a regular invoice. If no parcels are
+    /// missing to begin with, the draft is published right away. Returns
+    /// [`ProviderError::Exists`] if an invoice or draft with the same ID already exists. The
+    /// default implementation returns [`ProviderError::Unsupported`]
+    async fn create_draft_invoice<I>(&self, inv: I) -> Result<(crate::Invoice, Vec<super::Label>)>
+    where
+        I: Signed + Verified + Send + Sync,
+    {
+        let _ = inv;
+        Err(ProviderError::Unsupported)
+    }
+
+    /// Loads a draft invoice. Returns [`ProviderError::NotFound`] if there is no draft with the
+    /// given ID, including when it has already been published. The default implementation returns
+    /// [`ProviderError::Unsupported`]
+    async fn get_draft_invoice<I>(&self, id: I) -> Result<super::Invoice>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let _ = id;
+        Err(ProviderError::Unsupported)
+    }
+
+    /// Publishes a draft invoice if all of its parcels exist, returning the parcels that are still
+    /// missing. If the list is empty, the draft was atomically turned into a regular invoice and
+    /// added to the search index. Otherwise it is left as it is.
+    ///
+    /// Returns [`ProviderError::NotFound`] if there is no draft with the given ID. The default
+    /// implementation returns [`ProviderError::Unsupported`]
+    async fn publish_draft_invoice<I>(&self, id: I) -> Result<Vec<super::Label>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let _ = id;
+        Err(ProviderError::Unsupported)
+    }
+
+    /// Deletes all draft invoices that were created more than `ttl` ago, returning their IDs.
+    /// Parcels that were already created for them are removed by the next garbage collection. The
+    /// default implementation returns [`ProviderError::Unsupported`]
+    async fn expire_draft_invoices(&self, ttl: Duration) -> Result<Vec<Id>> {
+        let _ = ttl;
+        Err(ProviderError::Unsupported)
+    }
+
     /// Load an invoice and return it
     ///
     /// This will return an invoice if the bindle exists and is not yanked. The default
@@ -90,6 +162,29 @@ pub trait Provider {
         I: TryInto<Id> + Send,
         I::Error: Into<ProviderError>;
 
+    /// Permanently delete an invoice by ID, whether or not it is yanked. Unlike yanking, the
+    /// invoice will no longer be retrievable in any way and must be removed from any search index.
+    ///
+    /// Parcels are shared between bindles, so they are not removed when an invoice is deleted.
+    /// Terminal providers should instead offer a way to garbage collect unreferenced parcels.
+    /// Returns [`ProviderError::NotFound`] if the invoice does not exist
+    async fn delete_invoice<I>(&self, id: I) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>;
+
+    /// Lists the IDs of all invoices in storage. Yanked invoices are only included if
+    /// `include_yanked` is true.
+    ///
+    /// This is meant for tools that need to walk everything in a provider (such as backups,
+    /// migrations, or reindexing), so implementations should avoid loading every invoice into memory
+    /// at once. An invoice that cannot be read is returned as an error in the stream rather than
+    /// failing the whole listing. No particular order is guaranteed
+    async fn list_invoices(
+        &self,
+        include_yanked: bool,
+    ) -> Result<Box<dyn Stream<Item = Result<Id>> + Unpin + Send + Sync>>;
+
     /// Checks if the given parcel ID exists within an invoice. The default implementation will fetch
     /// the parcel and check if the given parcel ID exists. Returns the parcel label if valid. Most
     /// providers should implement some sort of caching for `get_yanked_invoice` to avoid fetching
@@ -101,15 +196,7 @@ pub trait Provider {
         I::Error: Into<ProviderError>,
     {
         let inv = self.get_yanked_invoice(bindle_id).await?;
-        match inv
-            .parcel
-            .unwrap_or_default()
-            .into_iter()
-            .find(|p| p.label.sha256 == parcel_id)
-        {
-            Some(p) => Ok(p.label),
-            None => Err(ProviderError::NotFound),
-        }
+        parcel_label(inv, parcel_id)
     }
 
     /// Creates a parcel with the associated sha. The parcel can be anything that implements
@@ -138,6 +225,113 @@ pub trait Provider {
         I: TryInto<Id> + Send,
         I::Error: Into<ProviderError>;
 
+    /// Get the bytes of a specific parcel within the given range. This is used for resuming
+    /// interrupted downloads and serving HTTP range requests.
+    ///
+    /// The end of the range is exclusive and is clamped to the size of the parcel, so
+    /// `offset..u64::MAX` can be used to fetch everything from `offset` onward. A range that is
+    /// empty (including one whose start is after its end) or that starts at or past the end of the
+    /// parcel returns an empty stream. The default implementation fetches
+    /// the whole parcel with `get_parcel` and discards anything outside the range, so terminal
+    /// providers that can seek within their storage should override it
+    async fn get_parcel_range<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+        range: Range<u64>,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let stream = self.get_parcel(bindle_id, parcel_id).await?;
+        if range.start >= range.end {
+            return Ok(Box::new(tokio_stream::empty()));
+        }
+        Ok(Box::new(slice_stream(stream, range)))
+    }
+
+    /// Starts a resumable upload of the given parcel, returning the number of bytes that have
+    /// already been received. If an upload of the parcel is already in progress, it is resumed
+    /// instead of being started over, so the returned offset may be greater than zero.
+    ///
+    /// Resumable uploads allow large parcels to be sent in multiple chunks using
+    /// `write_parcel_chunk`, which means an interrupted upload only needs to resend the data after
+    /// the last received byte. Once all of the data has been written, the upload must be completed
+    /// with `finish_parcel_upload`. Returns [`ProviderError::Exists`] if the parcel already exists.
+    /// The default implementation returns [`ProviderError::Unsupported`]
+    async fn start_parcel_upload<I>(&self, bindle_id: I, parcel_id: &str) -> Result<u64>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let _ = (bindle_id, parcel_id);
+        Err(ProviderError::Unsupported)
+    }
+
+    /// Returns the number of bytes received so far by an in progress upload of the given parcel.
+    /// Returns [`ProviderError::NotFound`] if there is no upload in progress. The default
+    /// implementation returns [`ProviderError::Unsupported`]
+    async fn parcel_upload_offset<I>(&self, bindle_id: I, parcel_id: &str) -> Result<u64>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let _ = (bindle_id, parcel_id);
+        Err(ProviderError::Unsupported)
+    }
+
+    /// Appends a chunk of data to an in progress upload of the given parcel, returning the total
+    /// number of bytes received. The offset must be equal to the number of bytes already received,
+    /// otherwise [`ProviderError::OffsetMismatch`] is returned with the current offset.
+    ///
+    /// Implementations should keep any data that was written before an error (such as a dropped
+    /// connection) so that the upload can be resumed from that point. Data that would go past the
+    /// size given in the parcel's label must be rejected with [`ProviderError::SizeMismatch`]. The
+    /// default implementation returns [`ProviderError::Unsupported`]
+    async fn write_parcel_chunk<I, R, B>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+        offset: u64,
+        data: R,
+    ) -> Result<u64>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+        R: Stream<Item = std::io::Result<B>> + Unpin + Send + Sync + 'static,
+        B: bytes::Buf + Send,
+    {
+        let _ = (bindle_id, parcel_id, offset, data);
+        Err(ProviderError::Unsupported)
+    }
+
+    /// Completes an in progress upload of the given parcel. Implementors MUST validate that the
+    /// uploaded data matches both the size in the parcel's label and the parcel's SHA before
+    /// storing the parcel. An upload that fails digest validation is discarded, while an upload
+    /// with missing data is kept so that it can be continued. The default implementation returns
+    /// [`ProviderError::Unsupported`]
+    async fn finish_parcel_upload<I>(&self, bindle_id: I, parcel_id: &str) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let _ = (bindle_id, parcel_id);
+        Err(ProviderError::Unsupported)
+    }
+
+    /// Cancels an in progress upload of the given parcel, discarding any data received so far.
+    /// Returns [`ProviderError::NotFound`] if there is no upload in progress. The default
+    /// implementation returns [`ProviderError::Unsupported`]
+    async fn abort_parcel_upload<I>(&self, bindle_id: I, parcel_id: &str) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let _ = (bindle_id, parcel_id);
+        Err(ProviderError::Unsupported)
+    }
+
     /// Checks if the given parcel exists in storage.
     ///
     /// This should not load the full parcel but only indicate if the parcel exists. For some
@@ -146,6 +340,32 @@ pub trait Provider {
     where
         I: TryInto<Id> + Send,
         I::Error: Into<ProviderError>;
+
+    /// Checks if the given parcel exists in storage, regardless of which bindles it belongs to.
+    ///
+    /// Parcels are content addressed, so a client can use this to avoid uploading a parcel that a
+    /// new bindle shares with one that already exists. Implementations must return `false` for any
+    /// ID that isn't a SHA-256 sum. The default implementation returns
+    /// [`ProviderError::Unsupported`]
+    async fn has_parcel(&self, parcel_id: &str) -> Result<bool> {
+        let _ = parcel_id;
+        Err(ProviderError::Unsupported)
+    }
+
+    /// Checks which of the given parcels exist in storage, regardless of which bindles they belong
+    /// to. Returns one entry for each of the given IDs, in the same order.
+    ///
+    /// This is what is used to find the missing parcels of an invoice, so providers that can check
+    /// many parcels at once (such as a database or a remote server) should override it. The
+    /// default implementation calls `has_parcel` for each parcel. If that isn't supported either,
+    /// the missing parcels of an invoice are found with `parcel_exists` instead
+    async fn parcels_exist(&self, parcel_ids: &[&str]) -> Result<Vec<bool>> {
+        let mut exists = Vec::with_capacity(parcel_ids.len());
+        for parcel_id in parcel_ids {
+            exists.push(self.has_parcel(parcel_id).await?);
+        }
+        Ok(exists)
+    }
 }
 
 /// ProviderError describes the possible error states when storing and retrieving bindles.
@@ -178,6 +398,16 @@ pub enum ProviderError {
         "a write operation is currently in progress for this resource and it cannot be accessed"
     )]
     WriteInProgress,
+    /// A chunk of a resumable upload was sent with an offset that doesn't match the amount of
+    /// data already received. Contains the current offset of the upload
+    #[error("upload offset does not match the {0} bytes already received")]
+    OffsetMismatch(u64),
+    /// The provider does not support the requested operation
+    #[error("operation is not supported by this provider")]
+    Unsupported,
+    /// Storing the resource would go over a storage quota. Contains a description of the quota
+    #[error("storage quota exceeded: {0}")]
+    QuotaExceeded(String),
     /// An error that occurs when the provider implementation uses a proxy and that proxy request
     /// encounters an error. Only available with the `client` feature enabled
     #[cfg(feature = "client")]
@@ -214,9 +444,154 @@ impl From<std::convert::Infallible> for ProviderError {
 impl From<serde_cbor::Error> for ProviderError {
     fn from(e: serde_cbor::Error) -> Self {
         if e.is_io() {
-            ProviderError::Io(std::io::Error::new(std::io::ErrorKind::Other, e))
+            ProviderError::Io(std::io::Error::other(e))
         } else {
             ProviderError::Other(format!("Unable to parse CBOR payload: {}", e))
         }
     }
 }
+
+/// Returns the label of the given parcel in the invoice, or [`ProviderError::NotFound`] if the
+/// invoice doesn't contain it
+pub(crate) fn parcel_label(inv: crate::Invoice, parcel_id: &str) -> Result<crate::Label> {
+    match inv
+        .parcel
+        .unwrap_or_default()
+        .into_iter()
+        .find(|p| p.label.sha256 == parcel_id)
+    {
+        Some(p) => Ok(p.label),
+        None => Err(ProviderError::NotFound),
+    }
+}
+
+/// Returns the labels of all parcels in the invoice that don't exist in the given store. Stores that
+/// can't check for parcels without an invoice are asked about each parcel with
+/// [`parcel_exists`](Provider::parcel_exists) instead
+pub(crate) async fn missing_parcels<P: Provider + Sync>(
+    store: &P,
+    inv: &crate::Invoice,
+) -> Result<Vec<crate::Label>> {
+    let labels: Vec<&crate::Label> = inv.parcel.iter().flatten().map(|p| &p.label).collect();
+    // if there are no parcels, bail early
+    if labels.is_empty() {
+        return Ok(Vec::new());
+    }
+    let ids: Vec<&str> = labels.iter().map(|l| l.sha256.as_str()).collect();
+    let exists = match store.parcels_exist(&ids).await {
+        Err(ProviderError::Unsupported) => {
+            let mut exists = Vec::with_capacity(labels.len());
+            for label in labels.iter() {
+                exists.push(store.parcel_exists(&inv.bindle.id, &label.sha256).await?);
+            }
+            exists
+        }
+        res => res?,
+    };
+    Ok(labels
+        .into_iter()
+        .zip(exists)
+        .filter(|(_, exists)| !exists)
+        .map(|(label, _)| label.clone())
+        .collect())
+}
+
+/// Asks the given store about all parcels that aren't marked as existing yet, updating `exists`
+/// with the answers. This is used by providers that layer one store on top of another
+pub(crate) async fn fill_parcels_exist<P: Provider + Sync>(
+    store: &P,
+    parcel_ids: &[&str],
+    exists: &mut [bool],
+) -> Result<()> {
+    let (indexes, unknown): (Vec<usize>, Vec<&str>) = parcel_ids
+        .iter()
+        .zip(exists.iter())
+        .enumerate()
+        .filter(|(_, (_, exists))| !**exists)
+        .map(|(i, (id, _))| (i, *id))
+        .unzip();
+    if unknown.is_empty() {
+        return Ok(());
+    }
+    let found = store.parcels_exist(&unknown).await?;
+    for (i, found) in indexes.into_iter().zip(found) {
+        exists[i] = found;
+    }
+    Ok(())
+}
+
+/// Returns true if the given parcel ID is a hex encoded SHA-256 sum. IDs that come from a user
+/// rather than from an invoice must be checked with this before they are used to find a parcel
+pub(crate) fn is_parcel_id(parcel_id: &str) -> bool {
+    parcel_id.len() == 64 && parcel_id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Takes a stream of parcel data and only returns the bytes that fall within the given range.
+/// Chunks before the range are skipped and the stream ends once the range has been read
+pub(crate) fn slice_stream<S, E>(
+    stream: S,
+    range: Range<u64>,
+) -> impl Stream<Item = core::result::Result<bytes::Bytes, E>> + Unpin + Send + Sync
+where
+    S: Stream<Item = core::result::Result<bytes::Bytes, E>> + Unpin + Send + Sync,
+    E: Send + Sync,
+{
+    let sliced = futures::StreamExt::scan(stream, 0u64, move |position, res| {
+        let item = match res {
+            Err(e) => Some(Some(Err(e))),
+            Ok(bytes) => {
+                let chunk_start = *position;
+                let chunk_end = chunk_start + bytes.len() as u64;
+                *position = chunk_end;
+                // An empty or reversed range can't contain anything either
+                if chunk_start >= range.end || range.start >= range.end {
+                    // Everything in the range has been read, so stop the stream
+                    None
+                } else if chunk_end <= range.start {
+                    Some(None)
+                } else {
+                    let from = range.start.saturating_sub(chunk_start) as usize;
+                    let to = (range.end.min(chunk_end) - chunk_start) as usize;
+                    Some(Some(Ok(bytes.slice(from..to))))
+                }
+            }
+        };
+        futures::future::ready(item)
+    });
+    tokio_stream::StreamExt::filter_map(sliced, |item| item)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn test_slice_stream() {
+        let chunks = || {
+            tokio_stream::iter(
+                vec!["abc", "def", "ghi"]
+                    .into_iter()
+                    .map(|c| Ok::<_, ProviderError>(bytes::Bytes::from_static(c.as_bytes()))),
+            )
+        };
+        let collect = |range: Range<u64>| async move {
+            slice_stream(chunks(), range)
+                .collect::<Result<Vec<_>>>()
+                .await
+                .expect("slicing shouldn't fail")
+                .concat()
+        };
+
+        assert_eq!(collect(0..u64::MAX).await, b"abcdefghi");
+        assert_eq!(collect(4..u64::MAX).await, b"efghi");
+        assert_eq!(collect(2..7).await, b"cdefg");
+        assert_eq!(collect(3..6).await, b"def");
+        assert!(collect(9..u64::MAX).await.is_empty());
+        assert!(collect(5..5).await.is_empty());
+        #[allow(clippy::reversed_empty_ranges)]
+        let reversed = 5..4;
+        assert!(collect(reversed).await.is_empty());
+    }
+}
@@ -0,0 +1,640 @@
+//! A provider that replicates all bindles across two other providers, such as a
+//! [`FileProvider`](crate::provider::file::FileProvider) on local disk and an object store.
+//!
+//! Writes (creating invoices and parcels, and yanking or deleting invoices) are sent to both
+//! members at the same time and succeed once the configured write quorum of members has
+//! acknowledged them. Reads go to the primary member first and fail over to the secondary member
+//! if the primary doesn't have the requested item or returns an IO error.
+//!
+//! A write that only reached one of the members (such as when the quorum is 1 or a member was
+//! unavailable) leaves the members out of sync. Running [`ReplicatedProvider::repair`] copies
+//! anything that is only stored in one of the members to the other one, and
+//! [`ReplicatedProvider::spawn_repair`] runs it periodically in the background.
+//!
+//! Deleting an invoice is the exception: it must be acknowledged by both members regardless of
+//! the write quorum, as a repair would otherwise copy the invoice back from the member that still
+//! has it. A delete that fails on either member returns an error and should be retried
+
+use std::convert::TryInto;
+use std::ops::Range;
+
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+use tracing::{debug, info, instrument, warn};
+
+use crate::provider::migrate::{migrate, MigrationReport};
+use crate::provider::{Provider, ProviderError, Result};
+use crate::verification::{NoopVerified, Verified};
+use crate::{Id, NoopSigned, Signed};
+
+/// The number of chunks of parcel data that are buffered for each member while a parcel is
+/// written to both of them. This limits how far ahead of a slow member a fast member can get
+const CHUNK_BUFFER_SIZE: usize = 16;
+
+/// A provider that writes every bindle to two member providers. See the
+/// [module documentation](self) for more details
+#[derive(Clone)]
+pub struct ReplicatedProvider<A, B> {
+    primary: A,
+    secondary: B,
+    write_quorum: usize,
+}
+
+/// A report of everything copied between the members during a repair
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    /// Everything that was copied from the primary member to the secondary member
+    pub to_secondary: MigrationReport,
+    /// Everything that was copied from the secondary member to the primary member
+    pub to_primary: MigrationReport,
+}
+
+impl RepairReport {
+    /// Returns true if both members contain the same bindles after the repair
+    pub fn is_consistent(&self) -> bool {
+        self.to_secondary.is_consistent() && self.to_primary.is_consistent()
+    }
+}
+
+impl<A, B> ReplicatedProvider<A, B>
+where
+    A: Provider + Send + Sync,
+    B: Provider + Send + Sync,
+{
+    /// Returns a new provider that replicates bindles to the given members. Reads prefer the
+    /// primary member. By default, a write only succeeds once both members have acknowledged it
+    pub fn new(primary: A, secondary: B) -> Self {
+        ReplicatedProvider {
+            primary,
+            secondary,
+            write_quorum: 2,
+        }
+    }
+
+    /// Sets the number of members (either 1 or 2) that must acknowledge a write for it to succeed.
+    /// Values outside of that range are clamped to it. Deletes always need both members
+    pub fn with_write_quorum(mut self, write_quorum: usize) -> Self {
+        self.write_quorum = write_quorum.clamp(1, 2);
+        self
+    }
+
+    /// Copies every invoice and parcel that is only stored in one of the members to the other
+    /// member, including the yanked status of invoices. See
+    /// [`migrate`](crate::provider::migrate::migrate) for details on how things are copied.
+    ///
+    /// An error is only returned if the invoices of one of the members could not be listed. All
+    /// other errors are recorded in the returned report
+    #[instrument(level = "trace", skip(self))]
+    pub async fn repair(&self) -> Result<RepairReport> {
+        info!("Beginning replica repair");
+        let report = RepairReport {
+            to_secondary: migrate(&self.primary, &self.secondary).await?,
+            to_primary: migrate(&self.secondary, &self.primary).await?,
+        };
+        info!(
+            copied_to_secondary =
+                report.to_secondary.copied_invoices + report.to_secondary.copied_parcels,
+            copied_to_primary =
+                report.to_primary.copied_invoices + report.to_primary.copied_parcels,
+            consistent = report.is_consistent(),
+            "Finished replica repair"
+        );
+        Ok(report)
+    }
+
+    /// Spawns a background task that runs a [`repair`](Self::repair) at the given interval, starting
+    /// after the first interval has elapsed. The task runs until the returned handle is aborted
+    #[cfg(feature = "server")]
+    pub fn spawn_repair(
+        self: std::sync::Arc<Self>,
+        interval: std::time::Duration,
+    ) -> tokio::task::JoinHandle<()>
+    where
+        A: 'static,
+        B: 'static,
+    {
+        tokio::spawn(async move {
+            let mut ticker =
+                tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+            loop {
+                ticker.tick().await;
+                match self.repair().await {
+                    Ok(report) if !report.is_consistent() => {
+                        warn!(
+                            ?report,
+                            "Replica repair finished but members are still inconsistent"
+                        )
+                    }
+                    Ok(_) => (),
+                    Err(e) => warn!(error = %e, "Unable to run replica repair"),
+                }
+            }
+        })
+    }
+
+    /// Combines the results of a write that was sent to both members. Errors for which
+    /// `already_done` returns true mean the member was already in the desired state, so they count
+    /// towards the quorum. If every member returned such an error, the primary's error is returned
+    fn combine<T>(
+        &self,
+        operation: &str,
+        primary: Result<T>,
+        secondary: Result<T>,
+        already_done: fn(&ProviderError) -> bool,
+    ) -> Result<T> {
+        combine_with_quorum(
+            self.write_quorum,
+            operation,
+            primary,
+            secondary,
+            already_done,
+        )
+    }
+}
+
+/// Same as [`ReplicatedProvider::combine`], but with the given quorum instead of the configured one
+fn combine_with_quorum<T>(
+    quorum: usize,
+    operation: &str,
+    primary: Result<T>,
+    secondary: Result<T>,
+    already_done: fn(&ProviderError) -> bool,
+) -> Result<T> {
+    let acked = |res: &Result<T>| match res {
+        Ok(_) => true,
+        Err(e) => already_done(e),
+    };
+    let acks = acked(&primary) as usize + acked(&secondary) as usize;
+    if acks < quorum {
+        warn!(operation, acks, quorum, "Write did not reach quorum");
+        // At least one of the members failed, so return its error
+        return match (primary, secondary) {
+            (Err(e), _) if !already_done(&e) => Err(e),
+            (_, Err(e)) => Err(e),
+            (Err(e), _) => Err(e),
+            // This is impossible as the quorum is at least 1
+            (Ok(v), Ok(_)) => Ok(v),
+        };
+    }
+
+    for (member, res) in [("primary", &primary), ("secondary", &secondary)] {
+        if let Err(e) = res {
+            if !already_done(e) {
+                warn!(operation, member, error = %e, "Write to replica failed. It will be copied by the next repair");
+            }
+        }
+    }
+    match (primary, secondary) {
+        (Ok(v), _) | (_, Ok(v)) => Ok(v),
+        (Err(e), _) => Err(e),
+    }
+}
+
+/// Returns whether a read that failed with the given error should be retried against the next
+/// member
+fn should_fail_over(e: &ProviderError) -> bool {
+    matches!(e, ProviderError::NotFound | ProviderError::Io(_))
+}
+
+fn is_exists(e: &ProviderError) -> bool {
+    matches!(e, ProviderError::Exists)
+}
+
+fn is_not_found(e: &ProviderError) -> bool {
+    matches!(e, ProviderError::NotFound)
+}
+
+/// Runs the given read against the primary member, failing over to the secondary member if needed
+macro_rules! fail_over {
+    ($self:ident, $member:ident => $read:expr) => {{
+        let res = {
+            let $member = &$self.primary;
+            $read.await
+        };
+        match res {
+            Err(e) if should_fail_over(&e) => {
+                debug!(error = %e, "Read from primary failed, trying secondary");
+                let $member = &$self.secondary;
+                $read.await
+            }
+            res => res,
+        }
+    }};
+}
+
+#[async_trait::async_trait]
+impl<A, B> Provider for ReplicatedProvider<A, B>
+where
+    A: Provider + Send + Sync,
+    B: Provider + Send + Sync,
+{
+    #[instrument(level = "trace", skip(self, inv))]
+    async fn create_invoice<I>(&self, inv: I) -> Result<(crate::Invoice, Vec<crate::Label>)>
+    where
+        I: Signed + Verified + Send + Sync,
+    {
+        // The invoice has already been verified and signed, so each member gets a copy as is
+        let inv = inv.signed();
+        let (primary, secondary) = futures::join!(
+            self.primary
+                .create_invoice(NoopSigned(NoopVerified(inv.clone()))),
+            self.secondary
+                .create_invoice(NoopSigned(NoopVerified(inv.clone()))),
+        );
+        // A parcel missing from either member needs to be uploaded so it can be written to both
+        let mut missing: Vec<crate::Label> = Vec::new();
+        for (_, labels) in [&primary, &secondary].into_iter().flatten() {
+            for label in labels {
+                if !missing.iter().any(|l| l.sha256 == label.sha256) {
+                    missing.push(label.clone());
+                }
+            }
+        }
+        let (created, _) = self.combine("create_invoice", primary, secondary, is_exists)?;
+        Ok((created, missing))
+    }
+
+    #[instrument(level = "trace", skip(self, id))]
+    async fn get_yanked_invoice<I>(&self, id: I) -> Result<crate::Invoice>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id = id.try_into().map_err(|e| e.into())?;
+        fail_over!(self, member => member.get_yanked_invoice(&parsed_id))
+    }
+
+    #[instrument(level = "trace", skip(self, id))]
+    async fn yank_invoice<I>(&self, id: I) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id = id.try_into().map_err(|e| e.into())?;
+        let (primary, secondary) = futures::join!(
+            self.primary.yank_invoice(&parsed_id),
+            self.secondary.yank_invoice(&parsed_id),
+        );
+        self.combine("yank_invoice", primary, secondary, |_| false)
+    }
+
+    #[instrument(level = "trace", skip(self, id))]
+    async fn delete_invoice<I>(&self, id: I) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        // Deletes have to reach both members, otherwise a repair would bring the invoice back
+        let parsed_id = id.try_into().map_err(|e| e.into())?;
+        let (primary, secondary) = futures::join!(
+            self.primary.delete_invoice(&parsed_id),
+            self.secondary.delete_invoice(&parsed_id),
+        );
+        combine_with_quorum(2, "delete_invoice", primary, secondary, is_not_found)
+    }
+
+    // Invoices that only made it to the secondary member aren't listed until they are repaired
+    #[instrument(level = "trace", skip(self))]
+    async fn list_invoices(
+        &self,
+        include_yanked: bool,
+    ) -> Result<Box<dyn Stream<Item = Result<Id>> + Unpin + Send + Sync>> {
+        fail_over!(self, member => member.list_invoices(include_yanked))
+    }
+
+    #[instrument(level = "trace", skip(self, bindle_id, data))]
+    async fn create_parcel<I, R, Bf>(&self, bindle_id: I, parcel_id: &str, data: R) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+        R: Stream<Item = std::io::Result<Bf>> + Unpin + Send + Sync + 'static,
+        Bf: bytes::Buf + Send,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        // The data can only be read once, so every chunk is sent to both members as it comes in
+        let (primary_tx, primary_rx) = mpsc::channel(CHUNK_BUFFER_SIZE);
+        let (secondary_tx, secondary_rx) = mpsc::channel(CHUNK_BUFFER_SIZE);
+        let feed = async move {
+            let mut data = data;
+            while let Some(res) = data.next().await {
+                let (first, second) = match res {
+                    Ok(mut buf) => {
+                        let chunk = buf.copy_to_bytes(buf.remaining());
+                        (Ok(chunk.clone()), Ok(chunk))
+                    }
+                    Err(e) => (Err(std::io::Error::new(e.kind(), e.to_string())), Err(e)),
+                };
+                let stop = first.is_err();
+                // A member that has stopped reading has already failed, but the other member
+                // should still get all of the data
+                let sent_primary = primary_tx.send(first).await.is_ok();
+                let sent_secondary = secondary_tx.send(second).await.is_ok();
+                if stop || !(sent_primary || sent_secondary) {
+                    break;
+                }
+            }
+        };
+        let (_, primary, secondary) = futures::join!(
+            feed,
+            self.primary
+                .create_parcel(&parsed_id, parcel_id, ReceiverStream::new(primary_rx)),
+            self.secondary
+                .create_parcel(&parsed_id, parcel_id, ReceiverStream::new(secondary_rx)),
+        );
+        self.combine("create_parcel", primary, secondary, is_exists)
+    }
+
+    #[instrument(level = "trace", skip(self, bindle_id))]
+    async fn get_parcel<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        fail_over!(self, member => member.get_parcel(&parsed_id, parcel_id))
+    }
+
+    #[instrument(level = "trace", skip(self, bindle_id))]
+    async fn get_parcel_range<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+        range: Range<u64>,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        fail_over!(self, member => member.get_parcel_range(&parsed_id, parcel_id, range.clone()))
+    }
+
+    // Resumable uploads are written to the primary member only, as the chunks of an upload can
+    // arrive over multiple requests. The finished parcel is then copied to the secondary member
+    #[instrument(level = "trace", skip(self, bindle_id))]
+    async fn start_parcel_upload<I>(&self, bindle_id: I, parcel_id: &str) -> Result<u64>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.primary.start_parcel_upload(bindle_id, parcel_id).await
+    }
+
+    #[instrument(level = "trace", skip(self, bindle_id))]
+    async fn parcel_upload_offset<I>(&self, bindle_id: I, parcel_id: &str) -> Result<u64>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.primary
+            .parcel_upload_offset(bindle_id, parcel_id)
+            .await
+    }
+
+    #[instrument(level = "trace", skip(self, bindle_id, data))]
+    async fn write_parcel_chunk<I, R, Bf>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+        offset: u64,
+        data: R,
+    ) -> Result<u64>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+        R: Stream<Item = std::io::Result<Bf>> + Unpin + Send + Sync + 'static,
+        Bf: bytes::Buf + Send,
+    {
+        self.primary
+            .write_parcel_chunk(bindle_id, parcel_id, offset, data)
+            .await
+    }
+
+    #[instrument(level = "trace", skip(self, bindle_id))]
+    async fn finish_parcel_upload<I>(&self, bindle_id: I, parcel_id: &str) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        self.primary
+            .finish_parcel_upload(&parsed_id, parcel_id)
+            .await?;
+        debug!("Copying finished upload to secondary");
+        let copied = match self.primary.get_parcel(&parsed_id, parcel_id).await {
+            Ok(stream) => {
+                let stream =
+                    stream.map(|res| res.map_err(|e| std::io::Error::other(e.to_string())));
+                self.secondary
+                    .create_parcel(&parsed_id, parcel_id, stream)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        self.combine("finish_parcel_upload", Ok(()), copied, is_exists)
+    }
+
+    #[instrument(level = "trace", skip(self, bindle_id))]
+    async fn abort_parcel_upload<I>(&self, bindle_id: I, parcel_id: &str) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.primary.abort_parcel_upload(bindle_id, parcel_id).await
+    }
+
+    #[instrument(level = "trace", skip(self, bindle_id))]
+    async fn parcel_exists<I>(&self, bindle_id: I, parcel_id: &str) -> Result<bool>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        // A parcel that doesn't exist in the primary could still be in the secondary
+        let res = fail_over!(self, member => async {
+            match member.parcel_exists(&parsed_id, parcel_id).await {
+                Ok(false) => Err(ProviderError::NotFound),
+                res => res,
+            }
+        });
+        match res {
+            Err(ProviderError::NotFound) => Ok(false),
+            res => res,
+        }
+    }
+
+    async fn has_parcel(&self, parcel_id: &str) -> Result<bool> {
+        // A parcel that doesn't exist in the primary could still be in the secondary
+        let res = fail_over!(self, member => async {
+            match member.has_parcel(parcel_id).await {
+                Ok(false) => Err(ProviderError::NotFound),
+                res => res,
+            }
+        });
+        match res {
+            Err(ProviderError::NotFound) => Ok(false),
+            res => res,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "providers"))]
+mod test {
+    use super::*;
+    use crate::provider::file::FileProvider;
+    use crate::search::NoopEngine;
+    use crate::testing;
+
+    use tokio::io::AsyncReadExt;
+    use tokio_util::codec::{BytesCodec, FramedRead};
+    use tokio_util::io::StreamReader;
+
+    async fn read_parcel<P: Provider + Sync>(store: &P, id: &Id, parcel_id: &str) -> Vec<u8> {
+        let stream = store.get_parcel(id, parcel_id).await.expect("get parcel");
+        let mut data = Vec::new();
+        StreamReader::new(stream.map(|res| res.map_err(std::io::Error::other)))
+            .read_to_end(&mut data)
+            .await
+            .expect("read parcel");
+        data
+    }
+
+    #[tokio::test]
+    async fn test_should_replicate_and_fail_over() {
+        let primary_dir = tempfile::tempdir().unwrap();
+        let secondary_dir = tempfile::tempdir().unwrap();
+        let primary = FileProvider::new(primary_dir.path(), NoopEngine::default()).await;
+        let secondary = FileProvider::new(secondary_dir.path(), NoopEngine::default()).await;
+        let store = ReplicatedProvider::new(primary.clone(), secondary.clone());
+
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let id = scaffold.invoice.bindle.id.clone();
+        let (_, missing) = store
+            .create_invoice(NoopSigned(NoopVerified(scaffold.invoice.clone())))
+            .await
+            .expect("create invoice");
+        assert_eq!(missing.len(), scaffold.parcel_files.len());
+        for parcel in scaffold.parcel_files.values() {
+            store
+                .create_parcel(
+                    &id,
+                    &parcel.sha,
+                    FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+                )
+                .await
+                .expect("create parcel");
+        }
+        store.yank_invoice(&id).await.expect("yank invoice");
+
+        for parcel in scaffold.parcel_files.values() {
+            assert_eq!(read_parcel(&primary, &id, &parcel.sha).await, parcel.data);
+            assert_eq!(read_parcel(&secondary, &id, &parcel.sha).await, parcel.data);
+        }
+        for member in [primary_dir.path(), secondary_dir.path()] {
+            let member = FileProvider::new(member, NoopEngine::default()).await;
+            assert!(matches!(
+                member.get_invoice(&id).await,
+                Err(ProviderError::Yanked)
+            ));
+        }
+
+        // Reads should fail over to the secondary if the primary lost its data
+        let parcel = scaffold.parcel_files.values().next().unwrap();
+        std::fs::remove_dir_all(primary.parcel_path(&parcel.sha)).unwrap();
+        assert!(store.parcel_exists(&id, &parcel.sha).await.unwrap());
+        assert_eq!(read_parcel(&store, &id, &parcel.sha).await, parcel.data);
+
+        // A repair should copy the parcel back to the primary
+        let report = store.repair().await.expect("repair");
+        assert!(report.is_consistent(), "Report: {:?}", report);
+        assert_eq!(report.to_primary.copied_parcels, 1);
+        assert_eq!(report.to_secondary.copied_parcels, 0);
+        assert_eq!(read_parcel(&primary, &id, &parcel.sha).await, parcel.data);
+    }
+
+    #[tokio::test]
+    async fn test_should_respect_write_quorum() {
+        let primary_dir = tempfile::tempdir().unwrap();
+        let broken_dir = tempfile::tempdir().unwrap();
+        let broken = broken_dir.path().join("store");
+        let primary = FileProvider::new(primary_dir.path(), NoopEngine::default()).await;
+        let secondary = FileProvider::new(&broken, NoopEngine::default()).await;
+        // A member whose root was replaced by a file can't store anything
+        std::fs::remove_dir_all(&broken).unwrap();
+        std::fs::write(&broken, b"").unwrap();
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+
+        let store = ReplicatedProvider::new(primary.clone(), secondary.clone());
+        assert!(store
+            .create_invoice(NoopSigned(NoopVerified(scaffold.invoice.clone())))
+            .await
+            .is_err());
+
+        // The invoice was still written to the primary by the last attempt, so retrying with a
+        // quorum of one should behave like a single provider that already has it
+        let store = store.with_write_quorum(1);
+        assert!(matches!(
+            store
+                .create_invoice(NoopSigned(NoopVerified(scaffold.invoice.clone())))
+                .await,
+            Err(ProviderError::Exists)
+        ));
+        let id = &scaffold.invoice.bindle.id;
+        let parcel = scaffold.parcel_files.values().next().unwrap();
+        store
+            .create_parcel(
+                id,
+                &parcel.sha,
+                FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+            )
+            .await
+            .expect("create parcel with a quorum of one");
+        assert_eq!(read_parcel(&store, id, &parcel.sha).await, parcel.data);
+    }
+
+    #[tokio::test]
+    async fn test_should_not_repair_deleted_invoices() {
+        let primary_dir = tempfile::tempdir().unwrap();
+        let secondary_dir = tempfile::tempdir().unwrap();
+        let primary = FileProvider::new(primary_dir.path(), NoopEngine::default()).await;
+        let secondary = FileProvider::new(secondary_dir.path(), NoopEngine::default()).await;
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let id = &scaffold.invoice.bindle.id;
+
+        let store =
+            ReplicatedProvider::new(primary.clone(), secondary.clone()).with_write_quorum(1);
+        store
+            .create_invoice(NoopSigned(NoopVerified(scaffold.invoice.clone())))
+            .await
+            .expect("create invoice");
+
+        // The secondary can still be read, but can't record any changes
+        let generation = secondary_dir.path().join("generation");
+        let _ = std::fs::remove_file(&generation);
+        std::fs::create_dir(&generation).unwrap();
+        assert!(
+            store.delete_invoice(id).await.is_err(),
+            "A delete that only reached one member should fail, even with a quorum of one"
+        );
+
+        std::fs::remove_dir(&generation).unwrap();
+        store
+            .delete_invoice(id)
+            .await
+            .expect("Retrying the delete should succeed");
+        store.repair().await.expect("repair");
+        assert!(matches!(
+            primary.get_yanked_invoice(id).await,
+            Err(ProviderError::NotFound)
+        ));
+        assert!(matches!(
+            secondary.get_yanked_invoice(id).await,
+            Err(ProviderError::NotFound)
+        ));
+    }
+}
@@ -0,0 +1,438 @@
+//! Retention policies that automatically yank or delete old versions of bindles.
+//!
+//! A policy is a list of [`RetentionRule`]s. Each rule selects versions by bindle name (using a
+//! glob) and by semver properties, and then expires the versions it selects that go over its
+//! limits. A rule can keep only the newest versions (by semver ordering) of each bindle name, and
+//! can expire versions older than a maximum age. The age of a version is based on the earliest
+//! `at` timestamp of its signatures, so versions without signatures are never expired by age.
+//!
+//! Expired versions are yanked by default, or permanently deleted if the rule says so. Yanked
+//! versions don't count towards the limits of a rule that yanks, but do for a rule that deletes.
+//! If more than one rule expires the same version, deleting it takes precedence over yanking it.
+//! Policies are applied with [`apply_retention`], usually from a background task.
+//!
+//! In a server config file, rules look like this:
+//!
+//! ```toml
+//! [[retention]]
+//! name = "example.com/ci/*"
+//! prerelease = true
+//! keep_latest = 20
+//! max_age_days = 30
+//! action = "delete"
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio_stream::StreamExt;
+use tracing::{debug, info, instrument, warn};
+
+use crate::provider::{Provider, ProviderError, Result};
+use crate::Id;
+
+/// The default amount of time between runs of a retention policy
+pub const DEFAULT_RETENTION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// What to do with a version once a rule expires it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RetentionAction {
+    /// Yank the version so it can no longer be fetched, but keep it stored
+    #[default]
+    Yank,
+    /// Permanently delete the version. Its parcels are removed by the next garbage collection
+    Delete,
+}
+
+/// A single rule of a retention policy. See the [module documentation](self) for more details
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetentionRule {
+    /// A glob matched against bindle names. `*` matches any number of characters (including `/`)
+    /// and `?` matches a single character
+    pub name: String,
+    /// If set, only pre-release versions (if true) or only release versions (if false) match
+    pub prerelease: Option<bool>,
+    /// If set, only versions with this major version match
+    pub major: Option<u64>,
+    /// If set, only this many of the newest matching versions of each bindle name are kept
+    pub keep_latest: Option<usize>,
+    /// If set, matching versions signed more than this many days ago are expired
+    pub max_age_days: Option<u64>,
+    #[serde(default)]
+    pub action: RetentionAction,
+}
+
+impl RetentionRule {
+    /// Checks that the rule has a name and at least one limit
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.name.is_empty() {
+            anyhow::bail!("Retention rule names must not be empty");
+        }
+        if self.keep_latest.is_none() && self.max_age_days.is_none() {
+            anyhow::bail!(
+                "Retention rule for {} must set keep_latest or max_age_days",
+                self.name
+            );
+        }
+        Ok(())
+    }
+
+    fn matches(&self, id: &Id) -> bool {
+        let version = id.version();
+        glob_match(&self.name, id.name())
+            && self
+                .prerelease
+                .is_none_or(|pre| pre != version.pre.is_empty())
+            && self.major.is_none_or(|major| major == version.major)
+    }
+}
+
+/// Options for applying a retention policy
+#[derive(Debug, Clone)]
+pub struct RetentionOptions {
+    /// If true, nothing will be yanked or deleted, but the returned report will contain
+    /// everything that would have been
+    pub dry_run: bool,
+    /// The time that ages are calculated from, in seconds since the Unix epoch
+    pub now: u64,
+}
+
+impl Default for RetentionOptions {
+    fn default() -> Self {
+        RetentionOptions {
+            dry_run: false,
+            now: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// A report of everything done while applying a retention policy
+#[derive(Debug, Clone, Default)]
+pub struct RetentionReport {
+    /// Whether or not this was a dry run. If true, nothing listed in this report was actually
+    /// yanked or deleted
+    pub dry_run: bool,
+    /// The number of invoices (including yanked invoices) checked against the rules
+    pub checked_invoices: usize,
+    /// The invoices that were yanked
+    pub yanked: Vec<Id>,
+    /// The invoices that were deleted
+    pub deleted: Vec<Id>,
+    /// The contents of the deleted invoices as they were right before they were deleted, in the
+    /// same order as `deleted`. This is empty for a dry run
+    pub deleted_invoices: Vec<crate::Invoice>,
+    /// A description of every error that occurred. Other invoices are still processed after an
+    /// error
+    pub failures: Vec<String>,
+}
+
+struct Version {
+    id: Id,
+    yanked: bool,
+    signed_at: Option<u64>,
+}
+
+/// Applies the given rules to every invoice in the store. See the [module documentation](self)
+/// for more details.
+///
+/// An error is only returned if the invoices in the store could not be listed. All other errors
+/// are recorded in the returned report
+#[instrument(level = "trace", skip(store, rules))]
+pub async fn apply_retention<P>(
+    store: &P,
+    rules: &[RetentionRule],
+    options: RetentionOptions,
+) -> Result<RetentionReport>
+where
+    P: Provider + Sync,
+{
+    let mut report = RetentionReport {
+        dry_run: options.dry_run,
+        ..Default::default()
+    };
+    if rules.is_empty() {
+        return Ok(report);
+    }
+    debug!(rules = rules.len(), "Applying retention policy");
+
+    let mut bindles: BTreeMap<String, Vec<Version>> = BTreeMap::new();
+    let mut ids = store.list_invoices(true).await?;
+    while let Some(res) = ids.next().await {
+        let id = match res {
+            Ok(id) => id,
+            Err(e) => {
+                report
+                    .failures
+                    .push(format!("Unable to read invoice: {}", e));
+                continue;
+            }
+        };
+        if !rules.iter().any(|r| glob_match(&r.name, id.name())) {
+            continue;
+        }
+        let inv = match store.get_yanked_invoice(&id).await {
+            Ok(inv) => inv,
+            // The invoice was deleted after it was listed
+            Err(ProviderError::NotFound) => continue,
+            Err(e) => {
+                report
+                    .failures
+                    .push(format!("Unable to read invoice {}: {}", id, e));
+                continue;
+            }
+        };
+        report.checked_invoices += 1;
+        bindles
+            .entry(id.name().to_owned())
+            .or_default()
+            .push(Version {
+                signed_at: inv.signature.iter().flatten().map(|s| s.at).min(),
+                yanked: inv.yanked.unwrap_or(false),
+                id,
+            });
+    }
+
+    let mut expired: HashMap<Id, RetentionAction> = HashMap::new();
+    for rule in rules {
+        for versions in bindles.values_mut() {
+            let mut matching: Vec<&Version> = versions
+                .iter()
+                .filter(|v| rule.matches(&v.id))
+                .filter(|v| rule.action == RetentionAction::Delete || !v.yanked)
+                .collect();
+            // Newest first
+            matching.sort_by(|a, b| b.id.version().cmp(a.id.version()));
+            for (i, version) in matching.into_iter().enumerate() {
+                let too_many = rule.keep_latest.is_some_and(|keep| i >= keep);
+                let too_old = match (rule.max_age_days, version.signed_at) {
+                    (Some(days), Some(at)) => {
+                        at.saturating_add(days.saturating_mul(SECONDS_PER_DAY)) < options.now
+                    }
+                    _ => false,
+                };
+                if too_many || too_old {
+                    let action = expired.entry(version.id.clone()).or_insert(rule.action);
+                    if rule.action == RetentionAction::Delete {
+                        *action = RetentionAction::Delete;
+                    }
+                }
+            }
+        }
+    }
+
+    let mut expired: Vec<_> = expired.into_iter().collect();
+    expired.sort_by_key(|(id, _)| id.to_string());
+    for (id, action) in expired {
+        if !options.dry_run {
+            let res = match action {
+                RetentionAction::Yank => store.yank_invoice(&id).await,
+                RetentionAction::Delete => delete_invoice(store, &id)
+                    .await
+                    .map(|inv| report.deleted_invoices.push(inv)),
+            };
+            match res {
+                Ok(_) => debug!(%id, ?action, "Applied retention policy to invoice"),
+                // The invoice was deleted after it was listed
+                Err(ProviderError::NotFound) => continue,
+                Err(e) => {
+                    warn!(%id, ?action, error = %e, "Unable to apply retention policy");
+                    report.failures.push(format!(
+                        "Unable to {} invoice {}: {}",
+                        match action {
+                            RetentionAction::Yank => "yank",
+                            RetentionAction::Delete => "delete",
+                        },
+                        id,
+                        e
+                    ));
+                    continue;
+                }
+            }
+        }
+        match action {
+            RetentionAction::Yank => report.yanked.push(id),
+            RetentionAction::Delete => report.deleted.push(id),
+        }
+    }
+    info!(
+        checked_invoices = report.checked_invoices,
+        yanked = report.yanked.len(),
+        deleted = report.deleted.len(),
+        dry_run = report.dry_run,
+        "Applied retention policy"
+    );
+    Ok(report)
+}
+
+/// Deletes the given invoice, returning it as it was right before it was deleted
+async fn delete_invoice<P: Provider + Sync>(store: &P, id: &Id) -> Result<crate::Invoice> {
+    let inv = store.get_yanked_invoice(id).await?;
+    store.delete_invoice(id).await?;
+    Ok(inv)
+}
+
+/// Matches text against a glob where `*` matches any number of characters and `?` matches a
+/// single character
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // The position of the last `*` in the pattern and the text position it was tried at, so we
+    // can backtrack and let it match one more character
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(all(test, feature = "providers"))]
+mod test {
+    use super::*;
+    use crate::provider::file::FileProvider;
+    use crate::search::NoopEngine;
+    use crate::signature::{Signature, SignatureRole};
+    use crate::testing;
+    use crate::verification::NoopVerified;
+    use crate::NoopSigned;
+
+    const NOW: u64 = 1_700_000_000;
+
+    async fn create(store: &FileProvider<NoopEngine>, id: &str, days_old: u64) {
+        let mut inv = testing::Scaffold::load("valid_v1").await.invoice;
+        inv.bindle.id = id.try_into().unwrap();
+        inv.parcel = None;
+        inv.group = None;
+        inv.signature = Some(vec![Signature {
+            by: "Test Runner".to_owned(),
+            signature: String::new(),
+            key: String::new(),
+            role: SignatureRole::Creator,
+            at: NOW - days_old * SECONDS_PER_DAY,
+        }]);
+        store
+            .create_invoice(NoopSigned(NoopVerified(inv)))
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("example.com/*", "example.com/foo/bar"));
+        assert!(glob_match("*/ci-?", "example.com/ci-1"));
+        assert!(glob_match("example.com/foo", "example.com/foo"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*foo*bar", "afoobbar"));
+        assert!(!glob_match("example.com/*", "example.org/foo"));
+        assert!(!glob_match("*/ci-?", "example.com/ci-10"));
+        assert!(!glob_match("foo", "foobar"));
+    }
+
+    #[tokio::test]
+    async fn test_apply_retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileProvider::new(dir.path(), NoopEngine::default()).await;
+        for i in 1..=4 {
+            create(&store, &format!("example.com/ci/1.0.0-ci.{}", i), 0).await;
+        }
+        create(&store, "example.com/ci/0.9.0-ci.1", 40).await;
+        create(&store, "example.com/ci/0.8.0", 100).await;
+        create(&store, "example.com/other/1.0.0-ci.1", 100).await;
+
+        let rules = vec![
+            RetentionRule {
+                name: "example.com/ci".to_owned(),
+                prerelease: Some(true),
+                major: None,
+                keep_latest: Some(3),
+                max_age_days: None,
+                action: RetentionAction::Yank,
+            },
+            RetentionRule {
+                name: "example.com/c?".to_owned(),
+                prerelease: Some(true),
+                major: Some(0),
+                keep_latest: None,
+                max_age_days: Some(30),
+                action: RetentionAction::Delete,
+            },
+        ];
+        let options = RetentionOptions {
+            dry_run: true,
+            now: NOW,
+        };
+
+        let report = apply_retention(&store, &rules, options.clone())
+            .await
+            .expect("Retention should succeed");
+        assert!(report.failures.is_empty(), "Report: {:?}", report);
+        // The other bindle doesn't match any rule, so it isn't checked
+        assert_eq!(6, report.checked_invoices);
+        let yanked: Vec<String> = report.yanked.iter().map(|id| id.to_string()).collect();
+        let deleted: Vec<String> = report.deleted.iter().map(|id| id.to_string()).collect();
+        assert_eq!(vec!["example.com/ci/1.0.0-ci.1"], yanked);
+        // The old pre-release is expired by both rules, and deleting takes precedence
+        assert_eq!(vec!["example.com/ci/0.9.0-ci.1"], deleted);
+        store
+            .get_invoice("example.com/ci/1.0.0-ci.1")
+            .await
+            .expect("Nothing should be yanked in a dry run");
+
+        let report = apply_retention(
+            &store,
+            &rules,
+            RetentionOptions {
+                dry_run: false,
+                ..options.clone()
+            },
+        )
+        .await
+        .expect("Retention should succeed");
+        assert!(report.failures.is_empty(), "Report: {:?}", report);
+        assert_eq!(1, report.deleted_invoices.len());
+        assert_eq!(report.deleted[0], report.deleted_invoices[0].bindle.id);
+        assert!(matches!(
+            store.get_invoice("example.com/ci/1.0.0-ci.1").await,
+            Err(ProviderError::Yanked)
+        ));
+        assert!(matches!(
+            store.get_yanked_invoice("example.com/ci/0.9.0-ci.1").await,
+            Err(ProviderError::NotFound)
+        ));
+        store
+            .get_invoice("example.com/ci/0.8.0")
+            .await
+            .expect("Release versions should not be expired");
+        store
+            .get_invoice("example.com/other/1.0.0-ci.1")
+            .await
+            .expect("Other bindles should not be expired");
+
+        // Yanked versions don't count towards the limit, so nothing else should be yanked
+        let report = apply_retention(&store, &rules, options)
+            .await
+            .expect("Retention should succeed");
+        assert!(report.yanked.is_empty(), "Report: {:?}", report);
+        assert!(report.deleted.is_empty(), "Report: {:?}", report);
+    }
+}
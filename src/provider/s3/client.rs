@@ -0,0 +1,553 @@
+//! A minimal client for the subset of the S3 REST API needed by the `S3Provider`. Requests are
+//! signed using [AWS Signature Version
+//! 4](https://docs.aws.amazon.com/general/latest/gr/signature-version-4.html) and use path style
+//! addressing so that they work against most S3-compatible services (such as MinIO)
+
+use std::ops::Range;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Body, Method, Response, StatusCode};
+use sha2::{Digest, Sha256};
+use tracing::{instrument, trace};
+use url::Url;
+
+use crate::provider::{ProviderError, Result};
+
+/// The SHA256 sum of an empty payload, used for signing requests without a body
+const EMPTY_PAYLOAD_SHA: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+const SIGNING_ALGORITHM: &str = "AWS4-HMAC-SHA256";
+const SERVICE_NAME: &str = "s3";
+const AMZ_DATE_HEADER: &str = "x-amz-date";
+const AMZ_CONTENT_SHA_HEADER: &str = "x-amz-content-sha256";
+
+/// Configuration for connecting to an S3-compatible object store
+#[derive(Clone, Debug)]
+pub struct S3Config {
+    /// The base URL of the object store (e.g. `https://s3.us-west-2.amazonaws.com` or
+    /// `http://localhost:9000` for a local MinIO instance)
+    pub endpoint: Url,
+    /// The name of the bucket in which to store bindles. This bucket must already exist
+    pub bucket: String,
+    /// The region of the bucket. Most S3-compatible services accept `us-east-1` if they do not
+    /// have a concept of regions
+    pub region: String,
+    /// The access key ID used for signing requests
+    pub access_key_id: String,
+    /// The secret access key used for signing requests
+    pub secret_access_key: String,
+    /// An optional prefix prepended to every object key. This allows multiple bindle stores to
+    /// share a single bucket
+    pub prefix: Option<String>,
+}
+
+impl S3Config {
+    /// Returns a new config for the given endpoint and bucket, loading the region and credentials
+    /// from the standard `AWS_REGION`, `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY` environment
+    /// variables. The region defaults to `us-east-1` if it is not set
+    pub fn from_env(endpoint: &str, bucket: &str) -> anyhow::Result<Self> {
+        let access_key_id = std::env::var("AWS_ACCESS_KEY_ID")
+            .map_err(|_| anyhow::anyhow!("AWS_ACCESS_KEY_ID must be set"))?;
+        let secret_access_key = std::env::var("AWS_SECRET_ACCESS_KEY")
+            .map_err(|_| anyhow::anyhow!("AWS_SECRET_ACCESS_KEY must be set"))?;
+        Ok(S3Config {
+            endpoint: Url::parse(endpoint)?,
+            bucket: bucket.to_owned(),
+            region: std::env::var("AWS_REGION").unwrap_or_else(|_| String::from("us-east-1")),
+            access_key_id,
+            secret_access_key,
+            prefix: None,
+        })
+    }
+}
+
+/// The result of a single page of a `ListObjectsV2` request
+pub(crate) struct ListPage {
+    pub keys: Vec<String>,
+    pub continuation_token: Option<String>,
+}
+
+/// A thin S3 client that signs and sends requests for single objects
+#[derive(Clone)]
+pub(crate) struct S3Client {
+    http: reqwest::Client,
+    config: S3Config,
+}
+
+impl S3Client {
+    pub(crate) fn new(config: S3Config) -> Result<Self> {
+        let http = reqwest::Client::builder()
+            .build()
+            .map_err(|e| ProviderError::Other(format!("Unable to build S3 client: {}", e)))?;
+        Ok(S3Client { http, config })
+    }
+
+    /// Returns the full object key for the given key, including any configured prefix
+    pub(crate) fn object_key(&self, key: &str) -> String {
+        match self.config.prefix.as_deref() {
+            Some(p) if !p.is_empty() => format!("{}/{}", p.trim_end_matches('/'), key),
+            _ => key.to_owned(),
+        }
+    }
+
+    /// Uploads an object. If `only_if_absent` is set, the upload will fail with
+    /// `ProviderError::Exists` if an object already exists with that key
+    #[instrument(level = "trace", skip(self, body))]
+    pub(crate) async fn put_object(
+        &self,
+        key: &str,
+        body: impl Into<Body> + std::fmt::Debug,
+        content_length: u64,
+        payload_sha: &str,
+        only_if_absent: bool,
+    ) -> Result<()> {
+        let mut headers = HeaderMap::new();
+        headers.insert(reqwest::header::CONTENT_LENGTH, content_length.into());
+        if only_if_absent {
+            headers.insert(
+                reqwest::header::IF_NONE_MATCH,
+                HeaderValue::from_static("*"),
+            );
+        }
+        let resp = self
+            .send(
+                Method::PUT,
+                key,
+                &[],
+                headers,
+                payload_sha,
+                Some(body.into()),
+            )
+            .await?;
+        match resp.status() {
+            s if s.is_success() => Ok(()),
+            StatusCode::PRECONDITION_FAILED | StatusCode::CONFLICT => Err(ProviderError::Exists),
+            _ => Err(error_from_response(resp).await),
+        }
+    }
+
+    /// Fetches an object, returning `ProviderError::NotFound` if it does not exist
+    #[instrument(level = "trace", skip(self))]
+    pub(crate) async fn get_object(&self, key: &str) -> Result<Response> {
+        let resp = self
+            .send(
+                Method::GET,
+                key,
+                &[],
+                HeaderMap::new(),
+                EMPTY_PAYLOAD_SHA,
+                None,
+            )
+            .await?;
+        match resp.status() {
+            s if s.is_success() => Ok(resp),
+            StatusCode::NOT_FOUND => Err(ProviderError::NotFound),
+            _ => Err(error_from_response(resp).await),
+        }
+    }
+
+    /// Fetches the given byte range of an object (with an exclusive end), returning
+    /// `ProviderError::NotFound` if it does not exist. Callers must check for a `206 Partial
+    /// Content` status, as S3 compatible stores may ignore the range and return the whole object. A
+    /// range starting past the end of the object returns a `416 Range Not Satisfiable` response
+    #[instrument(level = "trace", skip(self))]
+    pub(crate) async fn get_object_range(&self, key: &str, range: Range<u64>) -> Result<Response> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            reqwest::header::RANGE,
+            header_value(&format!("bytes={}-{}", range.start, range.end - 1))?,
+        );
+        let resp = self
+            .send(Method::GET, key, &[], headers, EMPTY_PAYLOAD_SHA, None)
+            .await?;
+        match resp.status() {
+            s if s.is_success() || s == StatusCode::RANGE_NOT_SATISFIABLE => Ok(resp),
+            StatusCode::NOT_FOUND => Err(ProviderError::NotFound),
+            _ => Err(error_from_response(resp).await),
+        }
+    }
+
+    /// Checks whether the given object exists without fetching its data
+    #[instrument(level = "trace", skip(self))]
+    pub(crate) async fn object_exists(&self, key: &str) -> Result<bool> {
+        let resp = self
+            .send(
+                Method::HEAD,
+                key,
+                &[],
+                HeaderMap::new(),
+                EMPTY_PAYLOAD_SHA,
+                None,
+            )
+            .await?;
+        match resp.status() {
+            s if s.is_success() => Ok(true),
+            StatusCode::NOT_FOUND => Ok(false),
+            _ => Err(error_from_response(resp).await),
+        }
+    }
+
+    /// Deletes an object. Deleting an object that does not exist is not an error
+    #[instrument(level = "trace", skip(self))]
+    pub(crate) async fn delete_object(&self, key: &str) -> Result<()> {
+        let resp = self
+            .send(
+                Method::DELETE,
+                key,
+                &[],
+                HeaderMap::new(),
+                EMPTY_PAYLOAD_SHA,
+                None,
+            )
+            .await?;
+        match resp.status() {
+            s if s.is_success() => Ok(()),
+            StatusCode::NOT_FOUND => Ok(()),
+            _ => Err(error_from_response(resp).await),
+        }
+    }
+
+    /// Lists a single page of object keys starting with the given prefix. The returned keys have
+    /// any configured prefix stripped so they can be passed directly back into this client
+    #[instrument(level = "trace", skip(self))]
+    pub(crate) async fn list_objects(
+        &self,
+        prefix: &str,
+        continuation_token: Option<&str>,
+    ) -> Result<ListPage> {
+        let full_prefix = self.object_key(prefix);
+        let mut query = vec![
+            ("list-type", "2".to_owned()),
+            ("prefix", full_prefix.clone()),
+        ];
+        if let Some(token) = continuation_token {
+            query.push(("continuation-token", token.to_owned()));
+        }
+        let resp = self
+            .send(
+                Method::GET,
+                "",
+                &query,
+                HeaderMap::new(),
+                EMPTY_PAYLOAD_SHA,
+                None,
+            )
+            .await?;
+        if !resp.status().is_success() {
+            return Err(error_from_response(resp).await);
+        }
+        let body = resp.text().await.map_err(map_reqwest_error)?;
+        let strip = full_prefix.len() - prefix.len();
+        let keys = xml_values(&body, "Key")
+            .into_iter()
+            .map(|k| k.get(strip..).unwrap_or_default().to_owned())
+            .collect();
+        let truncated = xml_values(&body, "IsTruncated")
+            .first()
+            .map(|v| v == "true")
+            .unwrap_or(false);
+        let continuation_token = if truncated {
+            xml_values(&body, "NextContinuationToken").pop()
+        } else {
+            None
+        };
+        Ok(ListPage {
+            keys,
+            continuation_token,
+        })
+    }
+
+    /// Signs and sends a request for the given object key. An empty key addresses the bucket
+    /// itself
+    async fn send(
+        &self,
+        method: Method,
+        key: &str,
+        query: &[(&str, String)],
+        mut headers: HeaderMap,
+        payload_sha: &str,
+        body: Option<Body>,
+    ) -> Result<Response> {
+        let mut path = format!("/{}", uri_encode(&self.config.bucket, true));
+        if !key.is_empty() {
+            path.push('/');
+            path.push_str(&uri_encode(&self.object_key(key), false));
+        }
+        // Query parameters must be sorted for the canonical request
+        let mut encoded_query: Vec<(String, String)> = query
+            .iter()
+            .map(|(k, v)| (uri_encode(k, true), uri_encode(v, true)))
+            .collect();
+        encoded_query.sort();
+        let query_string = encoded_query
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let mut url = self.config.endpoint.clone();
+        // The endpoint could contain a path, so we need to preserve it
+        let base_path = url.path().trim_end_matches('/').to_owned();
+        let full_path = format!("{}{}", base_path, path);
+        url.set_path(&full_path);
+        url.set_query(if query_string.is_empty() {
+            None
+        } else {
+            Some(&query_string)
+        });
+
+        let host = match (url.host_str(), url.port()) {
+            (Some(h), Some(p)) => format!("{}:{}", h, p),
+            (Some(h), None) => h.to_owned(),
+            (None, _) => {
+                return Err(ProviderError::Other(
+                    "S3 endpoint does not contain a host".to_owned(),
+                ))
+            }
+        };
+
+        let (amz_date, date) = amz_timestamps(SystemTime::now());
+        headers.insert(
+            HeaderName::from_static(AMZ_DATE_HEADER),
+            header_value(&amz_date)?,
+        );
+        headers.insert(
+            HeaderName::from_static(AMZ_CONTENT_SHA_HEADER),
+            header_value(payload_sha)?,
+        );
+
+        // Build the canonical headers, which always include the host header
+        let mut canonical: Vec<(String, String)> = headers
+            .iter()
+            .map(|(k, v)| {
+                (
+                    k.as_str().to_lowercase(),
+                    v.to_str().unwrap_or_default().trim().to_owned(),
+                )
+            })
+            .collect();
+        canonical.push(("host".to_owned(), host));
+        canonical.sort();
+        let signed_headers = canonical
+            .iter()
+            .map(|(k, _)| k.as_str())
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_headers: String = canonical
+            .iter()
+            .map(|(k, v)| format!("{}:{}\n", k, v))
+            .collect();
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method.as_str(),
+            full_path,
+            query_string,
+            canonical_headers,
+            signed_headers,
+            payload_sha
+        );
+        trace!(%canonical_request, "Built canonical request for signing");
+
+        let scope = format!(
+            "{}/{}/{}/aws4_request",
+            date, self.config.region, SERVICE_NAME
+        );
+        let string_to_sign = format!(
+            "{}\n{}\n{}\n{:x}",
+            SIGNING_ALGORITHM,
+            amz_date,
+            scope,
+            Sha256::digest(canonical_request.as_bytes())
+        );
+
+        let signing_key = [
+            date.as_str(),
+            self.config.region.as_str(),
+            SERVICE_NAME,
+            "aws4_request",
+        ]
+        .iter()
+        .fold(
+            format!("AWS4{}", self.config.secret_access_key).into_bytes(),
+            |key, part| hmac_sha256(&key, part.as_bytes()),
+        );
+        let signature = hex_encode(&hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+
+        headers.insert(
+            reqwest::header::AUTHORIZATION,
+            header_value(&format!(
+                "{} Credential={}/{}, SignedHeaders={}, Signature={}",
+                SIGNING_ALGORITHM, self.config.access_key_id, scope, signed_headers, signature
+            ))?,
+        );
+
+        let req = self.http.request(method, url).headers(headers);
+        let req = match body {
+            Some(b) => req.body(b),
+            None => req,
+        };
+        req.send().await.map_err(map_reqwest_error)
+    }
+}
+
+fn map_reqwest_error(e: reqwest::Error) -> ProviderError {
+    // Transport level errors are surfaced as IO errors as they are generally transient
+    ProviderError::Io(std::io::Error::other(e))
+}
+
+async fn error_from_response(resp: Response) -> ProviderError {
+    let status = resp.status();
+    let body = resp.text().await.unwrap_or_default();
+    let code = xml_values(&body, "Code").pop().unwrap_or_default();
+    ProviderError::Other(format!(
+        "S3 request failed with status {}: {}",
+        status, code
+    ))
+}
+
+fn header_value(val: &str) -> Result<HeaderValue> {
+    HeaderValue::from_str(val)
+        .map_err(|e| ProviderError::Other(format!("Invalid header value for S3 request: {}", e)))
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    // HMAC can take a key of any size, so this will never fail
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex_encode(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// URI encodes the given string as specified by the SigV4 docs. Slashes are only encoded if
+/// `encode_slash` is set (they should not be encoded in object keys)
+fn uri_encode(input: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(input.len());
+    for b in input.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(b as char)
+            }
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+/// Returns the timestamp (`YYYYMMDDTHHMMSSZ`) and date (`YYYYMMDD`) strings used for signing
+fn amz_timestamps(time: SystemTime) -> (String, String) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let rem = secs % 86400;
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let timestamp = format!(
+        "{}T{:02}{:02}{:02}Z",
+        date,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    );
+    (timestamp, date)
+}
+
+/// Converts a number of days since the unix epoch into a (year, month, day) tuple. This is Howard
+/// Hinnant's `civil_from_days` algorithm, which avoids pulling in a full date library just to
+/// format a timestamp
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Extracts the text of every element with the given tag name. S3 list responses are simple
+/// enough that this avoids needing a full XML parser
+fn xml_values(body: &str, tag: &str) -> Vec<String> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let mut values = Vec::new();
+    let mut rest = body;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        match rest.find(&close) {
+            Some(end) => {
+                values.push(xml_unescape(&rest[..end]));
+                rest = &rest[end + close.len()..];
+            }
+            None => break,
+        }
+    }
+    values
+}
+
+fn xml_unescape(val: &str) -> String {
+    val.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_timestamps() {
+        // 2013-05-24T00:00:00Z is the date used in the AWS signing examples
+        let time = UNIX_EPOCH + std::time::Duration::from_secs(1369353600);
+        let (timestamp, date) = amz_timestamps(time);
+        assert_eq!("20130524T000000Z", timestamp);
+        assert_eq!("20130524", date);
+
+        let time = UNIX_EPOCH + std::time::Duration::from_secs(951782400 + 3661);
+        let (timestamp, _) = amz_timestamps(time);
+        assert_eq!("20000229T010101Z", timestamp, "Leap days should be handled");
+    }
+
+    #[test]
+    fn test_uri_encode() {
+        assert_eq!("invoices/abc123", uri_encode("invoices/abc123", false));
+        assert_eq!("invoices%2Fabc123", uri_encode("invoices/abc123", true));
+        assert_eq!("a%20b%2Bc~", uri_encode("a b+c~", true));
+    }
+
+    #[test]
+    fn test_signing_key() {
+        // Example taken from the AWS SigV4 documentation
+        let key = ["20120215", "us-east-1", "iam", "aws4_request"]
+            .iter()
+            .fold(
+                "AWS4wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY"
+                    .as_bytes()
+                    .to_vec(),
+                |key, part| hmac_sha256(&key, part.as_bytes()),
+            );
+        assert_eq!(
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d",
+            hex_encode(&key)
+        );
+    }
+
+    #[test]
+    fn test_xml_values() {
+        let body = "<ListBucketResult><IsTruncated>true</IsTruncated><Contents><Key>invoices/a</Key></Contents><Contents><Key>invoices/b&amp;c</Key></Contents><NextContinuationToken>abc</NextContinuationToken></ListBucketResult>";
+        assert_eq!(vec!["invoices/a", "invoices/b&c"], xml_values(body, "Key"));
+        assert_eq!(vec!["true"], xml_values(body, "IsTruncated"));
+        assert!(xml_values(body, "Missing").is_empty());
+    }
+}
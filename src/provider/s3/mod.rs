@@ -0,0 +1,801 @@
+//! An S3-compatible object storage backed `Provider` implementation.
+//!
+//! Invoices are stored as TOML objects under `invoices/INVOICE_SHA` and parcels are stored as
+//! objects under `parcels/PARCEL_SHA`, mirroring the [file
+//! layout](https://github.com/deislabs/bindle/blob/master/docs/file-layout.md) used by the
+//! `FileProvider`. Because all state lives in the object store, multiple bindle servers can share
+//! the same bucket.
+//!
+//! This provider does not cache invoices, as another server could yank an invoice at any time.
+//! If caching is needed, wrap it in one of the [caches](crate::cache).
+//!
+//! This will only be available if the `s3` feature is enabled
+
+mod client;
+
+use std::convert::TryInto;
+use std::ops::Range;
+
+use sha2::{Digest, Sha256};
+use tokio::fs::File;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio_stream::{Stream, StreamExt};
+use tokio_util::codec::{BytesCodec, FramedRead};
+use tracing::{debug, error, info, instrument, trace, warn};
+use tracing_futures::Instrument;
+
+use crate::provider::{Provider, ProviderError, Result};
+use crate::search::Search;
+use crate::verification::Verified;
+use crate::{Id, Signed};
+
+use client::S3Client;
+pub use client::S3Config;
+
+/// The key prefix for invoice objects
+const INVOICE_PREFIX: &str = "invoices/";
+/// The key prefix for parcel objects
+const PARCEL_PREFIX: &str = "parcels/";
+
+/// An S3-compatible object storage backend for storing and retrieving bindles and parcels.
+///
+/// An S3Provider needs a search engine implementation. When invoices are created or yanked, the
+/// index will be updated.
+#[derive(Clone)]
+pub struct S3Provider<T> {
+    client: S3Client,
+    index: T,
+}
+
+impl<T: Search + Send + Sync> S3Provider<T> {
+    /// Returns a new provider using the given configuration. The configured bucket must already
+    /// exist
+    pub async fn new(config: S3Config, index: T) -> anyhow::Result<Self> {
+        debug!(endpoint = %config.endpoint, bucket = %config.bucket, "Creating new S3 provider");
+        let s3 = S3Provider {
+            client: S3Client::new(config)?,
+            index,
+        };
+        debug!("warming index");
+        if let Err(e) = s3.warm_index().await {
+            warn!(error = %e, "Error warming index");
+        }
+        Ok(s3)
+    }
+
+    /// This warms the index by loading all of the invoices currently in the bucket
+    #[instrument(level = "trace", skip(self))]
+    async fn warm_index(&self) -> anyhow::Result<()> {
+        info!("Beginning index warm");
+        let mut total_indexed: u64 = 0;
+        let mut token: Option<String> = None;
+        loop {
+            let page = self
+                .client
+                .list_objects(INVOICE_PREFIX, token.as_deref())
+                .await?;
+            for key in page.keys {
+                let sha = key.trim_start_matches(INVOICE_PREFIX);
+                // A single bad record shouldn't keep every other invoice out of the index, so
+                // anything that can't be loaded is skipped
+                let invoice = match self.load_invoice(sha).await {
+                    Ok(inv) => inv,
+                    Err(e) => {
+                        error!(%key, error = %e, "Unable to load invoice, skipping");
+                        continue;
+                    }
+                };
+                let digest = invoice.canonical_name();
+                if sha != digest {
+                    error!(
+                        %sha,
+                        %digest,
+                        "Invoice SHA did not match computed digest, skipping. Delete this record"
+                    );
+                    continue;
+                }
+
+                if let Err(e) = self.index.index(&invoice).await {
+                    error!(invoice_id = %invoice.bindle.id, error = %e, "Error indexing invoice");
+                }
+                total_indexed += 1;
+            }
+            match page.continuation_token {
+                Some(t) => token = Some(t),
+                None => break,
+            }
+        }
+        debug!(total_indexed, "Warmed index");
+        Ok(())
+    }
+
+    async fn load_invoice(&self, invoice_id: &str) -> Result<crate::Invoice> {
+        load_invoice(&self.client, invoice_id).await
+    }
+
+    async fn store_invoice(&self, inv: &crate::Invoice, only_if_absent: bool) -> Result<()> {
+        let data = toml::to_vec(inv)?;
+        let sha = format!("{:x}", Sha256::digest(&data));
+        self.client
+            .put_object(
+                &invoice_key(&inv.canonical_name()),
+                data.clone(),
+                data.len() as u64,
+                &sha,
+                only_if_absent,
+            )
+            .await
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: crate::search::Search + Send + Sync> Provider for S3Provider<T> {
+    #[instrument(level = "trace", skip(self, invoice), fields(invoice_id = tracing::field::Empty))]
+    async fn create_invoice<I>(&self, invoice: I) -> Result<(crate::Invoice, Vec<crate::Label>)>
+    where
+        I: Signed + Verified + Send + Sync,
+    {
+        let inv = invoice.signed();
+        tracing::span::Span::current()
+            .record("invoice_id", tracing::field::display(&inv.bindle.id));
+        // It is illegal to create a yanked invoice.
+        if inv.yanked.unwrap_or(false) {
+            debug!(id = %inv.bindle.id, "Invoice being created is set to yanked");
+            return Err(ProviderError::CreateYanked);
+        }
+
+        trace!("Checking if invoice already exists in bucket");
+        if self
+            .client
+            .object_exists(&invoice_key(&inv.canonical_name()))
+            .await?
+        {
+            debug!("Invoice being created already exists in storage");
+            return Err(ProviderError::Exists);
+        }
+
+        // We still ask the store to reject the write if the object exists in case another server
+        // created it in the meantime
+        debug!("Storing invoice in bucket");
+        self.store_invoice(&inv, true).await?;
+
+        // Attempt to update the index. Right now, we log an error if the index update
+        // fails.
+        if let Err(e) = self.index.index(&inv).await {
+            error!(error = %e, "Error indexing new invoice");
+        }
+
+        // if there are no parcels, bail early
+        if inv.parcel.is_none() {
+            return Ok((inv, Vec::with_capacity(0)));
+        }
+
+        trace!("Checking for missing parcels listed in newly created invoice");
+        let zero_vec = Vec::with_capacity(0);
+        let missing = inv
+            .parcel
+            .as_ref()
+            .unwrap_or(&zero_vec)
+            .iter()
+            .map(|k| async move {
+                match self
+                    .client
+                    .object_exists(&parcel_key(&k.label.sha256))
+                    .await
+                {
+                    Ok(true) => None,
+                    _ => Some(k.label.clone()),
+                }
+            });
+
+        let labels = futures::future::join_all(missing)
+            .instrument(tracing::trace_span!("lookup_missing"))
+            .await
+            .into_iter()
+            .flatten()
+            .collect();
+        Ok((inv, labels))
+    }
+
+    #[instrument(level = "trace", skip(self, id), fields(id))]
+    async fn get_yanked_invoice<I>(&self, id: I) -> Result<crate::Invoice>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id: Id = id.try_into().map_err(|e| e.into())?;
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
+
+        debug!("Getting invoice from bucket");
+        self.load_invoice(&parsed_id.sha()).await
+    }
+
+    #[instrument(level = "trace", skip(self, id), fields(id))]
+    async fn yank_invoice<I>(&self, id: I) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id = id.try_into().map_err(|e| e.into())?;
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
+        trace!("Fetching invoice from storage");
+        let mut inv = self.get_yanked_invoice(&parsed_id).await?;
+        inv.yanked = Some(true);
+
+        debug!("Yanking invoice");
+
+        // Attempt to update the index. Right now, we log an error if the index update
+        // fails.
+        trace!("Indexing yanked invoice");
+        if let Err(e) = self.index.index(&inv).await {
+            error!(error = %e, "Error indexing yanked invoice");
+        }
+
+        debug!("Writing yanked invoice to bucket");
+        self.store_invoice(&inv, false).await
+    }
+
+    #[instrument(level = "trace", skip(self, id), fields(id))]
+    async fn delete_invoice<I>(&self, id: I) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id: Id = id.try_into().map_err(|e| e.into())?;
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
+
+        // S3 doesn't tell us whether a deleted object existed, so check first
+        let key = invoice_key(&parsed_id.sha());
+        if !self.client.object_exists(&key).await? {
+            return Err(ProviderError::NotFound);
+        }
+        debug!("Deleting invoice from bucket");
+        self.client.delete_object(&key).await?;
+
+        // Attempt to update the index. Right now, we log an error if the index update
+        // fails.
+        trace!("Removing deleted invoice from index");
+        if let Err(e) = self.index.remove(&parsed_id).await {
+            error!(error = %e, "Error removing deleted invoice from index");
+        }
+        Ok(())
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    async fn list_invoices(
+        &self,
+        include_yanked: bool,
+    ) -> Result<Box<dyn Stream<Item = Result<Id>> + Unpin + Send + Sync>> {
+        // Only the keys are listed up front. Each invoice is loaded as the stream is consumed
+        let mut keys = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let page = self
+                .client
+                .list_objects(INVOICE_PREFIX, token.as_deref())
+                .await?;
+            keys.extend(page.keys);
+            match page.continuation_token {
+                Some(t) => token = Some(t),
+                None => break,
+            }
+        }
+        debug!(total = keys.len(), "Listing invoices");
+
+        let client = self.client.clone();
+        let stream = tokio_stream::iter(keys)
+            .then(move |key| {
+                let client = client.clone();
+                async move {
+                    match load_invoice(&client, key.trim_start_matches(INVOICE_PREFIX)).await {
+                        // The invoice was deleted after we listed it
+                        Err(ProviderError::NotFound) => None,
+                        res => Some(res),
+                    }
+                }
+            })
+            .filter_map(move |res| match res? {
+                Ok(inv) if !include_yanked && inv.yanked.unwrap_or(false) => None,
+                Ok(inv) => Some(Ok(inv.bindle.id)),
+                Err(e) => Some(Err(e)),
+            });
+        Ok(Box::new(Box::pin(stream)))
+    }
+
+    #[instrument(level = "trace", skip(self, bindle_id, data), fields(id))]
+    async fn create_parcel<I, R, B>(&self, bindle_id: I, parcel_id: &str, data: R) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+        R: Stream<Item = std::io::Result<B>> + Unpin + Send + Sync + 'static,
+        B: bytes::Buf + Send,
+    {
+        debug!("Validating bindle -> parcel relationship");
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
+        let label = self.validate_parcel(parsed_id, parcel_id).await?;
+
+        let key = parcel_key(parcel_id);
+        if self.client.object_exists(&key).await? {
+            debug!("Parcel already exists in bucket");
+            return Err(ProviderError::Exists);
+        }
+
+        // Objects can't be validated before they are visible in the bucket, so we spool the data
+        // to a local temp file first while hashing it
+        trace!("Spooling parcel data to temporary file");
+        let tempfile = tokio::task::spawn_blocking(tempfile::tempfile)
+            .await
+            .map_err(|e| ProviderError::Other(e.to_string()))??;
+        let mut file = File::from_std(tempfile);
+        let mut hasher = Sha256::new();
+        let mut written: u64 = 0;
+        let mut data = data;
+        while let Some(chunk) = data.next().await {
+            let mut chunk = chunk?;
+            while chunk.has_remaining() {
+                let bytes = chunk.chunk();
+                let len = bytes.len();
+                hasher.update(bytes);
+                file.write_all(bytes).await?;
+                written += len as u64;
+                chunk.advance(len);
+            }
+        }
+        file.flush().await?;
+
+        trace!(bytes_written = written, "Wrote data to temporary file");
+        if written != label.size {
+            info!(
+                expected = label.size,
+                read_bytes = written,
+                "Attempted to insert parcel with incorrect size"
+            );
+            return Err(ProviderError::SizeMismatch);
+        }
+        let calculated = format!("{:x}", hasher.finalize());
+        if label.sha256 != calculated {
+            info!(expected_sha = %label.sha256, %calculated, "Mismatched SHA when creating parcel");
+            return Err(ProviderError::DigestMismatch);
+        }
+
+        file.seek(std::io::SeekFrom::Start(0)).await?;
+        debug!("Uploading parcel to bucket");
+        self.client
+            .put_object(
+                &key,
+                reqwest::Body::wrap_stream(FramedRead::new(file, BytesCodec::new())),
+                written,
+                &calculated,
+                true,
+            )
+            .instrument(tracing::trace_span!("parcel_data_upload"))
+            .await
+    }
+
+    #[instrument(level = "trace", skip(self, bindle_id), fields(id))]
+    async fn get_parcel<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        debug!("Validating bindle -> parcel relationship");
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
+        self.validate_parcel(parsed_id, parcel_id).await?;
+
+        debug!("Getting parcel from bucket");
+        let resp = self.client.get_object(&parcel_key(parcel_id)).await?;
+        Ok::<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>, _>(Box::new(
+            resp.bytes_stream()
+                .map(|res| res.map_err(|e| ProviderError::Io(std::io::Error::other(e)))),
+        ))
+    }
+
+    #[instrument(level = "trace", skip(self, bindle_id), fields(id))]
+    async fn get_parcel_range<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+        range: Range<u64>,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        debug!("Validating bindle -> parcel relationship");
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
+        self.validate_parcel(parsed_id, parcel_id).await?;
+
+        // An empty range can't be expressed as a range header
+        if range.start >= range.end {
+            return Ok(Box::new(tokio_stream::empty()));
+        }
+
+        debug!(
+            start = range.start,
+            end = range.end,
+            "Getting parcel range from bucket"
+        );
+        let resp = self
+            .client
+            .get_object_range(&parcel_key(parcel_id), range.clone())
+            .await?;
+        let status = resp.status();
+        let stream = resp
+            .bytes_stream()
+            .map(|res| res.map_err(|e| ProviderError::Io(std::io::Error::other(e))));
+        match status {
+            reqwest::StatusCode::PARTIAL_CONTENT => Ok(Box::new(stream)),
+            // The range starts past the end of the parcel, so there is nothing to return
+            reqwest::StatusCode::RANGE_NOT_SATISFIABLE => Ok(Box::new(tokio_stream::empty())),
+            // The store ignored the range and returned the whole parcel
+            _ => Ok(Box::new(crate::provider::slice_stream(stream, range))),
+        }
+    }
+
+    #[instrument(level = "trace", skip(self, bindle_id), fields(id))]
+    async fn parcel_exists<I>(&self, bindle_id: I, parcel_id: &str) -> Result<bool>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        debug!("Validating bindle -> parcel relationship");
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        tracing::Span::current().record("id", tracing::field::display(&parsed_id));
+        self.validate_parcel(parsed_id, parcel_id).await?;
+
+        debug!("Checking if parcel exists in bucket");
+        self.has_parcel(parcel_id).await
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    async fn has_parcel(&self, parcel_id: &str) -> Result<bool> {
+        // The ID is used to build an object key, so anything that isn't a SHA can't be let through
+        if !crate::provider::is_parcel_id(parcel_id) {
+            return Ok(false);
+        }
+        self.client.object_exists(&parcel_key(parcel_id)).await
+    }
+}
+
+fn invoice_key(invoice_id: &str) -> String {
+    format!("{}{}", INVOICE_PREFIX, invoice_id)
+}
+
+fn parcel_key(parcel_id: &str) -> String {
+    format!("{}{}", PARCEL_PREFIX, parcel_id)
+}
+
+async fn load_invoice(client: &S3Client, invoice_id: &str) -> Result<crate::Invoice> {
+    let resp = client.get_object(&invoice_key(invoice_id)).await?;
+    let raw = resp
+        .bytes()
+        .await
+        .map_err(|e| ProviderError::Io(std::io::Error::other(e)))?;
+    Ok(toml::from_slice(&raw)?)
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+    use super::*;
+    use crate::verification::NoopVerified;
+    use crate::{testing, NoopSigned};
+
+    use std::collections::BTreeMap;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io::AsyncReadExt;
+    use tokio_util::io::StreamReader;
+    use warp::http::StatusCode;
+    use warp::Filter;
+
+    type Bucket = Arc<Mutex<BTreeMap<String, bytes::Bytes>>>;
+
+    /// Starts a minimal in memory stand-in for an S3-compatible service, returning its address and
+    /// the underlying bucket contents. It only supports the operations used by the provider and
+    /// does not validate signatures, though it does check that requests are signed
+    async fn start_stand_in() -> (SocketAddr, Bucket) {
+        let bucket: Bucket = Arc::new(Mutex::new(BTreeMap::new()));
+        let state = bucket.clone();
+        let routes = warp::path("bindle")
+            .and(warp::path::tail())
+            .and(warp::method())
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::header::optional::<String>("if-none-match"))
+            .and(warp::query::<BTreeMap<String, String>>())
+            .and(warp::body::bytes())
+            .map(
+                move |tail: warp::path::Tail,
+                      method: warp::http::Method,
+                      auth: Option<String>,
+                      if_none_match: Option<String>,
+                      query: BTreeMap<String, String>,
+                      body: bytes::Bytes| {
+                    let mut objects = state.lock().unwrap();
+                    let key = tail.as_str().to_owned();
+                    let builder = warp::http::Response::builder();
+                    if !auth.unwrap_or_default().starts_with("AWS4-HMAC-SHA256 Credential=") {
+                        return builder.status(StatusCode::FORBIDDEN).body(Vec::new());
+                    }
+                    match method.as_str() {
+                        "GET" if key.is_empty() => {
+                            let prefix = query.get("prefix").cloned().unwrap_or_default();
+                            let contents: String = objects
+                                .keys()
+                                .filter(|k| k.starts_with(&prefix))
+                                .map(|k| format!("<Contents><Key>{}</Key></Contents>", k))
+                                .collect();
+                            let body = format!(
+                                "<ListBucketResult><IsTruncated>false</IsTruncated>{}</ListBucketResult>",
+                                contents
+                            );
+                            builder.body(body.into_bytes())
+                        }
+                        "GET" => match objects.get(&key) {
+                            Some(data) => builder.body(data.to_vec()),
+                            None => builder.status(StatusCode::NOT_FOUND).body(Vec::new()),
+                        },
+                        "HEAD" => match objects.contains_key(&key) {
+                            true => builder.body(Vec::new()),
+                            false => builder.status(StatusCode::NOT_FOUND).body(Vec::new()),
+                        },
+                        "DELETE" => {
+                            objects.remove(&key);
+                            builder.status(StatusCode::NO_CONTENT).body(Vec::new())
+                        }
+                        "PUT" => {
+                            if if_none_match.is_some() && objects.contains_key(&key) {
+                                return builder
+                                    .status(StatusCode::PRECONDITION_FAILED)
+                                    .body(Vec::new());
+                            }
+                            objects.insert(key, body);
+                            builder.body(Vec::new())
+                        }
+                        _ => builder
+                            .status(StatusCode::METHOD_NOT_ALLOWED)
+                            .body(Vec::new()),
+                    }
+                },
+            );
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (addr, bucket)
+    }
+
+    fn config(addr: SocketAddr) -> S3Config {
+        S3Config {
+            endpoint: format!("http://{}", addr).parse().unwrap(),
+            bucket: "bindle".to_owned(),
+            region: "us-east-1".to_owned(),
+            access_key_id: "minioadmin".to_owned(),
+            secret_access_key: "minioadmin".to_owned(),
+            prefix: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_should_create_yank_invoice() {
+        let (addr, bucket) = start_stand_in().await;
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let store = S3Provider::new(config(addr), crate::search::StrictEngine::default())
+            .await
+            .unwrap();
+
+        let signed = NoopSigned(NoopVerified(scaffold.invoice.clone()));
+        let (_, missing) = store.create_invoice(signed).await.unwrap();
+        assert_eq!(1, missing.len());
+
+        assert!(bucket
+            .lock()
+            .unwrap()
+            .contains_key(&invoice_key(&scaffold.invoice.canonical_name())));
+
+        // Creating the same invoice twice should fail
+        let signed = NoopSigned(NoopVerified(scaffold.invoice.clone()));
+        assert!(matches!(
+            store.create_invoice(signed).await,
+            Err(ProviderError::Exists)
+        ));
+
+        store
+            .yank_invoice(&scaffold.invoice.bindle.id)
+            .await
+            .unwrap();
+        let inv = store
+            .get_yanked_invoice(&scaffold.invoice.bindle.id)
+            .await
+            .unwrap();
+        assert!(inv.yanked.unwrap_or(false));
+        assert!(matches!(
+            store.get_invoice(&scaffold.invoice.bindle.id).await,
+            Err(ProviderError::Yanked)
+        ));
+
+        // Yanked invoices should only be listed when asked for
+        let listed: Vec<Id> = store
+            .list_invoices(false)
+            .await
+            .unwrap()
+            .collect::<Result<_>>()
+            .await
+            .unwrap();
+        assert!(listed.is_empty());
+        let listed: Vec<Id> = store
+            .list_invoices(true)
+            .await
+            .unwrap()
+            .collect::<Result<_>>()
+            .await
+            .unwrap();
+        assert_eq!(vec![scaffold.invoice.bindle.id.clone()], listed);
+
+        store
+            .delete_invoice(&scaffold.invoice.bindle.id)
+            .await
+            .unwrap();
+        assert!(bucket.lock().unwrap().is_empty());
+        assert!(matches!(
+            store.delete_invoice(&scaffold.invoice.bindle.id).await,
+            Err(ProviderError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_should_write_read_parcel() {
+        let (addr, _) = start_stand_in().await;
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let parcel = scaffold.parcel_files.get("parcel").unwrap();
+        let store = S3Provider::new(config(addr), crate::search::StrictEngine::default())
+            .await
+            .unwrap();
+
+        let signed = NoopSigned(NoopVerified(scaffold.invoice.clone()));
+        store.create_invoice(signed).await.unwrap();
+
+        store
+            .create_parcel(
+                &scaffold.invoice.bindle.id,
+                &parcel.sha,
+                FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+            )
+            .await
+            .expect("create parcel");
+
+        assert!(store
+            .parcel_exists(&scaffold.invoice.bindle.id, &parcel.sha)
+            .await
+            .unwrap());
+
+        let stream = store
+            .get_parcel(&scaffold.invoice.bindle.id, &parcel.sha)
+            .await
+            .expect("load parcel data");
+        let mut data = Vec::new();
+        StreamReader::new(stream.map(|res| res.map_err(std::io::Error::other)))
+            .read_to_end(&mut data)
+            .await
+            .unwrap();
+        assert_eq!(data, parcel.data);
+
+        // The stand-in ignores range headers, so this also checks that the whole object is sliced
+        let stream = store
+            .get_parcel_range(&scaffold.invoice.bindle.id, &parcel.sha, 2..5)
+            .await
+            .expect("load parcel range");
+        let mut data = Vec::new();
+        StreamReader::new(stream.map(|res| res.map_err(std::io::Error::other)))
+            .read_to_end(&mut data)
+            .await
+            .unwrap();
+        assert_eq!(data, parcel.data[2..5]);
+
+        // A second upload of the same parcel should be rejected
+        let err = store
+            .create_parcel(
+                &scaffold.invoice.bindle.id,
+                &parcel.sha,
+                FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+            )
+            .await
+            .expect_err("duplicate parcel should fail");
+        assert!(matches!(err, ProviderError::Exists));
+    }
+
+    #[tokio::test]
+    async fn test_should_reject_invalid_parcels() {
+        let (addr, bucket) = start_stand_in().await;
+        let mut scaffold = testing::Scaffold::load("valid_v1").await;
+        let parcel = scaffold.parcel_files.get("parcel").unwrap().clone();
+        let store = S3Provider::new(config(addr), crate::search::StrictEngine::default())
+            .await
+            .unwrap();
+
+        let mut parcels = scaffold.invoice.parcel.take().unwrap();
+        parcels[0].label.size = 100000;
+        scaffold.invoice.parcel = Some(parcels);
+        let signed = NoopSigned(NoopVerified(scaffold.invoice.clone()));
+        store.create_invoice(signed).await.unwrap();
+
+        let err = store
+            .create_parcel(
+                &scaffold.invoice.bindle.id,
+                &parcel.sha,
+                FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+            )
+            .await
+            .expect_err("Creating a parcel with invalid length should fail");
+        assert!(matches!(err, ProviderError::SizeMismatch));
+
+        // Now use the correct size but the wrong data
+        let mut scaffold = testing::Scaffold::load("valid_v1").await;
+        scaffold.invoice.bindle.id = "another.com/bindle/1.0.0".parse().unwrap();
+        let signed = NoopSigned(NoopVerified(scaffold.invoice.clone()));
+        store.create_invoice(signed).await.unwrap();
+        let mut bad_data = parcel.data.clone();
+        bad_data[0] = bad_data[0].wrapping_add(1);
+        let err = store
+            .create_parcel(
+                &scaffold.invoice.bindle.id,
+                &parcel.sha,
+                FramedRead::new(std::io::Cursor::new(bad_data), BytesCodec::new()),
+            )
+            .await
+            .expect_err("Creating a parcel with invalid data should fail");
+        assert!(matches!(err, ProviderError::DigestMismatch));
+
+        assert!(
+            !bucket
+                .lock()
+                .unwrap()
+                .contains_key(&parcel_key(&parcel.sha)),
+            "Invalid parcels should never be uploaded"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_should_warm_index() {
+        let (addr, bucket) = start_stand_in().await;
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let store = S3Provider::new(config(addr), crate::search::StrictEngine::default())
+            .await
+            .unwrap();
+        let signed = NoopSigned(NoopVerified(scaffold.invoice.clone()));
+        store.create_invoice(signed).await.unwrap();
+
+        // Bad records shouldn't keep the other invoices out of the index. They are listed before
+        // the real invoice, so this also checks that warming carries on after them
+        {
+            let mut objects = bucket.lock().unwrap();
+            objects.insert(
+                invoice_key("0000000000000000-garbage"),
+                bytes::Bytes::from("not an invoice"),
+            );
+            let data = toml::to_vec(&scaffold.invoice).unwrap();
+            objects.insert(
+                invoice_key("0000000000000000-mismatched"),
+                bytes::Bytes::from(data),
+            );
+        }
+
+        // A new provider pointing at the same bucket should find the existing invoice
+        let index = crate::search::StrictEngine::default();
+        S3Provider::new(config(addr), index.clone()).await.unwrap();
+        let matches = index
+            .query(
+                scaffold.invoice.bindle.id.name(),
+                "",
+                crate::search::SearchOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(1, matches.total);
+    }
+}
@@ -0,0 +1,614 @@
+//! A provider that keeps recently accessed parcels on a fast (hot) provider and moves parcels that
+//! haven't been accessed for a while to a slower and usually cheaper (cold) provider.
+//!
+//! Invoices are small, so they are stored in both tiers. New parcels are always written to the hot
+//! tier. Every time a parcel is read, the time of the access is recorded in a small
+//! [sled](https://github.com/spacejam/sled) database so it survives restarts. Running
+//! [`TieredProvider::demote`] copies every parcel that has been idle for longer than the
+//! configured idle time to the cold tier and removes it from the hot tier. Reading a parcel that is
+//! only in the cold tier copies it back to the hot tier (promoting it) before it is returned.
+//!
+//! The hot tier has to be able to remove parcels, which is what the [`Evictable`] trait is for.
+//!
+//! This will only be available if the `providers` feature is enabled
+
+use std::convert::TryInto;
+use std::ops::Range;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio_stream::{Stream, StreamExt};
+use tracing::{debug, info, instrument, trace, warn};
+
+use crate::provider::embedded::map_sled_error;
+use crate::provider::{Provider, ProviderError, Result};
+use crate::verification::{NoopVerified, Verified};
+use crate::{Id, NoopSigned, Signed};
+
+/// The default amount of time a parcel must go without being accessed before it is demoted to
+/// the cold tier
+pub const DEFAULT_IDLE_TIME: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+
+const ACCESS_TREE_NAME: &str = "parcel_access";
+
+/// A provider that can remove individual parcels from storage. Parcels are shared between
+/// bindles, so this is only meant for providers that hold a copy of data that is also stored
+/// somewhere else, such as the hot tier of a [`TieredProvider`]
+#[async_trait::async_trait]
+pub trait Evictable {
+    /// Removes the given parcel from storage. Returns `ProviderError::NotFound` if the parcel
+    /// isn't stored and `ProviderError::WriteInProgress` if the parcel is currently being written
+    async fn evict_parcel(&self, parcel_id: &str) -> Result<()>;
+}
+
+/// The access metadata stored for each parcel in the hot tier
+#[derive(Debug, Serialize, Deserialize)]
+struct AccessRecord {
+    /// The bindle the parcel was last accessed through. Parcels can only be fetched through a
+    /// bindle, so this is needed to copy the parcel to the cold tier
+    bindle_id: String,
+    /// The time of the last access in seconds since the UNIX epoch
+    last_access: u64,
+}
+
+/// A report of everything done during a demotion run
+#[derive(Debug, Clone, Default)]
+pub struct DemotionReport {
+    /// The number of parcels in the hot tier that were checked
+    pub checked_parcels: usize,
+    /// The SHAs of all parcels that were moved to the cold tier
+    pub demoted_parcels: Vec<String>,
+    /// Descriptions of any parcels that could not be demoted. They are retried on the next run
+    pub failures: Vec<String>,
+}
+
+/// A provider that moves parcels between a hot and a cold tier depending on when they were last
+/// accessed. See the [module documentation](self) for more details
+#[derive(Clone)]
+pub struct TieredProvider<H, C> {
+    hot: H,
+    cold: C,
+    access: sled::Tree,
+    idle_time: Duration,
+}
+
+impl<H, C> TieredProvider<H, C>
+where
+    H: Provider + Evictable + Send + Sync,
+    C: Provider + Send + Sync,
+{
+    /// Returns a new provider with the given tiers. Access metadata is stored in a database at
+    /// `metadata_path`, which must not be shared with any other provider. Parcels are demoted
+    /// after [`DEFAULT_IDLE_TIME`] by default
+    pub async fn new<P: AsRef<Path>>(hot: H, cold: C, metadata_path: P) -> anyhow::Result<Self> {
+        debug!(metadata_path = %metadata_path.as_ref().display(), "Creating new tiered provider");
+        let path = metadata_path.as_ref().to_owned();
+        let access = tokio::task::spawn_blocking(move || {
+            sled::open(path).and_then(|db| db.open_tree(ACCESS_TREE_NAME))
+        })
+        .await??;
+        Ok(TieredProvider {
+            hot,
+            cold,
+            access,
+            idle_time: DEFAULT_IDLE_TIME,
+        })
+    }
+
+    /// Sets how long a parcel must go without being accessed before it is demoted
+    pub fn with_idle_time(mut self, idle_time: Duration) -> Self {
+        self.idle_time = idle_time;
+        self
+    }
+
+    /// Moves every parcel in the hot tier that hasn't been accessed within the idle time to the
+    /// cold tier.
+    ///
+    /// An error is only returned if the access metadata could not be read. Parcels that could not
+    /// be demoted are recorded in the returned report
+    #[instrument(level = "trace", skip(self))]
+    pub async fn demote(&self) -> Result<DemotionReport> {
+        info!(idle_time = ?self.idle_time, "Beginning parcel demotion");
+        let cutoff = now().saturating_sub(self.idle_time.as_secs());
+        let mut report = DemotionReport::default();
+        for res in self.access.iter() {
+            let (key, raw) = res.map_err(map_sled_error)?;
+            report.checked_parcels += 1;
+            let parcel_id = String::from_utf8_lossy(&key).into_owned();
+            let record: AccessRecord = serde_cbor::from_slice(&raw)?;
+            if record.last_access > cutoff {
+                continue;
+            }
+            match self.demote_parcel(&record, &parcel_id).await {
+                Ok(()) => {
+                    // Only forget the parcel if it wasn't accessed while it was being demoted
+                    self.access
+                        .compare_and_swap(&key, Some(raw), None as Option<&[u8]>)
+                        .map_err(map_sled_error)?
+                        .ok();
+                    report.demoted_parcels.push(parcel_id);
+                }
+                Err(e) => {
+                    warn!(%parcel_id, error = %e, "Unable to demote parcel");
+                    report
+                        .failures
+                        .push(format!("Unable to demote parcel {}: {}", parcel_id, e));
+                }
+            }
+        }
+        info!(
+            checked_parcels = report.checked_parcels,
+            demoted_parcels = report.demoted_parcels.len(),
+            failures = report.failures.len(),
+            "Finished parcel demotion"
+        );
+        Ok(report)
+    }
+
+    /// Spawns a background task that runs a [`demote`](Self::demote) at the given interval,
+    /// starting after the first interval has elapsed. The task runs until the returned handle is
+    /// aborted
+    #[cfg(feature = "server")]
+    pub fn spawn_demotion(
+        self: std::sync::Arc<Self>,
+        interval: Duration,
+    ) -> tokio::task::JoinHandle<()>
+    where
+        H: 'static,
+        C: 'static,
+    {
+        tokio::spawn(async move {
+            let mut ticker =
+                tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = self.demote().await {
+                    warn!(error = %e, "Unable to run parcel demotion");
+                }
+            }
+        })
+    }
+
+    async fn demote_parcel(&self, record: &AccessRecord, parcel_id: &str) -> Result<()> {
+        let bindle_id: Id = record.bindle_id.parse()?;
+        if !self.cold.parcel_exists(&bindle_id, parcel_id).await? {
+            debug!(%bindle_id, %parcel_id, "Copying parcel to cold tier");
+            let stream = match self.hot.get_parcel(&bindle_id, parcel_id).await {
+                Ok(s) => s,
+                // Someone else already removed it, so there is nothing left to demote
+                Err(ProviderError::NotFound) => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            let data = stream.map(|res| res.map_err(std::io::Error::other));
+            match self.cold.create_parcel(&bindle_id, parcel_id, data).await {
+                Ok(()) | Err(ProviderError::Exists) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        debug!(%parcel_id, "Removing parcel from hot tier");
+        match self.hot.evict_parcel(parcel_id).await {
+            Ok(()) | Err(ProviderError::NotFound) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Records an access of the given parcel. Failing to record an access only means the parcel
+    /// may be demoted early, so errors are logged rather than returned
+    fn touch(&self, bindle_id: &Id, parcel_id: &str) {
+        let record = AccessRecord {
+            bindle_id: bindle_id.to_string(),
+            last_access: now(),
+        };
+        let res = serde_cbor::to_vec(&record)
+            .map_err(ProviderError::from)
+            .and_then(|raw| self.access.insert(parcel_id, raw).map_err(map_sled_error));
+        if let Err(e) = res {
+            warn!(%parcel_id, error = %e, "Unable to record parcel access");
+        }
+    }
+
+    /// Makes sure the hot tier has the given invoice, copying it (and its yanked status) from the
+    /// cold tier if needed. Invoices created before the tiers were set up only exist in the cold
+    /// tier
+    async fn ensure_hot_invoice(&self, bindle_id: &Id) -> Result<()> {
+        match self.hot.get_yanked_invoice(bindle_id).await {
+            Ok(_) => return Ok(()),
+            Err(ProviderError::NotFound) => (),
+            Err(e) => return Err(e),
+        }
+        trace!(%bindle_id, "Copying invoice to hot tier");
+        let inv = self.cold.get_yanked_invoice(bindle_id).await?;
+        let yanked = inv.yanked.unwrap_or(false);
+        match self.hot.create_invoice(NoopSigned(NoopVerified(inv))).await {
+            Ok(_) | Err(ProviderError::Exists) => (),
+            Err(e) => return Err(e),
+        }
+        if yanked {
+            self.hot.yank_invoice(bindle_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Makes sure a parcel is ready to be read, promoting it to the hot tier if it is only in the
+    /// cold tier. Returns true if the parcel should be read from the hot tier. If promotion fails,
+    /// the parcel is read from the cold tier instead
+    async fn prepare_read(&self, bindle_id: &Id, parcel_id: &str) -> Result<bool> {
+        match self.hot.parcel_exists(bindle_id, parcel_id).await {
+            Ok(true) => return Ok(true),
+            Ok(false) | Err(ProviderError::NotFound) => (),
+            Err(e) => return Err(e),
+        }
+        match self.promote(bindle_id, parcel_id).await {
+            Ok(()) => Ok(true),
+            Err(ProviderError::NotFound) => Err(ProviderError::NotFound),
+            Err(e) => {
+                warn!(%bindle_id, %parcel_id, error = %e, "Unable to promote parcel, reading from cold tier");
+                Ok(false)
+            }
+        }
+    }
+
+    async fn promote(&self, bindle_id: &Id, parcel_id: &str) -> Result<()> {
+        debug!(%bindle_id, %parcel_id, "Promoting parcel to hot tier");
+        let stream = self.cold.get_parcel(bindle_id, parcel_id).await?;
+        self.ensure_hot_invoice(bindle_id).await?;
+        let data = stream.map(|res| res.map_err(std::io::Error::other));
+        match self.hot.create_parcel(bindle_id, parcel_id, data).await {
+            Ok(()) | Err(ProviderError::Exists) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[async_trait::async_trait]
+impl<H, C> Provider for TieredProvider<H, C>
+where
+    H: Provider + Evictable + Send + Sync,
+    C: Provider + Send + Sync,
+{
+    #[instrument(level = "trace", skip(self, inv))]
+    async fn create_invoice<I>(&self, inv: I) -> Result<(crate::Invoice, Vec<crate::Label>)>
+    where
+        I: Signed + Verified + Send + Sync,
+    {
+        // The cold tier is the source of truth, so it gets the invoice first. The invoice has
+        // already been verified and signed, so each tier gets a copy as is
+        let inv = inv.signed();
+        let (created, cold_missing) = self
+            .cold
+            .create_invoice(NoopSigned(NoopVerified(inv.clone())))
+            .await?;
+        match self.hot.create_invoice(NoopSigned(NoopVerified(inv))).await {
+            Ok(_) | Err(ProviderError::Exists) => (),
+            Err(e) => return Err(e),
+        }
+        // A parcel only needs to be uploaded if neither tier has it
+        let ids: Vec<&str> = cold_missing.iter().map(|l| l.sha256.as_str()).collect();
+        let in_hot = if ids.is_empty() {
+            Vec::new()
+        } else {
+            self.hot.parcels_exist(&ids).await?
+        };
+        let missing = cold_missing
+            .into_iter()
+            .zip(in_hot)
+            .filter(|(_, in_hot)| !in_hot)
+            .map(|(label, _)| label)
+            .collect();
+        Ok((created, missing))
+    }
+
+    #[instrument(level = "trace", skip(self, id))]
+    async fn get_yanked_invoice<I>(&self, id: I) -> Result<crate::Invoice>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id = id.try_into().map_err(|e| e.into())?;
+        match self.hot.get_yanked_invoice(&parsed_id).await {
+            Err(ProviderError::NotFound) => self.cold.get_yanked_invoice(&parsed_id).await,
+            res => res,
+        }
+    }
+
+    #[instrument(level = "trace", skip(self, id))]
+    async fn yank_invoice<I>(&self, id: I) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id = id.try_into().map_err(|e| e.into())?;
+        self.cold.yank_invoice(&parsed_id).await?;
+        match self.hot.yank_invoice(&parsed_id).await {
+            Ok(()) | Err(ProviderError::NotFound) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    #[instrument(level = "trace", skip(self, id))]
+    async fn delete_invoice<I>(&self, id: I) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id = id.try_into().map_err(|e| e.into())?;
+        self.cold.delete_invoice(&parsed_id).await?;
+        match self.hot.delete_invoice(&parsed_id).await {
+            Ok(()) | Err(ProviderError::NotFound) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    async fn list_invoices(
+        &self,
+        include_yanked: bool,
+    ) -> Result<Box<dyn Stream<Item = Result<Id>> + Unpin + Send + Sync>> {
+        self.cold.list_invoices(include_yanked).await
+    }
+
+    #[instrument(level = "trace", skip(self, bindle_id, data))]
+    async fn create_parcel<I, R, B>(&self, bindle_id: I, parcel_id: &str, data: R) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+        R: Stream<Item = std::io::Result<B>> + Unpin + Send + Sync + 'static,
+        B: bytes::Buf + Send,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        if self.cold.parcel_exists(&parsed_id, parcel_id).await? {
+            return Err(ProviderError::Exists);
+        }
+        self.ensure_hot_invoice(&parsed_id).await?;
+        self.hot.create_parcel(&parsed_id, parcel_id, data).await?;
+        self.touch(&parsed_id, parcel_id);
+        Ok(())
+    }
+
+    #[instrument(level = "trace", skip(self, bindle_id))]
+    async fn get_parcel<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        if !self.prepare_read(&parsed_id, parcel_id).await? {
+            return self.cold.get_parcel(&parsed_id, parcel_id).await;
+        }
+        self.touch(&parsed_id, parcel_id);
+        self.hot.get_parcel(&parsed_id, parcel_id).await
+    }
+
+    #[instrument(level = "trace", skip(self, bindle_id))]
+    async fn get_parcel_range<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+        range: Range<u64>,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        if !self.prepare_read(&parsed_id, parcel_id).await? {
+            return self
+                .cold
+                .get_parcel_range(&parsed_id, parcel_id, range)
+                .await;
+        }
+        self.touch(&parsed_id, parcel_id);
+        self.hot
+            .get_parcel_range(&parsed_id, parcel_id, range)
+            .await
+    }
+
+    // Resumable uploads are written to the hot tier, just like any other new parcel
+    #[instrument(level = "trace", skip(self, bindle_id))]
+    async fn start_parcel_upload<I>(&self, bindle_id: I, parcel_id: &str) -> Result<u64>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        if self.cold.parcel_exists(&parsed_id, parcel_id).await? {
+            return Err(ProviderError::Exists);
+        }
+        self.ensure_hot_invoice(&parsed_id).await?;
+        self.hot.start_parcel_upload(&parsed_id, parcel_id).await
+    }
+
+    #[instrument(level = "trace", skip(self, bindle_id))]
+    async fn parcel_upload_offset<I>(&self, bindle_id: I, parcel_id: &str) -> Result<u64>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.hot.parcel_upload_offset(bindle_id, parcel_id).await
+    }
+
+    #[instrument(level = "trace", skip(self, bindle_id, data))]
+    async fn write_parcel_chunk<I, R, B>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+        offset: u64,
+        data: R,
+    ) -> Result<u64>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+        R: Stream<Item = std::io::Result<B>> + Unpin + Send + Sync + 'static,
+        B: bytes::Buf + Send,
+    {
+        self.hot
+            .write_parcel_chunk(bindle_id, parcel_id, offset, data)
+            .await
+    }
+
+    #[instrument(level = "trace", skip(self, bindle_id))]
+    async fn finish_parcel_upload<I>(&self, bindle_id: I, parcel_id: &str) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        self.hot.finish_parcel_upload(&parsed_id, parcel_id).await?;
+        self.touch(&parsed_id, parcel_id);
+        Ok(())
+    }
+
+    #[instrument(level = "trace", skip(self, bindle_id))]
+    async fn abort_parcel_upload<I>(&self, bindle_id: I, parcel_id: &str) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.hot.abort_parcel_upload(bindle_id, parcel_id).await
+    }
+
+    // Checking for a parcel doesn't count as an access, so this never promotes anything
+    #[instrument(level = "trace", skip(self, bindle_id))]
+    async fn parcel_exists<I>(&self, bindle_id: I, parcel_id: &str) -> Result<bool>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        match self.hot.parcel_exists(&parsed_id, parcel_id).await {
+            Ok(true) => Ok(true),
+            Ok(false) | Err(ProviderError::NotFound) => {
+                self.cold.parcel_exists(&parsed_id, parcel_id).await
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn has_parcel(&self, parcel_id: &str) -> Result<bool> {
+        match self.hot.has_parcel(parcel_id).await {
+            Ok(true) => Ok(true),
+            Ok(false) => self.cold.has_parcel(parcel_id).await,
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn parcels_exist(&self, parcel_ids: &[&str]) -> Result<Vec<bool>> {
+        let mut exists = self.hot.parcels_exist(parcel_ids).await?;
+        super::fill_parcels_exist(&self.cold, parcel_ids, &mut exists).await?;
+        Ok(exists)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::provider::file::FileProvider;
+    use crate::search::NoopEngine;
+    use crate::testing;
+
+    use tokio::io::AsyncReadExt;
+    use tokio_util::codec::{BytesCodec, FramedRead};
+    use tokio_util::io::StreamReader;
+
+    async fn read_parcel<P: Provider + Sync>(store: &P, id: &Id, parcel_id: &str) -> Vec<u8> {
+        let stream = store.get_parcel(id, parcel_id).await.expect("get parcel");
+        let mut data = Vec::new();
+        StreamReader::new(stream.map(|res| res.map_err(std::io::Error::other)))
+            .read_to_end(&mut data)
+            .await
+            .expect("read parcel");
+        data
+    }
+
+    #[tokio::test]
+    async fn test_should_demote_and_promote_parcels() {
+        let hot_dir = tempfile::tempdir().unwrap();
+        let cold_dir = tempfile::tempdir().unwrap();
+        let metadata_dir = tempfile::tempdir().unwrap();
+        let hot = FileProvider::new(hot_dir.path(), NoopEngine::default()).await;
+        let cold = FileProvider::new(cold_dir.path(), NoopEngine::default()).await;
+        let store = TieredProvider::new(hot.clone(), cold.clone(), metadata_dir.path())
+            .await
+            .expect("create tiered provider");
+
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let id = scaffold.invoice.bindle.id.clone();
+        store
+            .create_invoice(NoopSigned(NoopVerified(scaffold.invoice.clone())))
+            .await
+            .expect("create invoice");
+        for parcel in scaffold.parcel_files.values() {
+            store
+                .create_parcel(
+                    &id,
+                    &parcel.sha,
+                    FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+                )
+                .await
+                .expect("create parcel");
+            assert!(hot.parcel_exists(&id, &parcel.sha).await.unwrap());
+            assert!(!cold.parcel_exists(&id, &parcel.sha).await.unwrap());
+        }
+
+        // Nothing has been idle long enough yet
+        let report = store.demote().await.expect("demote");
+        assert_eq!(report.checked_parcels, scaffold.parcel_files.len());
+        assert!(report.demoted_parcels.is_empty());
+        drop(store);
+
+        // Access times should survive a restart
+        let store = TieredProvider::new(hot.clone(), cold.clone(), metadata_dir.path())
+            .await
+            .expect("reopen tiered provider")
+            .with_idle_time(Duration::from_secs(0));
+        let report = store.demote().await.expect("demote");
+        assert!(report.failures.is_empty(), "Report: {:?}", report);
+        assert_eq!(report.demoted_parcels.len(), scaffold.parcel_files.len());
+        for parcel in scaffold.parcel_files.values() {
+            assert!(!hot.parcel_exists(&id, &parcel.sha).await.unwrap());
+            assert!(cold.parcel_exists(&id, &parcel.sha).await.unwrap());
+            assert!(store.parcel_exists(&id, &parcel.sha).await.unwrap());
+        }
+
+        // Reading a demoted parcel should promote it back to the hot tier
+        let parcel = scaffold.parcel_files.values().next().unwrap();
+        assert_eq!(read_parcel(&store, &id, &parcel.sha).await, parcel.data);
+        assert!(hot.parcel_exists(&id, &parcel.sha).await.unwrap());
+        assert_eq!(read_parcel(&hot, &id, &parcel.sha).await, parcel.data);
+
+        // Parcels in either tier should exist
+        let ids: Vec<&str> = scaffold
+            .parcel_files
+            .values()
+            .map(|p| p.sha.as_str())
+            .chain(std::iter::once("not-a-sha"))
+            .collect();
+        let mut expected = vec![true; scaffold.parcel_files.len()];
+        expected.push(false);
+        assert_eq!(store.parcels_exist(&ids).await.unwrap(), expected);
+
+        // A parcel that is already in the cold tier shouldn't be uploaded again
+        assert!(matches!(
+            store
+                .create_parcel(
+                    &id,
+                    &parcel.sha,
+                    FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+                )
+                .await,
+            Err(ProviderError::Exists)
+        ));
+    }
+}
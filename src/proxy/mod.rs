@@ -2,6 +2,7 @@
 //! client. This requires the `client` feature to be enabled
 
 use std::convert::TryInto;
+use std::ops::Range;
 
 use reqwest::StatusCode;
 use tokio_stream::{Stream, StreamExt};
@@ -33,7 +34,7 @@ impl<T> Proxy<T> {
 }
 
 #[async_trait::async_trait]
-impl<T: TokenManager + Send + Sync + 'static> Provider for Proxy<T> {
+impl<T: TokenManager + Clone + Send + Sync + 'static> Provider for Proxy<T> {
     /// Creates the invoice on the upstream server, signing the invoice as a proxy. The role and
     /// secret key parameters do not matter here
     async fn create_invoice<I>(&self, invoice: I) -> Result<(crate::Invoice, Vec<crate::Label>)>
@@ -69,6 +70,30 @@ impl<T: TokenManager + Send + Sync + 'static> Provider for Proxy<T> {
             .map_err(|e| e.into())
     }
 
+    async fn delete_invoice<I>(&self, id: I) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        // Parse the ID now because the error type constraint doesn't match that of the client
+        let parsed_id = id.try_into().map_err(|e| e.into())?;
+        self.client
+            .delete_invoice(parsed_id)
+            .await
+            .map_err(|e| e.into())
+    }
+
+    async fn list_invoices(
+        &self,
+        include_yanked: bool,
+    ) -> Result<Box<dyn Stream<Item = Result<Id>> + Unpin + Send + Sync>> {
+        Ok(Box::new(
+            self.client
+                .list_invoices(include_yanked)
+                .map(|res| res.map_err(ProviderError::from)),
+        ))
+    }
+
     async fn create_parcel<I, R, B>(&self, bindle_id: I, parcel_id: &str, data: R) -> Result<()>
     where
         I: TryInto<Id> + Send,
@@ -99,6 +124,91 @@ impl<T: TokenManager + Send + Sync + 'static> Provider for Proxy<T> {
         Ok(Box::new(stream.map(|res| res.map_err(|e| e.into()))))
     }
 
+    async fn get_parcel_range<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+        range: Range<u64>,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        // Forward the range upstream so only the requested bytes are transferred
+        self.client
+            .get_parcel_range(bindle_id, parcel_id, range)
+            .await
+    }
+
+    async fn start_parcel_upload<I>(&self, bindle_id: I, parcel_id: &str) -> Result<u64>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        // Parse the ID now because the error type constraint doesn't match that of the client
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        self.client
+            .start_parcel_upload(parsed_id, parcel_id)
+            .await
+            .map_err(|e| e.into())
+    }
+
+    async fn parcel_upload_offset<I>(&self, bindle_id: I, parcel_id: &str) -> Result<u64>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        self.client
+            .parcel_upload_offset(parsed_id, parcel_id)
+            .await
+            .map_err(|e| e.into())
+    }
+
+    async fn write_parcel_chunk<I, R, B>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+        offset: u64,
+        data: R,
+    ) -> Result<u64>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+        R: Stream<Item = std::io::Result<B>> + Unpin + Send + Sync + 'static,
+        B: bytes::Buf + Send,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        self.client
+            .write_parcel_chunk(parsed_id, parcel_id, offset, data)
+            .await
+            .map_err(|e| e.into())
+    }
+
+    async fn finish_parcel_upload<I>(&self, bindle_id: I, parcel_id: &str) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        self.client
+            .finish_parcel_upload(parsed_id, parcel_id)
+            .await
+            .map_err(|e| e.into())
+    }
+
+    async fn abort_parcel_upload<I>(&self, bindle_id: I, parcel_id: &str) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        self.client
+            .abort_parcel_upload(parsed_id, parcel_id)
+            .await
+            .map_err(|e| e.into())
+    }
+
     async fn parcel_exists<I>(&self, bindle_id: I, parcel_id: &str) -> Result<bool>
     where
         I: TryInto<Id> + Send,
@@ -128,4 +238,21 @@ impl<T: TokenManager + Send + Sync + 'static> Provider for Proxy<T> {
             })),
         }
     }
+
+    async fn has_parcel(&self, parcel_id: &str) -> Result<bool> {
+        self.client
+            .has_parcel(parcel_id)
+            .await
+            .map_err(|e| e.into())
+    }
+
+    async fn parcels_exist(&self, parcel_ids: &[&str]) -> Result<Vec<bool>> {
+        let existing: std::collections::HashSet<String> = self
+            .client
+            .existing_parcels(parcel_ids)
+            .await?
+            .into_iter()
+            .collect();
+        Ok(parcel_ids.iter().map(|id| existing.contains(*id)).collect())
+    }
 }
@@ -3,9 +3,15 @@
 use serde::{Deserialize, Serialize};
 
 mod noop;
+#[cfg(feature = "providers")]
+mod persistent;
+mod standard;
 mod strict;
 
 pub use noop::NoopEngine;
+#[cfg(feature = "providers")]
+pub use persistent::PersistentEngine;
+pub use standard::StandardEngine;
 pub use strict::StrictEngine;
 
 #[derive(Debug)]
@@ -75,6 +81,19 @@ impl Matches {
             total: 0,
         }
     }
+
+    /// Sets the total to the number of found invoices and returns the page of them selected by the
+    /// offset and limit
+    fn fill(&mut self, found: Vec<&crate::Invoice>) {
+        self.total = found.len() as u64;
+        self.invoices = found
+            .into_iter()
+            .skip(usize::try_from(self.offset).unwrap_or(usize::MAX))
+            .take(self.limit as usize)
+            .cloned()
+            .collect();
+        self.more = self.total > self.offset.saturating_add(self.invoices.len() as u64);
+    }
 }
 
 /// This trait describes the minimal set of features a Bindle provider must implement to provide
@@ -112,4 +131,67 @@ pub trait Search {
     /// as such, following the protocol specification's requirements for yanked
     /// invoices.
     async fn index(&self, document: &crate::Invoice) -> anyhow::Result<()>;
+
+    /// Removes the invoice with the given ID from the index so that it will no longer be returned
+    /// by any query. This is used when an invoice is permanently deleted.
+    ///
+    /// Removing an invoice that is not in the index is not an error
+    async fn remove(&self, id: &crate::Id) -> anyhow::Result<()>;
+
+    /// Removes every invoice from the index. This is used before the index is rebuilt from the
+    /// invoices in the store.
+    ///
+    /// Indexes that only live in memory start out empty, so this does nothing by default
+    async fn clear(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Returns the generation of the store that the index was last recorded to be in sync with
+    /// (see [`set_generation`](Search::set_generation)), or `None` if it isn't known.
+    ///
+    /// Providers use this when they are created to avoid rebuilding an index that survived a
+    /// restart. Indexes that don't persist anything never know, so this returns `None` by default
+    async fn generation(&self) -> anyhow::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Records that the index contains exactly the invoices of the given generation of the store.
+    /// Only indexes that persist across restarts need to keep this, so it does nothing by default
+    async fn set_generation(&self, _generation: u64) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Lets a search engine be shared, including as a trait object (`Arc<dyn Search + Send + Sync>`)
+/// when the engine is picked at runtime
+#[async_trait::async_trait]
+impl<S: Search + Send + Sync + ?Sized> Search for std::sync::Arc<S> {
+    async fn query(
+        &self,
+        term: &str,
+        filter: &str,
+        options: SearchOptions,
+    ) -> anyhow::Result<Matches> {
+        (**self).query(term, filter, options).await
+    }
+
+    async fn index(&self, document: &crate::Invoice) -> anyhow::Result<()> {
+        (**self).index(document).await
+    }
+
+    async fn remove(&self, id: &crate::Id) -> anyhow::Result<()> {
+        (**self).remove(id).await
+    }
+
+    async fn clear(&self) -> anyhow::Result<()> {
+        (**self).clear().await
+    }
+
+    async fn generation(&self) -> anyhow::Result<Option<u64>> {
+        (**self).generation().await
+    }
+
+    async fn set_generation(&self, generation: u64) -> anyhow::Result<()> {
+        (**self).set_generation(generation).await
+    }
 }
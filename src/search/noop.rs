@@ -20,4 +20,8 @@ impl Search for NoopEngine {
     async fn index(&self, _: &crate::Invoice) -> anyhow::Result<()> {
         Ok(())
     }
+
+    async fn remove(&self, _: &crate::Id) -> anyhow::Result<()> {
+        Ok(())
+    }
 }
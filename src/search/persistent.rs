@@ -0,0 +1,228 @@
+//! A query engine implementation that keeps its index on disk, so it doesn't have to be rebuilt
+//! every time the server starts
+
+use std::path::Path;
+
+use tracing::{debug, info, instrument, warn};
+
+use crate::search::{Matches, Search, SearchOptions, StandardEngine};
+
+/// The tree that indexed invoices are stored in, keyed by their name
+const INVOICE_TREE: &str = "invoices";
+/// The key (in the default tree) that the generation of the store is recorded under
+const GENERATION_KEY: &str = "generation";
+/// How many times to try opening a database that is still locked, and how long to wait in between.
+/// sled releases its lock from a background thread once the last handle to a database is dropped,
+/// so a database that was just closed (by this process or another) can still be locked for a
+/// moment
+const LOCK_RETRIES: u32 = 50;
+const LOCK_RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// A [`StandardEngine`] whose index is also stored in a [sled](https://github.com/spacejam/sled)
+/// database on disk. Opening it only has to read the indexed invoices back from the database,
+/// instead of the provider reading and parsing every invoice in the store.
+///
+/// Providers that track the generation of their store (the
+/// [`FileProvider`](crate::provider::file::FileProvider) and
+/// [`EmbeddedProvider`](crate::provider::embedded::EmbeddedProvider)) only rebuild the index when
+/// they are created if it isn't in sync with the store, for example after a crash or when another
+/// process changed the invoices in the store. Other providers index every invoice again, but
+/// won't remove invoices that were deleted in the meantime, so the index should be cleared before
+/// using it with them.
+///
+/// The database can only be opened by one process at a time
+#[derive(Clone)]
+pub struct PersistentEngine {
+    inner: StandardEngine,
+    db: sled::Db,
+    invoices: sled::Tree,
+}
+
+impl PersistentEngine {
+    /// Opens the index stored in the given directory (creating it if it doesn't exist) and loads
+    /// every invoice in it. An index that can't be read back is cleared, so that the provider
+    /// rebuilds it. A database that is still locked (for example because it was only just closed)
+    /// is retried for a few seconds before giving up
+    #[instrument(level = "trace", skip(path), fields(path = %path.as_ref().display()))]
+    pub async fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_owned();
+        let (db, invoices, loaded) = tokio::task::spawn_blocking(move || {
+            let db = open_db(&path)?;
+            let invoices = db.open_tree(INVOICE_TREE)?;
+            let loaded = invoices
+                .iter()
+                .map(|res| Ok(serde_cbor::from_slice::<crate::Invoice>(&res?.1)?))
+                .collect::<anyhow::Result<Vec<_>>>();
+            anyhow::Ok((db, invoices, loaded))
+        })
+        .await??;
+
+        let engine = PersistentEngine {
+            inner: StandardEngine::default(),
+            db,
+            invoices,
+        };
+        match loaded {
+            Ok(loaded) => {
+                debug!(
+                    total = loaded.len(),
+                    "Loading stored invoices into search index"
+                );
+                for invoice in loaded {
+                    engine.inner.index(&invoice).await?;
+                }
+            }
+            Err(e) => {
+                warn!(error = %e, "Unable to read stored search index, clearing it");
+                engine.clear().await?;
+            }
+        }
+        Ok(engine)
+    }
+}
+
+/// Opens the database at the given path, retrying for a few seconds if it is still locked
+fn open_db(path: &Path) -> sled::Result<sled::Db> {
+    let mut attempt = 1;
+    loop {
+        match sled::open(path) {
+            Err(sled::Error::Io(e)) if is_lock_error(&e) && attempt < LOCK_RETRIES => {
+                debug!(attempt, error = %e, "Search index is locked, retrying");
+                attempt += 1;
+                std::thread::sleep(LOCK_RETRY_INTERVAL);
+            }
+            res => return res,
+        }
+    }
+}
+
+/// sled doesn't return a dedicated error when the database is locked, only an IO error with a
+/// message
+fn is_lock_error(e: &std::io::Error) -> bool {
+    e.kind() == std::io::ErrorKind::WouldBlock || e.to_string().contains("could not acquire lock")
+}
+
+#[async_trait::async_trait]
+impl Search for PersistentEngine {
+    async fn query(
+        &self,
+        term: &str,
+        filter: &str,
+        options: SearchOptions,
+    ) -> anyhow::Result<Matches> {
+        self.inner.query(term, filter, options).await
+    }
+
+    async fn index(&self, invoice: &crate::Invoice) -> anyhow::Result<()> {
+        let key = invoice.name();
+        let serialized = serde_cbor::to_vec(invoice)?;
+        let invoices = self.invoices.clone();
+        tokio::task::spawn_blocking(move || invoices.insert(key, serialized)).await??;
+        self.inner.index(invoice).await
+    }
+
+    async fn remove(&self, id: &crate::Id) -> anyhow::Result<()> {
+        // This must match the key generated by `Invoice::name`
+        let key = format!("{}/{}", id.name(), id.version());
+        let invoices = self.invoices.clone();
+        tokio::task::spawn_blocking(move || invoices.remove(key)).await??;
+        self.inner.remove(id).await
+    }
+
+    async fn clear(&self) -> anyhow::Result<()> {
+        info!("Clearing search index");
+        // The generation MUST go first, so an index that is only partially rebuilt is never
+        // considered to be in sync
+        let db = self.db.clone();
+        let invoices = self.invoices.clone();
+        tokio::task::spawn_blocking(move || {
+            db.remove(GENERATION_KEY)?;
+            invoices.clear()
+        })
+        .await??;
+        self.db.flush_async().await?;
+        self.inner.clear().await
+    }
+
+    async fn generation(&self) -> anyhow::Result<Option<u64>> {
+        let raw = match self.db.get(GENERATION_KEY)? {
+            Some(raw) => raw,
+            None => return Ok(None),
+        };
+        let bytes: [u8; 8] = raw
+            .as_ref()
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Search index contains an invalid generation"))?;
+        Ok(Some(u64::from_be_bytes(bytes)))
+    }
+
+    async fn set_generation(&self, generation: u64) -> anyhow::Result<()> {
+        debug!(generation, "Recording generation in search index");
+        self.db.insert(GENERATION_KEY, &generation.to_be_bytes())?;
+        // Everything indexed before this point is flushed along with it
+        self.db.flush_async().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn persistent_engine_should_survive_reopening() {
+        let dir = tempfile::tempdir().expect("unable to create tempdir");
+        let keep = invoice_fixture("example.com/weather", "1.0.0");
+        let removed = invoice_fixture("example.com/weather", "2.0.0");
+        {
+            let searcher = PersistentEngine::open(dir.path()).await.unwrap();
+            assert_eq!(None, searcher.generation().await.unwrap());
+            searcher.index(&keep).await.unwrap();
+            searcher.index(&removed).await.unwrap();
+            searcher.remove(&removed.bindle.id).await.unwrap();
+            searcher.set_generation(2).await.unwrap();
+        }
+
+        let searcher = PersistentEngine::open(dir.path()).await.unwrap();
+        assert_eq!(Some(2), searcher.generation().await.unwrap());
+        let matches = searcher
+            .query("weather", "", SearchOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(1, matches.total);
+        assert_eq!(keep.bindle.id, matches.invoices[0].bindle.id);
+
+        searcher.clear().await.unwrap();
+        drop(searcher);
+        // Opening the database again while the previous handle is still being closed must wait
+        // for its lock instead of failing
+        for _ in 0..5 {
+            let searcher = PersistentEngine::open(dir.path()).await.unwrap();
+            assert_eq!(None, searcher.generation().await.unwrap());
+        }
+        let searcher = PersistentEngine::open(dir.path()).await.unwrap();
+        assert_eq!(None, searcher.generation().await.unwrap());
+        let matches = searcher
+            .query("", "", SearchOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(0, matches.total);
+    }
+
+    fn invoice_fixture(name: &str, version: &str) -> crate::Invoice {
+        crate::Invoice {
+            bindle_version: crate::BINDLE_VERSION_1.to_owned(),
+            yanked: None,
+            yanked_signature: None,
+            annotations: None,
+            bindle: crate::BindleSpec {
+                id: format!("{}/{}", name, version).parse().unwrap(),
+                description: None,
+                authors: None,
+            },
+            parcel: None,
+            group: None,
+            signature: None,
+        }
+    }
+}
@@ -0,0 +1,366 @@
+//! A standard query engine implementation. It matches query terms against several fields of a
+//! bindle and ranks the results, while still answering strict queries in strict mode
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use tokio::sync::RwLock;
+use tracing::{debug, instrument, trace};
+
+use crate::search::{Matches, Search, SearchOptions};
+
+/// Implements standard query processing, falling back to strict query processing for queries that
+/// ask for it.
+///
+/// In standard mode, the query is split into terms that must all match (case insensitively) a word
+/// in the name, version, authors, description or annotations of a bindle. Results are ranked by
+/// how well and in which fields the terms matched, with the name weighted highest.
+///
+/// Note that the protocol specification recommends against searching annotations, as they can
+/// contain information that is not intended for general consumption. Don't use this engine if
+/// that is a concern for the bindles being served
+#[derive(Clone, Default)]
+pub struct StandardEngine {
+    // Like in the strict engine, a BTreeMap keeps results with the same score in a predictable
+    // order
+    index: Arc<RwLock<BTreeMap<String, Document>>>,
+}
+
+/// The fields of a bindle that are searched in standard mode
+#[derive(Clone, Copy, Debug)]
+enum Field {
+    Name,
+    Version,
+    Authors,
+    Description,
+    Annotations,
+}
+
+impl Field {
+    /// How much a match in this field counts towards the score of a bindle
+    fn weight(&self) -> u32 {
+        match self {
+            Field::Name => 8,
+            Field::Version => 4,
+            Field::Authors => 3,
+            Field::Description => 2,
+            Field::Annotations => 1,
+        }
+    }
+}
+
+/// An indexed invoice along with the words of each of its searchable fields
+struct Document {
+    invoice: crate::Invoice,
+    fields: Vec<(Field, Vec<String>)>,
+}
+
+impl Document {
+    fn new(invoice: &crate::Invoice) -> Self {
+        let bindle = &invoice.bindle;
+        let mut fields = vec![
+            (Field::Name, tokenize(bindle.id.name())),
+            (Field::Version, tokenize(&bindle.id.version_string())),
+        ];
+        if let Some(authors) = &bindle.authors {
+            fields.push((
+                Field::Authors,
+                authors.iter().flat_map(|a| tokenize(a)).collect(),
+            ));
+        }
+        if let Some(description) = &bindle.description {
+            fields.push((Field::Description, tokenize(description)));
+        }
+        if let Some(annotations) = &invoice.annotations {
+            fields.push((
+                Field::Annotations,
+                annotations
+                    .iter()
+                    .flat_map(|(key, value)| tokenize(key).into_iter().chain(tokenize(value)))
+                    .collect(),
+            ));
+        }
+        Document {
+            invoice: invoice.clone(),
+            fields,
+        }
+    }
+
+    /// Returns the score of the best match of the given (tokenized) query term, or `None` if it
+    /// doesn't match anything
+    fn score(&self, term: &str) -> Option<u32> {
+        self.fields
+            .iter()
+            .filter_map(|(field, words)| {
+                words
+                    .iter()
+                    .filter_map(|word| match_quality(word, term))
+                    .max()
+                    .map(|quality| quality * field.weight())
+            })
+            .max()
+    }
+}
+
+/// Returns how well a query term matches a word: 3 for the whole word, 2 for the start of it and 1
+/// for anywhere in it
+fn match_quality(word: &str, term: &str) -> Option<u32> {
+    if word == term {
+        Some(3)
+    } else if word.starts_with(term) {
+        Some(2)
+    } else if word.contains(term) {
+        Some(1)
+    } else {
+        None
+    }
+}
+
+/// Splits the given text into lowercase words, treating anything that isn't a letter or number as
+/// a separator
+fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+#[async_trait::async_trait]
+impl Search for StandardEngine {
+    #[instrument(level = "trace", skip(self))]
+    async fn query(
+        &self,
+        term: &str,
+        filter: &str,
+        options: SearchOptions,
+    ) -> anyhow::Result<Matches> {
+        trace!("beginning search");
+        let index = self.index.read().await;
+        let candidates = index.values().filter(|doc| {
+            (options.yanked || !doc.invoice.yanked.unwrap_or(false))
+                && (filter.is_empty() || doc.invoice.version_in_range(filter))
+        });
+
+        let found: Vec<&crate::Invoice> = if options.strict {
+            // Per the spec, the term must be found in the name field of the bindle
+            candidates
+                .filter(|doc| super::strict::name_matches(&doc.invoice, term))
+                .map(|doc| &doc.invoice)
+                .collect()
+        } else {
+            // All terms are required, so a document only has a score if every term matched. An
+            // empty query matches everything
+            let terms = tokenize(term);
+            let mut scored: Vec<(u32, &Document)> = candidates
+                .filter_map(|doc| {
+                    terms
+                        .iter()
+                        .map(|t| doc.score(t))
+                        .sum::<Option<u32>>()
+                        .map(|score| (score, doc))
+                })
+                .collect();
+            // The sort is stable, so documents with the same score stay in index order
+            scored.sort_by(|(a, _), (b, _)| b.cmp(a));
+            scored.into_iter().map(|(_, doc)| &doc.invoice).collect()
+        };
+
+        debug!(total_matches = found.len(), "Found matches");
+        let mut matches = Matches::new(&options, term.to_owned());
+        matches.fill(found);
+        trace!("Returning {} found invoices", matches.invoices.len());
+        Ok(matches)
+    }
+
+    async fn index(&self, invoice: &crate::Invoice) -> anyhow::Result<()> {
+        self.index
+            .write()
+            .await
+            .insert(invoice.name(), Document::new(invoice));
+        Ok(())
+    }
+
+    async fn remove(&self, id: &crate::Id) -> anyhow::Result<()> {
+        // This must match the key generated by `Invoice::name`
+        self.index
+            .write()
+            .await
+            .remove(&format!("{}/{}", id.name(), id.version()));
+        Ok(())
+    }
+
+    async fn clear(&self) -> anyhow::Result<()> {
+        self.index.write().await.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Invoice;
+
+    #[tokio::test]
+    async fn standard_engine_should_match_and_rank_fields() {
+        let searcher = StandardEngine::default();
+        let mut weather = invoice_fixture("example.com/weather", "1.0.0");
+        weather.bindle.description = Some("Forecasts for the next week".to_owned());
+        let mut forecast = invoice_fixture("example.com/forecast", "0.1.0");
+        forecast.bindle.description = Some("Shows the weather".to_owned());
+        let mut annotated = invoice_fixture("example.com/radar", "2.0.0");
+        annotated.annotations = Some(
+            [("team".to_owned(), "Weather Services".to_owned())]
+                .into_iter()
+                .collect(),
+        );
+        let mut yanked = invoice_fixture("example.com/weather", "0.9.0");
+        yanked.yanked = Some(true);
+        for inv in [&weather, &forecast, &annotated, &yanked] {
+            searcher.index(inv).await.unwrap();
+        }
+
+        // Matches in the name rank highest, then the description and then annotations
+        let matches = searcher
+            .query("WEATHER", "", SearchOptions::default())
+            .await
+            .expect("found some matches");
+        assert!(!matches.strict);
+        let ids: Vec<_> = matches.invoices.iter().map(|i| i.name()).collect();
+        assert_eq!(
+            vec![
+                "example.com/weather/1.0.0",
+                "example.com/forecast/0.1.0",
+                "example.com/radar/2.0.0"
+            ],
+            ids
+        );
+        assert_eq!(3, matches.total);
+
+        // All terms must match, in any field
+        let matches = searcher
+            .query("weather next", "", SearchOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(1, matches.invoices.len());
+        assert_eq!(weather.bindle.id, matches.invoices[0].bindle.id);
+
+        // Authors are searched and the version filter is applied
+        let matches = searcher
+            .query("butcher", "^0.1", SearchOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(1, matches.invoices.len());
+        assert_eq!(forecast.bindle.id, matches.invoices[0].bindle.id);
+
+        // Yanked bindles are only returned when asked for
+        let matches = searcher
+            .query(
+                "weather",
+                "0.9.0",
+                SearchOptions {
+                    yanked: true,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(1, matches.invoices.len());
+        assert_eq!(yanked.bindle.id, matches.invoices[0].bindle.id);
+
+        // Pages are taken from the ranked results
+        let matches = searcher
+            .query(
+                "weather",
+                "",
+                SearchOptions {
+                    offset: 1,
+                    limit: 1,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert!(matches.more);
+        assert_eq!(forecast.bindle.id, matches.invoices[0].bindle.id);
+    }
+
+    #[tokio::test]
+    async fn standard_engine_should_respect_strict_mode() {
+        let searcher = StandardEngine::default();
+        let mut inv = invoice_fixture("example.com/foo/bar", "1.0.0");
+        inv.bindle.description = Some("baz".to_owned());
+        searcher.index(&inv).await.unwrap();
+        let matches = searcher
+            .query(
+                "FOO baz",
+                "",
+                SearchOptions {
+                    strict: true,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert!(matches.strict);
+        assert!(
+            matches.invoices.is_empty(),
+            "Strict mode should only match the name, case sensitively"
+        );
+        let matches = searcher
+            .query(
+                "bar foo",
+                "",
+                SearchOptions {
+                    strict: true,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert!(
+            matches.invoices.is_empty(),
+            "Strict mode should match the whole term, like the strict engine"
+        );
+        let matches = searcher
+            .query(
+                "foo/bar",
+                "",
+                SearchOptions {
+                    strict: true,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(1, matches.invoices.len());
+        let matches = searcher
+            .query("FOO baz", "", SearchOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(1, matches.invoices.len());
+
+        searcher.remove(&inv.bindle.id).await.unwrap();
+        let matches = searcher
+            .query("", "", SearchOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(0, matches.total);
+    }
+
+    fn invoice_fixture(name: &str, version: &str) -> Invoice {
+        Invoice {
+            bindle_version: crate::BINDLE_VERSION_1.to_owned(),
+            yanked: None,
+            yanked_signature: None,
+            annotations: None,
+            bindle: crate::BindleSpec {
+                id: format!("{}/{}", name, version).parse().unwrap(),
+                description: None,
+                authors: Some(vec!["m butcher".to_owned()]),
+            },
+            parcel: None,
+            group: None,
+            signature: None,
+        }
+    }
+}
@@ -1,7 +1,6 @@
 //! A strict query engine implementation. It always expects a strict match of query terms
 
 use std::collections::BTreeMap;
-use std::ops::RangeInclusive;
 use std::sync::Arc;
 
 use tokio::sync::RwLock;
@@ -26,6 +25,13 @@ impl Default for StrictEngine {
     }
 }
 
+/// Returns whether the invoice matches the term of a strict query, which is the case if the whole
+/// term is contained within the name of the bindle. This is shared by every engine that supports
+/// strict mode, so the same query returns the same results no matter which engine is used
+pub(crate) fn name_matches(invoice: &crate::Invoice, term: &str) -> bool {
+    invoice.bindle.id.name().contains(term)
+}
+
 #[async_trait::async_trait]
 impl Search for StrictEngine {
     #[instrument(level = "trace", skip(self))]
@@ -36,45 +42,29 @@ impl Search for StrictEngine {
         options: SearchOptions,
     ) -> anyhow::Result<Matches> {
         trace!("beginning search");
-        let mut found: Vec<crate::Invoice> = self
-            .index
-            .read()
-            .await
+        let index = self.index.read().await;
+        let found: Vec<&crate::Invoice> = index
             .iter()
             .filter(|(_, i)| {
                 // Per the spec:
                 // - if `term` is present, then it must be contained within the name field of the bindle.
                 // - if a version filter is present, then the version of the bindle must abide by the filter.
                 debug!(term, filter, "comparing term and filter");
-                i.bindle.id.name().contains(term)
-                    && (filter.is_empty() || i.version_in_range(filter))
+                name_matches(i, term) && (filter.is_empty() || i.version_in_range(filter))
             })
-            .map(|(_, v)| (*v).clone())
+            .map(|(_, v)| v)
             .collect();
 
         debug!(total_matches = found.len(), "Found matches");
         let mut matches = Matches::new(&options, term.to_owned());
         matches.strict = true;
         matches.yanked = false;
-        matches.total = found.len() as u64;
-
-        if matches.offset >= matches.total {
-            // We're past the end of the search results. Return an empty matches object.
-            matches.more = false;
-            return Ok(matches);
-        }
-
-        // Apply offset and limit
-        let mut last_index = matches.offset + matches.limit as u64 - 1;
-        if last_index >= matches.total {
-            last_index = matches.total - 1;
-        }
-
-        matches.more = matches.total > last_index + 1;
-        trace!(last_index, matches.more, "Getting next page of results");
-        let range = RangeInclusive::new(matches.offset as usize, last_index as usize);
-        matches.invoices = found.drain(range).collect();
-        trace!("Returning {} found invoices", matches.invoices.len());
+        matches.fill(found);
+        trace!(
+            matches.more,
+            "Returning {} found invoices",
+            matches.invoices.len()
+        );
 
         Ok(matches)
     }
@@ -86,6 +76,20 @@ impl Search for StrictEngine {
             .insert(invoice.name(), invoice.clone());
         Ok(())
     }
+
+    async fn remove(&self, id: &crate::Id) -> anyhow::Result<()> {
+        // This must match the key generated by `Invoice::name`
+        self.index
+            .write()
+            .await
+            .remove(&format!("{}/{}", id.name(), id.version()));
+        Ok(())
+    }
+
+    async fn clear(&self) -> anyhow::Result<()> {
+        self.index.write().await.clear();
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -141,8 +145,34 @@ mod test {
         // TODO: Need to test yanked bindles
     }
 
+    #[tokio::test]
+    async fn strict_engine_should_remove() {
+        let inv = invoice_fixture("my/bindle".to_owned(), "1.2.3".to_owned());
+        let inv2 = invoice_fixture("my/bindle".to_owned(), "1.3.0".to_owned());
+        let searcher = StrictEngine::default();
+        searcher.index(&inv).await.unwrap();
+        searcher.index(&inv2).await.unwrap();
+
+        searcher
+            .remove(&inv.bindle.id)
+            .await
+            .expect("successfully removed my/bindle/1.2.3");
+        let matches = searcher
+            .query("my/bindle", "", SearchOptions::default())
+            .await
+            .expect("found some matches");
+        assert_eq!(1, matches.invoices.len());
+        assert_eq!(inv2.bindle.id, matches.invoices[0].bindle.id);
+
+        // Removing something that doesn't exist is not an error
+        searcher
+            .remove(&inv.bindle.id)
+            .await
+            .expect("removing a nonexistent invoice should succeed");
+    }
+
     fn invoice_fixture(name: String, version: String) -> Invoice {
-        let labels = vec![
+        let labels = [
             crate::Label {
                 sha256: "abcdef1234567890987654321".to_owned(),
                 media_type: "text/toml".to_owned(),
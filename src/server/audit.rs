@@ -0,0 +1,357 @@
+//! An append-only audit log of the mutating operations performed against the server.
+//!
+//! Every invoice creation, parcel creation, yank, purge and login is recorded as an
+//! [`AuditEntry`](crate::AuditEntry) along with the principal of the user who performed it and
+//! the outcome. Yanks and purges the server performs on its own (like when applying a retention
+//! policy) are recorded under a fixed principal instead. Entries are written to an [`AuditSink`], of which there are two implementations:
+//! [`FileAuditSink`], which appends JSON lines to a file, and [`SledAuditSink`], which stores
+//! entries in an embedded sled database.
+
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::Mutex;
+use tracing::{instrument, warn};
+use warp::Reply;
+
+use crate::{AuditAction, AuditEntry, AuditOutcome, AuditQuery};
+
+const DEFAULT_QUERY_LIMIT: u32 = 100;
+const AUDIT_TREE_NAME: &str = "audit_log";
+
+/// A place to store audit entries
+#[async_trait::async_trait]
+pub trait AuditSink {
+    /// Appends the given entry to the log
+    async fn record(&self, entry: &AuditEntry) -> anyhow::Result<()>;
+
+    /// Returns the entries matching the given query, with the newest first
+    async fn query(&self, query: &AuditQuery) -> anyhow::Result<Vec<AuditEntry>>;
+}
+
+/// An audit sink that appends entries to a file, one JSON object per line
+pub struct FileAuditSink {
+    path: std::path::PathBuf,
+    file: Mutex<tokio::fs::File>,
+}
+
+impl FileAuditSink {
+    /// Opens the given file for appending, creating it if it doesn't exist
+    pub async fn new(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_owned();
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        Ok(FileAuditSink {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl AuditSink for FileAuditSink {
+    async fn record(&self, entry: &AuditEntry) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().await;
+        file.write_all(&line).await?;
+        file.flush().await?;
+        Ok(())
+    }
+
+    async fn query(&self, query: &AuditQuery) -> anyhow::Result<Vec<AuditEntry>> {
+        // Hold the lock so we don't read a partially written line
+        let _guard = self.file.lock().await;
+        let file = tokio::fs::File::open(&self.path).await?;
+        let mut lines = BufReader::new(file).lines();
+        let mut entries = Vec::new();
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: AuditEntry = serde_json::from_str(&line)?;
+            if matches(&entry, query) {
+                entries.push(entry);
+            }
+        }
+        let limit = query.limit.unwrap_or(DEFAULT_QUERY_LIMIT) as usize;
+        Ok(entries.into_iter().rev().take(limit).collect())
+    }
+}
+
+/// An audit sink that stores entries in an embedded sled database
+pub struct SledAuditSink {
+    db: sled::Db,
+    tree: sled::Tree,
+}
+
+impl SledAuditSink {
+    /// Opens (or creates) the sled database at the given path
+    pub async fn new(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_owned();
+        let db = tokio::task::spawn_blocking(move || sled::open(path)).await??;
+        let owned = db.clone();
+        let tree = tokio::task::spawn_blocking(move || owned.open_tree(AUDIT_TREE_NAME)).await??;
+        Ok(SledAuditSink { db, tree })
+    }
+}
+
+#[async_trait::async_trait]
+impl AuditSink for SledAuditSink {
+    async fn record(&self, entry: &AuditEntry) -> anyhow::Result<()> {
+        let serialized = serde_cbor::to_vec(entry)?;
+        let db = self.db.clone();
+        let tree = self.tree.clone();
+        tokio::task::spawn_blocking(move || {
+            // Big endian keys from a monotonic ID keep the tree in insertion order
+            let key = db.generate_id()?.to_be_bytes();
+            tree.insert(key, serialized)?;
+            tree.flush()?;
+            Ok::<_, sled::Error>(())
+        })
+        .await??;
+        Ok(())
+    }
+
+    async fn query(&self, query: &AuditQuery) -> anyhow::Result<Vec<AuditEntry>> {
+        let tree = self.tree.clone();
+        let limit = query.limit.unwrap_or(DEFAULT_QUERY_LIMIT) as usize;
+        let query = query.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut entries = Vec::new();
+            for res in tree.iter().rev() {
+                if entries.len() >= limit {
+                    break;
+                }
+                let (_, raw) = res?;
+                let entry: AuditEntry = serde_cbor::from_slice(&raw)?;
+                if matches(&entry, &query) {
+                    entries.push(entry);
+                }
+            }
+            Ok(entries)
+        })
+        .await?
+    }
+}
+
+fn matches(entry: &AuditEntry, query: &AuditQuery) -> bool {
+    if let Some(principal) = query.principal.as_deref() {
+        if entry.principal != principal {
+            return false;
+        }
+    }
+    if let Some(bindle) = query.bindle.as_deref() {
+        let id = match entry.bindle_id.as_deref() {
+            Some(id) => id,
+            None => return false,
+        };
+        // A bindle ID is the name followed by the version as the last path segment
+        let name = id.rsplit_once('/').map(|(name, _)| name).unwrap_or(id);
+        if id != bindle && name != bindle {
+            return false;
+        }
+    }
+    true
+}
+
+/// A handle for recording audit entries. Cloning a log is cheap and all clones write to the same
+/// sink. A default log is disabled and discards all entries
+#[derive(Clone, Default)]
+pub struct AuditLog {
+    sink: Option<Arc<dyn AuditSink + Send + Sync>>,
+}
+
+impl std::fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuditLog")
+            .field("enabled", &self.is_enabled())
+            .finish()
+    }
+}
+
+impl AuditLog {
+    /// Returns a log that writes entries to the given sink
+    pub fn new(sink: impl AuditSink + Send + Sync + 'static) -> Self {
+        AuditLog {
+            sink: Some(Arc::new(sink)),
+        }
+    }
+
+    /// Returns true if entries are being recorded
+    pub fn is_enabled(&self) -> bool {
+        self.sink.is_some()
+    }
+
+    /// Returns the entries matching the given query, with the newest first. Returns `None` if
+    /// the log is disabled
+    pub async fn query(&self, query: &AuditQuery) -> Option<anyhow::Result<Vec<AuditEntry>>> {
+        match self.sink.as_ref() {
+            Some(sink) => Some(sink.query(query).await),
+            None => None,
+        }
+    }
+
+    /// Records the outcome of an operation based on the status of its reply, passing the reply
+    /// through. Failing to write the entry is logged but doesn't fail the operation
+    #[instrument(level = "trace", skip(self, reply))]
+    pub(crate) async fn record_reply(
+        &self,
+        principal: String,
+        action: AuditAction,
+        bindle_id: Option<String>,
+        parcel_sha: Option<String>,
+        reply: impl Reply,
+    ) -> warp::reply::Response {
+        let response = reply.into_response();
+        self.record(principal, action, bindle_id, parcel_sha, response.status())
+            .await;
+        response
+    }
+
+    /// Records an operation the server performed on its own rather than in response to a request
+    /// (such as applying a retention policy) under the given principal. `status` is the status a
+    /// request for the same operation would have gotten. Failing to write the entry is logged
+    #[instrument(level = "trace", skip(self))]
+    pub async fn record(
+        &self,
+        principal: String,
+        action: AuditAction,
+        bindle_id: Option<String>,
+        parcel_sha: Option<String>,
+        status: warp::http::StatusCode,
+    ) {
+        let sink = match self.sink.as_ref() {
+            Some(s) => s,
+            None => return,
+        };
+        let entry = AuditEntry {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            principal,
+            action,
+            bindle_id,
+            parcel_sha,
+            outcome: if status.is_success() {
+                AuditOutcome::Success
+            } else {
+                AuditOutcome::Failure
+            },
+            status: status.as_u16(),
+        };
+        if let Err(e) = sink.record(&entry).await {
+            warn!(error = %e, ?entry, "Unable to write audit log entry");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(principal: &str, action: AuditAction, bindle_id: Option<&str>) -> AuditEntry {
+        AuditEntry {
+            timestamp: 0,
+            principal: principal.to_owned(),
+            action,
+            bindle_id: bindle_id.map(|s| s.to_owned()),
+            parcel_sha: None,
+            outcome: AuditOutcome::Success,
+            status: 201,
+        }
+    }
+
+    async fn test_sink(sink: impl AuditSink) {
+        sink.record(&entry(
+            "alice",
+            AuditAction::CreateInvoice,
+            Some("foo/1.0.0"),
+        ))
+        .await
+        .expect("should record");
+        sink.record(&entry("bob", AuditAction::YankInvoice, Some("foo/1.0.0")))
+            .await
+            .expect("should record");
+        sink.record(&entry(
+            "alice",
+            AuditAction::CreateInvoice,
+            Some("bar/1.0.0"),
+        ))
+        .await
+        .expect("should record");
+        sink.record(&entry("", AuditAction::Login, None))
+            .await
+            .expect("should record");
+
+        let all = sink
+            .query(&AuditQuery::default())
+            .await
+            .expect("should query");
+        assert_eq!(all.len(), 4, "All entries should be returned");
+        assert_eq!(all[0].action, AuditAction::Login, "Newest should be first");
+
+        let by_principal = sink
+            .query(&AuditQuery {
+                principal: Some("alice".into()),
+                ..Default::default()
+            })
+            .await
+            .expect("should query");
+        assert_eq!(by_principal.len(), 2);
+        assert!(by_principal.iter().all(|e| e.principal == "alice"));
+
+        let by_name = sink
+            .query(&AuditQuery {
+                bindle: Some("foo".into()),
+                ..Default::default()
+            })
+            .await
+            .expect("should query");
+        assert_eq!(by_name.len(), 2, "Bindle name should match all versions");
+
+        let by_id = sink
+            .query(&AuditQuery {
+                bindle: Some("bar/1.0.0".into()),
+                limit: Some(1),
+                ..Default::default()
+            })
+            .await
+            .expect("should query");
+        assert_eq!(by_id.len(), 1);
+        assert_eq!(by_id[0].bindle_id.as_deref(), Some("bar/1.0.0"));
+
+        let limited = sink
+            .query(&AuditQuery {
+                limit: Some(3),
+                ..Default::default()
+            })
+            .await
+            .expect("should query");
+        assert_eq!(limited.len(), 3, "Limit should be respected");
+    }
+
+    #[tokio::test]
+    async fn test_file_sink() {
+        let dir = tempfile::tempdir().expect("should create tempdir");
+        let sink = FileAuditSink::new(dir.path().join("audit.log"))
+            .await
+            .expect("should open sink");
+        test_sink(sink).await;
+    }
+
+    #[tokio::test]
+    async fn test_sled_sink() {
+        let dir = tempfile::tempdir().expect("should create tempdir");
+        let sink = SledAuditSink::new(dir.path())
+            .await
+            .expect("should open sink");
+        test_sink(sink).await;
+    }
+}
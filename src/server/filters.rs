@@ -13,7 +13,7 @@ use warp::Filter;
 use super::TOML_MIME_TYPE;
 use crate::authn::Authenticator;
 use crate::authz::always::Anonymous;
-use crate::authz::Authorizer;
+use crate::authz::{Authorizable, Authorizer};
 
 pub(crate) const PARCEL_ID_SEPARATOR: char = '@';
 
@@ -23,6 +23,40 @@ pub struct InvoiceQuery {
     pub yanked: Option<bool>,
 }
 
+/// Query string options for the invoice create endpoint
+#[derive(Debug, Deserialize)]
+pub struct CreateInvoiceQuery {
+    /// If true, the invoice is stored as a draft that stays hidden until all of its parcels have
+    /// been uploaded
+    pub staged: Option<bool>,
+}
+
+/// Query string options for the invoice delete endpoint
+#[derive(Debug, Deserialize)]
+pub struct DeleteQuery {
+    pub purge: Option<bool>,
+}
+
+/// Query string options for the resumable upload endpoint
+#[derive(Debug, Deserialize)]
+pub struct UploadQuery {
+    pub offset: u64,
+}
+
+/// A warp filter that only matches if the request asks for a bindle to be purged (i.e.
+/// permanently deleted rather than yanked) and rejects it otherwise
+pub fn purge() -> impl Filter<Extract = (), Error = Rejection> + Copy {
+    warp::query::<DeleteQuery>()
+        .and_then(|query: DeleteQuery| async move {
+            if query.purge.unwrap_or(false) {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
 /// A warp filter that returns the invoice ID if the path is for an invoice and rejects it otherwise
 pub fn invoice() -> impl Filter<Extract = (String,), Error = Rejection> + Copy {
     warp::path("_i")
@@ -47,7 +81,19 @@ pub fn invoice() -> impl Filter<Extract = (String,), Error = Rejection> + Copy {
 /// A warp filter that returns the invoice ID and parcel ID as a tuple if the path is for a parcel
 /// and rejects it otherwise
 pub fn parcel() -> impl Filter<Extract = ((String, String),), Error = Rejection> + Copy {
-    warp::path("_i")
+    parcel_under("_i")
+}
+
+/// A warp filter that returns the invoice ID and parcel ID as a tuple if the path is for a
+/// resumable parcel upload and rejects it otherwise
+pub fn upload() -> impl Filter<Extract = ((String, String),), Error = Rejection> + Copy {
+    parcel_under("_u")
+}
+
+fn parcel_under(
+    prefix: &'static str,
+) -> impl Filter<Extract = ((String, String),), Error = Rejection> + Copy {
+    warp::path(prefix)
         .and(warp::path::tail())
         .and_then(|tail: warp::path::Tail| {
             async move {
@@ -144,14 +190,16 @@ pub(crate) async fn handle_authn_rejection(
     }
 }
 
-/// A warp filter for adding an authorizer
+/// A warp filter that authenticates and authorizes the request, returning the principal of the
+/// user making it (which is empty for anonymous users). Routes that need the principal should use
+/// this instead of authenticating the request again, as authenticating can be expensive
 pub(crate) fn authenticate_and_authorize<
     Authn: Authenticator + Clone + Send + Sync,
     Authz: Authorizer + Clone + Send + Sync,
 >(
     authn: Authn,
     authz: Authz,
-) -> impl Filter<Extract = ((),), Error = Rejection> + Clone {
+) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
     authenticate(authn)
         .and(warp::path::full())
         .and(warp::method())
@@ -163,20 +211,62 @@ pub(crate) fn authenticate_and_authorize<
              authz: Authz| {
                 async move {
                     trace!(path = path.as_str(), %method, "Authorizing request");
-                    if let Err(e) = item.either(
+                    if let Err(e) = item.as_ref().either(
                         |anon| authz.authorize(anon, path.as_str(), &method),
                         |i| authz.authorize(i, path.as_str(), &method),
                     ) {
                         debug!(error = %e, "Authorization error");
                         return Err(warp::reject::custom(AuthzFail));
                     }
-                    Ok(())
+                    Ok(item.either(|anon| anon.principal(), |i| i.principal()))
                 }
                 .instrument(tracing::trace_span!("authorization"))
             },
         )
 }
 
+/// Same as [`authenticate_and_authorize`], but also only allows requests from users who are
+/// authorized to perform administrative operations
+pub(crate) fn authenticate_and_authorize_admin<
+    Authn: Authenticator + Clone + Send + Sync,
+    Authz: Authorizer + Clone + Send + Sync,
+>(
+    authn: Authn,
+    authz: Authz,
+) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    authenticate(authn)
+        .and(warp::path::full())
+        .and(warp::method())
+        .and(warp::any().map(move || authz.clone()))
+        .and_then(
+            |item: Either<Anonymous, Authn::Item>,
+             path: warp::path::FullPath,
+             method: warp::http::Method,
+             authz: Authz| {
+                async move {
+                    trace!(path = path.as_str(), %method, "Authorizing administrative request");
+                    if let Err(e) = item.as_ref().either(
+                        |anon| {
+                            authz
+                                .authorize(anon, path.as_str(), &method)
+                                .and_then(|_| authz.authorize_admin(anon))
+                        },
+                        |i| {
+                            authz
+                                .authorize(i, path.as_str(), &method)
+                                .and_then(|_| authz.authorize_admin(i))
+                        },
+                    ) {
+                        debug!(error = %e, "Admin authorization error");
+                        return Err(warp::reject::custom(AuthzFail));
+                    }
+                    Ok(item.either(|anon| anon.principal(), |i| i.principal()))
+                }
+                .instrument(tracing::trace_span!("admin_authorization"))
+            },
+        )
+}
+
 #[derive(Debug)]
 struct AuthzFail;
 
@@ -3,10 +3,12 @@ use std::convert::Infallible;
 use tracing::{debug, instrument, trace, trace_span};
 use warp::Reply;
 
-use super::filters::InvoiceQuery;
+use super::audit::AuditLog;
+use super::filters::{CreateInvoiceQuery, InvoiceQuery, UploadQuery};
+use super::quota::QuotaTracker;
 use super::reply;
 use crate::invoice::{SignatureRole, VerificationStrategy};
-use crate::provider::{Provider, ProviderError};
+use crate::provider::{is_parcel_id, Provider, ProviderError};
 use crate::search::Search;
 
 pub mod v1 {
@@ -14,7 +16,7 @@ pub mod v1 {
 
     use crate::{
         signature::{KeyEntry, KeyRing, SecretKeyStorage},
-        KeyOptions, LoginParams, QueryOptions, SignatureError,
+        AuditAction, AuditQuery, KeyOptions, LoginParams, QueryOptions, SignatureError,
     };
 
     use oauth2::reqwest::async_http_client;
@@ -57,18 +59,62 @@ pub mod v1 {
         ))
     }
 
-    #[instrument(level = "trace", skip(store, secret_store))]
-    pub async fn create_invoice<P: Provider, S: SecretKeyStorage>(
+    #[allow(clippy::too_many_arguments)]
+    pub async fn create_invoice<P: Provider + Sync, S: SecretKeyStorage>(
         store: P,
+        quotas: QuotaTracker,
+        audit: AuditLog,
+        principal: String,
         secret_store: S,
         strategy: VerificationStrategy,
         keyring: std::sync::Arc<KeyRing>,
+        query: CreateInvoiceQuery,
+        inv: crate::Invoice,
+        accept_header: Option<String>,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let bindle_id = inv.bindle.id.to_string();
+        let reply = _create_invoice(
+            store,
+            quotas,
+            secret_store,
+            strategy,
+            keyring,
+            query.staged.unwrap_or_default(),
+            inv,
+            accept_header,
+        )
+        .await?;
+        Ok(audit
+            .record_reply(
+                principal,
+                AuditAction::CreateInvoice,
+                Some(bindle_id),
+                None,
+                reply,
+            )
+            .await)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[instrument(level = "trace", skip(store, quotas, secret_store))]
+    async fn _create_invoice<P: Provider + Sync, S: SecretKeyStorage>(
+        store: P,
+        quotas: QuotaTracker,
+        secret_store: S,
+        strategy: VerificationStrategy,
+        keyring: std::sync::Arc<KeyRing>,
+        staged: bool,
         inv: crate::Invoice,
         accept_header: Option<String>,
     ) -> Result<impl warp::Reply, Infallible> {
         let accept = accept_header.unwrap_or_default();
         trace!("Create invoice request with invoice: {:?}", inv);
 
+        if let Err(e) = quotas.check_invoice(&inv.bindle.id) {
+            debug!(error = %e, "Invoice would exceed a quota");
+            return Ok(reply::into_reply(e));
+        }
+
         // Right here, I need to load one secret key and a ring of public keys.
         // Then I need to validate the invoice against the public keys, sign the invoice
         // with my private key, and THEN go on to store.create_invoice()
@@ -92,12 +138,24 @@ pub mod v1 {
             Err(e) => return Ok(reply::into_reply(ProviderError::FailedSigning(e))),
         };
 
-        let (invoice, labels) = match store.create_invoice(signed).await {
+        // A staged invoice is stored as a draft that only becomes visible once its last missing
+        // parcel is uploaded
+        let res = if staged {
+            store.create_draft_invoice(signed).await
+        } else {
+            store.create_invoice(signed).await
+        };
+        let (invoice, labels) = match res {
             Ok(l) => l,
             Err(e) => {
                 return Ok(reply::into_reply(e));
             }
         };
+        // Drafts only count towards quotas once they are published, which happens right away if
+        // nothing is missing
+        if !staged || labels.is_empty() {
+            quotas.record_invoice(&invoice, &labels);
+        }
         // If there are missing parcels that still need to be created, return a 202 to indicate that
         // things were accepted, but will not be fetchable until further action is taken
         if !labels.is_empty() {
@@ -162,8 +220,28 @@ pub mod v1 {
         Ok::<Box<dyn warp::Reply>, Infallible>(res)
     }
 
-    #[instrument(level = "trace", skip(store), fields(id = tail.as_str()))]
     pub async fn yank_invoice<P: Provider>(
+        tail: warp::path::Tail,
+        store: P,
+        audit: AuditLog,
+        principal: String,
+        accept_header: Option<String>,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let bindle_id = tail.as_str().to_owned();
+        let reply = _yank_invoice(tail, store, accept_header).await?;
+        Ok(audit
+            .record_reply(
+                principal,
+                AuditAction::YankInvoice,
+                Some(bindle_id),
+                None,
+                reply,
+            )
+            .await)
+    }
+
+    #[instrument(level = "trace", skip(store), fields(id = tail.as_str()))]
+    async fn _yank_invoice<P: Provider>(
         tail: warp::path::Tail,
         store: P,
         accept_header: Option<String>,
@@ -182,6 +260,65 @@ pub mod v1 {
         ))
     }
 
+    pub async fn delete_invoice<P: Provider + Sync>(
+        tail: warp::path::Tail,
+        store: P,
+        quotas: QuotaTracker,
+        audit: AuditLog,
+        principal: String,
+        accept_header: Option<String>,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let bindle_id = tail.as_str().to_owned();
+        let reply = _delete_invoice(tail, store, quotas, accept_header).await?;
+        Ok(audit
+            .record_reply(
+                principal,
+                AuditAction::DeleteInvoice,
+                Some(bindle_id),
+                None,
+                reply,
+            )
+            .await)
+    }
+
+    #[instrument(level = "trace", skip(store, quotas))]
+    async fn _delete_invoice<P: Provider + Sync>(
+        tail: warp::path::Tail,
+        store: P,
+        quotas: QuotaTracker,
+        accept_header: Option<String>,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let id = tail.as_str();
+        // The parcels of an invoice that counts towards a quota are needed to update its usage
+        // once the invoice is gone
+        let deleted = match crate::Id::try_from(id) {
+            Ok(parsed) if quotas.applies_to(&parsed) => {
+                match store.get_yanked_invoice(&parsed).await {
+                    Ok(inv) => Some(inv),
+                    Err(e) => {
+                        debug!(error = %e, "Got error while loading invoice to delete");
+                        return Ok(reply::into_reply(e));
+                    }
+                }
+            }
+            _ => None,
+        };
+        if let Err(e) = store.delete_invoice(id).await {
+            debug!(error = %e, "Got error during delete invoice request");
+            return Ok(reply::into_reply(e));
+        }
+        if let Some(inv) = deleted {
+            quotas.record_deleted_invoice(&inv);
+        }
+
+        let mut resp = std::collections::HashMap::new();
+        resp.insert("message", "invoice deleted");
+        Ok(warp::reply::with_status(
+            reply::serialized_data(&resp, accept_header.unwrap_or_default()),
+            warp::http::StatusCode::OK,
+        ))
+    }
+
     #[instrument(level = "trace", skip(store))]
     pub async fn head_invoice<P: Provider + Sync>(
         id: String,
@@ -201,11 +338,45 @@ pub mod v1 {
     }
 
     //////////// Parcel Functions ////////////
-    #[instrument(level = "trace", skip(store, body))]
     pub async fn create_parcel<P, B, D>(
         (bindle_id, sha): (String, String),
         body: B,
         store: P,
+        quotas: QuotaTracker,
+        audit: AuditLog,
+        principal: String,
+        accept_header: Option<String>,
+    ) -> Result<impl warp::Reply, Infallible>
+    where
+        P: Provider + Sync,
+        B: stream::Stream<Item = Result<D, warp::Error>> + Send + Sync + Unpin + 'static,
+        D: bytes::Buf + Send,
+    {
+        let reply = _create_parcel(
+            (bindle_id.clone(), sha.clone()),
+            body,
+            store,
+            quotas,
+            accept_header,
+        )
+        .await?;
+        Ok(audit
+            .record_reply(
+                principal,
+                AuditAction::CreateParcel,
+                Some(bindle_id),
+                Some(sha),
+                reply,
+            )
+            .await)
+    }
+
+    #[instrument(level = "trace", skip(store, quotas, body))]
+    async fn _create_parcel<P, B, D>(
+        (bindle_id, sha): (String, String),
+        body: B,
+        store: P,
+        quotas: QuotaTracker,
         accept_header: Option<String>,
     ) -> Result<impl warp::Reply, Infallible>
     where
@@ -216,23 +387,35 @@ pub mod v1 {
         trace!("Checking if parcel exists in bindle");
 
         // Validate that this sha belongs
-        if let Err(e) = parcel_in_bindle(&store, &bindle_id, &sha).await {
-            return Ok(e);
-        }
+        let (label, draft) = match parcel_in_draft_or_bindle(&store, &bindle_id, &sha).await {
+            Ok(l) => l,
+            Err(e) => return Ok(e),
+        };
+        let id = match check_parcel_quota(&quotas, &bindle_id, &label) {
+            Ok(id) => id,
+            Err(e) => return Ok(e),
+        };
 
         if let Err(e) = store
             .create_parcel(
-                bindle_id,
+                &id,
                 &sha,
-                body.map(|res| {
-                    res.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))
-                }),
+                body.map(|res| res.map_err(|e| std::io::Error::other(e.to_string()))),
             )
             .await
         {
             debug!(error = %e, "Got error while creating parcel in store");
+            // The parcel could have been uploaded for another bindle after a draft of this one
+            // was created, so the draft may be complete already
+            if matches!(e, ProviderError::Exists) {
+                publish_if_draft(&store, &quotas, &id).await;
+            }
             return Ok(reply::into_reply(e));
         }
+        if !draft {
+            quotas.record_parcel(&id, &label);
+        }
+        publish_if_draft(&store, &quotas, &id).await;
 
         let mut resp = std::collections::HashMap::new();
         resp.insert("message", "parcel created");
@@ -246,6 +429,7 @@ pub mod v1 {
     pub async fn get_parcel<P: Provider + Sync>(
         (bindle_id, id): (String, String),
         store: P,
+        range_header: Option<String>,
     ) -> Result<Box<dyn warp::Reply>, Infallible> {
         // Get parcel label to ascertain content type and length, and validate that it does exist
         let label = match parcel_in_bindle(&store, &bindle_id, &id).await {
@@ -253,7 +437,27 @@ pub mod v1 {
             Err(e) => return Ok::<Box<dyn warp::Reply>, Infallible>(Box::new(e)),
         };
 
-        let data = match store.get_parcel(bindle_id, &id).await {
+        let range = match parse_range(range_header.as_deref(), label.size) {
+            RequestedRange::Full => None,
+            RequestedRange::Partial(r) => Some(r),
+            RequestedRange::Unsatisfiable => {
+                debug!(size = label.size, "Requested range is not satisfiable");
+                return Ok::<Box<dyn warp::Reply>, Infallible>(Box::new(warp::reply::with_header(
+                    reply::reply_from_error(
+                        "requested range is not satisfiable",
+                        StatusCode::RANGE_NOT_SATISFIABLE,
+                    ),
+                    warp::http::header::CONTENT_RANGE,
+                    format!("bytes */{}", label.size),
+                )));
+            }
+        };
+
+        let data = match &range {
+            Some(r) => store.get_parcel_range(bindle_id, &id, r.clone()).await,
+            None => store.get_parcel(bindle_id, &id).await,
+        };
+        let data = match data {
             Ok(reader) => reader,
             Err(e) => {
                 debug!(error = %e, "Got error while getting parcel from store");
@@ -264,17 +468,28 @@ pub mod v1 {
         // TODO: If we start to use compression on the body, we'll need a new custom header for
         // _actual_ size of the parcel, so the client can reconstruct the label data from headers
         // without needing to read the whole (possibly large) file
-        let resp = warp::http::Response::builder()
+        let builder = warp::http::Response::builder()
             .header(warp::http::header::CONTENT_TYPE, label.media_type)
-            .header(warp::http::header::CONTENT_LENGTH, label.size)
-            .body(hyper::Body::wrap_stream(data))
-            .unwrap();
+            .header(warp::http::header::ACCEPT_RANGES, "bytes");
+        let (builder, status) = match range {
+            Some(r) => (
+                builder
+                    .header(warp::http::header::CONTENT_LENGTH, r.end - r.start)
+                    .header(
+                        warp::http::header::CONTENT_RANGE,
+                        format!("bytes {}-{}/{}", r.start, r.end - 1, label.size),
+                    ),
+                StatusCode::PARTIAL_CONTENT,
+            ),
+            None => (
+                builder.header(warp::http::header::CONTENT_LENGTH, label.size),
+                StatusCode::OK,
+            ),
+        };
+        let resp = builder.body(hyper::Body::wrap_stream(data)).unwrap();
 
         // Gotta box because this is not a toml reply type (which we use for sending error messages to the user)
-        Ok::<Box<dyn warp::Reply>, Infallible>(Box::new(warp::reply::with_status(
-            resp,
-            warp::http::StatusCode::OK,
-        )))
+        Ok::<Box<dyn warp::Reply>, Infallible>(Box::new(warp::reply::with_status(resp, status)))
     }
 
     #[instrument(level = "trace", skip(store))]
@@ -283,7 +498,8 @@ pub mod v1 {
         store: P,
     ) -> Result<Box<dyn warp::Reply>, Infallible> {
         trace!("Getting parcel data");
-        let inv = get_parcel((bindle_id, id), store).await?;
+        // Range requests are ignored for HEAD requests, so this always returns the full headers
+        let inv = get_parcel((bindle_id, id), store, None).await?;
 
         // Consume the response to we can take the headers
         let (parts, _) = inv.into_response().into_parts();
@@ -293,6 +509,250 @@ pub mod v1 {
         }))
     }
 
+    /// Checks if a parcel exists in the store without requiring a bindle that contains it
+    #[instrument(level = "trace", skip(store))]
+    pub async fn head_stored_parcel<P: Provider + Sync>(
+        sha: String,
+        store: P,
+    ) -> Result<Box<dyn warp::Reply>, Infallible> {
+        if !is_parcel_id(&sha) {
+            return Ok::<Box<dyn warp::Reply>, Infallible>(Box::new(reply::reply_from_error(
+                "parcel ID must be a SHA-256 sum",
+                StatusCode::BAD_REQUEST,
+            )));
+        }
+        let resp: Box<dyn warp::Reply> = match store.has_parcel(&sha).await {
+            Ok(true) => Box::new(StatusCode::OK),
+            Ok(false) => Box::new(reply::into_reply(ProviderError::NotFound)),
+            Err(e) => {
+                debug!(error = %e, "Got error while checking if parcel exists");
+                Box::new(reply::into_reply(e))
+            }
+        };
+        Ok(resp)
+    }
+
+    /// Returns which of the requested parcels exist in the store
+    #[instrument(level = "trace", skip(store, req), fields(num_parcels = req.parcel.len()))]
+    pub async fn existing_parcels<P: Provider + Sync>(
+        store: P,
+        req: crate::ParcelExistsRequest,
+        accept_header: Option<String>,
+    ) -> Result<impl warp::Reply, Infallible> {
+        if req.parcel.len() > crate::ParcelExistsRequest::MAX_PARCELS {
+            return Ok(reply::reply_from_error(
+                format!(
+                    "at most {} parcels can be checked at once",
+                    crate::ParcelExistsRequest::MAX_PARCELS
+                ),
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+        // Anything that isn't a SHA can't be a parcel, so there is no need to ask the store
+        let ids: Vec<&str> = req
+            .parcel
+            .iter()
+            .map(|sha| sha.as_str())
+            .filter(|sha| is_parcel_id(sha))
+            .collect();
+        let exists = match store.parcels_exist(&ids).await {
+            Ok(e) => e,
+            Err(e) => {
+                debug!(error = %e, "Got error while checking if parcels exist");
+                return Ok(reply::into_reply(e));
+            }
+        };
+        let existing = ids
+            .into_iter()
+            .zip(exists)
+            .filter(|(_, exists)| *exists)
+            .map(|(sha, _)| sha.to_owned())
+            .collect();
+        Ok(warp::reply::with_status(
+            reply::serialized_data(
+                &crate::ParcelExistsResponse { existing },
+                accept_header.unwrap_or_default(),
+            ),
+            StatusCode::OK,
+        ))
+    }
+
+    //////////// Upload Functions ////////////
+    #[instrument(level = "trace", skip(store, quotas))]
+    pub async fn start_upload<P: Provider + Sync>(
+        (bindle_id, sha): (String, String),
+        store: P,
+        quotas: QuotaTracker,
+        accept_header: Option<String>,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let (label, _) = match parcel_in_draft_or_bindle(&store, &bindle_id, &sha).await {
+            Ok(l) => l,
+            Err(e) => return Ok(e),
+        };
+        let id = match check_parcel_quota(&quotas, &bindle_id, &label) {
+            Ok(id) => id,
+            Err(e) => return Ok(e),
+        };
+
+        match store.start_parcel_upload(&id, &sha).await {
+            Ok(offset) => Ok(upload_status(offset, accept_header)),
+            Err(e) => {
+                debug!(error = %e, "Got error while starting upload");
+                Ok(reply::into_reply(e))
+            }
+        }
+    }
+
+    #[instrument(level = "trace", skip(store))]
+    pub async fn get_upload<P: Provider + Sync>(
+        (bindle_id, sha): (String, String),
+        store: P,
+        accept_header: Option<String>,
+    ) -> Result<impl warp::Reply, Infallible> {
+        if let Err(e) = parcel_in_draft_or_bindle(&store, &bindle_id, &sha).await {
+            return Ok(e);
+        }
+
+        match store.parcel_upload_offset(bindle_id, &sha).await {
+            Ok(offset) => Ok(upload_status(offset, accept_header)),
+            Err(e) => {
+                debug!(error = %e, "Got error while getting upload offset");
+                Ok(reply::into_reply(e))
+            }
+        }
+    }
+
+    #[instrument(level = "trace", skip(store, body))]
+    pub async fn upload_chunk<P, B, D>(
+        (bindle_id, sha): (String, String),
+        query: UploadQuery,
+        body: B,
+        store: P,
+        accept_header: Option<String>,
+    ) -> Result<impl warp::Reply, Infallible>
+    where
+        P: Provider + Sync,
+        B: stream::Stream<Item = Result<D, warp::Error>> + Send + Sync + Unpin + 'static,
+        D: bytes::Buf + Send,
+    {
+        if let Err(e) = parcel_in_draft_or_bindle(&store, &bindle_id, &sha).await {
+            return Ok(e);
+        }
+
+        match store
+            .write_parcel_chunk(
+                bindle_id,
+                &sha,
+                query.offset,
+                body.map(|res| res.map_err(|e| std::io::Error::other(e.to_string()))),
+            )
+            .await
+        {
+            Ok(offset) => Ok(upload_status(offset, accept_header)),
+            Err(e) => {
+                debug!(error = %e, "Got error while writing upload chunk");
+                Ok(reply::into_reply(e))
+            }
+        }
+    }
+
+    pub async fn finish_upload<P: Provider + Sync>(
+        (bindle_id, sha): (String, String),
+        store: P,
+        quotas: QuotaTracker,
+        audit: AuditLog,
+        principal: String,
+        accept_header: Option<String>,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let reply = _finish_upload(
+            (bindle_id.clone(), sha.clone()),
+            store,
+            quotas,
+            accept_header,
+        )
+        .await?;
+        // Finishing a resumable upload is when the parcel is actually created
+        Ok(audit
+            .record_reply(
+                principal,
+                AuditAction::CreateParcel,
+                Some(bindle_id),
+                Some(sha),
+                reply,
+            )
+            .await)
+    }
+
+    #[instrument(level = "trace", skip(store, quotas))]
+    async fn _finish_upload<P: Provider + Sync>(
+        (bindle_id, sha): (String, String),
+        store: P,
+        quotas: QuotaTracker,
+        accept_header: Option<String>,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let (label, draft) = match parcel_in_draft_or_bindle(&store, &bindle_id, &sha).await {
+            Ok(l) => l,
+            Err(e) => return Ok(e),
+        };
+        // The quota was already checked when the upload was started
+        let id = match crate::Id::try_from(bindle_id) {
+            Ok(id) => id,
+            Err(e) => return Ok(reply::into_reply(e.into())),
+        };
+
+        if let Err(e) = store.finish_parcel_upload(&id, &sha).await {
+            debug!(error = %e, "Got error while finishing upload");
+            return Ok(reply::into_reply(e));
+        }
+        if !draft {
+            quotas.record_parcel(&id, &label);
+        }
+        publish_if_draft(&store, &quotas, &id).await;
+
+        let mut resp = std::collections::HashMap::new();
+        resp.insert("message", "parcel created");
+        Ok(warp::reply::with_status(
+            reply::serialized_data(&resp, accept_header.unwrap_or_default()),
+            warp::http::StatusCode::OK,
+        ))
+    }
+
+    #[instrument(level = "trace", skip(store))]
+    pub async fn abort_upload<P: Provider + Sync>(
+        (bindle_id, sha): (String, String),
+        store: P,
+        accept_header: Option<String>,
+    ) -> Result<impl warp::Reply, Infallible> {
+        if let Err(e) = parcel_in_draft_or_bindle(&store, &bindle_id, &sha).await {
+            return Ok(e);
+        }
+
+        if let Err(e) = store.abort_parcel_upload(bindle_id, &sha).await {
+            debug!(error = %e, "Got error while aborting upload");
+            return Ok(reply::into_reply(e));
+        }
+
+        let mut resp = std::collections::HashMap::new();
+        resp.insert("message", "upload aborted");
+        Ok(warp::reply::with_status(
+            reply::serialized_data(&resp, accept_header.unwrap_or_default()),
+            warp::http::StatusCode::OK,
+        ))
+    }
+
+    fn upload_status(
+        offset: u64,
+        accept_header: Option<String>,
+    ) -> warp::reply::WithStatus<reply::SerializedData> {
+        warp::reply::with_status(
+            reply::serialized_data(
+                &crate::UploadStatusResponse { offset },
+                accept_header.unwrap_or_default(),
+            ),
+            StatusCode::OK,
+        )
+    }
+
     //////////// Relationship Functions ////////////
     #[instrument(level = "trace", skip(store), fields(id = tail.as_str()))]
     pub async fn get_missing<P: Provider + Sync + Clone>(
@@ -310,35 +770,11 @@ pub mod v1 {
             }
         };
 
-        let missing_futures = inv
-            .parcel
-            .unwrap_or_default()
-            .into_iter()
-            .map(|p| (p, id.to_owned(), store.clone()))
-            .map(|(p, bindle_id, store)| async move {
-                // We can't use a filter_map with async, so we need to map first, then collect things with a filter
-                match store.parcel_exists(bindle_id, &p.label.sha256).await {
-                    Ok(b) => {
-                        // For some reason, guard blocks on bools don't indicate to the compiler
-                        // that they are exhaustive, so hence the nested if block
-                        if b {
-                            // If it exists, don't include it
-                            Ok(None)
-                        } else {
-                            Ok(Some(p.label))
-                        }
-                    }
-                    Err(e) => Err(e),
-                }
-            });
-
-        let missing = match futures::future::join_all(missing_futures)
+        let missing = match crate::provider::missing_parcels(&store, &inv)
             .instrument(trace_span!("find_missing_parcels"))
             .await
-            .into_iter()
-            .collect::<Result<Vec<Option<crate::Label>>, crate::provider::ProviderError>>()
         {
-            Ok(m) => m.into_iter().flatten().collect::<Vec<crate::Label>>(),
+            Ok(m) => m,
             Err(e) => {
                 trace!("Got error during get missing request: {:?}", e);
                 return Ok(reply::into_reply(e));
@@ -356,8 +792,23 @@ pub mod v1 {
     //////////// Login Functions ////////////
 
     /// Redirects to a login request
-    #[instrument(level = "trace")]
     pub(crate) async fn login(
+        p: LoginParams,
+        client_id: String,
+        device_auth_url: String,
+        token_url: String,
+        audit: AuditLog,
+        principal: String,
+        accept_header: Option<String>,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let reply = _login(p, client_id, device_auth_url, token_url, accept_header).await?;
+        Ok(audit
+            .record_reply(principal, AuditAction::Login, None, None, reply)
+            .await)
+    }
+
+    #[instrument(level = "trace")]
+    async fn _login(
         _p: LoginParams,
         client_id: String,
         device_auth_url: String,
@@ -489,6 +940,52 @@ pub mod v1 {
         ))
     }
 
+    //////////// Quota Functions ////////////
+
+    #[instrument(level = "trace", skip(quotas))]
+    pub async fn quota_usage(
+        quotas: QuotaTracker,
+        accept_header: Option<String>,
+    ) -> Result<impl warp::Reply, Infallible> {
+        Ok(warp::reply::with_status(
+            reply::serialized_data(
+                &crate::QuotaUsageResponse {
+                    quota: quotas.usage(),
+                },
+                accept_header.unwrap_or_default(),
+            ),
+            warp::http::StatusCode::OK,
+        ))
+    }
+
+    //////////// Audit Functions ////////////
+
+    #[instrument(level = "trace", skip(audit))]
+    pub async fn audit_log(
+        query: AuditQuery,
+        audit: AuditLog,
+        accept_header: Option<String>,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let entry = match audit.query(&query).await {
+            Some(Ok(entries)) => entries,
+            Some(Err(e)) => {
+                tracing::error!(error = %e, "Unable to query audit log");
+                return Ok(reply::reply_from_error(
+                    "Unable to query audit log",
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                ));
+            }
+            None => return Ok(reply::into_reply(ProviderError::Unsupported)),
+        };
+        Ok(warp::reply::with_status(
+            reply::serialized_data(
+                &crate::AuditLogResponse { entry },
+                accept_header.unwrap_or_default(),
+            ),
+            warp::http::StatusCode::OK,
+        ))
+    }
+
     //////////// Helper Functions ////////////
 
     /// Fetches an invoice from the given store and checks that the given SHA exists within that
@@ -507,7 +1004,44 @@ pub mod v1 {
             Ok(i) => i,
             Err(e) => return Err(reply::into_reply(e)),
         };
+        label_in_invoice(inv, bindle_id, sha)
+    }
+
+    /// Same as `parcel_in_bindle`, but also finds parcels of draft invoices, returning whether the
+    /// parcel belongs to a draft along with its label. Drafts are hidden everywhere except for the
+    /// endpoints used to upload their parcels
+    async fn parcel_in_draft_or_bindle<P: Provider + Sync>(
+        store: &P,
+        bindle_id: &str,
+        sha: &str,
+    ) -> std::result::Result<
+        (crate::Label, bool),
+        warp::reply::WithStatus<crate::server::reply::SerializedData>,
+    > {
+        trace!("fetching invoice or draft data");
+        let (inv, draft) = match store.get_invoice(bindle_id).await {
+            Ok(i) => (i, false),
+            Err(ProviderError::NotFound) => match store.get_draft_invoice(bindle_id).await {
+                Ok(i) => (i, true),
+                // The provider doesn't support drafts, so there is nothing else to find
+                Err(ProviderError::Unsupported) => {
+                    return Err(reply::into_reply(ProviderError::NotFound))
+                }
+                Err(e) => return Err(reply::into_reply(e)),
+            },
+            Err(e) => return Err(reply::into_reply(e)),
+        };
+        label_in_invoice(inv, bindle_id, sha).map(|label| (label, draft))
+    }
 
+    fn label_in_invoice(
+        inv: crate::Invoice,
+        bindle_id: &str,
+        sha: &str,
+    ) -> std::result::Result<
+        crate::Label,
+        warp::reply::WithStatus<crate::server::reply::SerializedData>,
+    > {
         // Make sure the sha exists in the list
         let label = inv
             .parcel
@@ -522,6 +1056,103 @@ pub mod v1 {
             )),
         }
     }
+
+    /// Publishes the given bindle if it is a draft that is no longer missing any parcels, recording
+    /// it (and all of its parcels) in the quotas. A failure is only logged, as the parcel that
+    /// triggered this was stored successfully
+    async fn publish_if_draft<P: Provider + Sync>(
+        store: &P,
+        quotas: &QuotaTracker,
+        id: &crate::Id,
+    ) {
+        match store.publish_draft_invoice(id).await {
+            Ok(missing) if missing.is_empty() => {
+                debug!(%id, "Published draft invoice");
+                match store.get_yanked_invoice(id).await {
+                    Ok(inv) => quotas.record_invoice(&inv, &[]),
+                    Err(e) => {
+                        tracing::warn!(%id, error = %e, "Unable to load published invoice to update quotas")
+                    }
+                }
+            }
+            Ok(missing) => trace!(
+                %id,
+                missing = missing.len(),
+                "Draft invoice is still missing parcels"
+            ),
+            // Most bindles are not drafts
+            Err(ProviderError::NotFound) | Err(ProviderError::Unsupported) => (),
+            Err(e) => tracing::error!(%id, error = %e, "Unable to publish draft invoice"),
+        }
+    }
+
+    /// Checks that storing the parcel with the given label won't go over a quota, returning the
+    /// parsed bindle ID
+    fn check_parcel_quota(
+        quotas: &QuotaTracker,
+        bindle_id: &str,
+        label: &crate::Label,
+    ) -> std::result::Result<crate::Id, warp::reply::WithStatus<crate::server::reply::SerializedData>>
+    {
+        let id = crate::Id::try_from(bindle_id).map_err(|e| reply::into_reply(e.into()))?;
+        if let Err(e) = quotas.check_parcel(&id, label) {
+            debug!(error = %e, "Parcel would exceed a quota");
+            return Err(reply::into_reply(e));
+        }
+        Ok(id)
+    }
+}
+
+/// The portion of a parcel requested by a client using the `Range` header
+#[derive(Debug, PartialEq)]
+enum RequestedRange {
+    /// The whole parcel was requested, either because there was no range header or because the
+    /// header was one we don't support (such as multiple ranges)
+    Full,
+    /// A single range of bytes. The end of the range is exclusive
+    Partial(std::ops::Range<u64>),
+    /// The range can't be satisfied for a parcel of this size
+    Unsatisfiable,
+}
+
+/// Parses a `Range` header for a parcel of the given size. Only single byte ranges are supported.
+/// Any other range header is ignored as permitted by RFC 7233, which means the full parcel will be
+/// sent
+fn parse_range(header: Option<&str>, size: u64) -> RequestedRange {
+    let spec = match header.and_then(|h| h.trim().strip_prefix("bytes=")) {
+        // Multiple ranges aren't supported
+        Some(s) if !s.contains(',') => s.trim(),
+        _ => return RequestedRange::Full,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(parts) => parts,
+        None => return RequestedRange::Full,
+    };
+    match (start.parse::<u64>(), end.parse::<u64>()) {
+        // A suffix range, which requests the last N bytes
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 || size == 0 {
+                RequestedRange::Unsatisfiable
+            } else {
+                RequestedRange::Partial(size.saturating_sub(suffix)..size)
+            }
+        }
+        (Ok(start), Err(_)) if end.is_empty() => {
+            if start >= size {
+                RequestedRange::Unsatisfiable
+            } else {
+                RequestedRange::Partial(start..size)
+            }
+        }
+        (Ok(start), Ok(last)) if start <= last => {
+            if start >= size {
+                RequestedRange::Unsatisfiable
+            } else {
+                RequestedRange::Partial(start..last.saturating_add(1).min(size))
+            }
+        }
+        _ => RequestedRange::Full,
+    }
 }
 
 // A helper struct for HEAD responses that takes the raw headers from a GET request and puts them
@@ -538,3 +1169,49 @@ impl Reply for HeadResponse {
         resp
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(RequestedRange::Full, parse_range(None, 10));
+        assert_eq!(
+            RequestedRange::Partial(0..5),
+            parse_range(Some("bytes=0-4"), 10)
+        );
+        assert_eq!(
+            RequestedRange::Partial(3..10),
+            parse_range(Some("bytes=3-"), 10)
+        );
+        assert_eq!(
+            RequestedRange::Partial(7..10),
+            parse_range(Some("bytes=-3"), 10)
+        );
+        // Ranges past the end should be clamped
+        assert_eq!(
+            RequestedRange::Partial(5..10),
+            parse_range(Some("bytes=5-100"), 10)
+        );
+        assert_eq!(
+            RequestedRange::Partial(0..10),
+            parse_range(Some("bytes=-100"), 10)
+        );
+
+        assert_eq!(
+            RequestedRange::Unsatisfiable,
+            parse_range(Some("bytes=10-"), 10)
+        );
+        assert_eq!(
+            RequestedRange::Unsatisfiable,
+            parse_range(Some("bytes=-0"), 10)
+        );
+
+        // Unsupported or invalid ranges should be ignored
+        assert_eq!(RequestedRange::Full, parse_range(Some("bytes=0-1,4-5"), 10));
+        assert_eq!(RequestedRange::Full, parse_range(Some("bytes=5-1"), 10));
+        assert_eq!(RequestedRange::Full, parse_range(Some("items=0-1"), 10));
+        assert_eq!(RequestedRange::Full, parse_range(Some("bytes=a-b"), 10));
+    }
+}
@@ -2,8 +2,10 @@
 //! Spec](https://github.com/deislabs/bindle/blob/master/docs/protocol-spec.md), with associated
 //! HTTP handlers and functions
 
+pub mod audit;
 pub(crate) mod filters;
 mod handlers;
+pub mod quota;
 pub(crate) mod reply;
 
 mod routes;
@@ -40,6 +42,8 @@ pub async fn server<P, I, Authn, Authz, S>(
     keystore: S,
     verification_strategy: crate::VerificationStrategy,
     keyring: KeyRing,
+    quotas: quota::QuotaTracker,
+    audit: audit::AuditLog,
 ) -> anyhow::Result<()>
 where
     P: Provider + Clone + Send + Sync + 'static,
@@ -57,6 +61,8 @@ where
         keystore,
         verification_strategy,
         keyring,
+        quotas,
+        audit,
     );
 
     let server = warp::serve(api);
@@ -133,6 +139,8 @@ mod test {
             ks,
             VerificationStrategy::default(),
             valid_v1.keyring.clone(),
+            Default::default(),
+            Default::default(),
         );
 
         // Create an invoice pointing to those parcels and make sure the correct response is returned
@@ -310,6 +318,8 @@ mod test {
             ks,
             VerificationStrategy::default(),
             scaffold.keyring.clone(),
+            Default::default(),
+            Default::default(),
         );
 
         // Insert an invoice
@@ -358,6 +368,109 @@ mod test {
         toml::from_slice::<crate::Invoice>(res.body()).expect("should be valid invoice TOML");
     }
 
+    #[rstest]
+    #[tokio::test]
+    async fn test_purge<T>(
+        #[values(testing::setup(), testing::setup_embedded())]
+        #[future]
+        provider_setup: (T, StrictEngine, MockKeyStore),
+    ) where
+        T: Provider + Clone + Send + Sync + 'static,
+    {
+        let (store, index, ks) = provider_setup.await;
+        let scaffold = testing::Scaffold::load("incomplete").await;
+        let authn = crate::authn::http_basic::HttpBasic::from_file("test/data/htpasswd")
+            .await
+            .expect("Unable to load htpasswd file");
+        let auth_header = format!(
+            "Basic {}",
+            base64::engine::general_purpose::STANDARD.encode(b"admin:sw0rdf1sh")
+        );
+
+        store
+            .create_invoice(NoopSigned(NoopVerified(scaffold.invoice.clone())))
+            .await
+            .expect("Should be able to insert invoice");
+
+        let inv_path = format!("/v1/_i/{}", scaffold.invoice.name());
+        let purge_path = format!("{}?purge=true", inv_path);
+
+        // A user that isn't an admin should not be able to purge (or accidentally yank) the invoice
+        let api = super::routes::api(
+            store.clone(),
+            index.clone(),
+            authn.clone(),
+            crate::authz::admin::AdminAuthorizer::new(
+                crate::authz::anonymous_get::AnonymousGet,
+                Vec::new(),
+            ),
+            ks.clone(),
+            VerificationStrategy::default(),
+            scaffold.keyring.clone(),
+            Default::default(),
+            Default::default(),
+        );
+        let res = warp::test::request()
+            .method("DELETE")
+            .header("Authorization", &auth_header)
+            .path(&purge_path)
+            .reply(&api)
+            .await;
+
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::FORBIDDEN,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        store
+            .get_invoice(scaffold.invoice.bindle.id.clone())
+            .await
+            .expect("Invoice should still exist and not be yanked");
+
+        // An admin should be able to purge the invoice
+        let api = super::routes::api(
+            store.clone(),
+            index,
+            authn,
+            crate::authz::admin::AdminAuthorizer::new(
+                crate::authz::anonymous_get::AnonymousGet,
+                vec!["admin".to_owned()],
+            ),
+            ks,
+            VerificationStrategy::default(),
+            scaffold.keyring.clone(),
+            Default::default(),
+            Default::default(),
+        );
+        let res = warp::test::request()
+            .method("DELETE")
+            .header("Authorization", &auth_header)
+            .path(&purge_path)
+            .reply(&api)
+            .await;
+
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+
+        // The invoice should be gone, even when asking for yanked invoices
+        let res = warp::test::request()
+            .path(&format!("{}?yanked=true", inv_path))
+            .reply(&api)
+            .await;
+
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::NOT_FOUND,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+    }
+
     #[rstest]
     #[tokio::test]
     // This isn't meant to test all of the possible validation failures (that should be done in a unit
@@ -383,6 +496,8 @@ mod test {
             ks,
             VerificationStrategy::default(),
             valid.keyring.clone(),
+            Default::default(),
+            Default::default(),
         );
 
         store
@@ -428,6 +543,8 @@ mod test {
             keystore.clone(),
             VerificationStrategy::default(),
             scaffold.keyring.clone(),
+            Default::default(),
+            Default::default(),
         );
         // Insert a parcel
         let parcel = scaffold.parcel_files.get("parcel").expect("Missing parcel");
@@ -492,6 +609,237 @@ mod test {
         );
     }
 
+    #[rstest]
+    #[tokio::test]
+    async fn test_parcel_range<T>(
+        #[values(testing::setup(), testing::setup_embedded())]
+        #[future]
+        provider_setup: (T, StrictEngine, MockKeyStore),
+    ) where
+        T: Provider + Clone + Send + Sync + 'static,
+    {
+        let (store, index, keystore) = provider_setup.await;
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+
+        let api = super::routes::api(
+            store.clone(),
+            index,
+            AlwaysAuthenticate,
+            AlwaysAuthorize,
+            keystore,
+            VerificationStrategy::default(),
+            scaffold.keyring.clone(),
+            Default::default(),
+            Default::default(),
+        );
+        let parcel = scaffold.parcel_files.get("parcel").expect("Missing parcel");
+        let data = std::io::Cursor::new(parcel.data.clone());
+        store
+            .create_invoice(NoopSigned(NoopVerified(scaffold.invoice.clone())))
+            .await
+            .expect("Unable to insert invoice into store");
+        store
+            .create_parcel(
+                &scaffold.invoice.bindle.id,
+                &parcel.sha,
+                FramedRead::new(data, BytesCodec::default()),
+            )
+            .await
+            .expect("Unable to create parcel");
+        let path = format!("/v1/_i/{}@{}", scaffold.invoice.bindle.id, parcel.sha);
+        let size = parcel.data.len();
+
+        // A normal request should advertise range support
+        let res = warp::test::request().path(&path).reply(&api).await;
+        assert_eq!(res.status(), warp::http::StatusCode::OK);
+        assert_eq!(
+            res.headers()
+                .get(warp::http::header::ACCEPT_RANGES)
+                .expect("Accept-Ranges header should be set"),
+            "bytes"
+        );
+        assert_eq!(res.body().as_ref(), parcel.data.as_slice());
+
+        // Resuming from an offset should return the rest of the parcel
+        let res = warp::test::request()
+            .path(&path)
+            .header("Range", "bytes=2-")
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::PARTIAL_CONTENT,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        assert_eq!(
+            res.headers()
+                .get(warp::http::header::CONTENT_RANGE)
+                .expect("Content-Range header should be set"),
+            format!("bytes 2-{}/{}", size - 1, size).as_str()
+        );
+        assert_eq!(res.body().as_ref(), &parcel.data[2..]);
+
+        // A bounded range should only return the requested bytes
+        let res = warp::test::request()
+            .path(&path)
+            .header("Range", "bytes=1-3")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            res.headers()
+                .get(warp::http::header::CONTENT_LENGTH)
+                .expect("Content-Length header should be set"),
+            "3"
+        );
+        assert_eq!(res.body().as_ref(), &parcel.data[1..4]);
+
+        // A range starting past the end of the parcel cannot be satisfied
+        let res = warp::test::request()
+            .path(&path)
+            .header("Range", format!("bytes={}-", size))
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::RANGE_NOT_SATISFIABLE,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        assert_eq!(
+            res.headers()
+                .get(warp::http::header::CONTENT_RANGE)
+                .expect("Content-Range header should be set"),
+            format!("bytes */{}", size).as_str()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resumable_upload() {
+        let (store, index, keystore) = testing::setup().await;
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+
+        let api = super::routes::api(
+            store.clone(),
+            index,
+            AlwaysAuthenticate,
+            AlwaysAuthorize,
+            keystore,
+            VerificationStrategy::default(),
+            scaffold.keyring.clone(),
+            Default::default(),
+            Default::default(),
+        );
+        store
+            .create_invoice(NoopSigned(NoopVerified(scaffold.invoice.clone())))
+            .await
+            .expect("Unable to insert invoice into store");
+        let parcel = scaffold.parcel_files.get("parcel").expect("Missing parcel");
+        let path = format!("/v1/_u/{}@{}", scaffold.invoice.bindle.id, parcel.sha);
+        let offset_of = |body: &[u8]| {
+            toml::from_slice::<crate::UploadStatusResponse>(body)
+                .expect("should be a valid upload status")
+                .offset
+        };
+
+        let res = warp::test::request()
+            .method("POST")
+            .path(&path)
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        assert_eq!(0, offset_of(res.body()));
+
+        let res = warp::test::request()
+            .method("PATCH")
+            .path(&format!("{}?offset=0", path))
+            .body(&parcel.data[..3])
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        assert_eq!(3, offset_of(res.body()));
+
+        // A chunk at the wrong offset should conflict
+        let res = warp::test::request()
+            .method("PATCH")
+            .path(&format!("{}?offset=0", path))
+            .body(parcel.data.clone())
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::CONFLICT);
+
+        let res = warp::test::request().path(&path).reply(&api).await;
+        assert_eq!(res.status(), warp::http::StatusCode::OK);
+        assert_eq!(3, offset_of(res.body()));
+
+        let res = warp::test::request()
+            .method("PATCH")
+            .path(&format!("{}?offset=3", path))
+            .body(&parcel.data[3..])
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::OK);
+        assert_eq!(parcel.data.len() as u64, offset_of(res.body()));
+
+        let res = warp::test::request()
+            .method("PUT")
+            .path(&path)
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+
+        // The parcel should now be available
+        let res = warp::test::request()
+            .path(&format!(
+                "/v1/_i/{}@{}",
+                scaffold.invoice.bindle.id, parcel.sha
+            ))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::OK);
+        assert_eq!(res.body().as_ref(), parcel.data.as_slice());
+
+        // Providers that don't support resumable uploads should say so
+        let (store, index, keystore) = testing::setup_embedded().await;
+        store
+            .create_invoice(NoopSigned(NoopVerified(scaffold.invoice.clone())))
+            .await
+            .expect("Unable to insert invoice into store");
+        let api = super::routes::api(
+            store,
+            index,
+            AlwaysAuthenticate,
+            AlwaysAuthorize,
+            keystore,
+            VerificationStrategy::default(),
+            scaffold.keyring.clone(),
+            Default::default(),
+            Default::default(),
+        );
+        let res = warp::test::request()
+            .method("POST")
+            .path(&path)
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::NOT_IMPLEMENTED);
+    }
+
     #[rstest]
     #[tokio::test]
     // Once again, this isn't meant to exercise all of the query functionality, just that the API
@@ -514,6 +862,8 @@ mod test {
             ks,
             VerificationStrategy::default(),
             KeyRing::default(),
+            Default::default(),
+            Default::default(),
         );
         let bindles_to_insert = vec!["incomplete", "valid_v1", "valid_v2"];
 
@@ -623,6 +973,8 @@ mod test {
             ks,
             VerificationStrategy::default(),
             scaffold.keyring.clone(),
+            Default::default(),
+            Default::default(),
         );
 
         store
@@ -680,7 +1032,7 @@ mod test {
 
     #[rstest]
     #[tokio::test]
-    async fn test_host_signed<T>(
+    async fn test_stored_parcel_exists<T>(
         #[values(testing::setup(), testing::setup_embedded())]
         #[future]
         provider_setup: (T, StrictEngine, MockKeyStore),
@@ -688,19 +1040,20 @@ mod test {
         T: Provider + Clone + Send + Sync + 'static,
     {
         let (store, index, ks) = provider_setup.await;
-        let scaffold = testing::RawScaffold::load("valid_v1").await;
+        let scaffold = testing::RawScaffold::load("lotsa_parcels").await;
 
         let api = super::routes::api(
-            store,
+            store.clone(),
             index,
             AlwaysAuthenticate,
             AlwaysAuthorize,
             ks,
             VerificationStrategy::default(),
             scaffold.keyring.clone(),
+            Default::default(),
+            Default::default(),
         );
 
-        // Create a valid invoice and make sure the returned invoice is signed
         let res = warp::test::request()
             .method("POST")
             .header("Content-Type", "application/toml")
@@ -708,7 +1061,6 @@ mod test {
             .body(&scaffold.invoice)
             .reply(&api)
             .await;
-
         assert_eq!(
             res.status(),
             warp::http::StatusCode::ACCEPTED,
@@ -717,29 +1069,341 @@ mod test {
         );
         let create_res: crate::InvoiceCreateResponse =
             toml::from_slice(res.body()).expect("should be valid invoice response TOML");
+        let id = create_res.invoice.bindle.id;
 
-        assert!(
-            create_res
-                .invoice
-                .signature
-                .unwrap_or_default()
-                .into_iter()
-                .any(|sig| matches!(sig.role, crate::SignatureRole::Host)),
-            "Newly created invoice should be signed by the host"
-        );
-    }
-
-    #[rstest]
-    #[tokio::test]
-    async fn test_anonymous_get<T>(
-        #[values(testing::setup(), testing::setup_embedded())]
-        #[future]
-        provider_setup: (T, StrictEngine, MockKeyStore),
-    ) where
-        T: Provider + Clone + Send + Sync + 'static,
-    {
-        let (store, index, ks) = provider_setup.await;
-        let scaffold = testing::RawScaffold::load("valid_v1").await;
+        let mut files = scaffold.parcel_files.values();
+        let stored = files.next().expect("Scaffold should have parcels");
+        let missing = files.next().expect("Scaffold should have parcels");
+        let res = warp::test::request()
+            .method("POST")
+            .path(&format!("/v1/_i/{}@{}", id, stored.sha))
+            .body(stored.data.clone())
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::OK);
+
+        let res = warp::test::request()
+            .method("HEAD")
+            .path(&format!("/v1/_p/{}", stored.sha))
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Stored parcels should exist without a bindle"
+        );
+        let res = warp::test::request()
+            .method("HEAD")
+            .path(&format!("/v1/_p/{}", missing.sha))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::NOT_FOUND);
+        let res = warp::test::request()
+            .method("HEAD")
+            .path("/v1/_p/..%2F..%2Finvoices")
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::BAD_REQUEST,
+            "IDs that aren't a SHA should be rejected"
+        );
+
+        let body = toml::to_vec(&crate::ParcelExistsRequest {
+            parcel: vec![
+                missing.sha.clone(),
+                stored.sha.clone(),
+                "not-a-sha".to_owned(),
+            ],
+        })
+        .unwrap();
+        let res = warp::test::request()
+            .method("POST")
+            .header("Content-Type", "application/toml")
+            .path("/v1/_p")
+            .body(body)
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let exists_res: crate::ParcelExistsResponse =
+            toml::from_slice(res.body()).expect("should be valid parcel exists response TOML");
+        assert_eq!(exists_res.existing, vec![stored.sha.clone()]);
+
+        let body = toml::to_vec(&crate::ParcelExistsRequest {
+            parcel: vec![stored.sha.clone(); crate::ParcelExistsRequest::MAX_PARCELS + 1],
+        })
+        .unwrap();
+        let res = warp::test::request()
+            .method("POST")
+            .header("Content-Type", "application/toml")
+            .path("/v1/_p")
+            .body(body)
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::BAD_REQUEST,
+            "Too many parcels should be rejected"
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_staged_invoice<T>(
+        #[values(testing::setup(), testing::setup_embedded())]
+        #[future]
+        provider_setup: (T, StrictEngine, MockKeyStore),
+    ) where
+        T: Provider + Clone + Send + Sync + 'static,
+    {
+        let (store, index, ks) = provider_setup.await;
+        let scaffold = testing::RawScaffold::load("lotsa_parcels").await;
+        let parsed = testing::Scaffold::load("lotsa_parcels").await;
+        let total: u64 = parsed
+            .parcel_files
+            .values()
+            .map(|p| p.data.len() as u64)
+            .sum();
+        let quotas = super::quota::QuotaTracker::new(
+            super::quota::QuotaConfig {
+                quota: vec![super::quota::Quota {
+                    prefix: parsed.invoice.bindle.id.name().to_owned(),
+                    max_bytes: None,
+                    max_invoices: None,
+                }],
+            },
+            &store,
+        )
+        .await
+        .expect("Unable to create quota tracker");
+
+        let api = super::routes::api(
+            store.clone(),
+            index,
+            AlwaysAuthenticate,
+            AlwaysAuthorize,
+            ks,
+            VerificationStrategy::default(),
+            scaffold.keyring.clone(),
+            quotas.clone(),
+            Default::default(),
+        );
+
+        let res = warp::test::request()
+            .method("POST")
+            .header("Content-Type", "application/toml")
+            .path("/v1/_i?staged=true")
+            .body(&scaffold.invoice)
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::ACCEPTED,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let create_res: crate::InvoiceCreateResponse =
+            toml::from_slice(res.body()).expect("should be valid invoice response TOML");
+        let id = create_res.invoice.bindle.id;
+        assert_eq!(
+            create_res.missing.unwrap_or_default().len(),
+            scaffold.parcel_files.len(),
+            "All parcels should be missing"
+        );
+
+        let res = warp::test::request()
+            .method("POST")
+            .header("Content-Type", "application/toml")
+            .path("/v1/_i?staged=true")
+            .body(&scaffold.invoice)
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::CONFLICT,
+            "A draft should not be created twice"
+        );
+
+        let mut files = scaffold.parcel_files.values();
+        let first = files.next().expect("Scaffold should have parcels");
+        let res = warp::test::request()
+            .method("POST")
+            .path(&format!("/v1/_i/{}@{}", id, first.sha))
+            .body(first.data.clone())
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+
+        // The draft should stay hidden while parcels are still missing
+        let res = warp::test::request()
+            .path(&format!("/v1/_i/{}", id))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::NOT_FOUND);
+        let usage = quotas.usage();
+        assert_eq!(
+            (0, 0),
+            (usage[0].invoices, usage[0].bytes),
+            "Drafts should not count towards quotas"
+        );
+        let res = warp::test::request()
+            .path(&format!("/v1/_q?q={}", id.name()))
+            .reply(&api)
+            .await;
+        let matches: crate::Matches =
+            toml::from_slice(res.body()).expect("Unable to deserialize response");
+        assert!(
+            matches.invoices.is_empty(),
+            "Drafts should not be returned by queries"
+        );
+
+        for file in files {
+            let res = warp::test::request()
+                .method("POST")
+                .path(&format!("/v1/_i/{}@{}", id, file.sha))
+                .body(file.data.clone())
+                .reply(&api)
+                .await;
+            assert_eq!(
+                res.status(),
+                warp::http::StatusCode::OK,
+                "Body: {}",
+                String::from_utf8_lossy(res.body())
+            );
+        }
+
+        // Uploading the last parcel should publish the invoice
+        let res = warp::test::request()
+            .path(&format!("/v1/_i/{}", id))
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let res = warp::test::request()
+            .path(&format!("/v1/_q?q={}", id.name()))
+            .reply(&api)
+            .await;
+        let matches: crate::Matches =
+            toml::from_slice(res.body()).expect("Unable to deserialize response");
+        assert_eq!(
+            matches.invoices.len(),
+            1,
+            "Published invoice should be indexed"
+        );
+        let usage = quotas.usage();
+        assert_eq!(
+            (1, total),
+            (usage[0].invoices, usage[0].bytes),
+            "Published invoice should count towards quotas"
+        );
+
+        // Drafts whose parcels are never uploaded should expire
+        let mut inv: crate::Invoice = Scaffold::from(scaffold).invoice;
+        inv.bindle.id = "another.com/bindle/1.0.0".try_into().unwrap();
+        inv.parcel.as_mut().unwrap()[0].label.sha256 = "abc123".to_owned();
+        let (draft, missing) = store
+            .create_draft_invoice(NoopSigned(NoopVerified(inv)))
+            .await
+            .expect("Should be able to create draft");
+        assert_eq!(
+            missing.len(),
+            1,
+            "Only the changed parcel should be missing"
+        );
+        assert!(store
+            .expire_draft_invoices(std::time::Duration::from_secs(60 * 60))
+            .await
+            .expect("Should be able to expire drafts")
+            .is_empty());
+        let expired = store
+            .expire_draft_invoices(std::time::Duration::ZERO)
+            .await
+            .expect("Should be able to expire drafts");
+        assert_eq!(vec![draft.bindle.id.clone()], expired);
+        assert!(matches!(
+            store.get_draft_invoice(&draft.bindle.id).await,
+            Err(crate::provider::ProviderError::NotFound)
+        ));
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_host_signed<T>(
+        #[values(testing::setup(), testing::setup_embedded())]
+        #[future]
+        provider_setup: (T, StrictEngine, MockKeyStore),
+    ) where
+        T: Provider + Clone + Send + Sync + 'static,
+    {
+        let (store, index, ks) = provider_setup.await;
+        let scaffold = testing::RawScaffold::load("valid_v1").await;
+
+        let api = super::routes::api(
+            store,
+            index,
+            AlwaysAuthenticate,
+            AlwaysAuthorize,
+            ks,
+            VerificationStrategy::default(),
+            scaffold.keyring.clone(),
+            Default::default(),
+            Default::default(),
+        );
+
+        // Create a valid invoice and make sure the returned invoice is signed
+        let res = warp::test::request()
+            .method("POST")
+            .header("Content-Type", "application/toml")
+            .path("/v1/_i")
+            .body(&scaffold.invoice)
+            .reply(&api)
+            .await;
+
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::ACCEPTED,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let create_res: crate::InvoiceCreateResponse =
+            toml::from_slice(res.body()).expect("should be valid invoice response TOML");
+
+        assert!(
+            create_res
+                .invoice
+                .signature
+                .unwrap_or_default()
+                .into_iter()
+                .any(|sig| matches!(sig.role, crate::SignatureRole::Host)),
+            "Newly created invoice should be signed by the host"
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_anonymous_get<T>(
+        #[values(testing::setup(), testing::setup_embedded())]
+        #[future]
+        provider_setup: (T, StrictEngine, MockKeyStore),
+    ) where
+        T: Provider + Clone + Send + Sync + 'static,
+    {
+        let (store, index, ks) = provider_setup.await;
+        let scaffold = testing::RawScaffold::load("valid_v1").await;
 
         let api = super::routes::api(
             store,
@@ -751,6 +1415,8 @@ mod test {
             ks,
             VerificationStrategy::default(),
             scaffold.keyring.clone(),
+            Default::default(),
+            Default::default(),
         );
 
         // Creating the invoice without a token should fail
@@ -841,6 +1507,8 @@ mod test {
             keystore.clone(),
             VerificationStrategy::default(),
             KeyRing::default(),
+            Default::default(),
+            Default::default(),
         );
 
         // Creating the invoice without a token should fail
@@ -910,4 +1578,365 @@ mod test {
             String::from_utf8_lossy(res.body())
         );
     }
+
+    #[tokio::test]
+    async fn test_quotas() {
+        let bindles = testing::load_all_files().await;
+        let (store, index, ks) = testing::setup().await;
+        let valid_v1 = bindles.get("valid_v1").expect("Missing scaffold");
+        let valid_v2 = bindles.get("valid_v2").expect("Missing scaffold");
+        let authn = crate::authn::http_basic::HttpBasic::from_file("test/data/htpasswd")
+            .await
+            .expect("Unable to load htpasswd file");
+        let auth_header = format!(
+            "Basic {}",
+            base64::engine::general_purpose::STANDARD.encode(b"admin:sw0rdf1sh")
+        );
+
+        // Only leave enough room for all but the largest parcel
+        let mut parcels: Vec<_> = valid_v2.parcel_files.values().collect();
+        parcels.sort_by_key(|p| p.data.len());
+        let total: u64 = parcels.iter().map(|p| p.data.len() as u64).sum();
+        let quotas = super::quota::QuotaTracker::new(
+            super::quota::QuotaConfig {
+                quota: vec![super::quota::Quota {
+                    prefix: "enterprise.com/".to_owned(),
+                    max_bytes: Some(total - 1),
+                    max_invoices: Some(1),
+                }],
+            },
+            &store,
+        )
+        .await
+        .expect("Unable to create quota tracker");
+
+        let api = super::routes::api(
+            store,
+            index,
+            authn,
+            crate::authz::admin::AdminAuthorizer::new(
+                crate::authz::anonymous_get::AnonymousGet,
+                vec!["admin".to_owned()],
+            ),
+            ks,
+            VerificationStrategy::default(),
+            valid_v2.keyring.clone(),
+            quotas,
+            Default::default(),
+        );
+
+        let res = warp::test::request()
+            .method("POST")
+            .header("Authorization", &auth_header)
+            .header("Content-Type", "application/toml")
+            .path("/v1/_i")
+            .body(&valid_v2.invoice)
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::ACCEPTED,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let create_res: crate::InvoiceCreateResponse =
+            toml::from_slice(res.body()).expect("should be valid invoice response TOML");
+
+        let (last, rest) = parcels.split_last().unwrap();
+        for file in rest {
+            let res = warp::test::request()
+                .method("POST")
+                .header("Authorization", &auth_header)
+                .path(&format!(
+                    "/v1/_i/{}@{}",
+                    create_res.invoice.bindle.id, file.sha
+                ))
+                .body(file.data.clone())
+                .reply(&api)
+                .await;
+            assert_eq!(
+                res.status(),
+                warp::http::StatusCode::OK,
+                "Body: {}",
+                String::from_utf8_lossy(res.body())
+            );
+        }
+
+        // The last parcel should go over the byte limit
+        let res = warp::test::request()
+            .method("POST")
+            .header("Authorization", &auth_header)
+            .path(&format!(
+                "/v1/_i/{}@{}",
+                create_res.invoice.bindle.id, last.sha
+            ))
+            .body(last.data.clone())
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::INSUFFICIENT_STORAGE,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+
+        // Another invoice under the same prefix should go over the invoice limit
+        let res = warp::test::request()
+            .method("POST")
+            .header("Authorization", &auth_header)
+            .header("Content-Type", "application/toml")
+            .path("/v1/_i")
+            .body(&valid_v1.invoice)
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::INSUFFICIENT_STORAGE,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+
+        // Usage can only be viewed by admins
+        let res = warp::test::request().path("/v1/_quota").reply(&api).await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::FORBIDDEN,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+
+        let res = warp::test::request()
+            .header("Authorization", &auth_header)
+            .header("Accept", "application/json")
+            .path("/v1/_quota")
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let usage: crate::QuotaUsageResponse =
+            serde_json::from_slice(res.body()).expect("should be valid quota usage JSON");
+        assert_eq!(usage.quota.len(), 1);
+        assert_eq!(usage.quota[0].invoices, 1);
+        assert_eq!(usage.quota[0].bytes, total - last.data.len() as u64);
+        assert_eq!(usage.quota[0].max_bytes, Some(total - 1));
+
+        // Deleting the invoice should free up everything it used
+        let res = warp::test::request()
+            .method("DELETE")
+            .header("Authorization", &auth_header)
+            .path(&format!(
+                "/v1/_i/{}?purge=true",
+                create_res.invoice.bindle.id
+            ))
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let res = warp::test::request()
+            .header("Authorization", &auth_header)
+            .header("Accept", "application/json")
+            .path("/v1/_quota")
+            .reply(&api)
+            .await;
+        let usage: crate::QuotaUsageResponse =
+            serde_json::from_slice(res.body()).expect("should be valid quota usage JSON");
+        assert_eq!(usage.quota[0].invoices, 0);
+        assert_eq!(usage.quota[0].bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        let bindles = testing::load_all_files().await;
+        let (store, index, ks) = testing::setup().await;
+        let valid_v2 = bindles.get("valid_v2").expect("Missing scaffold");
+        let authn = CountingAuthenticator {
+            inner: crate::authn::http_basic::HttpBasic::from_file("test/data/htpasswd")
+                .await
+                .expect("Unable to load htpasswd file"),
+            calls: Default::default(),
+        };
+        let auth_header = format!(
+            "Basic {}",
+            base64::engine::general_purpose::STANDARD.encode(b"admin:sw0rdf1sh")
+        );
+        let audit_dir = tempfile::tempdir().expect("Unable to create tempdir");
+        let audit = super::audit::AuditLog::new(
+            super::audit::SledAuditSink::new(audit_dir.path())
+                .await
+                .expect("Unable to create audit sink"),
+        );
+
+        let api = super::routes::api(
+            store,
+            index,
+            authn.clone(),
+            crate::authz::admin::AdminAuthorizer::new(
+                crate::authz::anonymous_get::AnonymousGet,
+                vec!["admin".to_owned()],
+            ),
+            ks,
+            VerificationStrategy::default(),
+            valid_v2.keyring.clone(),
+            Default::default(),
+            audit,
+        );
+
+        let res = warp::test::request()
+            .method("POST")
+            .header("Authorization", &auth_header)
+            .header("Content-Type", "application/toml")
+            .path("/v1/_i")
+            .body(&valid_v2.invoice)
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::ACCEPTED,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let create_res: crate::InvoiceCreateResponse =
+            toml::from_slice(res.body()).expect("should be valid invoice response TOML");
+        let id = create_res.invoice.bindle.id;
+
+        let file = valid_v2.parcel_files.values().next().unwrap();
+        let res = warp::test::request()
+            .method("POST")
+            .header("Authorization", &auth_header)
+            .path(&format!("/v1/_i/{}@{}", id, file.sha))
+            .body(file.data.clone())
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+
+        let res = warp::test::request()
+            .method("DELETE")
+            .header("Authorization", &auth_header)
+            .path(&format!("/v1/_i/{}", id))
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+
+        // Failed operations should be recorded as well
+        let res = warp::test::request()
+            .method("POST")
+            .header("Authorization", &auth_header)
+            .header("Content-Type", "application/toml")
+            .path("/v1/_i")
+            .body(&valid_v2.invoice)
+            .reply(&api)
+            .await;
+        assert!(
+            res.status().is_client_error(),
+            "Creating an existing invoice should fail. Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+
+        // The log can only be viewed by admins
+        let res = warp::test::request().path("/v1/_audit").reply(&api).await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::FORBIDDEN,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+
+        let res = warp::test::request()
+            .header("Authorization", &auth_header)
+            .header("Accept", "application/json")
+            .path(&format!("/v1/_audit?bindle={}", id.name()))
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let log: crate::AuditLogResponse =
+            serde_json::from_slice(res.body()).expect("should be valid audit log JSON");
+        let actions: Vec<_> = log.entry.iter().map(|e| (e.action, e.outcome)).collect();
+        assert_eq!(
+            actions,
+            vec![
+                (
+                    crate::AuditAction::CreateInvoice,
+                    crate::AuditOutcome::Failure
+                ),
+                (
+                    crate::AuditAction::YankInvoice,
+                    crate::AuditOutcome::Success
+                ),
+                (
+                    crate::AuditAction::CreateParcel,
+                    crate::AuditOutcome::Success
+                ),
+                (
+                    crate::AuditAction::CreateInvoice,
+                    crate::AuditOutcome::Success
+                ),
+            ],
+            "Entries should be returned with the newest first"
+        );
+        assert!(log.entry.iter().all(|e| e.principal == "admin"));
+        assert_eq!(log.entry[2].parcel_sha.as_deref(), Some(file.sha.as_str()));
+
+        let res = warp::test::request()
+            .header("Authorization", &auth_header)
+            .header("Accept", "application/json")
+            .path("/v1/_audit?principal=nobody")
+            .reply(&api)
+            .await;
+        let log: crate::AuditLogResponse =
+            serde_json::from_slice(res.body()).expect("should be valid audit log JSON");
+        assert!(
+            log.entry.is_empty(),
+            "No entries should match the principal"
+        );
+
+        // Every authenticated request above (including the admin ones) should only have been
+        // authenticated once
+        assert_eq!(
+            6,
+            authn.calls.load(std::sync::atomic::Ordering::SeqCst),
+            "Requests should only be authenticated once"
+        );
+    }
+
+    /// An authenticator that counts how many times it was called
+    #[derive(Clone)]
+    struct CountingAuthenticator<A> {
+        inner: A,
+        calls: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl<A: crate::authn::Authenticator + Send + Sync> crate::authn::Authenticator
+        for CountingAuthenticator<A>
+    {
+        type Item = A::Item;
+
+        async fn authenticate(&self, auth_data: &str) -> anyhow::Result<Self::Item> {
+            self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            self.inner.authenticate(auth_data).await
+        }
+    }
 }
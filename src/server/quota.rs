@@ -0,0 +1,334 @@
+//! Storage quotas for bindles whose names start with a given prefix.
+//!
+//! Quotas are loaded from a TOML file that looks like this:
+//!
+//! ```toml
+//! [[quota]]
+//! prefix = "example.com/team-a/"
+//! max_bytes = 10737418240
+//! max_invoices = 1000
+//! ```
+//!
+//! The bytes used by a prefix are the total size of all distinct parcels referenced by the
+//! invoices under that prefix that have been stored. A parcel that is shared between bindles under
+//! different prefixes counts towards each of them. If the prefixes of multiple quotas match a
+//! bindle, all of them are enforced.
+//!
+//! Usage is calculated from the store when the server starts and then kept up to date as invoices
+//! and parcels are created and deleted. Draft invoices (and parcels uploaded for them) only count
+//! once the draft is published, so drafts that expire never affect usage. Quotas are checked before anything is stored, so
+//! concurrent uploads can go slightly over a limit
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use tokio_stream::StreamExt;
+use tracing::{debug, info, instrument};
+
+use crate::provider::{Provider, ProviderError, Result};
+use crate::{Id, Invoice, Label, QuotaUsage};
+
+/// The quotas to enforce, as loaded from a quota file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuotaConfig {
+    #[serde(default)]
+    pub quota: Vec<Quota>,
+}
+
+impl QuotaConfig {
+    /// Loads a quota config from the given TOML file
+    pub async fn load_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let raw = tokio::fs::read(path).await?;
+        let config: QuotaConfig = toml::from_slice(&raw)?;
+        for quota in config.quota.iter() {
+            if quota.prefix.is_empty() {
+                anyhow::bail!("Quota prefixes must not be empty");
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// A limit on the storage used by all bindles whose names start with a prefix
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Quota {
+    /// The bindle name prefix this quota applies to, such as `example.com/team-a/`
+    pub prefix: String,
+    /// The maximum total size of parcels in bytes. Unlimited if not set
+    pub max_bytes: Option<u64>,
+    /// The maximum number of invoices, including yanked invoices. Unlimited if not set
+    pub max_invoices: Option<u64>,
+}
+
+impl Quota {
+    fn applies_to(&self, id: &Id) -> bool {
+        id.name().starts_with(&self.prefix)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Usage {
+    invoices: u64,
+    bytes: u64,
+    /// The parcels that count towards the bytes used
+    parcels: HashSet<String>,
+    /// The number of invoices that reference each parcel, whether it has been stored or not
+    references: HashMap<String, u64>,
+}
+
+impl Usage {
+    fn add_invoice(&mut self, invoice: &Invoice) {
+        self.invoices += 1;
+        for parcel in invoice.parcel.iter().flatten() {
+            *self
+                .references
+                .entry(parcel.label.sha256.clone())
+                .or_default() += 1;
+        }
+    }
+
+    /// Removes the given invoice, along with any of its parcels that no other invoice references
+    fn remove_invoice(&mut self, invoice: &Invoice) {
+        self.invoices = self.invoices.saturating_sub(1);
+        for parcel in invoice.parcel.iter().flatten() {
+            let sha = &parcel.label.sha256;
+            match self.references.get_mut(sha) {
+                Some(count) if *count > 1 => *count -= 1,
+                _ => {
+                    self.references.remove(sha);
+                    if self.parcels.remove(sha) {
+                        self.bytes = self.bytes.saturating_sub(parcel.label.size);
+                    }
+                }
+            }
+        }
+    }
+
+    fn add_parcel(&mut self, label: &Label) {
+        if self.parcels.insert(label.sha256.clone()) {
+            self.bytes += label.size;
+        }
+    }
+}
+
+/// Keeps track of the usage of every configured quota. Cloning a tracker is cheap and all clones
+/// share the same usage. A default tracker has no quotas and allows everything
+#[derive(Debug, Clone, Default)]
+pub struct QuotaTracker {
+    quotas: Arc<Vec<Quota>>,
+    usage: Arc<Mutex<Vec<Usage>>>,
+}
+
+impl QuotaTracker {
+    /// Returns a tracker for the given quotas, with usage calculated from everything in the store
+    pub async fn new<P: Provider + Sync>(config: QuotaConfig, store: &P) -> Result<Self> {
+        let tracker = QuotaTracker {
+            usage: Arc::new(Mutex::new(
+                config.quota.iter().map(|_| Usage::default()).collect(),
+            )),
+            quotas: Arc::new(config.quota),
+        };
+        tracker.refresh(store).await?;
+        Ok(tracker)
+    }
+
+    /// Returns true if no quotas are configured
+    pub fn is_empty(&self) -> bool {
+        self.quotas.is_empty()
+    }
+
+    /// Returns true if any quota applies to the given bindle
+    pub fn applies_to(&self, id: &Id) -> bool {
+        self.quotas.iter().any(|q| q.applies_to(id))
+    }
+
+    /// Recalculates the usage of all quotas from everything in the store. This replaces the usage
+    /// recorded in the meantime, so it shouldn't be called while the store is being changed
+    #[instrument(level = "trace", skip(self, store))]
+    pub async fn refresh<P: Provider + Sync>(&self, store: &P) -> Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        info!(quotas = self.quotas.len(), "Calculating quota usage");
+        let mut usage: Vec<Usage> = self.quotas.iter().map(|_| Usage::default()).collect();
+        let mut ids = store.list_invoices(true).await?;
+        while let Some(id) = ids.next().await {
+            let id = id?;
+            if !self.applies_to(&id) {
+                continue;
+            }
+            let invoice = match store.get_yanked_invoice(&id).await {
+                Ok(inv) => inv,
+                // The invoice was deleted after it was listed
+                Err(ProviderError::NotFound) => continue,
+                Err(e) => return Err(e),
+            };
+            let mut stored = Vec::new();
+            for parcel in invoice.parcel.iter().flatten() {
+                if store.parcel_exists(&id, &parcel.label.sha256).await? {
+                    stored.push(&parcel.label);
+                }
+            }
+            for (quota, usage) in self.quotas.iter().zip(usage.iter_mut()) {
+                if quota.applies_to(&id) {
+                    usage.add_invoice(&invoice);
+                    stored.iter().for_each(|label| usage.add_parcel(label));
+                }
+            }
+        }
+        *self.lock() = usage;
+        debug!("Finished calculating quota usage");
+        Ok(())
+    }
+
+    /// Returns the current usage of every quota
+    pub fn usage(&self) -> Vec<QuotaUsage> {
+        let usage = self.lock();
+        self.quotas
+            .iter()
+            .zip(usage.iter())
+            .map(|(quota, usage)| QuotaUsage {
+                prefix: quota.prefix.clone(),
+                invoices: usage.invoices,
+                bytes: usage.bytes,
+                max_invoices: quota.max_invoices,
+                max_bytes: quota.max_bytes,
+            })
+            .collect()
+    }
+
+    /// Checks whether a new invoice with the given ID can be created. Returns
+    /// `ProviderError::QuotaExceeded` if that would go over the invoice limit of a quota or if a
+    /// quota is already out of space
+    pub fn check_invoice(&self, id: &Id) -> Result<()> {
+        let usage = self.lock();
+        for (quota, usage) in self.quotas.iter().zip(usage.iter()) {
+            if !quota.applies_to(id) {
+                continue;
+            }
+            if let Some(max) = quota.max_invoices {
+                if usage.invoices >= max {
+                    return Err(ProviderError::QuotaExceeded(format!(
+                        "bindles under {} are limited to {} invoices",
+                        quota.prefix, max
+                    )));
+                }
+            }
+            if let Some(max) = quota.max_bytes {
+                if usage.bytes >= max {
+                    return Err(ProviderError::QuotaExceeded(format!(
+                        "bindles under {} have used all of their {} bytes",
+                        quota.prefix, max
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Records a newly created invoice. Parcels that aren't in `missing` are already stored, so
+    /// they count towards the quota right away
+    pub fn record_invoice(&self, invoice: &Invoice, missing: &[Label]) {
+        let id = &invoice.bindle.id;
+        let mut usage = self.lock();
+        for (quota, usage) in self.quotas.iter().zip(usage.iter_mut()) {
+            if !quota.applies_to(id) {
+                continue;
+            }
+            usage.add_invoice(invoice);
+            invoice
+                .parcel
+                .iter()
+                .flatten()
+                .filter(|p| !missing.iter().any(|l| l.sha256 == p.label.sha256))
+                .for_each(|p| usage.add_parcel(&p.label));
+        }
+    }
+
+    /// Records a deleted invoice. Its parcels stop counting towards a quota once no other invoice
+    /// under the prefix references them
+    pub fn record_deleted_invoice(&self, invoice: &Invoice) {
+        let id = &invoice.bindle.id;
+        let mut usage = self.lock();
+        for (quota, usage) in self.quotas.iter().zip(usage.iter_mut()) {
+            if quota.applies_to(id) {
+                usage.remove_invoice(invoice);
+            }
+        }
+    }
+
+    /// Checks whether the parcel with the given label can be stored for the given bindle. Returns
+    /// `ProviderError::QuotaExceeded` if that would go over the byte limit of a quota
+    pub fn check_parcel(&self, id: &Id, label: &Label) -> Result<()> {
+        let usage = self.lock();
+        for (quota, usage) in self.quotas.iter().zip(usage.iter()) {
+            if !quota.applies_to(id) || usage.parcels.contains(&label.sha256) {
+                continue;
+            }
+            if let Some(max) = quota.max_bytes {
+                if usage.bytes.saturating_add(label.size) > max {
+                    return Err(ProviderError::QuotaExceeded(format!(
+                        "storing a parcel of {} bytes would exceed the limit of {} bytes for bindles under {} ({} bytes used)",
+                        label.size, max, quota.prefix, usage.bytes
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Records a newly stored parcel for the given bindle
+    pub fn record_parcel(&self, id: &Id, label: &Label) {
+        let mut usage = self.lock();
+        for (quota, usage) in self.quotas.iter().zip(usage.iter_mut()) {
+            if quota.applies_to(id) {
+                usage.add_parcel(label);
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Usage>> {
+        self.usage.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing;
+
+    #[tokio::test]
+    async fn test_deleting_invoice_keeps_shared_parcels() {
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let first = scaffold.invoice;
+        let mut second = first.clone();
+        second.bindle.id = format!("{}/9.9.9", first.bindle.id.name()).parse().unwrap();
+        let total: u64 = first.parcel.iter().flatten().map(|p| p.label.size).sum();
+
+        let tracker = QuotaTracker {
+            quotas: Arc::new(vec![Quota {
+                prefix: first.bindle.id.name().to_owned(),
+                max_bytes: None,
+                max_invoices: None,
+            }]),
+            usage: Arc::new(Mutex::new(vec![Usage::default()])),
+        };
+        tracker.record_invoice(&first, &[]);
+        tracker.record_invoice(&second, &[]);
+        assert_eq!(tracker.usage()[0].invoices, 2);
+        assert_eq!(tracker.usage()[0].bytes, total);
+
+        // The parcels are still referenced by the other invoice
+        tracker.record_deleted_invoice(&first);
+        assert_eq!(tracker.usage()[0].invoices, 1);
+        assert_eq!(tracker.usage()[0].bytes, total);
+
+        tracker.record_deleted_invoice(&second);
+        assert_eq!(tracker.usage()[0].invoices, 0);
+        assert_eq!(tracker.usage()[0].bytes, 0);
+    }
+}
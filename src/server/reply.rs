@@ -123,7 +123,11 @@ pub fn into_reply(error: ProviderError) -> warp::reply::WithStatus<SerializedDat
             // Remap the error in the case this is a not found error
             return reply_from_error(ProviderError::NotFound, StatusCode::NOT_FOUND);
         }
-        ProviderError::Exists | ProviderError::WriteInProgress => StatusCode::CONFLICT,
+        ProviderError::Exists
+        | ProviderError::WriteInProgress
+        | ProviderError::OffsetMismatch(_) => StatusCode::CONFLICT,
+        ProviderError::Unsupported => StatusCode::NOT_IMPLEMENTED,
+        ProviderError::QuotaExceeded(_) => StatusCode::INSUFFICIENT_STORAGE,
         ProviderError::Malformed(_)
         | ProviderError::Unserializable(_)
         | ProviderError::DigestMismatch
@@ -2,10 +2,15 @@ use std::sync::Arc;
 
 use warp::Filter;
 
-use crate::{invoice::HealthResponse, server::filters, signature::KeyRing};
+use crate::{
+    invoice::HealthResponse,
+    server::{audit::AuditLog, filters, quota::QuotaTracker},
+    signature::KeyRing,
+};
 
 /// A helper function that aggregates all routes into a complete API filter. If you only wish to
 /// serve specific endpoints or versions, you can assemble them with the individual submodules
+#[allow(clippy::too_many_arguments)]
 pub fn api<P, I, Authn, Authz, S>(
     store: P,
     index: I,
@@ -14,6 +19,8 @@ pub fn api<P, I, Authn, Authz, S>(
     secret_store: S,
     verification_strategy: crate::VerificationStrategy,
     keyring: KeyRing,
+    quotas: QuotaTracker,
+    audit: AuditLog,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
 where
     P: crate::provider::Provider + Clone + Send + Sync + 'static,
@@ -32,46 +39,119 @@ where
     // Use an Arc to avoid a possibly expensive clone of the keyring on every API call
     let wrapped_keyring = Arc::new(keyring);
     warp::path("v1")
-        .and(filters::authenticate_and_authorize(authn.clone(), authz))
-        .untuple_one()
         .and(
-            v1::invoice::query(index)
-                .or(v1::invoice::create_toml(
-                    store.clone(),
-                    secret_store.clone(),
-                    verification_strategy.clone(),
-                    wrapped_keyring.clone(),
-                ))
-                .boxed()
-                .or(v1::invoice::create_json(
-                    store.clone(),
-                    secret_store.clone(),
-                    verification_strategy,
-                    wrapped_keyring,
-                ))
-                .boxed()
-                .or(v1::invoice::get(store.clone()))
-                .boxed()
-                .or(v1::invoice::head(store.clone()))
-                .boxed()
-                .or(v1::invoice::yank(store.clone()))
-                .boxed()
-                .or(v1::parcel::create(store.clone()))
-                .boxed()
-                .or(v1::parcel::get(store.clone()))
-                .boxed()
-                .or(v1::parcel::head(store.clone()))
-                .boxed()
-                .or(v1::relationships::get_missing_parcels(store))
-                .boxed()
-                .or(v1::auth::login(
-                    authn.client_id().to_owned(),
-                    authn.auth_url().to_owned(),
-                    authn.token_url().to_owned(),
-                ))
-                .boxed()
-                .or(v1::keyring::host_keys(secret_store))
-                .boxed(),
+            // These routes record who made the request in the audit log, so they authenticate and
+            // authorize it themselves rather than doing it a second time. They handle their own
+            // auth failures so that a rejected request doesn't get authenticated again by the
+            // routes below
+            v1::invoice::create_toml(
+                store.clone(),
+                quotas.clone(),
+                audit.clone(),
+                authn.clone(),
+                authz.clone(),
+                secret_store.clone(),
+                verification_strategy.clone(),
+                wrapped_keyring.clone(),
+            )
+            .boxed()
+            .or(v1::invoice::create_json(
+                store.clone(),
+                quotas.clone(),
+                audit.clone(),
+                authn.clone(),
+                authz.clone(),
+                secret_store.clone(),
+                verification_strategy,
+                wrapped_keyring,
+            ))
+            .boxed()
+            // Purging must come before yanking as they share the same path and method
+            .or(v1::invoice::purge(
+                store.clone(),
+                quotas.clone(),
+                audit.clone(),
+                authn.clone(),
+                authz.clone(),
+            ))
+            .boxed()
+            .or(v1::invoice::yank(
+                store.clone(),
+                audit.clone(),
+                authn.clone(),
+                authz.clone(),
+            ))
+            .boxed()
+            .or(v1::parcel::create(
+                store.clone(),
+                quotas.clone(),
+                audit.clone(),
+                authn.clone(),
+                authz.clone(),
+            ))
+            .boxed()
+            .or(v1::upload::finish(
+                store.clone(),
+                quotas.clone(),
+                audit.clone(),
+                authn.clone(),
+                authz.clone(),
+            ))
+            .boxed()
+            .or(v1::quota::usage(
+                quotas.clone(),
+                authn.clone(),
+                authz.clone(),
+            ))
+            .boxed()
+            .or(v1::audit::query(
+                audit.clone(),
+                authn.clone(),
+                authz.clone(),
+            ))
+            .boxed()
+            .or(v1::auth::login(
+                authn.client_id().to_owned(),
+                authn.auth_url().to_owned(),
+                authn.token_url().to_owned(),
+                audit,
+                authn.clone(),
+                authz.clone(),
+            ))
+            .boxed()
+            .recover(filters::handle_authn_rejection)
+            .recover(filters::handle_authz_rejection)
+            .boxed()
+            .or(filters::authenticate_and_authorize(authn, authz)
+                .map(|_principal| ())
+                .untuple_one()
+                .and(
+                    v1::invoice::query(index)
+                        .or(v1::invoice::get(store.clone()))
+                        .boxed()
+                        .or(v1::invoice::head(store.clone()))
+                        .boxed()
+                        .or(v1::parcel::get(store.clone()))
+                        .boxed()
+                        .or(v1::parcel::head(store.clone()))
+                        .boxed()
+                        .or(v1::parcel::head_stored(store.clone()))
+                        .boxed()
+                        .or(v1::parcel::existing(store.clone()))
+                        .boxed()
+                        .or(v1::upload::start(store.clone(), quotas))
+                        .boxed()
+                        .or(v1::upload::status(store.clone()))
+                        .boxed()
+                        .or(v1::upload::chunk(store.clone()))
+                        .boxed()
+                        .or(v1::upload::abort(store.clone()))
+                        .boxed()
+                        .or(v1::relationships::get_missing_parcels(store))
+                        .boxed()
+                        .or(v1::keyring::host_keys(secret_store))
+                        .boxed(),
+                )),
         )
         .or(health)
         .boxed()
@@ -85,26 +165,39 @@ pub mod v1 {
     use crate::provider::Provider;
     use crate::search::Search;
     use crate::server::handlers::v1::*;
-    use crate::server::{filters, routes::with_store};
+    use crate::server::{
+        filters,
+        routes::{with_audit, with_quotas, with_store},
+    };
 
     use warp::Filter;
 
     pub mod auth {
         use super::*;
 
+        use crate::server::audit::AuditLog;
         use crate::LoginParams;
 
-        pub fn login(
+        pub fn login<Authn, Authz>(
             provider_client_id: String,
             device_auth_url: String,
             token_url: String,
-        ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+            audit: AuditLog,
+            authn: Authn,
+            authz: Authz,
+        ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+        where
+            Authn: crate::authn::Authenticator + Clone + Send + Sync + 'static,
+            Authz: crate::authz::Authorizer + Clone + Send + Sync + 'static,
+        {
             warp::path("login")
                 .and(warp::get())
                 .and(warp::query::<LoginParams>())
                 .and(warp::any().map(move || provider_client_id.clone()))
                 .and(warp::any().map(move || device_auth_url.clone()))
                 .and(warp::any().map(move || token_url.clone()))
+                .and(with_audit(audit))
+                .and(filters::authenticate_and_authorize(authn, authz))
                 .and(warp::header::optional::<String>("accept"))
                 .and_then(crate::server::handlers::v1::login)
                 .boxed()
@@ -113,7 +206,7 @@ pub mod v1 {
 
     pub mod invoice {
         use crate::{
-            server::routes::with_secret_store,
+            server::{audit::AuditLog, quota::QuotaTracker, routes::with_secret_store},
             signature::{KeyRing, SecretKeyStorage},
         };
 
@@ -135,8 +228,13 @@ pub mod v1 {
                 .and_then(query_invoices)
         }
 
-        pub fn create_toml<P, S>(
+        #[allow(clippy::too_many_arguments)]
+        pub fn create_toml<P, S, Authn, Authz>(
             store: P,
+            quotas: QuotaTracker,
+            audit: AuditLog,
+            authn: Authn,
+            authz: Authz,
             secret_store: S,
             verification_strategy: crate::VerificationStrategy,
             keyring: Arc<KeyRing>,
@@ -144,21 +242,39 @@ pub mod v1 {
         where
             P: Provider + Clone + Send + Sync,
             S: SecretKeyStorage + Clone + Send + Sync,
+            Authn: crate::authn::Authenticator + Clone + Send + Sync,
+            Authz: crate::authz::Authorizer + Clone + Send + Sync,
         {
             warp::path("_i")
                 .and(warp::path::end())
                 .and(warp::post())
+                // Requests without a content type are handled by the JSON route. This has to be
+                // checked before authenticating, so the request isn't authenticated again there
+                .and(
+                    warp::header::<String>("Content-Type")
+                        .map(|_| ())
+                        .untuple_one(),
+                )
                 .and(with_store(store))
+                .and(with_quotas(quotas))
+                .and(with_audit(audit))
+                .and(filters::authenticate_and_authorize(authn, authz))
                 .and(with_secret_store(secret_store))
                 .and(warp::any().map(move || verification_strategy.clone()))
                 .and(warp::any().map(move || keyring.clone()))
+                .and(warp::query::<filters::CreateInvoiceQuery>())
                 .and(filters::toml())
                 .and(warp::header::optional::<String>("accept"))
                 .and_then(create_invoice)
                 .recover(filters::handle_deserialize_rejection)
         }
-        pub fn create_json<P, S>(
+        #[allow(clippy::too_many_arguments)]
+        pub fn create_json<P, S, Authn, Authz>(
             store: P,
+            quotas: QuotaTracker,
+            audit: AuditLog,
+            authn: Authn,
+            authz: Authz,
             secret_store: S,
             verification_strategy: crate::VerificationStrategy,
             keyring: Arc<KeyRing>,
@@ -166,14 +282,20 @@ pub mod v1 {
         where
             P: Provider + Clone + Send + Sync,
             S: SecretKeyStorage + Clone + Send + Sync,
+            Authn: crate::authn::Authenticator + Clone + Send + Sync,
+            Authz: crate::authz::Authorizer + Clone + Send + Sync,
         {
             warp::path("_i")
                 .and(warp::path::end())
                 .and(warp::post())
                 .and(with_store(store))
+                .and(with_quotas(quotas))
+                .and(with_audit(audit))
+                .and(filters::authenticate_and_authorize(authn, authz))
                 .and(with_secret_store(secret_store))
                 .and(warp::any().map(move || verification_strategy.clone()))
                 .and(warp::any().map(move || keyring.clone()))
+                .and(warp::query::<filters::CreateInvoiceQuery>())
                 .and(warp::body::json())
                 .and(warp::header::optional::<String>("accept"))
                 .and_then(create_invoice)
@@ -209,16 +331,49 @@ pub mod v1 {
                 .and_then(head_invoice)
         }
 
-        pub fn yank<P>(
+        pub fn purge<P, Authn, Authz>(
             store: P,
+            quotas: QuotaTracker,
+            audit: AuditLog,
+            authn: Authn,
+            authz: Authz,
         ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
         where
             P: Provider + Clone + Send + Sync,
+            Authn: crate::authn::Authenticator + Clone + Send + Sync,
+            Authz: crate::authz::Authorizer + Clone + Send + Sync,
         {
             warp::path("_i")
                 .and(warp::path::tail())
                 .and(warp::delete())
+                .and(filters::purge())
                 .and(with_store(store))
+                .and(with_quotas(quotas))
+                .and(with_audit(audit))
+                .and(filters::authenticate_and_authorize_admin(authn, authz))
+                .and(warp::header::optional::<String>("accept"))
+                .and_then(delete_invoice)
+                // Handle authz failures here so an unauthorized purge doesn't fall through to a yank
+                .recover(filters::handle_authz_rejection)
+        }
+
+        pub fn yank<P, Authn, Authz>(
+            store: P,
+            audit: AuditLog,
+            authn: Authn,
+            authz: Authz,
+        ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+        where
+            P: Provider + Clone + Send + Sync,
+            Authn: crate::authn::Authenticator + Clone + Send + Sync,
+            Authz: crate::authz::Authorizer + Clone + Send + Sync,
+        {
+            warp::path("_i")
+                .and(warp::path::tail())
+                .and(warp::delete())
+                .and(with_store(store))
+                .and(with_audit(audit))
+                .and(filters::authenticate_and_authorize(authn, authz))
                 .and(warp::header::optional::<String>("accept"))
                 .and_then(yank_invoice)
         }
@@ -227,16 +382,27 @@ pub mod v1 {
     pub mod parcel {
         use super::*;
 
-        pub fn create<P>(
+        use crate::server::{audit::AuditLog, quota::QuotaTracker};
+
+        pub fn create<P, Authn, Authz>(
             store: P,
+            quotas: QuotaTracker,
+            audit: AuditLog,
+            authn: Authn,
+            authz: Authz,
         ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
         where
             P: Provider + Clone + Send + Sync,
+            Authn: crate::authn::Authenticator + Clone + Send + Sync,
+            Authz: crate::authz::Authorizer + Clone + Send + Sync,
         {
             filters::parcel()
                 .and(warp::post())
                 .and(warp::body::stream())
                 .and(with_store(store))
+                .and(with_quotas(quotas))
+                .and(with_audit(audit))
+                .and(filters::authenticate_and_authorize(authn, authz))
                 .and(warp::header::optional::<String>("accept"))
                 .and_then(create_parcel)
         }
@@ -250,6 +416,7 @@ pub mod v1 {
             filters::parcel()
                 .and(warp::get())
                 .and(with_store(store))
+                .and(warp::header::optional::<String>("range"))
                 .and_then(get_parcel)
         }
 
@@ -264,6 +431,120 @@ pub mod v1 {
                 .and(with_store(store))
                 .and_then(head_parcel)
         }
+
+        /// Checks if a parcel exists, regardless of which bindles it belongs to
+        pub fn head_stored<P>(
+            store: P,
+        ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+        where
+            P: Provider + Clone + Send + Sync,
+        {
+            warp::path!("_p" / String)
+                .and(warp::head())
+                .and(with_store(store))
+                .and_then(head_stored_parcel)
+        }
+
+        /// Returns which of a list of parcels exist, regardless of which bindles they belong to
+        pub fn existing<P>(
+            store: P,
+        ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+        where
+            P: Provider + Clone + Send + Sync,
+        {
+            warp::path("_p")
+                .and(warp::path::end())
+                .and(warp::post())
+                .and(with_store(store))
+                .and(filters::toml())
+                .and(warp::header::optional::<String>("accept"))
+                .and_then(existing_parcels)
+                .recover(filters::handle_deserialize_rejection)
+        }
+    }
+
+    pub mod upload {
+        use super::*;
+
+        use crate::server::{audit::AuditLog, quota::QuotaTracker};
+
+        pub fn start<P>(
+            store: P,
+            quotas: QuotaTracker,
+        ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+        where
+            P: Provider + Clone + Send + Sync,
+        {
+            filters::upload()
+                .and(warp::post())
+                .and(with_store(store))
+                .and(with_quotas(quotas))
+                .and(warp::header::optional::<String>("accept"))
+                .and_then(start_upload)
+        }
+
+        pub fn status<P>(
+            store: P,
+        ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+        where
+            P: Provider + Clone + Send + Sync,
+        {
+            filters::upload()
+                .and(warp::get())
+                .and(with_store(store))
+                .and(warp::header::optional::<String>("accept"))
+                .and_then(get_upload)
+        }
+
+        pub fn chunk<P>(
+            store: P,
+        ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+        where
+            P: Provider + Clone + Send + Sync,
+        {
+            filters::upload()
+                .and(warp::patch())
+                .and(warp::query::<filters::UploadQuery>())
+                .and(warp::body::stream())
+                .and(with_store(store))
+                .and(warp::header::optional::<String>("accept"))
+                .and_then(upload_chunk)
+        }
+
+        pub fn finish<P, Authn, Authz>(
+            store: P,
+            quotas: QuotaTracker,
+            audit: AuditLog,
+            authn: Authn,
+            authz: Authz,
+        ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+        where
+            P: Provider + Clone + Send + Sync,
+            Authn: crate::authn::Authenticator + Clone + Send + Sync,
+            Authz: crate::authz::Authorizer + Clone + Send + Sync,
+        {
+            filters::upload()
+                .and(warp::put())
+                .and(with_store(store))
+                .and(with_quotas(quotas))
+                .and(with_audit(audit))
+                .and(filters::authenticate_and_authorize(authn, authz))
+                .and(warp::header::optional::<String>("accept"))
+                .and_then(finish_upload)
+        }
+
+        pub fn abort<P>(
+            store: P,
+        ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+        where
+            P: Provider + Clone + Send + Sync,
+        {
+            filters::upload()
+                .and(warp::delete())
+                .and(with_store(store))
+                .and(warp::header::optional::<String>("accept"))
+                .and_then(abort_upload)
+        }
     }
 
     pub mod relationships {
@@ -286,6 +567,61 @@ pub mod v1 {
         }
     }
 
+    pub mod quota {
+        use super::*;
+
+        use crate::server::quota::QuotaTracker;
+
+        pub fn usage<Authn, Authz>(
+            quotas: QuotaTracker,
+            authn: Authn,
+            authz: Authz,
+        ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+        where
+            Authn: crate::authn::Authenticator + Clone + Send + Sync,
+            Authz: crate::authz::Authorizer + Clone + Send + Sync,
+        {
+            warp::path("_quota")
+                .and(warp::path::end())
+                .and(warp::get())
+                .and(filters::authenticate_and_authorize_admin(authn, authz))
+                .map(|_principal| ())
+                .untuple_one()
+                .and(with_quotas(quotas))
+                .and(warp::header::optional::<String>("accept"))
+                .and_then(quota_usage)
+                .recover(filters::handle_authz_rejection)
+        }
+    }
+
+    pub mod audit {
+        use super::*;
+
+        use crate::server::audit::AuditLog;
+
+        pub fn query<Authn, Authz>(
+            audit: AuditLog,
+            authn: Authn,
+            authz: Authz,
+        ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+        where
+            Authn: crate::authn::Authenticator + Clone + Send + Sync,
+            Authz: crate::authz::Authorizer + Clone + Send + Sync,
+        {
+            warp::path("_audit")
+                .and(warp::path::end())
+                .and(warp::get())
+                .and(filters::authenticate_and_authorize_admin(authn, authz))
+                .map(|_principal| ())
+                .untuple_one()
+                .and(warp::query::<crate::AuditQuery>())
+                .and(with_audit(audit))
+                .and(warp::header::optional::<String>("accept"))
+                .and_then(audit_log)
+                .recover(filters::handle_authz_rejection)
+        }
+    }
+
     pub mod keyring {
         use super::*;
 
@@ -327,3 +663,15 @@ where
     // We have to clone for this to be Fn instead of FnOnce
     warp::any().map(move || store.clone())
 }
+
+pub(crate) fn with_quotas(
+    quotas: QuotaTracker,
+) -> impl Filter<Extract = (QuotaTracker,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || quotas.clone())
+}
+
+pub(crate) fn with_audit(
+    audit: AuditLog,
+) -> impl Filter<Extract = (AuditLog,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || audit.clone())
+}
@@ -149,9 +149,11 @@ impl StandaloneRead {
             .map(|(sha, path)| (sha, path, inv.bindle.id.clone(), (*client).clone()))
             .map(|(sha, path, bindle_id, client)| async move {
                 debug!(%sha, "Uploading parcel to server");
-                client
-                    .create_parcel_from_file(bindle_id, &sha, path)
-                    .await?;
+                match client.create_parcel_from_file(bindle_id, &sha, path).await {
+                    // Someone else uploaded the same parcel while we were working
+                    Ok(_) | Err(ClientError::ParcelAlreadyExists) => (),
+                    Err(e) => return Err(e),
+                }
                 debug!(%sha, "Finished uploading parcel to server");
                 Ok(())
             });
@@ -220,7 +222,7 @@ async fn create_or_get_invoice<T: TokenManager>(
             debug!(invoice_id = %id, "Invoice created");
             Ok(resp)
         }
-        Err(e) if matches!(e, crate::client::ClientError::InvoiceAlreadyExists) => {
+        Err(crate::client::ClientError::InvoiceAlreadyExists) => {
             info!(invoice_id = %id, "Invoice already exists on the bindle server. Fetching existing invoice and missing parcels list");
             let invoice = client.get_invoice(&id).await?;
             let missing = client.get_missing_parcels(id).await?;
@@ -416,8 +418,7 @@ impl StandaloneWrite {
             trace!(path = %path.display(), "Writing parcel");
 
             while let Some(b) = stream.next().await {
-                let b =
-                    b.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+                let b = b.map_err(|e| std::io::Error::other(e.to_string()))?;
                 file.write_all(&b).await?;
             }
             file.flush().await?;
@@ -33,7 +33,7 @@ async fn setup_data<T: TokenManager>(client: &Client<T>) {
                 .await
             {
                 Ok(_) => continue,
-                Err(e) if matches!(e, bindle::client::ClientError::ParcelAlreadyExists) => continue,
+                Err(bindle::client::ClientError::ParcelAlreadyExists) => continue,
                 Err(e) => panic!("Unable to insert parcel: {}", e),
             };
         }
@@ -354,6 +354,41 @@ async fn test_yank() {
     assert_status(output, "Should be able to yank a bindle");
 }
 
+#[tokio::test]
+async fn test_delete() {
+    let controller = TestController::new(BINARY_NAME).await;
+    setup_data(&controller.client).await;
+
+    let output = std::process::Command::new("cargo")
+        .args([
+            "run",
+            "--features",
+            "cli",
+            "--bin",
+            "bindle",
+            "--",
+            "delete",
+            "enterprise.com/warpcore/1.0.0",
+        ])
+        .env(ENV_BINDLE_URL, &controller.base_url)
+        .output()
+        .expect("Should be able to run command");
+
+    assert_status(output, "Should be able to delete a bindle");
+
+    match controller
+        .client
+        .get_yanked_invoice("enterprise.com/warpcore/1.0.0")
+        .await
+    {
+        Err(bindle::client::ClientError::InvoiceNotFound) => (),
+        res => panic!(
+            "Expected deleted invoice to not be found, got {:?}",
+            res.map(|_| ())
+        ),
+    }
+}
+
 #[tokio::test]
 async fn test_no_bindles() {
     let controller = TestController::new(BINARY_NAME).await;
@@ -6,7 +6,7 @@ use test_util::TestController;
 
 use std::convert::TryInto;
 
-use bindle::{signature::SecretKeyStorage, testing, SignatureRole};
+use bindle::{provider::Provider, signature::SecretKeyStorage, testing, SignatureRole};
 
 use tokio_stream::StreamExt;
 
@@ -136,6 +136,126 @@ async fn test_streaming_successful() {
     );
 }
 
+#[tokio::test]
+async fn test_resume_parcel_stream() {
+    let controller = TestController::new(BINARY_NAME).await;
+
+    let scaffold = testing::Scaffold::load("valid_v1").await;
+    let inv = controller
+        .client
+        .create_invoice(scaffold.invoice.clone())
+        .await
+        .expect("unable to create invoice")
+        .invoice;
+    let parcel = scaffold.parcel_files.get("parcel").expect("Missing parcel");
+    controller
+        .client
+        .create_parcel(&inv.bindle.id, &parcel.sha, parcel.data.clone())
+        .await
+        .expect("Unable to create parcel");
+
+    // Simulate a download that was interrupted partway through
+    let offset = 4;
+    let mut stream = controller
+        .client
+        .get_parcel_stream_from(&inv.bindle.id, &parcel.sha, offset as u64)
+        .await
+        .expect("unable to get parcel");
+
+    let mut data = parcel.data[..offset].to_vec();
+    while let Some(res) = stream.next().await {
+        let bytes = res.expect("Shouldn't get an error in stream");
+        data.extend(bytes);
+    }
+    assert_eq!(
+        data, parcel.data,
+        "Resumed parcel data should match the original"
+    );
+
+    // Resuming a download that already finished should return nothing
+    let mut stream = controller
+        .client
+        .get_parcel_stream_from(&inv.bindle.id, &parcel.sha, parcel.data.len() as u64)
+        .await
+        .expect("unable to get parcel");
+    assert!(
+        stream.next().await.is_none(),
+        "Stream should be empty when resuming from the end of the parcel"
+    );
+}
+
+#[tokio::test]
+async fn test_resume_parcel_upload() {
+    let controller = TestController::new(BINARY_NAME).await;
+
+    let root = std::env::var("CARGO_MANIFEST_DIR").expect("Unable to get project directory");
+    let base = std::path::PathBuf::from(root).join("tests/scaffolds/valid_v1");
+
+    let inv = controller
+        .client
+        .create_invoice_from_file(base.join("invoice.toml"))
+        .await
+        .expect("unable to create invoice")
+        .invoice;
+
+    let parcel_path = base.join("parcels/parcel.dat");
+    let parcel_sha = inv.parcel.expect("Should have parcels in invoice")[0]
+        .label
+        .sha256
+        .to_owned();
+    let data = tokio::fs::read(&parcel_path)
+        .await
+        .expect("Unable to read parcel data");
+
+    // Simulate an upload that was interrupted after the first chunk
+    let offset = controller
+        .client
+        .start_parcel_upload(&inv.bindle.id, &parcel_sha)
+        .await
+        .expect("Unable to start upload");
+    assert_eq!(0, offset, "A new upload should start at the beginning");
+    let offset = controller
+        .client
+        .write_parcel_chunk(
+            &inv.bindle.id,
+            &parcel_sha,
+            offset,
+            tokio_stream::once(Ok::<_, std::io::Error>(bytes::Bytes::copy_from_slice(
+                &data[..4],
+            ))),
+        )
+        .await
+        .expect("Unable to write chunk");
+    assert_eq!(4, offset, "Upload offset should include the written chunk");
+    assert_eq!(
+        4,
+        controller
+            .client
+            .parcel_upload_offset(&inv.bindle.id, &parcel_sha)
+            .await
+            .expect("Unable to get upload offset")
+    );
+
+    // Uploading the file should pick up where the previous upload left off
+    controller
+        .client
+        .create_parcel_from_file(&inv.bindle.id, &parcel_sha, &parcel_path)
+        .await
+        .expect("Unable to create parcel");
+
+    let mut stream = controller
+        .client
+        .get_parcel_stream(&inv.bindle.id, &parcel_sha)
+        .await
+        .expect("unable to get parcel");
+    let mut fetched = Vec::new();
+    while let Some(res) = stream.next().await {
+        let bytes = res.expect("Shouldn't get an error in stream");
+        fetched.extend(bytes);
+    }
+    assert_eq!(fetched, data, "Uploaded parcel data should match the file");
+}
+
 #[tokio::test]
 async fn test_already_created() {
     let controller = TestController::new(BINARY_NAME).await;
@@ -227,6 +347,106 @@ async fn test_missing() {
     }
 }
 
+#[tokio::test]
+async fn test_existing_parcels() {
+    let controller = TestController::new(BINARY_NAME).await;
+
+    let scaffold = testing::Scaffold::load("lotsa_parcels").await;
+    let mut parcels = scaffold.parcel_files.values();
+    let stored = parcels.next().expect("Scaffold should have parcels");
+    let missing = parcels.next().expect("Scaffold should have parcels");
+
+    let inv = controller
+        .client
+        .create_invoice(scaffold.invoice.clone())
+        .await
+        .expect("unable to create invoice")
+        .invoice;
+    controller
+        .client
+        .create_parcel(&inv.bindle.id, &stored.sha, stored.data.clone())
+        .await
+        .expect("Unable to create parcel");
+
+    assert!(
+        controller
+            .client
+            .has_parcel(&stored.sha)
+            .await
+            .expect("Should be able to check for a parcel"),
+        "Uploaded parcel should exist"
+    );
+    assert!(
+        !controller
+            .client
+            .has_parcel(&missing.sha)
+            .await
+            .expect("Should be able to check for a parcel"),
+        "Parcel that wasn't uploaded should not exist"
+    );
+
+    let existing = controller
+        .client
+        .existing_parcels(&[missing.sha.as_str(), stored.sha.as_str()])
+        .await
+        .expect("Should be able to check for parcels");
+    assert_eq!(existing, vec![stored.sha.clone()]);
+
+    // A proxy should ask the upstream server about all of the parcels at once
+    let proxy = bindle::proxy::Proxy::new(
+        controller.client.clone(),
+        bindle::SecretKeyEntry::new("test <test@example.com>", vec![SignatureRole::Proxy]),
+    );
+    let exists = proxy
+        .parcels_exist(&[missing.sha.as_str(), stored.sha.as_str(), "not-a-sha"])
+        .await
+        .expect("Should be able to check for parcels through a proxy");
+    assert_eq!(exists, vec![false, true, false]);
+}
+
+#[tokio::test]
+async fn test_list_invoices() {
+    let controller = TestController::new(BINARY_NAME).await;
+
+    let mut ids = Vec::new();
+    for name in ["valid_v1", "valid_v2"] {
+        let scaffold = testing::Scaffold::load(name).await;
+        let inv = controller
+            .client
+            .create_invoice(scaffold.invoice)
+            .await
+            .expect("unable to create invoice")
+            .invoice;
+        ids.push(inv.bindle.id);
+    }
+    controller
+        .client
+        .yank_invoice(&ids[0])
+        .await
+        .expect("unable to yank invoice");
+
+    let listed = controller
+        .client
+        .list_invoices(false)
+        .collect::<Result<Vec<_>, _>>()
+        .await
+        .expect("unable to list invoices");
+    assert_eq!(
+        vec![ids[1].clone()],
+        listed,
+        "Yanked invoices should not be listed"
+    );
+
+    let mut listed = controller
+        .client
+        .list_invoices(true)
+        .collect::<Result<Vec<_>, _>>()
+        .await
+        .expect("unable to list invoices");
+    listed.sort_by_key(|id| id.to_string());
+    assert_eq!(ids, listed, "All invoices should be listed");
+}
+
 #[tokio::test]
 async fn test_charset() {
     let controller = TestController::new(BINARY_NAME).await;
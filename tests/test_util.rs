@@ -19,6 +19,7 @@ pub const BINARY_NAME: &str = "bindle-server.exe";
 const SECRET_KEY_FILE: &str = "secret_keys.toml";
 const KEYRING_FILE: &str = "keyring.toml";
 
+#[allow(dead_code)]
 pub struct TestController {
     pub client: Client<NoToken>,
     pub base_url: String,